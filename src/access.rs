@@ -0,0 +1,72 @@
+//! Allow/deny rules for locations.
+//!
+//! A rule is `all`, an address or CIDR network, `country:XX` or
+//! `continent:XX` (ISO codes, matched against the GeoIP database).
+
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+use crate::cidr::Cidr;
+use crate::geoip::{GeoIp, Origin};
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum AccessRule {
+    All,
+    Network(Cidr),
+    Country(String),
+    Continent(String),
+}
+
+impl AccessRule {
+    pub fn is_geographic(&self) -> bool {
+        matches!(self, AccessRule::Country(_) | AccessRule::Continent(_))
+    }
+
+    fn matches(&self, ip: IpAddr, origin: &Origin) -> bool {
+        match self {
+            AccessRule::All => true,
+            AccessRule::Network(network) => network.contains(ip),
+            AccessRule::Country(code) => origin.country.as_deref() == Some(code.as_str()),
+            AccessRule::Continent(code) => origin.continent.as_deref() == Some(code.as_str()),
+        }
+    }
+}
+
+impl TryFrom<String> for AccessRule {
+    type Error = String;
+
+    fn try_from(rule: String) -> Result<AccessRule, String> {
+        if rule == "all" {
+            Ok(AccessRule::All)
+        } else if let Some(code) = rule.strip_prefix("country:") {
+            Ok(AccessRule::Country(code.to_ascii_uppercase()))
+        } else if let Some(code) = rule.strip_prefix("continent:") {
+            Ok(AccessRule::Continent(code.to_ascii_uppercase()))
+        } else {
+            rule.parse().map(AccessRule::Network)
+        }
+    }
+}
+
+/// Deny rules win over allow rules; a non-empty allow list admits only
+/// the clients it matches. The GeoIP database is consulted only when a
+/// geographic rule needs it.
+pub fn is_allowed(
+    allow: &[AccessRule],
+    deny: &[AccessRule],
+    ip: IpAddr,
+    geoip: Option<&GeoIp>,
+) -> bool {
+    let needs_origin = allow.iter().chain(deny).any(AccessRule::is_geographic);
+    let origin = match geoip {
+        Some(geoip) if needs_origin => geoip.lookup(ip),
+        _ => Origin::default(),
+    };
+
+    if deny.iter().any(|rule| rule.matches(ip, &origin)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|rule| rule.matches(ip, &origin))
+}
@@ -0,0 +1,694 @@
+//! Certificates from an ACME CA such as Let's Encrypt (RFC 8555), for the
+//! names given with `--acme-domain`.
+//!
+//! HTTP-01 challenges are answered from memory, on the `--https-redirect`
+//! listener and on the TLS port for CAs that follow its redirect. The
+//! account key, certificate and certificate key are kept in `--acme-dir`,
+//! and the certificate is renewed once less than `RENEW_BEFORE` of its
+//! validity is left; handshakes pick up a new one as soon as it is issued.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+};
+use rustls::client::ClientConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::RootCertStore;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsConnector;
+
+use crate::config::Config;
+use crate::date;
+use crate::der;
+use crate::http::Response;
+use crate::redirect;
+use crate::spool;
+
+/// Let's Encrypt's production directory, the default `--acme-directory`.
+pub const DEFAULT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Where CAs look for the answers to HTTP-01 challenges.
+pub const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Certificates are renewed when they expire sooner than this.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// How often the certificate's expiry is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// How long to wait after a failed order, well within the CA's rate limits.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Pause between checks of a pending authorization or order.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Checks before a pending authorization or order is given up on.
+const MAX_POLLS: usize = 60;
+
+/// Longest exchange with the CA.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// CA bundles of common systems, used unless `SSL_CERT_FILE` names one.
+const SYSTEM_ROOTS: [&str; 3] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+// Object identifiers of the certificate request, as DER contents.
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Key authorizations of pending challenges, by token.
+#[derive(Default)]
+pub struct Challenges {
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl Challenges {
+    /// The answer to a request for `CHALLENGE_PREFIX` followed by `token`.
+    pub fn answer(&self, token: &str) -> Option<Response> {
+        let tokens = self.tokens.lock().unwrap();
+        let authorization = tokens.get(token)?;
+        Some(Response::new(
+            200,
+            "application/octet-stream",
+            authorization.as_bytes(),
+        ))
+    }
+}
+
+/// The certificate handshakes use, replaced when it is renewed.
+#[derive(Debug, Default)]
+pub struct Certificates {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// Obtains and renews the certificate.
+pub struct Acme {
+    domains: Vec<String>,
+    dir: PathBuf,
+    directory: String,
+    email: Option<String>,
+    challenges: Arc<Challenges>,
+    certificates: Arc<Certificates>,
+}
+
+impl Acme {
+    pub fn new(
+        config: &Config,
+        challenges: Arc<Challenges>,
+        certificates: Arc<Certificates>,
+    ) -> Acme {
+        Acme {
+            domains: config.acme_domains.clone(),
+            dir: config.acme_dir.clone(),
+            directory: config.acme_directory.clone(),
+            email: config.acme_email.clone(),
+            challenges,
+            certificates,
+        }
+    }
+
+    /// Installs the stored certificate, if any, and keeps it renewed.
+    pub async fn run(self) {
+        loop {
+            let wait = match self.renew().await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(err) => {
+                    let domains = self.domains.join(", ");
+                    eprintln!("ACME: no certificate for {domains}: {err}");
+                    RETRY_INTERVAL
+                }
+            };
+            sleep(wait).await;
+        }
+    }
+
+    /// Orders a certificate unless the stored one is valid for a while yet.
+    async fn renew(&self) -> io::Result<()> {
+        if let Some((not_after, key)) = self.load() {
+            *self.certificates.current.write().unwrap() = Some(key);
+            if not_after > SystemTime::now() + RENEW_BEFORE {
+                return Ok(());
+            }
+        }
+        println!(
+            "ACME: ordering a certificate for {}",
+            self.domains.join(", ")
+        );
+        let (chain, key) = self.order().await?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        save(
+            &self.dir.join("cert.key"),
+            pem("PRIVATE KEY", &key).as_bytes(),
+        )
+        .await?;
+        save(&self.dir.join("cert.pem"), chain.as_bytes()).await?;
+        let (not_after, key) = self
+            .load()
+            .ok_or_else(|| io::Error::other("the CA sent an unusable certificate"))?;
+        *self.certificates.current.write().unwrap() = Some(key);
+        println!(
+            "ACME: certificate valid until {}",
+            date::http_date(not_after)
+        );
+        Ok(())
+    }
+
+    /// The stored certificate and its expiry, if it covers every domain.
+    fn load(&self) -> Option<(SystemTime, Arc<CertifiedKey>)> {
+        let chain = CertificateDer::pem_file_iter(self.dir.join("cert.pem"))
+            .ok()?
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        let key = PrivateKeyDer::from_pem_file(self.dir.join("cert.key")).ok()?;
+        let (not_after, mut names) = certificate_info(chain.first()?)?;
+        let mut domains = self.domains.clone();
+        names.sort();
+        domains.sort();
+        if names != domains {
+            return None;
+        }
+        let provider = rustls::crypto::ring::default_provider();
+        let key = CertifiedKey::from_der(chain, key, &provider).ok()?;
+        Some((not_after, Arc::new(key)))
+    }
+
+    /// Goes through an order, returning the PEM certificate chain and the
+    /// PKCS#8 key it certifies.
+    async fn order(&self) -> io::Result<(String, Vec<u8>)> {
+        let client = Client::new()?;
+        let directory = client.request("GET", &self.directory, None).await?.json()?;
+        let url = |name: &str| {
+            directory[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| io::Error::other(format!("the directory has no {name}")))
+        };
+        let account = Account::load_or_create(&self.dir.join("account.key")).await?;
+        let mut session = Session {
+            client,
+            account,
+            kid: None,
+            nonce: None,
+            new_nonce: url("newNonce")?,
+        };
+
+        let contact: Vec<String> = self
+            .email
+            .iter()
+            .map(|email| format!("mailto:{email}"))
+            .collect();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+        let reply = session.post(&url("newAccount")?, Some(&payload)).await?;
+        session.kid = Some(reply.location()?);
+
+        let identifiers: Vec<Value> = self
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let payload = json!({ "identifiers": identifiers });
+        let reply = session.post(&url("newOrder")?, Some(&payload)).await?;
+        let order_url = reply.location()?;
+        let order = reply.json()?;
+        for authorization in order["authorizations"].as_array().into_iter().flatten() {
+            let authorization = authorization
+                .as_str()
+                .ok_or_else(|| io::Error::other("invalid authorization URL"))?;
+            self.authorize(&mut session, authorization).await?;
+        }
+
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .map_err(|_| io::Error::other("cannot generate a key"))?;
+        let csr = certificate_request(&self.domains, key.as_ref())?;
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or_else(|| io::Error::other("the order has no finalize URL"))?;
+        let payload = json!({ "csr": URL_SAFE_NO_PAD.encode(csr) });
+        session.post(finalize, Some(&payload)).await?;
+        let order = session
+            .poll(&order_url, &["pending", "ready", "processing"])
+            .await?;
+        let certificate = match (order["status"].as_str(), order["certificate"].as_str()) {
+            (Some("valid"), Some(certificate)) => certificate.to_string(),
+            _ => return Err(problem("the order failed", &order)),
+        };
+        let chain = session.post(&certificate, None).await?.body;
+        let chain = String::from_utf8(chain).map_err(io::Error::other)?;
+        Ok((chain, key.as_ref().to_vec()))
+    }
+
+    /// Proves control of a domain through its HTTP-01 challenge.
+    async fn authorize(&self, session: &mut Session, url: &str) -> io::Result<()> {
+        let authorization = session.post(url, None).await?.json()?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|challenge| challenge["type"] == "http-01")
+            .ok_or_else(|| problem("no http-01 challenge", &authorization))?;
+        let (Some(token), Some(challenge_url)) =
+            (challenge["token"].as_str(), challenge["url"].as_str())
+        else {
+            return Err(problem("invalid challenge", challenge));
+        };
+        let key_authorization = format!("{token}.{}", session.account.thumbprint);
+        self.challenges
+            .tokens
+            .lock()
+            .unwrap()
+            .insert(token.to_string(), key_authorization);
+        let validated = async {
+            session.post(challenge_url, Some(&json!({}))).await?;
+            session.poll(url, &["pending"]).await
+        }
+        .await;
+        self.challenges.tokens.lock().unwrap().remove(token);
+        let authorization = validated?;
+        match authorization["status"].as_str() {
+            Some("valid") => Ok(()),
+            _ => Err(problem("validation failed", &authorization)),
+        }
+    }
+}
+
+/// The account key, which signs every request to the CA.
+struct Account {
+    key: EcdsaKeyPair,
+    jwk: Value,
+    /// Base64url SHA-256 of the JWK, part of every key authorization.
+    thumbprint: String,
+}
+
+impl Account {
+    async fn load_or_create(path: &Path) -> io::Result<Account> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match PrivateKeyDer::from_pem_file(path) {
+            Ok(key) => key.secret_der().to_vec(),
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| io::Error::other("cannot generate a key"))?;
+                tokio::fs::create_dir_all(path.parent().unwrap_or(Path::new("."))).await?;
+                save(path, pem("PRIVATE KEY", pkcs8.as_ref()).as_bytes()).await?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|err| io::Error::other(format!("{}: {err}", path.display())))?;
+        // An uncompressed point: 0x04, then both coordinates.
+        let point = key.public_key().as_ref();
+        let (x, y) = point[1..].split_at(32);
+        let (x, y) = (URL_SAFE_NO_PAD.encode(x), URL_SAFE_NO_PAD.encode(y));
+        // Members in lexicographic order, as RFC 7638 hashes them.
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        let thumbprint =
+            URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, canonical.as_bytes()));
+        Ok(Account {
+            key,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+        })
+    }
+}
+
+/// Requests of one order, each signed with a fresh nonce.
+struct Session {
+    client: Client,
+    account: Account,
+    /// Account URL, which signed requests name once the account exists.
+    kid: Option<String>,
+    nonce: Option<String>,
+    new_nonce: String,
+}
+
+impl Session {
+    /// Sends a signed request, with `payload` or as a POST-as-GET without.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> io::Result<Reply> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self
+                    .client
+                    .request("HEAD", &self.new_nonce, None)
+                    .await?
+                    .nonce()
+                    .ok_or_else(|| io::Error::other("no nonce"))?,
+            };
+            let body = self.sign(url, &nonce, payload)?;
+            let reply = self.client.request("POST", url, Some(&body)).await?;
+            self.nonce = reply.nonce();
+            if reply.status < 400 {
+                return Ok(reply);
+            }
+            let error = reply.json().unwrap_or_default();
+            // Nonces expire; the CA sends a fresh one with the error.
+            if error["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(problem(&format!("{url} answered {}", reply.status), &error));
+        }
+    }
+
+    /// Fetches `url` until its status is none of `pending`.
+    async fn poll(&mut self, url: &str, pending: &[&str]) -> io::Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let object = self.post(url, None).await?.json()?;
+            if !pending.iter().any(|status| object["status"] == *status) {
+                return Ok(object);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        Err(io::Error::other(format!("{url} is still pending")))
+    }
+
+    /// A flattened JWS of `payload` (RFC 7515), signed with ES256.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> io::Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = kid.as_str().into(),
+            None => protected["jwk"] = self.account.jwk.clone(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload.map_or(String::new(), |payload| {
+            URL_SAFE_NO_PAD.encode(payload.to_string())
+        });
+        let signature = self
+            .account
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{protected}.{payload}").as_bytes(),
+            )
+            .map_err(|_| io::Error::other("cannot sign"))?;
+        let jws = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature),
+        });
+        Ok(jws.to_string().into_bytes())
+    }
+}
+
+/// An HTTPS client for the CA, one connection per request.
+struct Client {
+    connector: TlsConnector,
+}
+
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn nonce(&self) -> Option<String> {
+        self.header("Replay-Nonce").map(str::to_string)
+    }
+
+    fn location(&self) -> io::Result<String> {
+        self.header("Location")
+            .map(str::to_string)
+            .ok_or_else(|| io::Error::other("no Location in the CA's answer"))
+    }
+
+    fn json(&self) -> io::Result<Value> {
+        serde_json::from_slice(&self.body).map_err(io::Error::other)
+    }
+}
+
+impl Client {
+    /// Trusts the CAs of `SSL_CERT_FILE`, or else of the system bundle.
+    fn new() -> io::Result<Client> {
+        let bundle = match std::env::var_os("SSL_CERT_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => SYSTEM_ROOTS
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.is_file())
+                .ok_or_else(|| io::Error::other("no CA bundle; set SSL_CERT_FILE"))?,
+        };
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(&bundle).map_err(io::Error::other)? {
+            // Bundles hold the odd certificate webpki can't parse.
+            let _ = roots.add(certificate.map_err(io::Error::other)?);
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Client {
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    async fn request(&self, method: &str, url: &str, body: Option<&[u8]>) -> io::Result<Reply> {
+        timeout(REQUEST_TIMEOUT, self.exchange(method, url, body))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, url.to_string())))
+    }
+
+    async fn exchange(&self, method: &str, url: &str, body: Option<&[u8]>) -> io::Result<Reply> {
+        let invalid = || io::Error::other(format!("unsupported URL {url}"));
+        let rest = url.strip_prefix("https://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let host = redirect::strip_port(authority);
+        let port = match authority[host.len()..].strip_prefix(':') {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => 443,
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host.to_string()).map_err(|_| invalid())?;
+        let stream = TcpStream::connect((host, port)).await?;
+        let mut stream = self.connector.connect(name, stream).await?;
+
+        let mut head = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: rustywebserver\r\n\
+             Connection: close\r\n"
+        );
+        if let Some(body) = body {
+            head.push_str("Content-Type: application/jose+json\r\n");
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body.unwrap_or_default()).await?;
+        stream.flush().await?;
+
+        let mut reply = Vec::new();
+        match stream.read_to_end(&mut reply).await {
+            Ok(_) => {}
+            // Servers often close without a TLS close_notify.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !reply.is_empty() => {}
+            Err(err) => return Err(err),
+        }
+        parse_reply(&reply, method == "HEAD")
+            .ok_or_else(|| io::Error::other(format!("invalid answer from {url}")))
+    }
+}
+
+fn parse_reply(reply: &[u8], head_only: bool) -> Option<Reply> {
+    let end = reply.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&reply[..end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut reply = Reply {
+        status,
+        headers,
+        body: reply[end + 4..].to_vec(),
+    };
+    if head_only {
+        reply.body.clear();
+    } else if reply
+        .header("Transfer-Encoding")
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+    {
+        reply.body = dechunk(&reply.body)?;
+    } else if let Some(length) = reply
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok())
+    {
+        reply.body.truncate(length);
+    }
+    Some(reply)
+}
+
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let end = body.windows(2).position(|window| window == b"\r\n")?;
+        let line = std::str::from_utf8(&body[..end]).ok()?;
+        let size = usize::from_str_radix(line.split(';').next()?.trim(), 16).ok()?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// An error quoting the `detail` of an ACME problem document.
+fn problem(context: &str, object: &Value) -> io::Error {
+    let detail = object["detail"]
+        .as_str()
+        .or_else(|| object["error"]["detail"].as_str())
+        .or_else(|| {
+            object["challenges"]
+                .as_array()?
+                .iter()
+                .find_map(|challenge| challenge["error"]["detail"].as_str())
+        });
+    match detail {
+        Some(detail) => io::Error::other(format!("{context}: {detail}")),
+        None => io::Error::other(context.to_string()),
+    }
+}
+
+/// A PKCS#10 request for a certificate naming `domains`, signed with the
+/// P-256 key `pkcs8`.
+fn certificate_request(domains: &[String], pkcs8: &[u8]) -> io::Result<Vec<u8>> {
+    let rng = SystemRandom::new();
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8, &rng)
+        .map_err(|_| io::Error::other("invalid key"))?;
+    let sequence = |parts: &[Vec<u8>]| der::encode(0x30, &parts.concat());
+    // CAs take the names from the extension; the subject is for show, and
+    // only fits names of up to 64 bytes.
+    let subject = match domains.first() {
+        Some(domain) if domain.len() <= 64 => {
+            let name = sequence(&[
+                der::encode(0x06, COMMON_NAME),
+                der::encode(0x0c, domain.as_bytes()),
+            ]);
+            sequence(&[der::encode(0x31, &name)])
+        }
+        _ => sequence(&[]),
+    };
+    let algorithm = sequence(&[
+        der::encode(0x06, EC_PUBLIC_KEY),
+        der::encode(0x06, PRIME256V1),
+    ]);
+    let point = [&[0], key.public_key().as_ref()].concat();
+    let public_key = sequence(&[algorithm, der::encode(0x03, &point)]);
+    let names: Vec<Vec<u8>> = domains
+        .iter()
+        .map(|domain| der::encode(0x82, domain.as_bytes()))
+        .collect();
+    let extension = sequence(&[
+        der::encode(0x06, SUBJECT_ALT_NAME),
+        der::encode(0x04, &sequence(&names)),
+    ]);
+    let attribute = sequence(&[
+        der::encode(0x06, EXTENSION_REQUEST),
+        der::encode(0x31, &sequence(&[extension])),
+    ]);
+    let info = sequence(&[
+        der::encode(0x02, &[0]),
+        subject,
+        public_key,
+        der::encode(0xa0, &attribute),
+    ]);
+    let signature = key
+        .sign(&rng, &info)
+        .map_err(|_| io::Error::other("cannot sign"))?;
+    let signature = [&[0], signature.as_ref()].concat();
+    Ok(sequence(&[
+        info,
+        sequence(&[der::encode(0x06, ECDSA_WITH_SHA256)]),
+        der::encode(0x03, &signature),
+    ]))
+}
+
+/// The expiry and DNS names of a DER certificate.
+fn certificate_info(certificate: &[u8]) -> Option<(SystemTime, Vec<String>)> {
+    let fields = der::certificate_fields(certificate)?;
+    let validity = der::elements(fields.get(3)?.1)?;
+    let not_after = date::parse_asn1_time(std::str::from_utf8(validity.get(1)?.1).ok()?)?;
+    let mut names = Vec::new();
+    if let Some((_, extensions)) = fields.iter().find(|(tag, _)| *tag == 0xa3) {
+        let (_, extensions, _) = der::element(extensions)?;
+        for (_, extension) in der::elements(extensions)? {
+            let parts = der::elements(extension)?;
+            if parts.first()?.1 != SUBJECT_ALT_NAME {
+                continue;
+            }
+            let (_, alt_names, _) = der::element(parts.last()?.1)?;
+            for (tag, name) in der::elements(alt_names)? {
+                if tag == 0x82 {
+                    names.push(String::from_utf8_lossy(name).to_ascii_lowercase());
+                }
+            }
+        }
+    }
+    Some((not_after, names))
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+/// Replaces `path` with `contents` at once, readable by the server's user
+/// only.
+async fn save(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let partial = path.with_extension("new");
+    let _ = tokio::fs::remove_file(&partial).await;
+    let mut file = spool::private_file().open(&partial).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&partial, path).await
+}
@@ -0,0 +1,141 @@
+//! Administrative API under `/_admin/`, enabled by `--admin-token`.
+//!
+//! Every request must carry `Authorization: Bearer <token>`.
+//!
+//! | Method | Path                            |                              |
+//! |--------|---------------------------------|------------------------------|
+//! | GET    | `/_admin/maintenance`           | whether maintenance is on    |
+//! | POST   | `/_admin/maintenance/enable`    | switch maintenance mode on   |
+//! | POST   | `/_admin/maintenance/disable`   | switch maintenance mode off  |
+//! | POST   | `/_admin/cache/purge?path=P`    | drop cached copies of path P |
+//! | POST   | `/_admin/cache/purge?glob=G`    | drop cached paths matching G |
+//! | GET    | `/_admin/drain`                 | whether the server drains    |
+//! | POST   | `/_admin/drain`                 | fail `/readyz` from now on   |
+//! | POST   | `/_admin/undrain`               | make `/readyz` succeed again |
+//! | GET    | `/_admin/root`                  | the root folder served       |
+//! | POST   | `/_admin/root?path=DIR`         | serve DIR from now on        |
+//! | GET    | `/_admin/bandwidth`             | bytes sent per host          |
+
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::http::{self, Request, Response};
+use crate::purge::{self, Pattern};
+use crate::server::Server;
+
+pub const PREFIX: &str = "/_admin/";
+
+pub async fn handle(server: &Server, request: &Request) -> Response {
+    let Some(token) = &server.config.admin_token else {
+        return Response::error(404);
+    };
+    if !is_authorized(request, token) {
+        let mut response = Response::error(401);
+        response.set_header("WWW-Authenticate", "Bearer");
+        return response;
+    }
+
+    let endpoint = request.path.strip_prefix(PREFIX).unwrap_or_default();
+    match (request.method.as_str(), endpoint) {
+        ("GET", "maintenance") => {
+            let active = server.maintenance.is_active().await;
+            json(format!("{{\"maintenance\":{active}}}"))
+        }
+        ("POST", "maintenance/enable") => {
+            server.maintenance.set_enabled(true);
+            json("{\"maintenance\":true}".to_string())
+        }
+        ("POST", "maintenance/disable") => {
+            server.maintenance.set_enabled(false);
+            let active = server.maintenance.is_active().await;
+            json(format!("{{\"maintenance\":{active}}}"))
+        }
+        ("POST", "cache/purge") => {
+            let query = http::parse_query(&request.query);
+            let pattern = match query
+                .first()
+                .map(|&(key, value)| (key, http::percent_decode(value)))
+            {
+                Some(("path", path)) if query.len() == 1 => Pattern::Path(path),
+                Some(("glob", glob)) if query.len() == 1 => Pattern::Glob(glob),
+                _ => return Response::error(400),
+            };
+            let purged = purge::broadcast(&server.purges, pattern).await;
+            json(format!("{{\"purged\":{purged}}}"))
+        }
+        ("GET", "drain") => {
+            let draining = server.draining.load(Ordering::Relaxed);
+            json(format!("{{\"draining\":{draining}}}"))
+        }
+        ("POST", "drain" | "undrain") => {
+            let draining = endpoint == "drain";
+            server.draining.store(draining, Ordering::Relaxed);
+            json(format!("{{\"draining\":{draining}}}"))
+        }
+        ("GET", "root") => json(format!("{{\"root\":{}}}", path_json(&server.site().root))),
+        ("POST", "root") => {
+            let query = http::parse_query(&request.query);
+            let [("path", path)] = query.as_slice() else {
+                return Response::error(400);
+            };
+            let root = match tokio::fs::canonicalize(http::percent_decode(path)).await {
+                Ok(root) if root.is_dir() => root,
+                _ => return Response::error(400),
+            };
+            let purged = server.switch_root(root.clone()).await;
+            println!("Root folder: {}", root.display());
+            json(format!(
+                "{{\"root\":{},\"purged\":{purged}}}",
+                path_json(&root)
+            ))
+        }
+        ("GET", "bandwidth") => {
+            let hosts: Vec<serde_json::Value> = server
+                .bandwidth
+                .snapshot()
+                .into_iter()
+                .map(|(host, month, total)| {
+                    serde_json::json!({"host": host, "month": month, "total": total})
+                })
+                .collect();
+            json(serde_json::json!({ "hosts": hosts }).to_string())
+        }
+        (
+            _,
+            "maintenance"
+            | "maintenance/enable"
+            | "maintenance/disable"
+            | "cache/purge"
+            | "drain"
+            | "undrain"
+            | "root"
+            | "bandwidth",
+        ) => Response::error(405),
+        _ => Response::error(404),
+    }
+}
+
+fn json(body: String) -> Response {
+    Response::new(200, "application/json", body)
+}
+
+fn path_json(path: &Path) -> serde_json::Value {
+    serde_json::Value::from(path.to_string_lossy())
+}
+
+/// Whether `request` carries `Authorization: Bearer <token>`.
+pub fn is_authorized(request: &Request, token: &str) -> bool {
+    let Some(given) = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Compare in constant time so the token can't be guessed byte by byte.
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,110 @@
+//! Client address anonymization for the access log, with `--anonymize-ips`.
+//!
+//! `mask` zeroes the last octet of IPv4 addresses and the last 80 bits of
+//! IPv6 ones, the usual truncation for analytics. `hash` replaces the whole
+//! address with a keyed hash, so the requests of one client can still be
+//! correlated; the key is random, never written anywhere and replaced every
+//! day, after which old hashes can't be linked to new ones.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// How long a hash key is used before it is replaced.
+const KEY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy)]
+pub enum Mode {
+    Mask,
+    Hash,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Mode, ()> {
+        match value {
+            "mask" => Ok(Mode::Mask),
+            "hash" => Ok(Mode::Hash),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct Anonymizer {
+    mode: Mode,
+    key: Mutex<(Instant, hmac::Key)>,
+}
+
+impl Anonymizer {
+    pub fn new(mode: Mode) -> Anonymizer {
+        Anonymizer {
+            mode,
+            key: Mutex::new((Instant::now(), new_key())),
+        }
+    }
+
+    /// How `ip` appears in the log.
+    pub fn client(&self, ip: IpAddr) -> String {
+        match self.mode {
+            Mode::Mask => mask(ip).to_string(),
+            Mode::Hash => {
+                let mut key = self.key.lock().unwrap();
+                if key.0.elapsed() >= KEY_LIFETIME {
+                    *key = (Instant::now(), new_key());
+                }
+                let octets = match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                    IpAddr::V6(ip) => ip.octets(),
+                };
+                let tag = hmac::sign(&key.1, &octets);
+                let hex: String = tag.as_ref()[..8]
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect();
+                format!("client-{hex}")
+            }
+        }
+    }
+}
+
+/// How `ip` appears in the log, anonymized when `anonymizer` is given.
+pub fn client(anonymizer: Option<&Anonymizer>, ip: IpAddr) -> String {
+    match anonymizer {
+        Some(anonymizer) => anonymizer.client(ip),
+        None => ip.to_string(),
+    }
+}
+
+fn mask(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                0,
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+    }
+}
+
+fn new_key() -> hmac::Key {
+    let mut secret = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .expect("system random number generator failed");
+    hmac::Key::new(hmac::HMAC_SHA256, &secret)
+}
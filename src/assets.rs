@@ -0,0 +1,58 @@
+//! Fingerprinted assets listed in a build manifest.
+//!
+//! The manifest is a JSON object mapping logical names to the hashed files
+//! produced by the build, relative to the root folder:
+//!
+//! ```json
+//! { "assets/app.js": "assets/app.3f9c2.js" }
+//! ```
+//!
+//! Requests for a logical name are served from the hashed file. Hashed
+//! paths are sent with immutable cache headers, since a new build changes
+//! the hash; logical paths must be revalidated, as their content changes
+//! from one build to the next.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+pub const REVALIDATE: &str = "no-cache";
+
+pub struct AssetManifest {
+    /// Logical request path to fingerprinted request path, both with a leading `/`.
+    assets: HashMap<String, String>,
+    fingerprinted: HashSet<String>,
+}
+
+impl AssetManifest {
+    pub fn load(path: &Path) -> Result<AssetManifest, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+        let entries: HashMap<String, String> = serde_json::from_str(&text)
+            .map_err(|err| format!("invalid asset manifest {}: {err}", path.display()))?;
+
+        let assets: HashMap<String, String> = entries
+            .into_iter()
+            .map(|(logical, hashed)| (absolute(&logical), absolute(&hashed)))
+            .collect();
+        let fingerprinted = assets.values().cloned().collect();
+        Ok(AssetManifest {
+            assets,
+            fingerprinted,
+        })
+    }
+
+    /// Returns the fingerprinted path for a logical asset path.
+    pub fn resolve(&self, path: &str) -> Option<&str> {
+        self.assets.get(path).map(String::as_str)
+    }
+
+    pub fn is_fingerprinted(&self, path: &str) -> bool {
+        self.fingerprinted.contains(path)
+    }
+}
+
+fn absolute(path: &str) -> String {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    format!("/{}", path.trim_start_matches('/'))
+}
@@ -0,0 +1,212 @@
+//! External authorization (`auth_request` on a location).
+//!
+//! Before a request is handled, a subrequest carrying its headers is sent to
+//! an upstream (`http://host:port/path`) or run as a script (a path under
+//! the root folder, like `/scripts/auth.sh`), with the original method and
+//! target in `X-Original-Method` and `X-Original-URI`. A `2xx` answer lets
+//! the request through, with the headers named in `auth_headers` copied
+//! from the answer onto it; `401` and `403` are passed on to the client, and
+//! anything else answers `500`. A script sets its status with a `Status:`
+//! header.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::http::{Request, Response};
+use crate::resolve::{resolve, Resolved};
+use crate::scripts::{self, Script};
+use crate::server::Server;
+use crate::upstream::{is_hop_by_hop, Upstream};
+
+/// Time the auth endpoint may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest answer head read from an upstream.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum AuthRequest {
+    Upstream(Upstream),
+    /// Request path of a script under the root folder.
+    Script(String),
+}
+
+impl TryFrom<String> for AuthRequest {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<AuthRequest, String> {
+        match value.starts_with('/') {
+            true => Ok(AuthRequest::Script(value)),
+            false => value.parse().map(AuthRequest::Upstream),
+        }
+    }
+}
+
+/// Runs the auth subrequest for `request`. Returns the request to handle,
+/// with the headers in `forward` copied from the answer, or the response
+/// refusing it.
+pub async fn check(
+    server: &Server,
+    auth: &AuthRequest,
+    forward: &[String],
+    request: &Request,
+) -> Result<Request, Response> {
+    let answer = match tokio::time::timeout(TIMEOUT, subrequest(server, auth, request)).await {
+        Ok(Ok(answer)) => answer,
+        Ok(Err(err)) => {
+            eprintln!("auth request for {} failed: {err}", request.path);
+            return Err(Response::error(500));
+        }
+        Err(_) => {
+            eprintln!("auth request for {} timed out", request.path);
+            return Err(Response::error(500));
+        }
+    };
+
+    match answer.status {
+        200..=299 => {
+            let mut request = request.clone();
+            for name in forward {
+                request
+                    .headers
+                    .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+                if let Some(value) = answer.header(name) {
+                    request.headers.push((name.clone(), value.to_string()));
+                }
+            }
+            Ok(request)
+        }
+        status @ (401 | 403) => {
+            let mut response = Response::error(status);
+            if let Some(challenge) = answer.header("WWW-Authenticate") {
+                response.set_header("WWW-Authenticate", challenge);
+            }
+            Err(response)
+        }
+        status => {
+            eprintln!("auth request for {} answered {status}", request.path);
+            Err(Response::error(500))
+        }
+    }
+}
+
+/// The parts of the auth answer that matter: its status and headers.
+struct Answer {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Answer {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The original request's headers, without its body and with where it was
+/// going.
+fn subrequest_headers(request: &Request) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = request
+        .headers
+        .iter()
+        .filter(|(name, _)| {
+            !is_hop_by_hop(name)
+                && !["Host", "Content-Length", "Content-Type"]
+                    .iter()
+                    .any(|skipped| skipped.eq_ignore_ascii_case(name))
+        })
+        .cloned()
+        .collect();
+    headers.push(("X-Original-Method".to_string(), request.method.clone()));
+    headers.push(("X-Original-URI".to_string(), request.target.clone()));
+    headers
+}
+
+async fn subrequest(
+    server: &Server,
+    auth: &AuthRequest,
+    request: &Request,
+) -> std::io::Result<Answer> {
+    match auth {
+        AuthRequest::Upstream(upstream) => {
+            let target = match upstream.path.as_str() {
+                "" => "/",
+                path => path,
+            };
+            let mut head = format!(
+                "GET {target} HTTP/1.1\r\nHost: {}\r\n",
+                upstream.authority()
+            );
+            for (name, value) in subrequest_headers(request) {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+            head.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+
+            let mut stream = upstream.connect().await?;
+            stream.write_all(head.as_bytes()).await?;
+            let mut buffer = Vec::new();
+            let mut chunk = [0u8; 4096];
+            let end = loop {
+                if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break end;
+                }
+                let read = stream.read(&mut chunk).await?;
+                if read == 0 || buffer.len() > MAX_HEAD_SIZE {
+                    return Err(std::io::Error::other("invalid answer"));
+                }
+                buffer.extend_from_slice(&chunk[..read]);
+            };
+            let head = String::from_utf8_lossy(&buffer[..end]);
+            let mut lines = head.split("\r\n");
+            let status = lines
+                .next()
+                .and_then(|line| line.split(' ').nth(1))
+                .and_then(|status| status.parse().ok())
+                .ok_or_else(|| std::io::Error::other("invalid status line"))?;
+            let headers = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .collect();
+            Ok(Answer { status, headers })
+        }
+        AuthRequest::Script(path) => {
+            let root = server.site_for(request).root.clone();
+            let file = match resolve(&root, path).await {
+                Ok(Resolved {
+                    path,
+                    is_dir: false,
+                    ..
+                }) => path,
+                _ => return Err(std::io::Error::other(format!("no auth script {path}"))),
+            };
+            let subrequest = Request {
+                method: "GET".to_string(),
+                target: path.clone(),
+                path: path.clone(),
+                query: String::new(),
+                version: request.version.clone(),
+                headers: subrequest_headers(request),
+                body: Vec::new().into(),
+                tls: request.tls.clone(),
+                peer: request.peer,
+            };
+            let script = Script {
+                path: &file,
+                name: path,
+                root: &root,
+            };
+            let (config, timeout) = (&server.config, server.config.script_timeout(None));
+            let response =
+                scripts::execute_script(&script, &subrequest, config, None, timeout).await;
+            Ok(Answer {
+                status: response.status,
+                headers: response.headers,
+            })
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Bytes sent per virtual host, named by the request's `Host` header, and
+//! the quotas of the `[[bandwidth]]` tables of the config file:
+//!
+//! ```toml
+//! [[bandwidth]]
+//! host = "example.com"
+//! monthly = 107374182400  # bytes per calendar month (UTC), then 509
+//! rate = 1048576          # bytes per second over RATE_WINDOW, then 503
+//! ```
+//!
+//! Counters live in memory and start over when the server restarts.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::date::DateTime;
+use crate::http::{Request, Response};
+
+/// Period over which `rate` is averaged.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Hosts counted separately; further ones share `OTHER_HOSTS`, so made-up
+/// `Host` headers can't grow the table without bound.
+const MAX_HOSTS: usize = 1000;
+const OTHER_HOSTS: &str = "*";
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// Host name, without port.
+    pub host: String,
+    /// Bytes the host may send per calendar month.
+    pub monthly: Option<u64>,
+    /// Bytes per second the host may send, averaged over `RATE_WINDOW`.
+    pub rate: Option<u64>,
+}
+
+pub struct Bandwidth {
+    quotas: Vec<Quota>,
+    hosts: Mutex<HashMap<String, Arc<Mutex<Usage>>>>,
+}
+
+/// Bytes sent for one host.
+pub struct Usage {
+    total: u64,
+    /// `(year, month)` that `month_bytes` counts.
+    month: (i64, u32),
+    month_bytes: u64,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl Usage {
+    fn new() -> Usage {
+        Usage {
+            total: 0,
+            month: current_month(),
+            month_bytes: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Counts `bytes` more sent.
+    pub fn charge(&mut self, bytes: u64) {
+        self.roll();
+        self.total += bytes;
+        self.month_bytes += bytes;
+        self.window_bytes += bytes;
+    }
+
+    /// Starts new periods once the current ones are over.
+    fn roll(&mut self) {
+        let month = current_month();
+        if month != self.month {
+            self.month = month;
+            self.month_bytes = 0;
+        }
+        if self.window_start.elapsed() >= RATE_WINDOW {
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+    }
+}
+
+impl Bandwidth {
+    pub fn new(quotas: &[Quota]) -> Bandwidth {
+        let quotas = quotas
+            .iter()
+            .map(|quota| Quota {
+                host: quota.host.to_ascii_lowercase(),
+                ..quota.clone()
+            })
+            .collect();
+        Bandwidth {
+            quotas,
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// The counters of the host `request` is for.
+    pub fn usage(&self, request: &Request) -> Arc<Mutex<Usage>> {
+        let host = host(request);
+        let mut hosts = self.hosts.lock().unwrap();
+        let key = match hosts.len() < MAX_HOSTS || hosts.contains_key(&host) {
+            true => host,
+            false => OTHER_HOSTS.to_string(),
+        };
+        hosts
+            .entry(key)
+            .or_insert_with(|| Arc::new(Mutex::new(Usage::new())))
+            .clone()
+    }
+
+    /// Refuses the request when its host is over quota: 509 for the monthly
+    /// volume, 503 until the rate window ends for the rate.
+    pub fn check(&self, request: &Request, usage: &Mutex<Usage>) -> Result<(), Response> {
+        let host = host(request);
+        let Some(quota) = self.quotas.iter().find(|quota| quota.host == host) else {
+            return Ok(());
+        };
+        let mut usage = usage.lock().unwrap();
+        usage.roll();
+        if quota
+            .monthly
+            .is_some_and(|monthly| usage.month_bytes >= monthly)
+        {
+            return Err(Response::error(509));
+        }
+        let window = RATE_WINDOW.as_secs();
+        if quota
+            .rate
+            .is_some_and(|rate| usage.window_bytes >= rate.saturating_mul(window))
+        {
+            let mut response = Response::error(503);
+            let retry_after = RATE_WINDOW.saturating_sub(usage.window_start.elapsed());
+            response.set_header("Retry-After", (retry_after.as_secs() + 1).to_string());
+            return Err(response);
+        }
+        Ok(())
+    }
+
+    /// Every host's counters, sorted by name.
+    pub fn snapshot(&self) -> Vec<(String, u64, u64)> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut snapshot: Vec<(String, u64, u64)> = hosts
+            .iter()
+            .map(|(host, usage)| {
+                let mut usage = usage.lock().unwrap();
+                usage.roll();
+                (host.clone(), usage.month_bytes, usage.total)
+            })
+            .collect();
+        snapshot.sort();
+        snapshot
+    }
+}
+
+fn current_month() -> (i64, u32) {
+    let now = DateTime::from_system_time(SystemTime::now());
+    (now.year, now.month)
+}
+
+/// The lowercase `Host` of `request` without its port, or `-`.
+fn host(request: &Request) -> String {
+    let Some(host) = request.header("Host") else {
+        return "-".to_string();
+    };
+    let name = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.to_ascii_lowercase()
+}
+
+/// A connection whose written bytes are charged to a host, once the request
+/// says which.
+pub struct Metered<S> {
+    inner: S,
+    usage: Option<Arc<Mutex<Usage>>>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S) -> Metered<S> {
+        Metered { inner, usage: None }
+    }
+
+    pub fn charge_to(&mut self, usage: Arc<Mutex<Usage>>) {
+        self.usage = Some(usage);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(usage)) = (&poll, &self.usage) {
+            usage.lock().unwrap().charge(*written as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
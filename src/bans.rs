@@ -0,0 +1,194 @@
+//! Temporary bans for clients that keep failing authorization.
+//!
+//! Every 401/403 answered to a client is counted; once a client reaches
+//! `threshold` failures inside `window` it is refused at accept time for
+//! `duration`. Independently of banning, each failure and ban is logged in a
+//! fixed format so fail2ban (or similar) can enforce bans at the firewall:
+//!
+//! ```text
+//! 2024-05-01T12:30:00Z rustywebserver: auth failure from 10.0.0.7: 403 /private
+//! 2024-05-01T12:30:05Z rustywebserver: banned 10.0.0.7 for 600s after 5 failures
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::date;
+
+/// Number of tracked clients above which stale entries are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Clone)]
+pub struct BanConfig {
+    /// Failures needed to trigger a ban; 0 disables banning.
+    pub threshold: u32,
+    pub window: Duration,
+    pub duration: Duration,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        BanConfig {
+            threshold: 0,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Offender {
+    failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+pub struct BanList {
+    config: BanConfig,
+    offenders: Mutex<HashMap<IpAddr, Offender>>,
+}
+
+impl BanList {
+    pub fn new(config: BanConfig) -> BanList {
+        BanList {
+            config,
+            offenders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let offenders = self.offenders.lock().unwrap();
+        offenders
+            .get(&ip)
+            .and_then(|offender| offender.banned_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// Records a 401/403 answered to `ip`, banning it once it crosses the threshold.
+    pub fn record_failure(&self, ip: IpAddr, status: u16, path: &str) {
+        eprintln!(
+            "{} rustywebserver: auth failure from {ip}: {status} {path}",
+            date::iso8601(SystemTime::now())
+        );
+        if self.config.threshold == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut offenders = self.offenders.lock().unwrap();
+        if offenders.len() > PRUNE_THRESHOLD {
+            offenders.retain(|_, offender| !self.is_stale(offender, now));
+        }
+
+        let offender = offenders.entry(ip).or_default();
+        if offender.banned_until.is_some_and(|until| until > now) {
+            return;
+        }
+        offender.banned_until = None;
+        offender.failures.push_back(now);
+        while offender
+            .failures
+            .front()
+            .is_some_and(|&failure| now.duration_since(failure) > self.config.window)
+        {
+            offender.failures.pop_front();
+        }
+
+        if offender.failures.len() >= self.config.threshold as usize {
+            offender.failures.clear();
+            offender.banned_until = Some(now + self.config.duration);
+            eprintln!(
+                "{} rustywebserver: banned {ip} for {}s after {} failures",
+                date::iso8601(SystemTime::now()),
+                self.config.duration.as_secs(),
+                self.config.threshold
+            );
+        }
+    }
+
+    fn is_stale(&self, offender: &Offender, now: Instant) -> bool {
+        let banned = offender.banned_until.is_some_and(|until| until > now);
+        let recent = offender
+            .failures
+            .back()
+            .is_some_and(|&failure| now.duration_since(failure) <= self.config.window);
+        !banned && !recent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    fn bans(threshold: u32, window_ms: u64, duration_ms: u64) -> BanList {
+        BanList::new(BanConfig {
+            threshold,
+            window: Duration::from_millis(window_ms),
+            duration: Duration::from_millis(duration_ms),
+        })
+    }
+
+    #[test]
+    fn bans_at_the_threshold() {
+        let bans = bans(3, 60_000, 60_000);
+        for _ in 0..2 {
+            bans.record_failure(CLIENT, 403, "/private");
+            assert!(!bans.is_banned(CLIENT));
+        }
+        bans.record_failure(CLIENT, 401, "/private");
+        assert!(bans.is_banned(CLIENT));
+        assert!(!bans.is_banned(OTHER));
+    }
+
+    #[test]
+    fn threshold_of_zero_never_bans() {
+        let bans = bans(0, 60_000, 60_000);
+        for _ in 0..100 {
+            bans.record_failure(CLIENT, 403, "/private");
+        }
+        assert!(!bans.is_banned(CLIENT));
+    }
+
+    #[test]
+    fn forgets_failures_outside_the_window() {
+        let bans = bans(3, 200, 60_000);
+        bans.record_failure(CLIENT, 403, "/");
+        bans.record_failure(CLIENT, 403, "/");
+        sleep(Duration::from_millis(300));
+        bans.record_failure(CLIENT, 403, "/");
+        assert!(!bans.is_banned(CLIENT));
+        bans.record_failure(CLIENT, 403, "/");
+        bans.record_failure(CLIENT, 403, "/");
+        assert!(bans.is_banned(CLIENT));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let bans = bans(1, 60_000, 200);
+        bans.record_failure(CLIENT, 403, "/");
+        assert!(bans.is_banned(CLIENT));
+        sleep(Duration::from_millis(300));
+        assert!(!bans.is_banned(CLIENT));
+        // With a threshold of 1, the next failure bans it again.
+        bans.record_failure(CLIENT, 403, "/");
+        assert!(bans.is_banned(CLIENT));
+    }
+
+    #[test]
+    fn failures_while_banned_are_not_counted() {
+        let bans = bans(2, 60_000, 200);
+        bans.record_failure(CLIENT, 403, "/");
+        bans.record_failure(CLIENT, 403, "/");
+        assert!(bans.is_banned(CLIENT));
+        bans.record_failure(CLIENT, 403, "/");
+        sleep(Duration::from_millis(300));
+        bans.record_failure(CLIENT, 403, "/");
+        assert!(!bans.is_banned(CLIENT));
+    }
+}
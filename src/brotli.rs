@@ -0,0 +1,627 @@
+//! A small brotli encoder (RFC 7932) for `--compress`.
+//!
+//! Each meta-block is LZ77 commands found with a hash chain, coded with one
+//! prefix code per alphabet and no block switching or context modeling.
+//! That gives up some of what brotli can do, but the output decodes with
+//! any brotli decoder and is about as small as gzip's at the same level.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// `WBITS`, the window size announced in the stream header.
+const WINDOW_BITS: u32 = 16;
+/// Farthest a copy may reach back.
+const MAX_DISTANCE: usize = (1 << WINDOW_BITS) - 16;
+/// Largest `MLEN`; longer input is split into several meta-blocks.
+const MAX_META_BLOCK: usize = 1 << 24;
+const MIN_MATCH: usize = 4;
+/// Match length past which the search stops looking for a longer one.
+const NICE_MATCH: usize = 258;
+/// Hash table size for the largest inputs; small ones get a smaller table.
+const MAX_HASH_BITS: u32 = 15;
+
+const LITERAL_ALPHABET: usize = 256;
+const COMMAND_ALPHABET: usize = 704;
+/// 16 short codes and 48 direct ones, with `NPOSTFIX` and `NDIRECT` of 0.
+const DISTANCE_ALPHABET: usize = 64;
+
+/// `(base, extra bits)` of each insert length code.
+const INSERT_CODES: [(u32, u32); 24] = [
+    (0, 0),
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 1),
+    (8, 1),
+    (10, 2),
+    (14, 2),
+    (18, 3),
+    (26, 3),
+    (34, 4),
+    (50, 4),
+    (66, 5),
+    (98, 5),
+    (130, 6),
+    (194, 7),
+    (322, 8),
+    (578, 9),
+    (1090, 10),
+    (2114, 12),
+    (6210, 14),
+    (22594, 24),
+];
+
+/// `(base, extra bits)` of each copy length code.
+const COPY_CODES: [(u32, u32); 24] = [
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 1),
+    (12, 1),
+    (14, 2),
+    (18, 2),
+    (22, 3),
+    (30, 3),
+    (38, 4),
+    (54, 4),
+    (70, 5),
+    (102, 5),
+    (134, 6),
+    (198, 7),
+    (326, 8),
+    (582, 9),
+    (1094, 10),
+    (2118, 24),
+];
+
+/// Order in which the code lengths of the code length alphabet are sent.
+const CODE_LENGTH_ORDER: [usize; 18] =
+    [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Compresses `input`. `level` (0 to 9) sets how hard matches are searched.
+pub fn compress(input: &[u8], level: u32) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    // WBITS = 16 is a single zero bit.
+    writer.write(1, 0);
+    if input.is_empty() {
+        // ISLAST, ISLASTEMPTY.
+        writer.write(2, 0b11);
+        return writer.finish();
+    }
+    let mut finder = MatchFinder::new(input.len(), 4 << level.min(9));
+    let mut start = 0;
+    while start < input.len() {
+        let end = (start + MAX_META_BLOCK).min(input.len());
+        let commands = finder.commands(input, start, end);
+        write_meta_block(&mut writer, input, start, end, &commands);
+        start = end;
+    }
+    writer.finish()
+}
+
+/// Literals `input[insert]` followed by a copy of `copy` bytes from
+/// `distance` back, if `copy` is not 0.
+struct Command {
+    insert: std::ops::Range<usize>,
+    copy: usize,
+    distance: usize,
+}
+
+/// Hash chains of the positions seen so far, by their first four bytes.
+struct MatchFinder {
+    hash_bits: u32,
+    head: Vec<usize>,
+    /// The previous position with the same hash, for the last
+    /// `MAX_DISTANCE + 1` positions.
+    previous: Vec<usize>,
+    max_chain: usize,
+}
+
+impl MatchFinder {
+    fn new(input_length: usize, max_chain: usize) -> MatchFinder {
+        let hash_bits = (usize::BITS - input_length.leading_zeros()).clamp(8, MAX_HASH_BITS);
+        MatchFinder {
+            hash_bits,
+            head: vec![usize::MAX; 1 << hash_bits],
+            previous: vec![usize::MAX; input_length.min(MAX_DISTANCE + 1)],
+            max_chain,
+        }
+    }
+
+    fn hash(&self, bytes: &[u8]) -> usize {
+        let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        (word.wrapping_mul(0x1E35_A7BD) >> (32 - self.hash_bits)) as usize
+    }
+
+    fn insert(&mut self, input: &[u8], position: usize) {
+        if position + MIN_MATCH > input.len() {
+            return;
+        }
+        let hash = self.hash(&input[position..]);
+        let slot = position % self.previous.len();
+        self.previous[slot] = self.head[hash];
+        self.head[hash] = position;
+    }
+
+    /// The longest earlier match for `input[position..end]`, as
+    /// `(length, distance)`.
+    fn longest(&self, input: &[u8], position: usize, end: usize) -> Option<(usize, usize)> {
+        if position + MIN_MATCH > end {
+            return None;
+        }
+        let mut candidate = self.head[self.hash(&input[position..])];
+        let mut best: Option<(usize, usize)> = None;
+        for _ in 0..self.max_chain {
+            if candidate == usize::MAX || candidate >= position {
+                break;
+            }
+            let distance = position - candidate;
+            if distance > MAX_DISTANCE {
+                break;
+            }
+            let length = input[candidate..]
+                .iter()
+                .zip(&input[position..end])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length >= MIN_MATCH && best.is_none_or(|(best, _)| length > best) {
+                best = Some((length, distance));
+                if length >= NICE_MATCH || position + length == end {
+                    break;
+                }
+            }
+            let next = self.previous[candidate % self.previous.len()];
+            if next == usize::MAX || next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        best
+    }
+
+    /// Commands producing `input[start..end]`, with copies reaching back
+    /// into earlier meta-blocks.
+    fn commands(&mut self, input: &[u8], start: usize, end: usize) -> Vec<Command> {
+        let mut commands = Vec::new();
+        let mut literals = start;
+        let mut position = start;
+        while position < end {
+            match self.longest(input, position, end) {
+                Some((length, distance)) => {
+                    commands.push(Command {
+                        insert: literals..position,
+                        copy: length,
+                        distance,
+                    });
+                    for skipped in position..position + length {
+                        self.insert(input, skipped);
+                    }
+                    position += length;
+                    literals = position;
+                }
+                None => {
+                    self.insert(input, position);
+                    position += 1;
+                }
+            }
+        }
+        if literals < end {
+            commands.push(Command {
+                insert: literals..end,
+                copy: 0,
+                distance: 0,
+            });
+        }
+        commands
+    }
+}
+
+/// A command's insert-and-copy symbol and its extra bits, as
+/// `(symbol, [(bits, value); 2])`.
+fn command_symbol(insert: usize, copy: usize) -> (usize, [(u32, u32); 2]) {
+    let (insert_code, insert_extra) = length_code(&INSERT_CODES, insert as u32);
+    // An insert-only command ends the meta-block before its copy, so any
+    // copy length does.
+    let (copy_code, copy_extra) = length_code(&COPY_CODES, copy.max(2) as u32);
+    let base = match (insert_code >> 3, copy_code >> 3) {
+        (0, 0) => 128,
+        (0, 1) => 192,
+        (0, _) => 384,
+        (1, 0) => 256,
+        (1, 1) => 320,
+        (1, _) => 512,
+        (_, 0) => 448,
+        (_, 1) => 576,
+        _ => 640,
+    };
+    let symbol = base + ((insert_code & 7) << 3) + (copy_code & 7);
+    (symbol, [insert_extra, copy_extra])
+}
+
+/// The code for `value` in `codes`, and its extra bits as `(bits, value)`.
+fn length_code(codes: &[(u32, u32); 24], value: u32) -> (usize, (u32, u32)) {
+    let code = codes
+        .iter()
+        .rposition(|&(base, _)| base <= value)
+        .unwrap_or(0);
+    let (base, bits) = codes[code];
+    (code, (bits, value - base))
+}
+
+/// The distance symbol of `distance` and its extra bits.
+fn distance_symbol(distance: usize) -> (usize, (u32, u32)) {
+    let x = distance as u32 + 3;
+    let bits = 31 - x.leading_zeros() - 1;
+    let high = (x >> bits) - 2;
+    let hcode = 2 * (bits - 1) + high;
+    (16 + hcode as usize, (bits, x - ((2 + high) << bits)))
+}
+
+fn write_meta_block(
+    writer: &mut BitWriter,
+    input: &[u8],
+    start: usize,
+    end: usize,
+    commands: &[Command],
+) {
+    let mut literal_counts = vec![0u32; LITERAL_ALPHABET];
+    let mut command_counts = vec![0u32; COMMAND_ALPHABET];
+    let mut distance_counts = vec![0u32; DISTANCE_ALPHABET];
+    for command in commands {
+        for &byte in &input[command.insert.clone()] {
+            literal_counts[byte as usize] += 1;
+        }
+        command_counts[command_symbol(command.insert.len(), command.copy).0] += 1;
+        if command.copy > 0 {
+            distance_counts[distance_symbol(command.distance).0] += 1;
+        }
+    }
+
+    let last = end == input.len();
+    let length = end - start;
+    writer.write(1, u64::from(last));
+    if last {
+        // ISLASTEMPTY
+        writer.write(1, 0);
+    }
+    let nibbles = match length - 1 {
+        0..=0xFFFF => 4,
+        0x1_0000..=0xF_FFFF => 5,
+        _ => 6,
+    };
+    writer.write(2, nibbles - 4);
+    writer.write(4 * nibbles as u32, (length - 1) as u64);
+    if !last {
+        // ISUNCOMPRESSED
+        writer.write(1, 0);
+    }
+    // One block type for each category, NPOSTFIX and NDIRECT of 0, the
+    // LSB6 context mode, one literal tree and one distance tree.
+    writer.write(3, 0);
+    writer.write(6, 0);
+    writer.write(2, 0);
+    writer.write(2, 0);
+
+    let literals = PrefixCode::build(writer, &literal_counts, 8);
+    let symbols = PrefixCode::build(writer, &command_counts, 10);
+    let distances = PrefixCode::build(writer, &distance_counts, 6);
+
+    for command in commands {
+        let (symbol, extra) = command_symbol(command.insert.len(), command.copy);
+        symbols.write(writer, symbol);
+        for (bits, value) in extra {
+            writer.write(bits, u64::from(value));
+        }
+        for &byte in &input[command.insert.clone()] {
+            literals.write(writer, byte as usize);
+        }
+        if command.copy > 0 {
+            let (symbol, (bits, value)) = distance_symbol(command.distance);
+            distances.write(writer, symbol);
+            writer.write(bits, u64::from(value));
+        }
+    }
+}
+
+/// Canonical prefix code: `(length, bit-reversed code)` per symbol.
+struct PrefixCode {
+    codes: Vec<(u32, u64)>,
+}
+
+impl PrefixCode {
+    /// Builds the code for `counts` and writes its description.
+    /// `alphabet_bits` is the width of a symbol in a simple code.
+    fn build(writer: &mut BitWriter, counts: &[u32], alphabet_bits: u32) -> PrefixCode {
+        let used: Vec<usize> = (0..counts.len())
+            .filter(|&symbol| counts[symbol] > 0)
+            .collect();
+        if used.len() <= 1 {
+            // A simple code of one symbol, which takes no bits to send.
+            writer.write(2, 1);
+            writer.write(2, 0);
+            writer.write(alphabet_bits, used.first().copied().unwrap_or(0) as u64);
+            return PrefixCode {
+                codes: vec![(0, 0); counts.len()],
+            };
+        }
+        let lengths = code_lengths(counts, 15);
+        write_complex(writer, &lengths);
+        PrefixCode {
+            codes: canonical(&lengths),
+        }
+    }
+
+    fn write(&self, writer: &mut BitWriter, symbol: usize) {
+        let (length, code) = self.codes[symbol];
+        writer.write(length, code);
+    }
+}
+
+/// Sends `lengths` as a complex prefix code.
+fn write_complex(writer: &mut BitWriter, lengths: &[u32]) {
+    let last = lengths.iter().rposition(|&length| length > 0).unwrap_or(0);
+    let mut tokens: Vec<(usize, u32)> = Vec::new();
+    let mut index = 0;
+    while index <= last {
+        let length = lengths[index];
+        let run = lengths[index..=last]
+            .iter()
+            .take_while(|&&other| other == length)
+            .count();
+        index += run;
+        if length != 0 {
+            tokens.extend(std::iter::repeat_n((length as usize, 0), run));
+            continue;
+        }
+        push_zeros(&mut tokens, run);
+    }
+
+    let mut token_counts = [0u32; 18];
+    for &(symbol, _) in &tokens {
+        token_counts[symbol] += 1;
+    }
+    let distinct = token_counts.iter().filter(|&&count| count > 0).count();
+    let token_lengths = code_lengths(&token_counts, 5);
+    // HSKIP = 0, then the code length code lengths, trailing zeros left out
+    // unless a single symbol leaves the code incomplete.
+    writer.write(2, 0);
+    let stored = match distinct {
+        1 => 18,
+        _ => CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| token_lengths[symbol] > 0)
+            .map_or(0, |position| position + 1),
+    };
+    for &symbol in &CODE_LENGTH_ORDER[..stored] {
+        let (bits, value) = match token_lengths[symbol] {
+            0 => (2, 0),
+            1 => (4, 7),
+            2 => (3, 3),
+            3 => (2, 2),
+            4 => (2, 1),
+            _ => (4, 15),
+        };
+        writer.write(bits, value);
+    }
+
+    let codes = match distinct {
+        1 => vec![(0, 0); 18],
+        _ => canonical(&token_lengths),
+    };
+    for (symbol, extra) in tokens {
+        let (length, code) = codes[symbol];
+        writer.write(length, code);
+        if symbol == 17 {
+            writer.write(3, u64::from(extra));
+        }
+    }
+}
+
+/// Appends a run of `run` zero lengths, using repeat code 17 for runs of
+/// three or more. Consecutive 17s multiply, so longer runs are written
+/// as base-8 digits, most significant first.
+fn push_zeros(tokens: &mut Vec<(usize, u32)>, mut run: usize) {
+    if run == 11 {
+        tokens.push((0, 0));
+        run -= 1;
+    }
+    if run < 3 {
+        tokens.extend(std::iter::repeat_n((0, 0), run));
+        return;
+    }
+    let first = tokens.len();
+    run -= 3;
+    loop {
+        tokens.push((17, (run & 7) as u32));
+        run >>= 3;
+        if run == 0 {
+            break;
+        }
+        run -= 1;
+    }
+    tokens[first..].reverse();
+}
+
+/// Huffman code lengths for `counts`, none longer than `limit`. Symbols
+/// with a count of 0 get length 0.
+fn code_lengths(counts: &[u32], limit: u32) -> Vec<u32> {
+    let mut floor = 1;
+    loop {
+        let lengths = huffman(counts, floor);
+        if lengths.iter().all(|&length| length <= limit) {
+            return lengths;
+        }
+        // Flattening the counts shortens the longest codes.
+        floor *= 2;
+    }
+}
+
+fn huffman(counts: &[u32], floor: u32) -> Vec<u32> {
+    let mut lengths = vec![0u32; counts.len()];
+    // Nodes are leaves (the symbols) followed by the merged ones.
+    let mut parents: Vec<usize> = vec![usize::MAX; counts.len()];
+    let mut heap = BinaryHeap::new();
+    for (symbol, &count) in counts.iter().enumerate() {
+        if count > 0 {
+            heap.push(Reverse((u64::from(count.max(floor)), symbol)));
+        }
+    }
+    if heap.len() == 1 {
+        let Reverse((_, symbol)) = heap.pop().unwrap();
+        lengths[symbol] = 1;
+        return lengths;
+    }
+    while heap.len() > 1 {
+        let Reverse((first_weight, first)) = heap.pop().unwrap();
+        let Reverse((second_weight, second)) = heap.pop().unwrap();
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[first] = node;
+        parents[second] = node;
+        heap.push(Reverse((first_weight + second_weight, node)));
+    }
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        if counts[symbol] == 0 {
+            continue;
+        }
+        let mut node = symbol;
+        while parents[node] != usize::MAX {
+            node = parents[node];
+            *length += 1;
+        }
+    }
+    lengths
+}
+
+/// Assigns canonical codes, shorter ones first and in symbol order within
+/// a length, bit-reversed since prefix codes are sent from their most
+/// significant bit.
+fn canonical(lengths: &[u32]) -> Vec<(u32, u64)> {
+    let mut next = [0u64; 16];
+    let mut code = 0u64;
+    for length in 1..16 {
+        code = (code
+            + lengths
+                .iter()
+                .filter(|&&other| other == length - 1 && other > 0)
+                .count() as u64)
+            << 1;
+        next[length as usize] = code;
+    }
+    lengths
+        .iter()
+        .map(|&length| {
+            if length == 0 {
+                return (0, 0);
+            }
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            (length, reverse(code, length))
+        })
+        .collect()
+}
+
+fn reverse(code: u64, length: u32) -> u64 {
+    code.reverse_bits() >> (64 - length)
+}
+
+/// Packs bits least significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, bits: u32, value: u64) {
+        if bits == 0 {
+            return;
+        }
+        self.buffer |= value << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        brotli_decompressor::Decompressor::new(compressed, 4096)
+            .read_to_end(&mut output)
+            .unwrap();
+        output
+    }
+
+    /// Bytes no match finder can do much with.
+    fn noise(length: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..length)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n".repeat(200);
+        let mut far = noise(3 * MAX_DISTANCE);
+        far.extend_from_within(..1000);
+        for input in [
+            &b""[..],
+            b"a",
+            b"abcabcabcabcabc",
+            &text,
+            &noise(70_000),
+            &far,
+        ] {
+            for level in [0, 1, 6, 9] {
+                let compressed = compress(input, level);
+                assert_eq!(decompress(&compressed), input, "{} bytes", input.len());
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_repetition() {
+        let text = b"<li><a href=\"/files/\">files</a></li>\n".repeat(1000);
+        assert!(compress(&text, 6).len() < text.len() / 50);
+        // Incompressible input grows only a little.
+        let input = noise(10_000);
+        assert!(compress(&input, 6).len() < input.len() + input.len() / 50);
+    }
+
+    #[test]
+    fn splits_meta_blocks() {
+        let mut input = noise(1000).repeat(MAX_META_BLOCK / 1000 + 2);
+        input.truncate(MAX_META_BLOCK + 1500);
+        assert_eq!(decompress(&compress(&input, 1)), input);
+    }
+}
@@ -0,0 +1,270 @@
+//! In-memory cache of small static files, along with rendered directory
+//! listings and the responses of scripts with `--script-cache`.
+//!
+//! Entries are keyed by canonical path and checked against the file's size
+//! and mtime on every lookup. With a filesystem watcher (`--watch` or
+//! `--dev`) they are also dropped as soon as a change is reported below
+//! their path, which catches replacements that keep the old size and mtime
+//! (`cp -p`, `rsync -t`).
+
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::digest;
+use crate::http::{Body, Response};
+use crate::purge::Pattern;
+
+/// Files larger than this are never cached.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+pub struct CachedFile {
+    pub content: Arc<Vec<u8>>,
+    pub etag: String,
+    digest: OnceLock<String>,
+    len: u64,
+    modified: Option<SystemTime>,
+    last_used: AtomicU64,
+}
+
+impl CachedFile {
+    /// The `sha-256` digest field of the content, computed on first use.
+    pub fn digest(&self) -> &str {
+        self.digest.get_or_init(|| digest::sha256(&self.content))
+    }
+}
+
+pub struct FileCache {
+    capacity: u64,
+    entries: Mutex<HashMap<PathBuf, Arc<CachedFile>>>,
+    clock: AtomicU64,
+}
+
+impl FileCache {
+    /// Creates a cache holding up to `capacity` bytes; 0 disables caching.
+    pub fn new(capacity: u64) -> FileCache {
+        FileCache {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> Option<Arc<CachedFile>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(path)?;
+        if entry.len != metadata.len() || entry.modified != metadata.modified().ok() {
+            return None;
+        }
+        entry.last_used.store(
+            self.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        Some(entry.clone())
+    }
+
+    /// Caches `content` read from `path`, evicting the least recently used
+    /// entries when over capacity.
+    pub fn insert(&self, path: &Path, metadata: &Metadata, content: Arc<Vec<u8>>) {
+        let len = content.len() as u64;
+        if len > MAX_FILE_SIZE || len > self.capacity {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let mut total: u64 = entries.values().map(|entry| entry.len).sum();
+        while total + len > self.capacity {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = entries.remove(&oldest) {
+                total -= evicted.len;
+            }
+        }
+
+        entries.insert(
+            path.to_path_buf(),
+            Arc::new(CachedFile {
+                content,
+                etag: etag(metadata),
+                digest: OnceLock::new(),
+                len,
+                modified: metadata.modified().ok(),
+                last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+            }),
+        );
+    }
+
+    /// Drops every entry at or below `path`.
+    pub fn invalidate(&self, path: &Path) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|cached, _| !cached.starts_with(path));
+    }
+
+    /// Drops the files whose request path below one of `roots` matches
+    /// `pattern` and returns how many there were.
+    pub fn purge(&self, roots: &[PathBuf], pattern: &Pattern) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|cached, _| !roots.iter().any(|root| pattern.matches_file(root, cached)));
+        before - entries.len()
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Rendered directory listings kept at most.
+const MAX_LISTINGS: usize = 256;
+
+/// Rendered directory listings, keyed by request path and rebuilt when the
+/// directory's modification time changes (which adding, removing or
+/// renaming an entry does).
+#[derive(Default)]
+pub struct ListingCache {
+    entries: Mutex<HashMap<String, Listing>>,
+}
+
+struct Listing {
+    modified: Option<SystemTime>,
+    html: Arc<String>,
+}
+
+impl ListingCache {
+    pub fn get(&self, url_path: &str, metadata: &Metadata) -> Option<Arc<String>> {
+        let entries = self.entries.lock().unwrap();
+        let listing = entries.get(url_path)?;
+        (listing.modified == metadata.modified().ok()).then(|| listing.html.clone())
+    }
+
+    pub fn insert(&self, url_path: &str, metadata: &Metadata, html: Arc<String>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_LISTINGS && !entries.contains_key(url_path) {
+            if let Some(evicted) = entries.keys().next().cloned() {
+                entries.remove(&evicted);
+            }
+        }
+        let modified = metadata.modified().ok();
+        entries.insert(url_path.to_string(), Listing { modified, html });
+    }
+
+    /// Drops every page of the listings whose path matches `pattern`.
+    pub fn purge(&self, pattern: &Pattern) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|key, _| {
+            let path = key.split_once('?').map_or(key.as_str(), |(path, _)| path);
+            !pattern.matches_key(path)
+        });
+        before - entries.len()
+    }
+}
+
+/// Script responses kept at most.
+const MAX_RESPONSES: usize = 256;
+
+/// Largest script response body kept.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Responses of scripts with `--script-cache`, keyed by request path and
+/// query, each kept until its time to live runs out.
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, CachedResponse>>,
+}
+
+struct CachedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl ResponseCache {
+    /// A copy of the response stored under `key`, with an `Age` header,
+    /// unless it has expired.
+    pub fn get(&self, key: &str) -> Option<Response> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let entry = entries.get(key)?;
+        if entry.expires <= now {
+            entries.remove(key);
+            return None;
+        }
+        let mut response = Response::with_body(entry.status, "", Body::Shared(entry.body.clone()));
+        response.headers = entry.headers.clone();
+        let age = now.duration_since(entry.stored).as_secs();
+        response.set_header("Age", age.to_string());
+        Some(response)
+    }
+
+    /// Keeps `response` for `ttl` if it is a complete `200` small enough,
+    /// meant for anyone: without `Cache-Control: no-store` or `private`,
+    /// and without a `Set-Cookie`.
+    pub fn insert(&self, key: &str, response: &Response, ttl: Duration) {
+        let personal = response.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("Set-Cookie")
+                || name.eq_ignore_ascii_case("Cache-Control")
+                    && value.split(',').any(|directive| {
+                        let name = directive.split('=').next().unwrap_or_default().trim();
+                        name.eq_ignore_ascii_case("no-store")
+                            || name.eq_ignore_ascii_case("private")
+                    })
+        });
+        if response.status != 200
+            || personal
+            || response.stream.is_some()
+            || response.body.len() > MAX_RESPONSE_SIZE
+        {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if entries.len() >= MAX_RESPONSES && !entries.contains_key(key) {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= MAX_RESPONSES {
+                if let Some(evicted) = entries.keys().next().cloned() {
+                    entries.remove(&evicted);
+                }
+            }
+        }
+        let entry = CachedResponse {
+            status: response.status,
+            headers: response.headers.clone(),
+            body: response.body.share(),
+            stored: now,
+            expires: now + ttl,
+        };
+        entries.insert(key.to_string(), entry);
+    }
+
+    /// Drops the responses for the paths matching `pattern`.
+    pub fn purge(&self, pattern: &Pattern) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|key, _| {
+            let path = key.split_once('?').map_or(key.as_str(), |(path, _)| path);
+            !pattern.matches_key(path)
+        });
+        before - entries.len()
+    }
+}
+
+/// An entity tag derived from size and modification time.
+pub fn etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", metadata.len(), modified.as_nanos())
+}
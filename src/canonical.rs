@@ -0,0 +1,45 @@
+//! Redirects from alternate host names to the canonical one, configured
+//! with `--redirect-host www.example.com=example.com`.
+
+use crate::http::{Request, Response};
+use crate::redirect::strip_port;
+
+pub struct HostRedirect {
+    alias: String,
+    canonical: String,
+}
+
+impl std::str::FromStr for HostRedirect {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<HostRedirect, ()> {
+        let (alias, canonical) = value.split_once('=').ok_or(())?;
+        if alias.is_empty() || canonical.is_empty() {
+            return Err(());
+        }
+        Ok(HostRedirect {
+            alias: alias.to_ascii_lowercase(),
+            canonical: canonical.to_string(),
+        })
+    }
+}
+
+/// Returns a 301 to the same path and query on the canonical host when the
+/// request's `Host` is one of the aliases.
+pub fn redirect(rules: &[HostRedirect], request: &Request) -> Option<Response> {
+    let host = request.header("Host")?;
+    let name = strip_port(host);
+    let port = &host[name.len()..];
+    let name = name.trim_end_matches('.');
+    let rule = rules
+        .iter()
+        .find(|rule| rule.alias.eq_ignore_ascii_case(name))?;
+
+    // A network-path reference keeps whatever scheme the client used.
+    let mut response = Response::error(301);
+    response.set_header(
+        "Location",
+        format!("//{}{}{}", rule.canonical, port, request.target),
+    );
+    Some(response)
+}
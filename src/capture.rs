@@ -0,0 +1,82 @@
+//! Recording of served requests with `--capture DIR`, for `rustywebserver
+//! replay`.
+//!
+//! Every request is written to its own JSON file in `DIR`, named so that
+//! files sort in the order the requests were answered, together with the
+//! status and headers of the response it got.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::http::{Request, Response};
+
+/// One captured exchange.
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    pub method: String,
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    /// Request body, base64-encoded.
+    pub body: String,
+    pub status: u16,
+    /// Response headers as sent, including `Content-Length`.
+    pub response_headers: Vec<(String, String)>,
+}
+
+impl Record {
+    pub fn body(&self) -> Result<Vec<u8>, String> {
+        STANDARD
+            .decode(&self.body)
+            .map_err(|err| format!("invalid body: {err}"))
+    }
+}
+
+pub struct Capture {
+    dir: PathBuf,
+    /// Distinguishes files written within the same millisecond.
+    sequence: AtomicU64,
+}
+
+impl Capture {
+    pub fn new(dir: PathBuf) -> std::io::Result<Capture> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Capture {
+            dir,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    /// Writes `request` and `response` to a new file in the background.
+    pub fn record(&self, request: &Request, response: &Response) {
+        let mut response_headers = response.headers.clone();
+        response_headers.push((
+            "Content-Length".to_string(),
+            response.body.len().to_string(),
+        ));
+        let record = Record {
+            method: request.method.clone(),
+            target: request.target.clone(),
+            headers: request.headers.clone(),
+            body: STANDARD.encode(&*request.body),
+            status: response.status,
+            response_headers,
+        };
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{millis:013}-{sequence:06}.json"));
+        tokio::spawn(async move {
+            let json = serde_json::to_vec_pretty(&record).expect("records serialize");
+            if let Err(err) = tokio::fs::write(&path, json).await {
+                eprintln!("cannot write {}: {err}", path.display());
+            }
+        });
+    }
+}
@@ -0,0 +1,89 @@
+//! The meta-variables of CGI/1.1 (RFC 3875) that describe a request to the
+//! program answering it.
+
+use crate::http::{Request, Response};
+use crate::redirect::strip_port;
+
+/// The standard variables for `request`, handled by the program that
+/// `script_name` names, on a server listening on `port`. Every header
+/// becomes an `HTTP_` variable, except the ones with variables of their
+/// own and `Proxy`, which programs would mistake for their proxy settings.
+pub fn variables(request: &Request, script_name: &str, port: u16) -> Vec<(String, String)> {
+    let mut variables = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", "rustywebserver".to_string()),
+        ("SERVER_PROTOCOL", request.version.clone()),
+        (
+            "SERVER_NAME",
+            strip_port(request.header("Host").unwrap_or_default()).to_string(),
+        ),
+        ("SERVER_PORT", port.to_string()),
+        ("REQUEST_METHOD", request.method.clone()),
+        ("REQUEST_URI", request.target.clone()),
+        ("SCRIPT_NAME", script_name.to_string()),
+        (
+            "PATH_INFO",
+            request.path[script_name.len().min(request.path.len())..].to_string(),
+        ),
+        ("QUERY_STRING", request.query.clone()),
+    ];
+    if let Some(content_type) = request.header("Content-Type") {
+        variables.push(("CONTENT_TYPE", content_type.to_string()));
+    }
+    if !request.body.is_empty() || request.header("Content-Length").is_some() {
+        variables.push(("CONTENT_LENGTH", request.body.len().to_string()));
+    }
+    if let Some(peer) = request.peer {
+        variables.push(("REMOTE_ADDR", peer.ip().to_string()));
+        if peer.port() != 0 {
+            variables.push(("REMOTE_PORT", peer.port().to_string()));
+        }
+    }
+    if request.tls.is_some() {
+        variables.push(("HTTPS", "on".to_string()));
+    }
+    let mut variables: Vec<(String, String)> = variables
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    for (name, value) in &request.headers {
+        let skipped = ["Content-Type", "Content-Length", "Proxy"]
+            .iter()
+            .any(|skipped| skipped.eq_ignore_ascii_case(name));
+        if skipped {
+            continue;
+        }
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        // Repeated headers are combined, as a proxy would.
+        match variables.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => variables.push((name, value.clone())),
+        }
+    }
+    variables
+}
+
+/// Takes the status of a program's response from its `Status` header,
+/// which isn't sent on: `302` for a `Location` without one, else `200`. A
+/// status that can't be read is the program's error, so `500`.
+pub fn apply_status(response: &mut Response) {
+    let status = response.header("Status").map(|status| {
+        status
+            .split_whitespace()
+            .next()
+            .and_then(|status| status.parse().ok())
+            .filter(|status| (200..600).contains(status))
+            .unwrap_or(500)
+    });
+    response.status = match status {
+        Some(status) => status,
+        None if response.header("Location").is_some() => 302,
+        None => 200,
+    };
+    response
+        .headers
+        .retain(|(name, _)| !name.eq_ignore_ascii_case("Status"));
+}
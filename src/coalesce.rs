@@ -0,0 +1,56 @@
+//! Single-flight reads: concurrent requests for the same uncached file share
+//! one disk read instead of each reading the whole file.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+/// Errors are kept as their OS code when they have one, so callers can
+/// still tell running out of descriptors apart.
+type Flight = Arc<OnceCell<Result<Arc<Vec<u8>>, (io::ErrorKind, Option<i32>)>>>;
+
+#[derive(Default)]
+pub struct SingleFlight {
+    flights: Mutex<HashMap<String, Flight>>,
+}
+
+impl SingleFlight {
+    /// Runs `read` for `key` unless a read for the same key is already in
+    /// progress, in which case its result is shared. If the reading request
+    /// goes away, a waiting one takes over.
+    pub async fn read<F>(&self, key: String, read: F) -> io::Result<Arc<Vec<u8>>>
+    where
+        F: Future<Output = io::Result<Vec<u8>>>,
+    {
+        let flight = self
+            .flights
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = flight
+            .get_or_init(|| async {
+                read.await
+                    .map(Arc::new)
+                    .map_err(|err| (err.kind(), err.raw_os_error()))
+            })
+            .await
+            .clone();
+
+        let mut flights = self.flights.lock().unwrap();
+        if flights
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            flights.remove(&key);
+        }
+        result.map_err(|(kind, code)| match code {
+            Some(code) => io::Error::from_raw_os_error(code),
+            None => io::Error::from(kind),
+        })
+    }
+}
@@ -0,0 +1,135 @@
+//! On-the-fly compression of responses, enabled with `--compress`.
+//!
+//! Only `200` responses at least `min_size` bytes long whose type is in the
+//! whitelist are compressed, with brotli or gzip, whichever the client's
+//! `Accept-Encoding` prefers; brotli wins a tie. Locations can opt out with
+//! `compress = false`.
+
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::brotli;
+use crate::digest;
+use crate::http::{Request, Response};
+
+#[derive(Clone)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Compression level, 0 (fastest) to 9 (smallest).
+    pub level: u32,
+    /// Bodies shorter than this are sent as they are.
+    pub min_size: usize,
+    /// Compressible types: exact `type/subtype`s or `type/*` ranges.
+    pub types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: false,
+            level: 6,
+            min_size: 1024,
+            types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+/// Compresses `response` in place when its type and size qualify and the
+/// client accepts brotli or gzip.
+pub fn apply(config: &CompressionConfig, request: &Request, response: &mut Response) {
+    if response.status != 200
+        || response.stream.is_some()
+        || response.body.len() < config.min_size
+        || response.header("Content-Encoding").is_some()
+        || !response
+            .header("Content-type")
+            .is_some_and(|content_type| is_compressible(config, content_type))
+    {
+        return;
+    }
+    response.add_vary("Accept-Encoding");
+    let accept = request.header("Accept-Encoding").unwrap_or_default();
+    let Some(coding) = preferred_coding(accept) else {
+        return;
+    };
+
+    let compressed = match coding {
+        "br" => brotli::compress(&response.body, config.level),
+        _ => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(config.level));
+            if encoder.write_all(&response.body).is_err() {
+                return;
+            }
+            let Ok(compressed) = encoder.finish() else {
+                return;
+            };
+            compressed
+        }
+    };
+    response.body = compressed.into();
+    response.set_header("Content-Encoding", coding);
+    if let Some(etag) = response.header("ETag") {
+        let etag = format!("{}-{coding}\"", etag.trim_end_matches('"'));
+        response.set_header("ETag", etag);
+    }
+    if response.header("Content-Digest").is_some() {
+        let digest = digest::sha256(&response.body);
+        response.set_header("Content-Digest", digest);
+    }
+}
+
+fn is_compressible(config: &CompressionConfig, content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let (kind, _) = essence.split_once('/').unwrap_or((essence, ""));
+    config.types.iter().any(|allowed| {
+        allowed.eq_ignore_ascii_case(essence)
+            || allowed
+                .strip_suffix("/*")
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(kind))
+    })
+}
+
+/// The coding `accept` rates highest, brotli on a tie, or `None` when it
+/// takes neither.
+fn preferred_coding(accept: &str) -> Option<&'static str> {
+    let br = encoding_quality(accept, "br");
+    let gzip = encoding_quality(accept, "gzip");
+    if br == 0.0 && gzip == 0.0 {
+        return None;
+    }
+    match br >= gzip {
+        true => Some("br"),
+        false => Some("gzip"),
+    }
+}
+
+/// Returns the quality an `Accept-Encoding` header assigns to `coding`,
+/// falling back to a `*` entry.
+pub fn encoding_quality(accept: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+    for entry in accept.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|value| value.parse().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return quality;
+        }
+        if name == "*" {
+            wildcard = Some(quality);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
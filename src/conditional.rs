@@ -0,0 +1,127 @@
+//! Conditional requests (RFC 9110, section 13).
+//!
+//! Writes honor `If-Match` and `If-Unmodified-Since`, so two clients editing
+//! the same file can't silently overwrite each other: a write based on a
+//! stale copy fails with `412 Precondition Failed`. Static files and script
+//! responses carrying an `ETag` or `Last-Modified` header are turned into
+//! `304 Not Modified` when `If-None-Match` or `If-Modified-Since` match.
+//! An `If-Modified-Since` later than the server's clock is ignored, as the
+//! client can't have seen a version from the future.
+
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache;
+use crate::date;
+use crate::http::Request;
+
+/// Whether a `GET` whose response carries `etag` and `last_modified` can be
+/// answered with `304 Not Modified`. `If-None-Match` (compared weakly) takes
+/// precedence over `If-Modified-Since`.
+pub fn not_modified(request: &Request, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    if !matches!(request.method.as_str(), "GET" | "HEAD") {
+        return false;
+    }
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return if_none_match.trim() == "*"
+            || etag
+                .is_some_and(|etag| if_none_match.split(',').any(|tag| weak(tag) == weak(etag)));
+    }
+    let since = request
+        .header("If-Modified-Since")
+        .and_then(date::parse_http_date)
+        .filter(|&since| since <= SystemTime::now());
+    let modified = last_modified.and_then(date::parse_http_date);
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+/// Whether a write to a file whose current state is `metadata` (`None` if it
+/// doesn't exist) may go ahead.
+pub fn write_allowed(request: &Request, metadata: Option<&Metadata>) -> bool {
+    if let Some(if_match) = request.header("If-Match") {
+        let Some(metadata) = metadata else {
+            return false;
+        };
+        if if_match.trim() == "*" {
+            return true;
+        }
+        // Weak tags never match in this strong comparison.
+        let etag = cache::etag(metadata);
+        return if_match.split(',').map(str::trim).any(|tag| tag == etag);
+    }
+
+    let since = request
+        .header("If-Unmodified-Since")
+        .and_then(date::parse_http_date);
+    let modified = metadata.and_then(|metadata| metadata.modified().ok());
+    match (since, modified) {
+        (Some(since), Some(modified)) => {
+            // HTTP dates have whole seconds.
+            let seconds = |time: SystemTime| {
+                time.duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs())
+            };
+            seconds(modified) <= seconds(since)
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn get(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            target: "/".to_string(),
+            path: "/".to_string(),
+            query: String::new(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new().into(),
+            tls: None,
+            peer: None,
+        }
+    }
+
+    const MODIFIED: &str = "Mon, 21 Oct 2013 20:13:21 GMT";
+
+    #[test]
+    fn compares_modification_dates() {
+        let not_modified = |since: &str| {
+            super::not_modified(&get(&[("If-Modified-Since", since)]), None, Some(MODIFIED))
+        };
+        assert!(not_modified(MODIFIED));
+        assert!(not_modified("Tue, 22 Oct 2013 00:00:00 GMT"));
+        assert!(!not_modified("Sun, 20 Oct 2013 00:00:00 GMT"));
+        assert!(!not_modified("yesterday"));
+        // A date past the server's clock is ignored.
+        let tomorrow = date::http_date(SystemTime::now() + Duration::from_secs(86_400));
+        assert!(!not_modified(&tomorrow));
+    }
+
+    #[test]
+    fn prefers_entity_tags() {
+        let etag = Some("\"v1\"");
+        let check = |tags: &str| {
+            let request = get(&[("If-None-Match", tags), ("If-Modified-Since", MODIFIED)]);
+            not_modified(&request, etag, Some(MODIFIED))
+        };
+        assert!(check("\"v1\""));
+        assert!(check("\"v0\", W/\"v1\""));
+        assert!(check("*"));
+        // A mismatched tag wins over a matching date.
+        assert!(!check("\"v2\""));
+
+        let mut post = get(&[("If-None-Match", "*")]);
+        post.method = "POST".to_string();
+        assert!(!not_modified(&post, etag, None));
+    }
+}
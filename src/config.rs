@@ -0,0 +1,711 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::acme;
+use crate::anonymize;
+use crate::bandwidth::Quota;
+use crate::bans::BanConfig;
+use crate::canonical::HostRedirect;
+use crate::cidr::Cidr;
+use crate::compress::CompressionConfig;
+#[cfg(unix)]
+use crate::credentials::Credentials;
+use crate::fastcgi::FastCgiRoute;
+use crate::handlers::{Handler, SCRIPTS_PREFIX};
+use crate::headers::HeaderRule;
+use crate::inflate;
+use crate::locations::Location;
+use crate::proxy::{Balance, ProxyRoute};
+use crate::scripts::{Interpreter, ScriptLimits};
+use crate::spool;
+use crate::userdir;
+use crate::vhost::VirtualHost;
+use crate::wellknown::{FallbackFavicon, SecurityTxt};
+
+pub const USAGE: &str = "Usage: rustywebserver PORT ROOT_FOLDER [OPTIONS]
+       rustywebserver replay CAPTURE_DIR HOST:PORT [--ignore-header NAME]...
+       rustywebserver selftest ROOT_FOLDER [OPTIONS]
+
+Options:
+    --overlay-base DIR    serve static files missing from ROOT_FOLDER from DIR
+                          (repeatable, earlier folders win)
+    --vhost HOST=DIR      serve requests for HOST (or *.DOMAIN) from DIR instead of ROOT_FOLDER
+                          (repeatable)
+    --config FILE         load [[location]] settings from a TOML file
+    --geoip-db FILE       MaxMind GeoLite2/GeoIP2 country database for geo rules
+    --dev                 development defaults: live reload of HTML pages, no caching,
+                          CORS from any origin, detailed request logs, /.well-known served
+    --server-timing       send a Server-Timing header with the time spent reading, routing and
+                          answering each request
+    --watch               watch the root folder so cached files are dropped as soon as they change
+    --file-cache BYTES    keep up to BYTES of small files in memory (default 0, disabled)
+    --open-file-cache N   keep up to N files open between requests (default 0, disabled)
+    --open-file-cache-valid SECS
+                          re-check cached open files after SECS (default 30)
+    --mmap-min BYTES      memory-map files of at least BYTES instead of reading them
+    --mmap-max BYTES      largest file to memory-map (default 67108864)
+    --digest              send sha-256 Repr-Digest and Content-Digest headers with static files
+    --compress            brotli or gzip responses for clients that accept it
+    --compress-level N    compression level from 0 to 9 (default 6)
+    --compress-min-size BYTES
+                          leave smaller responses uncompressed (default 1024)
+    --compress-types LIST comma-separated types to compress, `type/*` allowed
+                          (default text/*,application/json,application/javascript,application/xml,image/svg+xml)
+    --precompressed       serve FILE.br or FILE.gz in place of FILE to clients accepting that coding
+    --max-inflated-size BYTES
+                          largest gzip-encoded request body once decoded (default 67108864)
+    --body-buffer-size BYTES
+                          keep request bodies up to BYTES in memory, larger ones in temporary files
+                          (default 1048576)
+    --max-body-size BYTES answer 413 to requests announcing a larger body (default: no limit)
+    --script-timeout SECS answer 504 and kill scripts still running after SECS (0 for no limit, default 30)
+    --script-cache SECS   keep responses of scripts for GET requests for SECS, by path and query
+                          (default: not kept)
+    --write-timeout SECS  drop connections still receiving their response after SECS (default: no limit)
+    --idle-timeout SECS   close connections that send no complete request head within SECS,
+                          including between kept-alive requests (0 for no limit, default 15)
+    --asset-manifest FILE serve logical asset names from the hashed files listed in FILE (JSON)
+    --autoindex           list directories that have no index.html
+    --thumbnails          show image previews in directory listings
+    --thumbnail-dir DIR   where generated previews are kept (default: a directory under the system temp dir)
+    --search              search file names below a directory with ?q= (and contents with &content=1)
+    --robots-txt          answer /robots.txt, when the root has none, disallowing restricted locations
+    --sitemap             answer /sitemap.xml, when the root has none, with every HTML file
+    --proxy PREFIX=URL[,URL...]
+                          forward requests below PREFIX to the HTTP server at URL, e.g.
+                          /api=http://127.0.0.1:9000, spreading them over several (repeatable)
+    --proxy-balance round-robin|least-connections
+                          how --proxy picks among several upstreams (default round-robin)
+    --fastcgi PATTERN=ADDRESS
+                          have files matching PATTERN, e.g. *.php, answered by the FastCGI
+                          server at host:port or unix:PATH (repeatable)
+    --cgi-dir PREFIX      run files below PREFIX, e.g. /cgi-bin, as CGI scripts instead of those
+                          below /scripts (repeatable)
+    --cgi-extension EXT   run files ending in EXT, e.g. .cgi, as CGI scripts anywhere (repeatable)
+    --script-cpu SECS     kill scripts that use more than SECS of CPU time (Unix)
+    --script-memory BYTES cap the address space of each script (Unix)
+    --script-file-size BYTES
+                          largest file a script may write (Unix)
+    --script-open-files N most files a script may have open at once (Unix)
+    --cgi-user USER       run scripts as USER, by name or ID, when the server runs as root (Unix)
+    --cgi-group GROUP     run scripts with GROUP instead of the user's primary group (Unix)
+    --script-env NAME[=VALUE]
+                          give scripts NAME from the server's environment, or set to VALUE;
+                          they otherwise only get PATH, LANG, LC_ALL, TZ and TMPDIR (repeatable)
+    --interpreter EXT=PROGRAM[,EXT=PROGRAM...]
+                          run scripts ending in EXT through PROGRAM, e.g. .py=python3 (repeatable)
+    --workers N           long-lived processes kept per script of the `worker` handler (default 4)
+    --worker-requests N   replace a worker after N requests (0 for never, default 1000)
+    --userdir PATTERN     serve /~USER/ from PATTERN with * replaced by USER, e.g. /home/*/public_html
+    --tls-cert FILE       serve HTTPS with this PEM certificate chain (needs --tls-key)
+    --tls-key FILE        PEM private key for --tls-cert
+    --tls-client-ca FILE  require client certificates issued by a CA in this PEM file
+    --http2               offer HTTP/2 to TLS clients through ALPN
+    --acme-domain NAME    serve HTTPS with a certificate for NAME from an ACME CA such as
+                          Let's Encrypt (repeatable, needs --https-redirect on port 80)
+    --acme-dir DIR        where the ACME account key and certificate are kept (default acme)
+    --acme-directory URL  directory of the ACME CA (default: Let's Encrypt)
+    --acme-email ADDR     contact address for the ACME account
+    --redirect-host ALIAS=HOST
+                          redirect requests for host ALIAS to HOST (repeatable)
+    --https-redirect PORT also listen on PORT and redirect plain HTTP there to HTTPS
+    --https-port PORT     port HTTPS clients are redirected to (default 443)
+    --hsts-max-age SECS   send Strict-Transport-Security over HTTPS with this max-age
+    --hsts-preload        add includeSubDomains and preload to Strict-Transport-Security
+    --admin-token TOKEN   enable the /_admin/ API for requests bearing TOKEN
+    --maintenance-flag FILE
+                          answer 503 while FILE exists
+    --maintenance-page FILE
+                          HTML page sent with maintenance 503s
+    --maintenance-retry-after SECS
+                          Retry-After sent during maintenance (default 300)
+    --fallback-favicon icon|empty
+                          answer /favicon.ico with a built-in icon or 204 when the root has none
+    --allow-put           accept PUT uploads anywhere under the root, not only in writable locations
+    --allow-delete        accept DELETE of files and empty directories under the root
+    --upload-quota BYTES  refuse uploads that would take the root folder past BYTES
+    --max-connections-per-ip N
+                          refuse connections from a client that already has N open (0 disables, default 0)
+    --connection-limit-exempt CIDR
+                          do not cap connections from CIDR (repeatable)
+    --max-active N        handle at most N requests at once, queueing the rest (0 disables, default 0)
+    --queue-size N        requests that may wait for --max-active, by priority (default 100)
+    --shards N            run N single-threaded shards with their own listener and caches
+                          (0 for one per core)
+    --anonymize-ips mask|hash
+                          log client addresses with the last octet (IPv6: 80 bits) zeroed,
+                          or as a hash keyed with a random key replaced daily
+    --trusted-proxies CIDR[,CIDR...]
+                          take the client address from X-Forwarded-For on connections from
+                          these reverse proxies (repeatable)
+    --proxy-protocol      expect a PROXY protocol (v1 or v2) header from a load balancer on
+                          every connection, and take the client address from it
+    --capture DIR         record every request and response head in DIR for `rustywebserver replay`
+    --kv-store            give scripts a shared key-value store (see KV_URL and KV_TOKEN)
+    --kv-file FILE        keep the key-value store in FILE across restarts (implies --kv-store)
+    --lua-handlers DIR    answer /lua/NAME by running DIR/NAME.lua (builds with the `lua` feature)
+    --ban-threshold N     ban a client after N 401/403 responses (0 disables, default 0)
+    --ban-window SECS     window in which failures are counted (default 60)
+    --ban-duration SECS   how long a ban lasts (default 600)";
+
+/// Server configuration, built from the command line and the optional config file.
+pub struct Config {
+    pub port: u16,
+    pub root: PathBuf,
+    pub overlay_bases: Vec<PathBuf>,
+    pub vhosts: Vec<VirtualHost>,
+    pub bans: BanConfig,
+    pub geoip_db: Option<PathBuf>,
+    pub dev: bool,
+    pub server_timing: bool,
+    pub watch: bool,
+    pub file_cache: u64,
+    pub open_file_cache: usize,
+    pub open_file_cache_valid: u64,
+    pub mmap_min: Option<u64>,
+    pub mmap_max: u64,
+    pub digest: bool,
+    pub compression: CompressionConfig,
+    pub precompressed: bool,
+    pub max_inflated_size: u64,
+    pub body_buffer_size: u64,
+    pub max_body_size: Option<u64>,
+    pub script_timeout: Option<u64>,
+    pub script_cache: Option<u64>,
+    pub write_timeout: Option<u64>,
+    pub idle_timeout: u64,
+    pub asset_manifest: Option<PathBuf>,
+    pub autoindex: bool,
+    pub search: bool,
+    pub userdir: Option<String>,
+    pub proxies: Vec<ProxyRoute>,
+    pub proxy_balance: Balance,
+    pub fastcgi: Vec<FastCgiRoute>,
+    pub interpreters: Vec<Interpreter>,
+    /// Processes per script of the `worker` handler, at least one.
+    pub workers: usize,
+    /// Requests a worker serves before it is replaced, 0 for no limit.
+    pub worker_requests: u64,
+    /// Request path prefixes, with a `/` at each end.
+    pub cgi_dirs: Vec<String>,
+    /// Without the dot.
+    pub cgi_extensions: Vec<String>,
+    /// Variables passed to scripts, with the value to set or `None` to pass
+    /// the server's own.
+    pub script_env: Vec<(String, Option<String>)>,
+    pub script_limits: ScriptLimits,
+    /// Who scripts run as, from `--cgi-user` and `--cgi-group`.
+    #[cfg(unix)]
+    pub script_credentials: Option<Credentials>,
+    pub robots_txt: bool,
+    pub sitemap: bool,
+    pub thumbnails: bool,
+    pub thumbnail_dir: PathBuf,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub http2: bool,
+    pub acme_domains: Vec<String>,
+    pub acme_dir: PathBuf,
+    pub acme_directory: String,
+    pub acme_email: Option<String>,
+    pub host_redirects: Vec<HostRedirect>,
+    pub https_redirect: Option<u16>,
+    pub https_port: u16,
+    pub hsts_max_age: Option<u64>,
+    pub hsts_preload: bool,
+    pub admin_token: Option<String>,
+    pub maintenance_flag: Option<PathBuf>,
+    pub maintenance_page: Option<PathBuf>,
+    pub maintenance_retry_after: u64,
+    pub fallback_favicon: Option<FallbackFavicon>,
+    pub security_txt: Option<SecurityTxt>,
+    pub allow_put: bool,
+    pub allow_delete: bool,
+    pub upload_quota: Option<u64>,
+    pub max_connections_per_ip: usize,
+    pub connection_limit_exempt: Vec<Cidr>,
+    pub max_active: usize,
+    pub queue_size: usize,
+    pub shards: Option<usize>,
+    pub lua_handlers: Option<PathBuf>,
+    pub header_rules: Vec<HeaderRule>,
+    pub bandwidth_quotas: Vec<Quota>,
+    pub kv_store: bool,
+    pub capture: Option<PathBuf>,
+    pub anonymize_ips: Option<anonymize::Mode>,
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<Cidr>,
+    pub kv_file: Option<PathBuf>,
+    pub locations: Vec<Location>,
+}
+
+/// Contents of the file given with `--config`.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    location: Vec<Location>,
+    security_txt: Option<SecurityTxt>,
+    #[serde(default)]
+    headers: Vec<HeaderRule>,
+    #[serde(default)]
+    bandwidth: Vec<Quota>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<ConfigFile, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+        toml::from_str(&text).map_err(|err| format!("invalid config {}: {err}", path.display()))
+    }
+}
+
+impl Config {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut positional = Vec::new();
+        let mut overlay_bases = Vec::new();
+        let mut vhosts = Vec::new();
+        let mut bans = BanConfig::default();
+        let mut geoip_db = None;
+        let mut file = ConfigFile::default();
+        let mut dev = false;
+        let mut server_timing = false;
+        let mut watch = false;
+        let mut file_cache = 0;
+        let mut open_file_cache = 0;
+        let mut open_file_cache_valid = 30;
+        let mut mmap_min = None;
+        let mut mmap_max = 64 * 1024 * 1024;
+        let mut digest = false;
+        let mut compression = CompressionConfig::default();
+        let mut precompressed = false;
+        let mut max_inflated_size = inflate::DEFAULT_LIMIT;
+        let mut body_buffer_size = spool::DEFAULT_THRESHOLD;
+        let mut max_body_size = None;
+        let mut script_timeout = Some(30);
+        let mut script_cache = None;
+        let mut write_timeout = None;
+        let mut idle_timeout = 15;
+        let mut asset_manifest = None;
+        let mut autoindex = false;
+        let mut search = false;
+        let mut userdir = None;
+        let mut proxies = Vec::new();
+        let mut proxy_balance = Balance::default();
+        let mut fastcgi = Vec::new();
+        let mut interpreters = Vec::new();
+        let mut workers = 4;
+        let mut worker_requests = 1000;
+        let mut cgi_dirs = Vec::new();
+        let mut cgi_extensions = Vec::new();
+        let mut script_env = Vec::new();
+        let mut script_limits = ScriptLimits::default();
+        let mut cgi_user = None;
+        let mut cgi_group = None;
+        let mut robots_txt = false;
+        let mut sitemap = false;
+        let mut thumbnails = false;
+        let mut thumbnail_dir = std::env::temp_dir().join("rustywebserver-thumbnails");
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut tls_client_ca = None;
+        let mut http2 = false;
+        let mut acme_domains = Vec::new();
+        let mut acme_dir = PathBuf::from("acme");
+        let mut acme_directory = acme::DEFAULT_DIRECTORY.to_string();
+        let mut acme_email = None;
+        let mut host_redirects = Vec::new();
+        let mut https_redirect = None;
+        let mut https_port = 443;
+        let mut hsts_max_age = None;
+        let mut hsts_preload = false;
+        let mut admin_token = None;
+        let mut maintenance_flag = None;
+        let mut maintenance_page = None;
+        let mut maintenance_retry_after = 300;
+        let mut fallback_favicon = None;
+        let mut allow_put = false;
+        let mut allow_delete = false;
+        let mut upload_quota = None;
+        let mut max_connections_per_ip = 0;
+        let mut connection_limit_exempt = Vec::new();
+        let mut max_active = 0;
+        let mut queue_size = 100;
+        let mut shards = None;
+        let mut lua_handlers = None;
+        let mut kv_store = false;
+        let mut capture = None;
+        let mut anonymize_ips = None;
+        let mut proxy_protocol = false;
+        let mut trusted_proxies = Vec::new();
+        let mut kv_file = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--overlay-base" => overlay_bases.push(parse_value(&arg, args.next())?),
+                "--vhost" => vhosts.push(parse_value(&arg, args.next())?),
+                "--config" => file = ConfigFile::load(&parse_value::<PathBuf>(&arg, args.next())?)?,
+                "--geoip-db" => geoip_db = Some(parse_value(&arg, args.next())?),
+                "--dev" => dev = true,
+                "--server-timing" => server_timing = true,
+                "--watch" => watch = true,
+                "--file-cache" => file_cache = parse_value(&arg, args.next())?,
+                "--open-file-cache" => open_file_cache = parse_value(&arg, args.next())?,
+                "--open-file-cache-valid" => {
+                    open_file_cache_valid = parse_value(&arg, args.next())?
+                }
+                "--mmap-min" => mmap_min = Some(parse_value(&arg, args.next())?),
+                "--mmap-max" => mmap_max = parse_value(&arg, args.next())?,
+                "--digest" => digest = true,
+                "--compress" => compression.enabled = true,
+                "--compress-level" => compression.level = parse_value(&arg, args.next())?,
+                "--compress-min-size" => compression.min_size = parse_value(&arg, args.next())?,
+                "--compress-types" => {
+                    let types: String = parse_value(&arg, args.next())?;
+                    compression.types = types
+                        .split(',')
+                        .map(|kind| kind.trim().to_string())
+                        .collect();
+                }
+                "--precompressed" => precompressed = true,
+                "--max-inflated-size" => max_inflated_size = parse_value(&arg, args.next())?,
+                "--body-buffer-size" => body_buffer_size = parse_value(&arg, args.next())?,
+                "--max-body-size" => max_body_size = Some(parse_value(&arg, args.next())?),
+                "--script-timeout" => script_timeout = Some(parse_value(&arg, args.next())?),
+                "--script-cache" => script_cache = Some(parse_value(&arg, args.next())?),
+                "--write-timeout" => write_timeout = Some(parse_value(&arg, args.next())?),
+                "--idle-timeout" => idle_timeout = parse_value(&arg, args.next())?,
+                "--asset-manifest" => asset_manifest = Some(parse_value(&arg, args.next())?),
+                "--autoindex" => autoindex = true,
+                "--search" => search = true,
+                "--robots-txt" => robots_txt = true,
+                "--sitemap" => sitemap = true,
+                "--proxy" => proxies.push(args.next().ok_or("--proxy requires a value")?.parse()?),
+                "--proxy-balance" => proxy_balance = parse_value(&arg, args.next())?,
+                "--fastcgi" => {
+                    fastcgi.push(args.next().ok_or("--fastcgi requires a value")?.parse()?)
+                }
+                "--cgi-dir" => {
+                    let dir: String = parse_value(&arg, args.next())?;
+                    let dir = dir.trim_matches('/');
+                    cgi_dirs.push(match dir.is_empty() {
+                        true => "/".to_string(),
+                        false => format!("/{dir}/"),
+                    });
+                }
+                "--cgi-extension" => {
+                    let extension: String = parse_value(&arg, args.next())?;
+                    cgi_extensions.push(extension.trim_start_matches('.').to_string());
+                }
+                "--script-cpu" => script_limits.cpu_time = Some(parse_value(&arg, args.next())?),
+                "--script-memory" => script_limits.memory = Some(parse_value(&arg, args.next())?),
+                "--script-file-size" => {
+                    script_limits.file_size = Some(parse_value(&arg, args.next())?)
+                }
+                "--script-open-files" => {
+                    script_limits.open_files = Some(parse_value(&arg, args.next())?)
+                }
+                "--cgi-user" => cgi_user = Some(parse_value::<String>(&arg, args.next())?),
+                "--cgi-group" => cgi_group = Some(parse_value::<String>(&arg, args.next())?),
+                "--script-env" => {
+                    let entry: String = parse_value(&arg, args.next())?;
+                    script_env.push(match entry.split_once('=') {
+                        Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                        None => (entry, None),
+                    });
+                }
+                "--workers" => workers = parse_value(&arg, args.next())?,
+                "--worker-requests" => worker_requests = parse_value(&arg, args.next())?,
+                "--interpreter" => {
+                    let list = args.next().ok_or("--interpreter requires a value")?;
+                    for interpreter in list.split(',') {
+                        interpreters.push(interpreter.parse()?);
+                    }
+                }
+                "--userdir" => userdir = Some(parse_value::<String>(&arg, args.next())?),
+                "--thumbnails" => thumbnails = true,
+                "--thumbnail-dir" => thumbnail_dir = parse_value(&arg, args.next())?,
+                "--tls-cert" => tls_cert = Some(parse_value(&arg, args.next())?),
+                "--tls-key" => tls_key = Some(parse_value(&arg, args.next())?),
+                "--tls-client-ca" => tls_client_ca = Some(parse_value(&arg, args.next())?),
+                "--http2" => http2 = true,
+                "--acme-domain" => acme_domains
+                    .push(parse_value::<String>(&arg, args.next())?.to_ascii_lowercase()),
+                "--acme-dir" => acme_dir = parse_value(&arg, args.next())?,
+                "--acme-directory" => acme_directory = parse_value(&arg, args.next())?,
+                "--acme-email" => acme_email = Some(parse_value(&arg, args.next())?),
+                "--redirect-host" => host_redirects.push(parse_value(&arg, args.next())?),
+                "--https-redirect" => https_redirect = Some(parse_value(&arg, args.next())?),
+                "--https-port" => https_port = parse_value(&arg, args.next())?,
+                "--hsts-max-age" => hsts_max_age = Some(parse_value(&arg, args.next())?),
+                "--hsts-preload" => hsts_preload = true,
+                "--admin-token" => admin_token = Some(parse_value(&arg, args.next())?),
+                "--maintenance-flag" => maintenance_flag = Some(parse_value(&arg, args.next())?),
+                "--maintenance-page" => maintenance_page = Some(parse_value(&arg, args.next())?),
+                "--maintenance-retry-after" => {
+                    maintenance_retry_after = parse_value(&arg, args.next())?
+                }
+                "--fallback-favicon" => fallback_favicon = Some(parse_value(&arg, args.next())?),
+                "--allow-put" => allow_put = true,
+                "--allow-delete" => allow_delete = true,
+                "--upload-quota" => upload_quota = Some(parse_value(&arg, args.next())?),
+                "--max-connections-per-ip" => {
+                    max_connections_per_ip = parse_value(&arg, args.next())?
+                }
+                "--connection-limit-exempt" => {
+                    connection_limit_exempt.push(parse_value(&arg, args.next())?)
+                }
+                "--max-active" => max_active = parse_value(&arg, args.next())?,
+                "--queue-size" => queue_size = parse_value(&arg, args.next())?,
+                "--shards" => shards = Some(parse_value(&arg, args.next())?),
+                "--capture" => capture = Some(parse_value(&arg, args.next())?),
+                "--anonymize-ips" => anonymize_ips = Some(parse_value(&arg, args.next())?),
+                "--proxy-protocol" => proxy_protocol = true,
+                "--trusted-proxies" => {
+                    let list = args.next().ok_or("--trusted-proxies requires a value")?;
+                    for cidr in list.split(',') {
+                        trusted_proxies.push(cidr.trim().parse()?);
+                    }
+                }
+                "--kv-store" => kv_store = true,
+                "--kv-file" => {
+                    kv_store = true;
+                    kv_file = Some(parse_value(&arg, args.next())?);
+                }
+                "--lua-handlers" => lua_handlers = Some(parse_value(&arg, args.next())?),
+                "--ban-threshold" => bans.threshold = parse_value(&arg, args.next())?,
+                "--ban-window" => {
+                    bans.window = Duration::from_secs(parse_value(&arg, args.next())?)
+                }
+                "--ban-duration" => {
+                    bans.duration = Duration::from_secs(parse_value(&arg, args.next())?)
+                }
+                _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+                _ => positional.push(arg),
+            }
+        }
+
+        let [port, root] = <[String; 2]>::try_from(positional)
+            .map_err(|_| "expected PORT and ROOT_FOLDER".to_string())?;
+        let port = port.parse().map_err(|_| format!("invalid port {port}"))?;
+
+        if geoip_db.is_none() {
+            let geographic = file.location.iter().find(|location| {
+                location
+                    .allow
+                    .iter()
+                    .chain(&location.deny)
+                    .any(|rule| rule.is_geographic())
+            });
+            if let Some(location) = geographic {
+                return Err(format!(
+                    "location {} uses country/continent rules but --geoip-db is not set",
+                    location.path
+                ));
+            }
+        }
+
+        if cfg!(not(feature = "lua")) {
+            let lua = file.location.iter().find(|location| {
+                location
+                    .handlers
+                    .values()
+                    .any(|handler| *handler == Handler::Lua)
+            });
+            if let Some(location) = lua {
+                return Err(format!(
+                    "location {} uses the lua handler, which requires a build with the `lua` feature",
+                    location.path
+                ));
+            }
+        }
+
+        for rule in &file.headers {
+            rule.validate()?;
+        }
+
+        if let Some(pattern) = &userdir {
+            userdir::validate(pattern)?;
+        }
+
+        if compression.level > 9 {
+            return Err("--compress-level must be between 0 and 9".to_string());
+        }
+
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err("--tls-cert and --tls-key must be given together".to_string());
+        }
+        if !acme_domains.is_empty() {
+            if tls_cert.is_some() {
+                return Err("--acme-domain can't be combined with --tls-cert".to_string());
+            }
+            if https_redirect.is_none() {
+                return Err("--acme-domain needs --https-redirect to answer challenges".to_string());
+            }
+        }
+        if tls_cert.is_none() && acme_domains.is_empty() {
+            if http2 {
+                return Err("--http2 needs --tls-cert or --acme-domain".to_string());
+            }
+            if tls_client_ca.is_some() {
+                return Err("--tls-client-ca needs --tls-cert or --acme-domain".to_string());
+            }
+        }
+
+        if hsts_preload && hsts_max_age.is_none() {
+            return Err("--hsts-preload requires --hsts-max-age".to_string());
+        }
+
+        if cfg!(not(feature = "lua")) && lua_handlers.is_some() {
+            return Err("--lua-handlers requires a build with the `lua` feature".to_string());
+        }
+
+        if workers == 0 {
+            return Err("--workers must be at least 1".to_string());
+        }
+
+        if cfg!(not(unix)) && !script_limits.is_empty() {
+            return Err("script resource limits are only supported on Unix".to_string());
+        }
+
+        #[cfg(unix)]
+        let script_credentials = Credentials::resolve(cgi_user.as_deref(), cgi_group.as_deref())?;
+        #[cfg(not(unix))]
+        if cgi_user.is_some() || cgi_group.is_some() {
+            return Err("--cgi-user and --cgi-group are only supported on Unix".to_string());
+        }
+
+        if cgi_dirs.is_empty() {
+            cgi_dirs.push(SCRIPTS_PREFIX.to_string());
+        }
+
+        Ok(Config {
+            port,
+            root: PathBuf::from(root),
+            overlay_bases,
+            vhosts,
+            bans,
+            geoip_db,
+            dev,
+            server_timing,
+            watch,
+            file_cache,
+            open_file_cache,
+            open_file_cache_valid,
+            mmap_min,
+            mmap_max,
+            digest,
+            compression,
+            precompressed,
+            max_inflated_size,
+            body_buffer_size,
+            max_body_size,
+            script_timeout,
+            script_cache,
+            write_timeout,
+            idle_timeout,
+            asset_manifest,
+            autoindex,
+            search,
+            userdir,
+            proxies,
+            proxy_balance,
+            fastcgi,
+            interpreters,
+            workers,
+            worker_requests,
+            cgi_dirs,
+            cgi_extensions,
+            script_env,
+            script_limits,
+            #[cfg(unix)]
+            script_credentials,
+            robots_txt,
+            sitemap,
+            thumbnails,
+            thumbnail_dir,
+            tls_cert,
+            tls_key,
+            tls_client_ca,
+            http2,
+            acme_domains,
+            acme_dir,
+            acme_directory,
+            acme_email,
+            host_redirects,
+            https_redirect,
+            https_port,
+            hsts_max_age,
+            hsts_preload,
+            admin_token,
+            maintenance_flag,
+            maintenance_page,
+            maintenance_retry_after,
+            fallback_favicon,
+            security_txt: file.security_txt,
+            allow_put,
+            allow_delete,
+            upload_quota,
+            max_connections_per_ip,
+            connection_limit_exempt,
+            max_active,
+            queue_size,
+            shards,
+            lua_handlers,
+            header_rules: file.headers,
+            bandwidth_quotas: file.bandwidth,
+            kv_store,
+            capture,
+            anonymize_ips,
+            proxy_protocol,
+            trusted_proxies,
+            kv_file,
+            locations: file.location,
+        })
+    }
+
+    /// `--max-body-size`, or the override of `location`.
+    pub fn max_body_size(&self, location: Option<&Location>) -> Option<u64> {
+        location
+            .and_then(|location| location.max_body_size)
+            .or(self.max_body_size)
+    }
+
+    /// `--script-timeout`, or the override of `location`.
+    pub fn script_timeout(&self, location: Option<&Location>) -> Option<Duration> {
+        location
+            .and_then(|location| location.script_timeout)
+            .or(self.script_timeout)
+            .filter(|&secs| secs != 0)
+            .map(Duration::from_secs)
+    }
+
+    /// `--script-cache`, or the override of `location`; `None` when
+    /// responses aren't kept.
+    pub fn script_cache(&self, location: Option<&Location>) -> Option<Duration> {
+        location
+            .and_then(|location| location.script_cache)
+            .or(self.script_cache)
+            .filter(|&secs| secs != 0)
+            .map(Duration::from_secs)
+    }
+
+    /// `--write-timeout`, or the override of `location`.
+    pub fn write_timeout(&self, location: Option<&Location>) -> Option<Duration> {
+        location
+            .and_then(|location| location.write_timeout)
+            .or(self.write_timeout)
+            .map(Duration::from_secs)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{option} requires a value"))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {value} for {option}"))
+}
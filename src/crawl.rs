@@ -0,0 +1,161 @@
+//! Generated `/robots.txt` and `/sitemap.xml` for sites that have none,
+//! with `--robots-txt` and `--sitemap`.
+//!
+//! `robots.txt` disallows every location guarded by access rules, a URL
+//! signature or an auth request, since crawlers could not fetch those
+//! anyway. The sitemap lists the HTML files of the root folder and its
+//! overlay bases, with their modification dates.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::admin;
+use crate::config::Config;
+use crate::date;
+use crate::handlers;
+use crate::http::{self, Request, Response};
+use crate::locations::{self, Location};
+use crate::resolve;
+
+pub const ROBOTS_PATH: &str = "/robots.txt";
+pub const SITEMAP_PATH: &str = "/sitemap.xml";
+
+/// Most URLs a single sitemap may hold.
+const MAX_URLS: usize = 50_000;
+
+/// The site's address, as the client reached it.
+fn base_url(request: &Request) -> String {
+    let scheme = match request.tls {
+        Some(_) => "https",
+        None => "http",
+    };
+    let host = request.header("Host").unwrap_or("localhost");
+    format!("{scheme}://{host}")
+}
+
+fn is_restricted(location: &Location) -> bool {
+    !location.allow.is_empty()
+        || !location.deny.is_empty()
+        || location.signing_key.is_some()
+        || location.auth_request.is_some()
+}
+
+/// Path prefixes crawlers are asked to stay out of.
+fn disallowed(config: &Config) -> Vec<&str> {
+    let mut paths: Vec<&str> = config
+        .locations
+        .iter()
+        .filter(|location| is_restricted(location))
+        .map(|location| location.path.as_str())
+        .collect();
+    if config.admin_token.is_some() {
+        paths.push(admin::PREFIX);
+    }
+    paths
+}
+
+pub fn robots_txt(config: &Config, request: &Request) -> Response {
+    let mut body = String::from("User-agent: *\n");
+    let disallowed = disallowed(config);
+    if disallowed.is_empty() {
+        body.push_str("Disallow:\n");
+    }
+    for path in disallowed {
+        body.push_str(&format!("Disallow: {path}\n"));
+    }
+    if config.sitemap {
+        body.push_str(&format!("\nSitemap: {}{SITEMAP_PATH}\n", base_url(request)));
+    }
+    Response::new(200, "text/plain; charset=utf-8", body)
+}
+
+pub async fn sitemap(config: &Config, roots: &[PathBuf], request: &Request) -> Response {
+    let roots: Vec<(PathBuf, Vec<PathBuf>)> = roots
+        .iter()
+        .map(|root| (root.clone(), handlers::cgi_folders(config, root)))
+        .collect();
+    let Ok(pages) = tokio::task::spawn_blocking(move || {
+        let mut pages = BTreeMap::new();
+        for (root, scripts) in &roots {
+            collect_pages(root, root, scripts, &mut pages);
+        }
+        pages
+    })
+    .await
+    else {
+        return Response::error(500);
+    };
+
+    let base = base_url(request);
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    let pages = pages
+        .iter()
+        .filter(|(path, _)| !locations::find(&config.locations, path).is_some_and(is_restricted));
+    for (path, modified) in pages.take(MAX_URLS) {
+        body.push_str(&format!(
+            "<url><loc>{}</loc><lastmod>{}</lastmod></url>\n",
+            escape(&format!("{base}{}", http::percent_encode_path(path))),
+            date::iso8601(*modified)
+        ));
+    }
+    body.push_str("</urlset>\n");
+    Response::new(200, "application/xml", body)
+}
+
+/// Adds the HTML files below `dir` by request path; pages already found in
+/// an earlier layer are kept. `index.html` stands for its directory. The
+/// `scripts` folders are left out.
+fn collect_pages(
+    root: &Path,
+    dir: &Path,
+    scripts: &[PathBuf],
+    pages: &mut BTreeMap<String, SystemTime>,
+) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if resolve::is_hidden(entry.file_name().as_os_str()) {
+            continue;
+        }
+        let path = entry.path();
+        // Symlinks are skipped so the walk can't leave the folder.
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            if !scripts.contains(&path) {
+                collect_pages(root, &path, scripts, pages);
+            }
+            continue;
+        }
+        let is_html = name.ends_with(".html") || name.ends_with(".htm");
+        if !file_type.is_file() || !is_html {
+            continue;
+        }
+        let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) else {
+            continue;
+        };
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let mut url = String::new();
+        for component in relative.components() {
+            url.push('/');
+            url.push_str(&component.as_os_str().to_string_lossy());
+        }
+        if let Some(dir) = url.strip_suffix("/index.html") {
+            url = format!("{dir}/");
+        }
+        pages.entry(url).or_insert(modified);
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,117 @@
+//! The user and group that scripts run as, with `--cgi-user` and
+//! `--cgi-group`, for a server started as root to bind a low port.
+//!
+//! Both take a name or a numeric ID. A user without a group runs with the
+//! user's primary group, and supplementary groups are dropped either way.
+
+use std::ffi::CString;
+use std::io;
+
+/// Size of the buffer that the `getpwnam_r` family fills with strings.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Copy)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl Credentials {
+    /// The credentials that `user` and `group` name, or `None` when neither
+    /// is given. Switching needs root, so other servers are refused.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Credentials>, String> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+        // SAFETY: geteuid has no preconditions.
+        if unsafe { libc::geteuid() } != 0 {
+            return Err("--cgi-user and --cgi-group need the server to run as root".to_string());
+        }
+        let (uid, primary) = match user {
+            Some(user) => find_user(user).ok_or_else(|| format!("unknown --cgi-user {user}"))?,
+            // SAFETY: getegid has no preconditions.
+            None => (0, unsafe { libc::getegid() }),
+        };
+        let gid = match group {
+            Some(group) => {
+                find_group(group).ok_or_else(|| format!("unknown --cgi-group {group}"))?
+            }
+            None => primary,
+        };
+        Ok(Some(Credentials { uid, gid }))
+    }
+
+    /// Switches the calling process to these credentials, between fork and
+    /// exec: the groups first, while it still may.
+    pub fn apply(&self) -> io::Result<()> {
+        // SAFETY: the group list is one valid gid, and setgid and setuid
+        // have no preconditions.
+        let failed = unsafe {
+            libc::setgroups(1, &self.gid) != 0
+                || libc::setgid(self.gid) != 0
+                || libc::setuid(self.uid) != 0
+        };
+        match failed {
+            true => Err(io::Error::last_os_error()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// The ID and primary group of the user named or numbered `user`.
+fn find_user(user: &str) -> Option<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).ok()?;
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    // SAFETY: an all-zero passwd is valid, if meaningless, until filled.
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the lengths given.
+    unsafe {
+        match user.parse() {
+            Ok(uid) => libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+            Err(_) => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+        };
+    }
+    match found.is_null() {
+        true => None,
+        false => Some((entry.pw_uid, entry.pw_gid)),
+    }
+}
+
+/// The ID of the group named or numbered `group`.
+fn find_group(group: &str) -> Option<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid);
+    }
+    let name = CString::new(group).ok()?;
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    // SAFETY: an all-zero group is valid, if meaningless, until filled.
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the lengths given.
+    unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        );
+    }
+    match found.is_null() {
+        true => None,
+        false => Some(entry.gr_gid),
+    }
+}
@@ -0,0 +1,98 @@
+//! Per-response CSP nonces for served HTML.
+//!
+//! Every `<script>` and `<style>` tag of the page gets a `nonce` attribute
+//! and the response carries a policy that only admits tags with that nonce.
+//! The rewrite works on chunks as they are read, holding back only a
+//! possibly incomplete tag name at the end of each chunk.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+
+const TAGS: [&[u8]; 2] = [b"<script", b"<style"];
+
+/// Longest tag name plus the byte that must follow it.
+const LOOKAHEAD: usize = 8;
+
+pub fn generate_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    STANDARD.encode(bytes)
+}
+
+pub fn policy(nonce: &str) -> String {
+    format!(
+        "script-src 'nonce-{nonce}'; style-src 'nonce-{nonce}'; object-src 'none'; base-uri 'none'"
+    )
+}
+
+pub struct NonceInjector {
+    attribute: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl NonceInjector {
+    pub fn new(nonce: &str) -> NonceInjector {
+        NonceInjector {
+            attribute: format!(" nonce=\"{nonce}\"").into_bytes(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Rewrites the next chunk of the document, returning what can be sent.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        let input = std::mem::take(&mut self.pending);
+        let mut output = Vec::with_capacity(input.len() + self.attribute.len());
+
+        let mut i = 0;
+        while i < input.len() {
+            if input[i] != b'<' {
+                output.push(input[i]);
+                i += 1;
+                continue;
+            }
+            if input.len() - i < LOOKAHEAD {
+                self.pending = input[i..].to_vec();
+                return output;
+            }
+            match tag_at(&input[i..]) {
+                Some(tag) => {
+                    output.extend_from_slice(&input[i..i + tag.len()]);
+                    output.extend_from_slice(&self.attribute);
+                    i += tag.len();
+                }
+                None => {
+                    output.push(b'<');
+                    i += 1;
+                }
+            }
+        }
+        output
+    }
+
+    /// Flushes whatever was held back at the end of the document.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let rest = std::mem::take(&mut self.pending);
+        match tag_at(&rest) {
+            Some(tag) if rest.len() > tag.len() => {
+                let mut output = rest[..tag.len()].to_vec();
+                output.extend_from_slice(&self.attribute);
+                output.extend_from_slice(&rest[tag.len()..]);
+                output
+            }
+            _ => rest,
+        }
+    }
+}
+
+/// Returns the tag opened at the start of `input`, if it is one we rewrite.
+fn tag_at(input: &[u8]) -> Option<&'static [u8]> {
+    TAGS.into_iter().find(|tag| {
+        input.len() > tag.len()
+            && input[..tag.len()].eq_ignore_ascii_case(tag)
+            && matches!(input[tag.len()], b'>' | b' ' | b'\t' | b'\r' | b'\n' | b'/')
+    })
+}
@@ -0,0 +1,308 @@
+//! Just enough DER (X.690) to write certificate requests and to read the
+//! fields of certificates: their validity, names and extensions.
+
+/// Attribute types of distinguished names, as DER contents, with the short
+/// names RFC 4514 and OpenSSL give them.
+const ATTRIBUTES: [(&[u8], &str); 11] = [
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x05], "serialNumber"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x09], "street"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (
+        &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01],
+        "emailAddress",
+    ),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19],
+        "DC",
+    ),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01],
+        "UID",
+    ),
+];
+
+/// Splits the DER element at the start of `input` into its tag, its
+/// contents and what follows it.
+pub fn element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = match first {
+        0..=0x7f => (usize::from(first), rest),
+        0x81..=0x84 => {
+            let (bytes, rest) = rest.split_at_checked(usize::from(first & 0x7f))?;
+            let length = bytes
+                .iter()
+                .fold(0, |length, &byte| length << 8 | usize::from(byte));
+            (length, rest)
+        }
+        _ => return None,
+    };
+    let (contents, rest) = rest.split_at_checked(length)?;
+    Some((tag, contents, rest))
+}
+
+/// The tags and contents of the DER elements filling `input`.
+pub fn elements(mut input: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut all = Vec::new();
+    while !input.is_empty() {
+        let (tag, contents, rest) = element(input)?;
+        all.push((tag, contents));
+        input = rest;
+    }
+    Some(all)
+}
+
+/// An element with `tag` and `contents`.
+pub fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    match contents.len() {
+        length @ 0..0x80 => encoded.push(length as u8),
+        length => {
+            let bytes = length.to_be_bytes();
+            let skip = bytes.iter().take_while(|&&byte| byte == 0).count();
+            encoded.push(0x80 | (bytes.len() - skip) as u8);
+            encoded.extend_from_slice(&bytes[skip..]);
+        }
+    }
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+/// The fields of a DER certificate's TBSCertificate, without the optional
+/// version: serialNumber, signature, issuer, validity, subject,
+/// subjectPublicKeyInfo, then the tagged optional ones.
+pub fn certificate_fields(certificate: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let (_, certificate, _) = element(certificate)?;
+    let (_, tbs, _) = element(certificate)?;
+    let mut fields = elements(tbs)?;
+    if fields.first()?.0 == 0xa0 {
+        fields.remove(0);
+    }
+    Some(fields)
+}
+
+/// The contents of a distinguished name as an RFC 4514 string, such as
+/// `CN=alice,O=Example\, Inc.,C=RO`.
+pub fn distinguished_name(name: &[u8]) -> Option<String> {
+    let mut relative = Vec::new();
+    for (_, set) in elements(name)? {
+        let mut attributes = Vec::new();
+        for (_, attribute) in elements(set)? {
+            let parts = elements(attribute)?;
+            let (&(_, oid), &(tag, value)) = (parts.first()?, parts.get(1)?);
+            let name = ATTRIBUTES
+                .iter()
+                .find(|(known, _)| *known == oid)
+                .map_or_else(|| dotted(oid), |(_, name)| name.to_string());
+            let value = match string(tag, value) {
+                Some(value) => escape(&value),
+                // Values of other types are written as their whole DER.
+                None => format!("#{}", hex(&encode(tag, value))),
+            };
+            attributes.push(format!("{name}={value}"));
+        }
+        relative.push(attributes.join("+"));
+    }
+    // The most significant attribute comes last in DER and first in text.
+    relative.reverse();
+    Some(relative.join(","))
+}
+
+/// The first value of attribute `short_name` in a distinguished name.
+pub fn name_attribute(name: &[u8], short_name: &str) -> Option<String> {
+    let (oid, _) = ATTRIBUTES.iter().find(|(_, known)| *known == short_name)?;
+    for (_, set) in elements(name)? {
+        for (_, attribute) in elements(set)? {
+            let parts = elements(attribute)?;
+            if parts.first()?.1 == *oid {
+                let (tag, value) = *parts.get(1)?;
+                return string(tag, value);
+            }
+        }
+    }
+    None
+}
+
+/// The text of a directory string, which comes in several encodings.
+fn string(tag: u8, value: &[u8]) -> Option<String> {
+    match tag {
+        // UTF8String, PrintableString, IA5String, VisibleString.
+        0x0c | 0x13 | 0x16 | 0x1a => Some(String::from_utf8_lossy(value).into_owned()),
+        // TeletexString, which certificates in practice fill with Latin-1.
+        0x14 => Some(value.iter().map(|&byte| char::from(byte)).collect()),
+        // BMPString.
+        0x1e => {
+            let units: Vec<u16> = value
+                .chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+                .collect();
+            Some(String::from_utf16_lossy(&units))
+        }
+        _ => None,
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for (index, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (index == 0 && matches!(c, '#' | ' '))
+            || (index == value.chars().count() - 1 && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// An object identifier in dotted form, such as `2.5.4.3`.
+fn dotted(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc: u64 = 0;
+    for &byte in oid {
+        arc = arc << 7 | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    let arcs: Vec<String> = arcs.iter().map(u64::to_string).collect();
+    arcs.join(".")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CN: &[u8] = &[0x55, 0x04, 0x03];
+    const O: &[u8] = &[0x55, 0x04, 0x0a];
+    const C: &[u8] = &[0x55, 0x04, 0x06];
+
+    /// A relative distinguished name of `attributes`, as DER.
+    fn set(attributes: &[(&[u8], u8, &[u8])]) -> Vec<u8> {
+        let attributes: Vec<u8> = attributes
+            .iter()
+            .flat_map(|&(oid, tag, value)| {
+                encode(0x30, &[encode(0x06, oid), encode(tag, value)].concat())
+            })
+            .collect();
+        encode(0x31, &attributes)
+    }
+
+    #[test]
+    fn encodes_lengths() {
+        for (length, head) in [
+            (0, &[0x04, 0x00][..]),
+            (0x7f, &[0x04, 0x7f]),
+            (0x80, &[0x04, 0x81, 0x80]),
+            (0x1234, &[0x04, 0x82, 0x12, 0x34]),
+        ] {
+            let contents = vec![7; length];
+            let encoded = encode(0x04, &contents);
+            assert_eq!(&encoded[..head.len()], head);
+            let trailing = [encoded.as_slice(), b"rest"].concat();
+            assert_eq!(
+                element(&trailing),
+                Some((0x04, contents.as_slice(), &b"rest"[..]))
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_elements() {
+        // Indefinite and overlong lengths, and contents cut short.
+        for input in [
+            &[0x30, 0x80, 0x00, 0x00][..],
+            &[0x04, 0x85, 0, 0, 0, 0, 1, 0],
+            &[0x04, 0x03, 1, 2],
+            &[0x04, 0x82, 0x01],
+            &[0x04],
+        ] {
+            assert_eq!(element(input), None, "{input:02x?}");
+        }
+        let both = [encode(0x02, &[1]), encode(0x05, &[])].concat();
+        assert_eq!(
+            elements(&both),
+            Some(vec![(0x02, &[1][..]), (0x05, &[][..])])
+        );
+        assert_eq!(elements(&both[..both.len() - 1]), None);
+    }
+
+    #[test]
+    fn writes_distinguished_names() {
+        let name = [
+            set(&[(C, 0x13, b"RO")]),
+            set(&[(O, 0x13, b"Example, Inc.")]),
+            set(&[
+                (CN, 0x0c, "alice".as_bytes()),
+                (&[0x55, 0x04, 0x2a], 0x02, &[1]),
+            ]),
+        ]
+        .concat();
+        assert_eq!(
+            distinguished_name(&name).as_deref(),
+            Some("CN=alice+2.5.4.42=#020101,O=Example\\, Inc.,C=RO")
+        );
+        assert_eq!(name_attribute(&name, "O").as_deref(), Some("Example, Inc."));
+        assert_eq!(name_attribute(&name, "CN").as_deref(), Some("alice"));
+        assert_eq!(name_attribute(&name, "OU"), None);
+    }
+
+    #[test]
+    fn decodes_strings() {
+        let name = [
+            set(&[(CN, 0x1e, &[0x00, 0x23, 0x01, 0x03, 0x00, 0x20])]),
+            set(&[(O, 0x14, &[0x63, 0xe9])]),
+        ]
+        .concat();
+        // BMPString `#ă ` and TeletexString Latin-1, with the leading `#`
+        // and trailing space escaped.
+        assert_eq!(
+            distinguished_name(&name).as_deref(),
+            Some("O=cé,CN=\\#ă\\ ")
+        );
+    }
+
+    #[test]
+    fn skips_certificate_version() {
+        let fields = [
+            encode(0x02, &[5]),
+            encode(0x30, &[]),
+            encode(0x30, &set(&[(CN, 0x0c, b"ca")])),
+        ];
+        for version in [encode(0xa0, &encode(0x02, &[2])), Vec::new()] {
+            let tbs = encode(0x30, &[version, fields.concat()].concat());
+            let certificate = encode(0x30, &[tbs, encode(0x30, &[]), encode(0x03, &[0])].concat());
+            let parsed = certificate_fields(&certificate).unwrap();
+            assert_eq!(parsed.len(), 3);
+            assert_eq!(parsed[0], (0x02, &[5][..]));
+            assert_eq!(name_attribute(parsed[2].1, "CN").as_deref(), Some("ca"));
+        }
+    }
+
+    #[test]
+    fn writes_object_identifiers() {
+        assert_eq!(dotted(CN), "2.5.4.3");
+        assert_eq!(
+            dotted(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d]),
+            "1.2.840.113549"
+        );
+        assert_eq!(dotted(&[0x88, 0x37]), "2.999");
+    }
+}
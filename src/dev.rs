@@ -0,0 +1,51 @@
+//! Request defaults for `--dev`, on top of live reload: nothing is cached,
+//! any origin may make cross-origin requests, every request is logged in
+//! detail and `/.well-known/` is served despite being hidden.
+
+use std::time::Duration;
+
+use crate::http::{Request, Response};
+
+/// Hidden names `--dev` serves anyway.
+pub const VISIBLE_HIDDEN: &[&str] = &[".well-known"];
+
+/// Answers CORS preflight requests.
+pub fn preflight(request: &Request) -> Option<Response> {
+    if request.method != "OPTIONS" || request.header("Access-Control-Request-Method").is_none() {
+        return None;
+    }
+    let mut response = Response::new(204, "text/plain", Vec::new());
+    response.set_header(
+        "Access-Control-Allow-Methods",
+        "GET, HEAD, POST, PUT, DELETE, OPTIONS",
+    );
+    if let Some(headers) = request.header("Access-Control-Request-Headers") {
+        response.set_header("Access-Control-Allow-Headers", headers);
+    }
+    response.set_header("Access-Control-Max-Age", "600");
+    Some(response)
+}
+
+pub fn apply(request: &Request, response: &mut Response) {
+    response.set_header("Cache-Control", "no-store");
+    match request.header("Origin") {
+        Some(origin) => {
+            response.set_header("Access-Control-Allow-Origin", origin);
+            response.set_header("Access-Control-Allow-Credentials", "true");
+            response.add_vary("Origin");
+        }
+        None => response.set_header("Access-Control-Allow-Origin", "*"),
+    }
+}
+
+/// Prints the request headers and how long the response took, below the
+/// usual log line.
+pub fn log_details(request: &Request, elapsed: Duration) {
+    for (name, value) in &request.headers {
+        println!("    {name}: {value}");
+    }
+    if !request.body.is_empty() {
+        println!("    ({} byte body)", request.body.len());
+    }
+    println!("    handled in {:.1} ms", elapsed.as_secs_f64() * 1000.0);
+}
@@ -0,0 +1,31 @@
+//! RFC 9530 integrity fields (`Content-Digest`, `Repr-Digest`), sha-256 only.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+
+/// Field value for `content`: `sha-256=:<base64>:`.
+pub fn sha256(content: &[u8]) -> String {
+    format!(
+        "sha-256=:{}:",
+        STANDARD.encode(digest(&SHA256, content).as_ref())
+    )
+}
+
+/// Checks the `sha-256` member of a digest field against `content`. Fields
+/// listing only other algorithms are accepted, as they cannot be checked.
+pub fn verify(field: &str, content: &[u8]) -> bool {
+    let expected = field.split(',').find_map(|member| {
+        let (algorithm, value) = member.split_once('=')?;
+        algorithm
+            .trim()
+            .eq_ignore_ascii_case("sha-256")
+            .then(|| value.trim().trim_matches(':'))
+    });
+    match expected {
+        Some(expected) => STANDARD
+            .decode(expected)
+            .is_ok_and(|expected| expected == digest(&SHA256, content).as_ref()),
+        None => true,
+    }
+}
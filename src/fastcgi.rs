@@ -0,0 +1,311 @@
+//! FastCGI, with `--fastcgi PATTERN=ADDRESS`: requests for files matching
+//! `PATTERN` are answered by the FastCGI server at `ADDRESS`, such as
+//! php-fpm, instead of a process started for each one.
+//!
+//! Patterns without a `/` are matched against the file name, so `*.php`
+//! covers every directory; with one, against the whole path, as in
+//! `/app/**.php`. `ADDRESS` is `host:port`, or `unix:` and a socket path.
+//! The file must exist under the root; the server gets its absolute path as
+//! `SCRIPT_FILENAME` along with the CGI variables, and its output is passed
+//! on as it arrives.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::TcpStream;
+
+use crate::cgi;
+use crate::glob;
+use crate::http::{Relayed, Request, Response, STREAM_BUFFER};
+use crate::resolve::{resolve, Resolved};
+use crate::scripts;
+use crate::server::Server;
+
+/// Time the FastCGI server may take to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time the application may take to send its header block.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Output without an empty line past which the application is taken to be
+/// broken.
+const MAX_HEAD: usize = 64 * 1024;
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+
+/// Connections carry a single request, so its ID never changes.
+const REQUEST_ID: u16 = 1;
+
+pub struct FastCgiRoute {
+    pub pattern: String,
+    pub address: Address,
+}
+
+pub enum Address {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for FastCgiRoute {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<FastCgiRoute, String> {
+        let invalid = || format!("invalid --fastcgi {value}, expected PATTERN=ADDRESS");
+        let (pattern, address) = value.split_once('=').ok_or_else(invalid)?;
+        if pattern.is_empty() {
+            return Err(invalid());
+        }
+        let address = match address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) if !path.is_empty() => Address::Unix(PathBuf::from(path)),
+            Some(_) => return Err(invalid()),
+            None if address.contains(':') => Address::Tcp(address.to_string()),
+            None => return Err(invalid()),
+        };
+        Ok(FastCgiRoute {
+            pattern: pattern.to_string(),
+            address,
+        })
+    }
+}
+
+impl FastCgiRoute {
+    fn matches(&self, path: &str) -> bool {
+        match self.pattern.contains('/') {
+            true => glob::matches(&self.pattern, path),
+            false => glob::matches(&self.pattern, path.rsplit('/').next().unwrap_or_default()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.address {
+            Address::Tcp(address) => address.clone(),
+            #[cfg(unix)]
+            Address::Unix(path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+/// The first route whose pattern matches `path`.
+pub fn find<'a>(routes: &'a [FastCgiRoute], path: &str) -> Option<&'a FastCgiRoute> {
+    routes.iter().find(|route| route.matches(path))
+}
+
+/// Has `request` answered through `route`. A server that can't be reached
+/// or breaks the protocol gets `502`, and one that sends no header block in
+/// time `504`.
+pub async fn forward(server: &Server, route: &FastCgiRoute, request: &Request) -> Response {
+    let root = server.site_for(request).root.clone();
+    let script = match resolve(&root, &request.path).await {
+        Ok(Resolved {
+            path,
+            is_dir: false,
+            ..
+        }) => path,
+        Ok(_) => return Response::error(404),
+        Err(err) => return Response::error(err.status()),
+    };
+    let params = params(server, request, &root, &script);
+    let answered = match &route.address {
+        Address::Tcp(address) => match connect(TcpStream::connect(address)).await {
+            Ok(stream) => exchange(stream, &params, request).await,
+            Err(err) => Err(err),
+        },
+        #[cfg(unix)]
+        Address::Unix(path) => match connect(tokio::net::UnixStream::connect(path)).await {
+            Ok(stream) => exchange(stream, &params, request).await,
+            Err(err) => Err(err),
+        },
+    };
+    match answered {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!(
+                "fastcgi {} {} to {} failed: {err}",
+                request.method,
+                request.path,
+                route.describe()
+            );
+            match err.kind() {
+                io::ErrorKind::TimedOut => Response::error(504),
+                _ => Response::error(502),
+            }
+        }
+    }
+}
+
+async fn connect<S>(connecting: impl std::future::Future<Output = io::Result<S>>) -> io::Result<S> {
+    tokio::time::timeout(CONNECT_TIMEOUT, connecting)
+        .await
+        .unwrap_or_else(|_| Err(timed_out()))
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "timed out")
+}
+
+/// The CGI variables, and those that FastCGI servers look for the script in.
+fn params(server: &Server, request: &Request, root: &Path, script: &Path) -> Vec<(String, String)> {
+    let mut params = cgi::variables(request, &request.path, server.config.port);
+    params.push((
+        "SCRIPT_FILENAME".to_string(),
+        script.to_string_lossy().into_owned(),
+    ));
+    params.push((
+        "DOCUMENT_ROOT".to_string(),
+        root.to_string_lossy().into_owned(),
+    ));
+    params.push(("DOCUMENT_URI".to_string(), request.path.clone()));
+    // php-fpm refuses to run scripts without it, as a guard against
+    // requests that didn't come through a web server.
+    params.push(("REDIRECT_STATUS".to_string(), "200".to_string()));
+    params
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    stream: S,
+    params: &[(String, String)],
+    request: &Request,
+) -> io::Result<Response> {
+    let mut stream = BufReader::new(stream);
+    let mut begin = RESPONDER.to_be_bytes().to_vec();
+    // Flags 0: the server closes the connection when it is done.
+    begin.extend_from_slice(&[0; 6]);
+    let mut outgoing = record(BEGIN_REQUEST, &begin);
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    outgoing.extend(stream_records(PARAMS, &encoded));
+    outgoing.extend(stream_records(STDIN, &request.body));
+    stream.write_all(&outgoing).await?;
+    stream.flush().await?;
+
+    let mut output = Vec::new();
+    let reading = async {
+        while scripts::find_head(&output).is_none() {
+            if output.len() > MAX_HEAD {
+                return Err(io::Error::other("no header block"));
+            }
+            match read_record(&mut stream, &request.path).await? {
+                Some(content) => output.extend_from_slice(&content),
+                None => return Ok(true),
+            }
+        }
+        Ok(false)
+    };
+    let ended = tokio::time::timeout(HEAD_TIMEOUT, reading)
+        .await
+        .map_err(|_| timed_out())??;
+    let body_start = scripts::find_head(&output).map_or(0, |(_, body_start)| body_start);
+    let mut response = scripts::parse_output(&output[..body_start]);
+    if ended {
+        response.body = output[body_start..].to_vec().into();
+        return Ok(response);
+    }
+
+    let (mut writer, reader) = tokio::io::duplex(STREAM_BUFFER);
+    let failed = Arc::new(AtomicBool::new(false));
+    let relayed = Relayed {
+        reader,
+        failed: failed.clone(),
+    };
+    let path = request.path.clone();
+    let rest = output[body_start..].to_vec();
+    tokio::spawn(async move {
+        let relaying = relay(&mut stream, &mut writer, rest, &path).await;
+        if relaying.is_err() {
+            // Set before the writer is dropped, so the reader sees it at
+            // the end of the data.
+            failed.store(true, Ordering::SeqCst);
+        }
+    });
+    response.set_stream(relayed, None);
+    Ok(response)
+}
+
+/// Passes the rest of the application's output on, starting with what was
+/// read along with the header block.
+async fn relay<S: AsyncRead + Unpin>(
+    stream: &mut BufReader<S>,
+    writer: &mut DuplexStream,
+    rest: Vec<u8>,
+    path: &str,
+) -> io::Result<()> {
+    writer.write_all(&rest).await?;
+    while let Some(content) = read_record(stream, path).await? {
+        writer.write_all(&content).await?;
+    }
+    Ok(())
+}
+
+/// The content of the next `STDOUT` record, or `None` once the request has
+/// ended. What the application writes to `STDERR` goes to the error log.
+async fn read_record<S: AsyncRead + Unpin>(
+    stream: &mut BufReader<S>,
+    path: &str,
+) -> io::Result<Option<Vec<u8>>> {
+    loop {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await?;
+        let length = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        let mut content = vec![0u8; length + usize::from(header[6])];
+        stream.read_exact(&mut content).await?;
+        content.truncate(length);
+        match header[1] {
+            STDOUT if !content.is_empty() => return Ok(Some(content)),
+            STDERR => {
+                for line in String::from_utf8_lossy(&content).lines() {
+                    eprintln!("fastcgi {path}: {line}");
+                }
+            }
+            END_REQUEST => return Ok(None),
+            // The empty record that ends STDOUT, and anything unknown.
+            _ => {}
+        }
+    }
+}
+
+fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+    let mut record = vec![VERSION, kind];
+    record.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    // No padding, and a reserved byte.
+    record.extend_from_slice(&[0, 0]);
+    record.extend_from_slice(content);
+    record
+}
+
+/// `content` as records of a stream type, ended by an empty one.
+fn stream_records(kind: u8, content: &[u8]) -> Vec<u8> {
+    let mut records = Vec::new();
+    for chunk in content.chunks(usize::from(u16::MAX)) {
+        records.extend(record(kind, chunk));
+    }
+    records.extend(record(kind, &[]));
+    records
+}
+
+/// Name and value lengths take one byte below 128, else four with the top
+/// bit set.
+fn encode_length(encoded: &mut Vec<u8>, length: usize) {
+    match u8::try_from(length) {
+        Ok(short) if short < 0x80 => encoded.push(short),
+        _ => encoded.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes()),
+    }
+}
@@ -0,0 +1,86 @@
+//! Running out of file descriptors.
+//!
+//! When `accept` fails with `EMFILE`/`ENFILE`, the accept loop backs off for
+//! a growing delay instead of spinning on the error, and logs the limit once
+//! per episode. Requests whose file or script cannot be opened for the same
+//! reason get a `503` with `Retry-After`, so clients retry later rather than
+//! seeing a server error.
+
+use std::io;
+use std::time::Duration;
+
+use crate::http::Response;
+
+/// First and longest accept back-off.
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// `ERROR_TOO_MANY_OPEN_FILES` from the Win32 API.
+#[cfg(not(unix))]
+const ERROR_TOO_MANY_OPEN_FILES: i32 = 4;
+
+pub fn is_exhausted(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    return matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE));
+    #[cfg(not(unix))]
+    return err.raw_os_error() == Some(ERROR_TOO_MANY_OPEN_FILES);
+}
+
+/// The response for a failed open: `503` when out of descriptors, else `500`.
+pub fn error_response(err: &io::Error) -> Response {
+    if !is_exhausted(err) {
+        return Response::error(500);
+    }
+    let mut response = Response::error(503);
+    response.set_header("Retry-After", "1");
+    response
+}
+
+/// Accept-loop back-off, reset after every successful accept.
+#[derive(Default)]
+pub struct Backoff {
+    delay: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn reset(&mut self) {
+        if self.delay.take().is_some() {
+            eprintln!("accepting connections again");
+        }
+    }
+
+    /// Waits after an accept error, longer each time descriptors run out.
+    pub async fn wait(&mut self, err: &io::Error) {
+        if !is_exhausted(err) {
+            eprintln!("accept failed: {err}");
+            return;
+        }
+        let delay = match self.delay {
+            Some(delay) => (delay * 2).min(MAX_BACKOFF),
+            None => {
+                eprintln!("accept failed: {err}; {}", describe_limit());
+                MIN_BACKOFF
+            }
+        };
+        self.delay = Some(delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn describe_limit() -> String {
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit only writes to the struct passed in.
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+            return format!(
+                "open file limit is {} (hard limit {}); raise it with ulimit -n",
+                limit.rlim_cur, limit.rlim_max
+            );
+        }
+    }
+    "open file limit reached".to_string()
+}
@@ -0,0 +1,574 @@
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+use crate::cache::{self, CachedFile};
+use crate::compress;
+use crate::conditional;
+use crate::csp::{self, NonceInjector};
+use crate::date;
+use crate::dev;
+use crate::digest;
+use crate::fdlimit;
+use crate::http::{self, Body, Request, Response};
+use crate::locations::Location;
+use crate::openfiles;
+use crate::range;
+use crate::resolve::{self, resolve, Resolved};
+use crate::search;
+use crate::server::Server;
+use crate::thumbs;
+use crate::userdir;
+
+/// Serves the file or directory at the decoded request `path`. Directories
+/// without an index file are listed only when autoindex is enabled.
+pub async fn serve(
+    server: &Server,
+    request: &Request,
+    path: &str,
+    location: Option<&Location>,
+) -> Response {
+    let site = server.site_for(request);
+    let cache_key = site.cache_key(path);
+    if let Some(open) = server.open_files.get(&cache_key) {
+        return serve_file(server, request, &open.resolved, location, Some(open.file)).await;
+    }
+
+    let visible = match server.config.dev {
+        true => dev::VISIBLE_HIDDEN,
+        false => &[],
+    };
+    let user_folder;
+    let (roots, relative) = match &server.config.userdir {
+        Some(pattern) => match userdir::map(pattern, path).await {
+            Some(Ok((folder, below))) => {
+                user_folder = [folder];
+                (&user_folder[..], below)
+            }
+            Some(Err(err)) => return Response::error(err.status()),
+            None => (&site.roots[..], path),
+        },
+        None => (&site.roots[..], path),
+    };
+    let target = match resolve::resolve_layered(roots, relative, visible).await {
+        Ok(target) => target,
+        Err(err) => return Response::error(err.status()),
+    };
+
+    if target.is_dir && server.config.search {
+        let query = http::parse_query(&request.query);
+        let param = |name: &str| {
+            query
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| http::form_decode(value))
+        };
+        if let Some(q) = param("q").filter(|q| !q.is_empty()) {
+            let contents = param("content").is_some_and(|value| value == "1");
+            let results = search::search(&target.path, path, &q, contents).await;
+            return search::render(request, &q, &results);
+        }
+    }
+
+    if target.is_dir {
+        let index = format!("{}/index.html", relative.trim_end_matches('/'));
+        if let Ok(index @ Resolved { is_dir: false, .. }) =
+            resolve::resolve_layered(roots, &index, &[]).await
+        {
+            return serve_file(server, request, &index, location, None).await;
+        }
+        let autoindex = location
+            .and_then(|location| location.autoindex)
+            .unwrap_or(server.config.autoindex);
+        if !autoindex {
+            return Response::error(404);
+        }
+        let Some(page) = Page::from_query(&request.query) else {
+            return Response::error(400);
+        };
+        // Listings merge the directory from every layer, so a change below
+        // the top one would go unnoticed by the cache.
+        let cacheable = roots.len() == 1;
+        let key = format!(
+            "{cache_key}?page={}&per_page={}",
+            page.number, page.per_page
+        );
+        if let Some(listing) = server.listings.get(&key, &target.metadata) {
+            return Response::with_body(200, "text/html; charset=utf-8", Body::Shared(listing));
+        }
+        let mut dirs = Vec::new();
+        for root in roots {
+            if let Ok(Resolved {
+                path, is_dir: true, ..
+            }) = resolve(root, relative).await
+            {
+                dirs.push(path);
+            }
+        }
+        let thumbnails = server.thumbnails.is_some();
+        return match generate_directory_listing(&dirs, path, thumbnails, page).await {
+            Ok(Some(listing)) => {
+                let listing = Arc::new(listing);
+                if cacheable {
+                    server
+                        .listings
+                        .insert(&key, &target.metadata, listing.clone());
+                }
+                Response::with_body(200, "text/html; charset=utf-8", Body::Shared(listing))
+            }
+            Ok(None) => Response::error(404),
+            Err(_) => Response::error(500),
+        };
+    }
+    let handle = match server.open_files.is_enabled() {
+        true => match server.open_files.open(&cache_key, &target).await {
+            Ok(open) => Some(open.file),
+            Err(err) => return fdlimit::error_response(&err),
+        },
+        false => None,
+    };
+    serve_file(server, request, &target, location, handle).await
+}
+
+async fn serve_file(
+    server: &Server,
+    request: &Request,
+    file: &Resolved,
+    location: Option<&Location>,
+    handle: Option<Arc<File>>,
+) -> Response {
+    if !server.config.precompressed {
+        return serve_identity(server, request, file, location, handle).await;
+    }
+    let mut response = match precompressed(server, request, file, location).await {
+        Some(response) => response,
+        None => serve_identity(server, request, file, location, handle).await,
+    };
+    response.add_vary("Accept-Encoding");
+    response
+}
+
+/// Sends `FILE.br` or `FILE.gz` for `file` to a client accepting that
+/// coding, whichever it prefers. Sidecars must be regular files no older
+/// than `file`; range requests and pages getting a CSP nonce are left to
+/// `serve_identity`.
+async fn precompressed(
+    server: &Server,
+    request: &Request,
+    file: &Resolved,
+    location: Option<&Location>,
+) -> Option<Response> {
+    let accept = request.header("Accept-Encoding")?;
+    let content_type = content_type(&file.path);
+    let rewritten = location.is_some_and(|location| location.csp_nonce)
+        && content_type.starts_with("text/html");
+    if rewritten || request.header("Range").is_some() {
+        return None;
+    }
+    let mut codings = [("br", "br"), ("gzip", "gz")].map(|(coding, extension)| {
+        (
+            compress::encoding_quality(accept, coding),
+            coding,
+            extension,
+        )
+    });
+    // Stable, so brotli stays first on a tie.
+    codings.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for (quality, coding, extension) in codings {
+        if quality == 0.0 {
+            continue;
+        }
+        let mut path = file.path.clone().into_os_string();
+        path.push(format!(".{extension}"));
+        let path = PathBuf::from(path);
+        // Not following symlinks keeps the sidecar next to the checked file.
+        let Ok(metadata) = tokio::fs::symlink_metadata(&path).await else {
+            continue;
+        };
+        if !metadata.is_file() || metadata.modified().ok() < file.metadata.modified().ok() {
+            continue;
+        }
+
+        let etag = format!(
+            "{}-{coding}\"",
+            cache::etag(&file.metadata).trim_end_matches('"')
+        );
+        let last_modified = file.metadata.modified().ok().map(date::http_date);
+        if let Some(matched) = matching_etag(request.header("If-None-Match"), &etag) {
+            let mut response = Response::new(304, content_type, Vec::new());
+            response.set_header("ETag", matched);
+            set_last_modified(&mut response, last_modified);
+            return Some(response);
+        }
+        let content = match server.cache.get(&path, &metadata) {
+            Some(cached) => cached.content.clone(),
+            None => {
+                let content = Arc::new(read_file(&path).await.ok()?);
+                server.cache.insert(&path, &metadata, content.clone());
+                content
+            }
+        };
+        let mut response = Response::with_body(200, content_type, Body::Shared(content));
+        response.set_header("Content-Encoding", coding);
+        response.set_header("ETag", etag);
+        set_last_modified(&mut response, last_modified);
+        return Some(response);
+    }
+    None
+}
+
+async fn serve_identity(
+    server: &Server,
+    request: &Request,
+    file: &Resolved,
+    location: Option<&Location>,
+    handle: Option<Arc<File>>,
+) -> Response {
+    let content_type = content_type(&file.path);
+    let nonce = location
+        .is_some_and(|location| location.csp_nonce)
+        .then(csp::generate_nonce)
+        .filter(|_| content_type.starts_with("text/html"));
+
+    let cached = server.cache.get(&file.path, &file.metadata);
+    let etag = match &cached {
+        Some(cached) => cached.etag.clone(),
+        None => cache::etag(&file.metadata),
+    };
+
+    // Rewritten pages differ on every response, so they carry no validators.
+    let last_modified = file.metadata.modified().ok().map(date::http_date);
+    if nonce.is_none() {
+        let if_none_match = request.header("If-None-Match");
+        let matched = match if_none_match {
+            Some(_) => matching_etag(if_none_match, &etag),
+            None => conditional::not_modified(request, None, last_modified.as_deref())
+                .then_some(etag.as_str()),
+        };
+        if let Some(matched) = matched {
+            let mut response = Response::new(304, content_type, Vec::new());
+            response.set_header("ETag", matched);
+            set_last_modified(&mut response, last_modified);
+            return response;
+        }
+        let length = file.metadata.len();
+        match range::requested(request, &etag, last_modified.as_deref(), length) {
+            Some(Ok(range)) => {
+                let part = match read_range(server, file, handle, cached.as_deref(), &range).await {
+                    Ok(part) => part,
+                    Err(err) => return fdlimit::error_response(&err),
+                };
+                let mut response = range::partial(content_type, part, range, length);
+                response.set_header("ETag", etag);
+                set_last_modified(&mut response, last_modified);
+                return response;
+            }
+            Some(Err(())) => return range::not_satisfiable(length),
+            None => {}
+        }
+    }
+
+    let content = match &cached {
+        Some(cached) => Body::Shared(cached.content.clone()),
+        None => match map_file(server, file, handle.as_deref()).await {
+            Some(mapped) => Body::Shared(Arc::new(mapped)),
+            None => {
+                let key = format!("{}{}", file.path.display(), etag);
+                let read = server.reads.read(key, async {
+                    match handle {
+                        Some(handle) => {
+                            openfiles::read_at(handle, 0, file.metadata.len() as usize).await
+                        }
+                        None => read_file(&file.path).await,
+                    }
+                });
+                let content = match read.await {
+                    Ok(content) => content,
+                    Err(err) => return fdlimit::error_response(&err),
+                };
+                server
+                    .cache
+                    .insert(&file.path, &file.metadata, content.clone());
+                Body::Shared(content)
+            }
+        },
+    };
+
+    let Some(nonce) = nonce else {
+        let mut response = Response::with_body(200, content_type, content.clone());
+        response.set_header("ETag", etag);
+        response.set_header("Accept-Ranges", "bytes");
+        set_last_modified(&mut response, last_modified);
+        if server.config.digest {
+            let digest = match &cached {
+                Some(cached) => cached.digest().to_string(),
+                None => digest::sha256(&content),
+            };
+            set_digest(&mut response, digest);
+        }
+        return response;
+    };
+
+    let mut injector = NonceInjector::new(&nonce);
+    let mut body = injector.push(&content);
+    body.extend(injector.finish());
+    let mut response = Response::new(200, content_type, body);
+    response.set_header("Content-Security-Policy", csp::policy(&nonce));
+    if server.config.digest {
+        let digest = digest::sha256(&response.body);
+        set_digest(&mut response, digest);
+    }
+    response
+}
+
+/// `range` of `file`: part of the cached copy or of a mapping when there is
+/// one, else read on its own, so that a range of a large file doesn't read
+/// all of it.
+async fn read_range(
+    server: &Server,
+    file: &Resolved,
+    handle: Option<Arc<File>>,
+    cached: Option<&CachedFile>,
+    range: &Range<u64>,
+) -> io::Result<Body> {
+    let whole = match cached {
+        Some(cached) => Some(Body::Shared(cached.content.clone())),
+        None => map_file(server, file, handle.as_deref())
+            .await
+            .map(|mapped| Body::Shared(Arc::new(mapped))),
+    };
+    if let Some(whole) = whole {
+        // The file may have shrunk since its metadata was read.
+        let end = (range.end as usize).min(whole.len());
+        return Ok(range::slice(&whole, (range.start as usize).min(end)..end));
+    }
+    let handle = match handle {
+        Some(handle) => handle,
+        None => Arc::new(resolve::open(&file.path).await?.into_std().await),
+    };
+    let len = (range.end - range.start) as usize;
+    Ok(openfiles::read_at(handle, range.start, len).await?.into())
+}
+
+/// Returns the tag of `If-None-Match` naming the file's `etag`, either as
+/// is or in a compressed variant (`"...-gzip"`, `"...-br"`).
+fn matching_etag<'a>(if_none_match: Option<&'a str>, etag: &str) -> Option<&'a str> {
+    let stem = etag.trim_end_matches('"');
+    if_none_match?.split(',').map(str::trim).find(|tag| {
+        let tag = tag.strip_prefix("W/").unwrap_or(tag);
+        tag == etag
+            || tag
+                .strip_prefix(stem)
+                .is_some_and(|rest| rest.starts_with('-') && rest.ends_with('"'))
+    })
+}
+
+fn set_last_modified(response: &mut Response, last_modified: Option<String>) {
+    if let Some(last_modified) = last_modified {
+        response.set_header("Last-Modified", last_modified);
+    }
+}
+
+/// Sends the same digest as `Repr-Digest` and `Content-Digest`, which agree
+/// while bodies are sent without a content coding.
+fn set_digest(response: &mut Response, digest: String) {
+    response.set_header("Repr-Digest", digest.clone());
+    response.set_header("Content-Digest", digest);
+}
+
+/// Maps files whose size lies between `--mmap-min` and `--mmap-max`, so they
+/// are sent straight from the page cache. Returns `None` for other sizes and
+/// when mapping fails, in which case the file is read as usual.
+async fn map_file(server: &Server, file: &Resolved, handle: Option<&File>) -> Option<Mmap> {
+    let min = server.config.mmap_min?;
+    let len = file.metadata.len();
+    if len == 0 || len < min || len > server.config.mmap_max {
+        return None;
+    }
+    let opened;
+    let handle = match handle {
+        Some(handle) => handle,
+        None => {
+            opened = resolve::open(&file.path).await.ok()?.into_std().await;
+            &opened
+        }
+    };
+    // SAFETY: the mapping is read-only. A file truncated while it is being
+    // sent raises SIGBUS, which is why mapping is opt-in.
+    match unsafe { Mmap::map(handle) } {
+        Ok(mapped) => Some(mapped),
+        Err(err) => {
+            eprintln!("cannot map {}: {err}", file.path.display());
+            None
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+async fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut content = Vec::new();
+    resolve::open(path).await?.read_to_end(&mut content).await?;
+    Ok(content)
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let file = resolve::open(path).await?.into_std().await;
+    let len = file.metadata()?.len() as usize;
+    openfiles::read_at(Arc::new(file), 0, len).await
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Entries shown per listing page unless `?per_page=` asks otherwise.
+const DEFAULT_PER_PAGE: usize = 1000;
+
+/// Largest `?per_page=` accepted.
+const MAX_PER_PAGE: usize = 10_000;
+
+/// One page of a directory listing, numbered from 1.
+#[derive(Clone, Copy)]
+pub struct Page {
+    pub number: usize,
+    pub per_page: usize,
+}
+
+impl Page {
+    /// Reads `?page=N&per_page=M` from the query string. Returns `None` when
+    /// either is not a positive number or `per_page` exceeds the maximum.
+    pub fn from_query(query: &str) -> Option<Page> {
+        let mut page = Page {
+            number: 1,
+            per_page: DEFAULT_PER_PAGE,
+        };
+        for (key, value) in http::parse_query(query) {
+            match key {
+                "page" => page.number = value.parse().ok().filter(|&number| number > 0)?,
+                "per_page" => {
+                    page.per_page = value
+                        .parse()
+                        .ok()
+                        .filter(|per_page| (1..=MAX_PER_PAGE).contains(per_page))?
+                }
+                _ => {}
+            }
+        }
+        Some(page)
+    }
+}
+
+/// Renders an HTML page linking to the entries of `dirs` (the same directory
+/// in each root layer) on `page`, skipping hidden ones, with links to the
+/// neighbouring pages. With `thumbnails`, images are shown with a preview.
+/// Returns `None` for a page past the end.
+pub async fn generate_directory_listing(
+    dirs: &[PathBuf],
+    url_path: &str,
+    thumbnails: bool,
+    page: Page,
+) -> std::io::Result<Option<String>> {
+    let mut names = Vec::new();
+    for dir in dirs {
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            if entry.file_type().await?.is_dir() {
+                names.push(format!("{name}/"));
+            } else {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+
+    let pages = names.len().div_ceil(page.per_page).max(1);
+    if page.number > pages {
+        return Ok(None);
+    }
+    let shown = names
+        .into_iter()
+        .skip((page.number - 1) * page.per_page)
+        .take(page.per_page);
+
+    // Links are percent-encoded, then escaped like the text, so names
+    // holding `#`, `?` or `%` still lead to their file.
+    let url_path = url_path.trim_end_matches('/');
+    let base = escape_html(&http::percent_encode_path(url_path));
+    let mut html = format!("<html><h1>Index of {}/</h1><ul>", escape_html(url_path));
+    if !base.is_empty() {
+        html.push_str(&format!("<li><a href=\"{base}/..\">..</a></li>"));
+    }
+    for name in shown {
+        let link = escape_html(&http::percent_encode_path(&name));
+        let preview = match thumbnails && thumbs::is_image(&name) {
+            true => format!(
+                "<img src=\"{}{base}/{link}\" alt=\"\" loading=\"lazy\"> ",
+                thumbs::PREFIX.trim_end_matches('/'),
+            ),
+            false => String::new(),
+        };
+        html.push_str(&format!(
+            "<li>{preview}<a href=\"{base}/{link}\">{}</a></li>",
+            escape_html(&name)
+        ));
+    }
+    html.push_str("</ul>");
+    if pages > 1 {
+        let link = |number: usize, label: &str| {
+            format!(
+                "<a href=\"{base}/?page={number}&amp;per_page={}\">{label}</a>",
+                page.per_page
+            )
+        };
+        html.push_str("<p>");
+        if page.number > 1 {
+            html.push_str(&link(page.number - 1, "previous"));
+            html.push(' ');
+        }
+        html.push_str(&format!("page {} of {pages}", page.number));
+        if page.number < pages {
+            html.push(' ');
+            html.push_str(&link(page.number + 1, "next"));
+        }
+        html.push_str("</p>");
+    }
+    html.push_str("</html>");
+    Ok(Some(html))
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,144 @@
+//! Client addresses from `X-Forwarded-For`, for connections from the
+//! reverse proxies named with `--trusted-proxies`.
+//!
+//! Each proxy appends the address it received the request from, so the
+//! header is read from the right: addresses are skipped while they belong
+//! to trusted proxies, and the first one that doesn't is the client.
+//! Anything to its left was sent by the client itself and can't be trusted.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::cidr::Cidr;
+use crate::http::Request;
+
+/// The client that `request` came from over a connection from `peer`. An
+/// address taken from the header has no port, so it gets port 0.
+pub fn client(trusted: &[Cidr], request: &Request, peer: SocketAddr) -> SocketAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    // Proxies may add a header of their own rather than extend the last.
+    let forwarded: Vec<&str> = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("X-Forwarded-For"))
+        .flat_map(|(_, value)| value.split(','))
+        .collect();
+    let mut client = peer.ip();
+    for entry in forwarded.iter().rev() {
+        if !is_trusted(client) {
+            break;
+        }
+        match parse(entry.trim()) {
+            Some(ip) => client = ip,
+            // A garbled entry ends the chain at the last proxy that vouched
+            // for it.
+            None => break,
+        }
+    }
+    match client == peer.ip() {
+        true => peer,
+        false => SocketAddr::new(client, 0),
+    }
+}
+
+/// An address as proxies write them: bare, or with a port, IPv6 then in
+/// brackets.
+fn parse(entry: &str) -> Option<IpAddr> {
+    entry
+        .parse()
+        .ok()
+        .or_else(|| entry.parse::<SocketAddr>().ok().map(|address| address.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "10.0.0.1:5000";
+
+    fn client_of(trusted: &str, headers: &[&str]) -> String {
+        let trusted: Vec<Cidr> = trusted
+            .split(' ')
+            .map(|cidr| cidr.parse().unwrap())
+            .collect();
+        let request = Request {
+            method: "GET".to_string(),
+            target: "/".to_string(),
+            path: "/".to_string(),
+            query: String::new(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|&value| ("X-Forwarded-For".to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new().into(),
+            tls: None,
+            peer: None,
+        };
+        client(&trusted, &request, PEER.parse().unwrap()).to_string()
+    }
+
+    #[test]
+    fn ignores_untrusted_peers() {
+        assert_eq!(client_of("192.0.2.0/24", &["198.51.100.7"]), PEER);
+        assert_eq!(client_of("10.0.0.0/8", &[]), PEER);
+    }
+
+    #[test]
+    fn takes_the_rightmost_untrusted_hop() {
+        assert_eq!(client_of("10.0.0.1", &["198.51.100.7"]), "198.51.100.7:0");
+        // The client wrote the leading entries itself.
+        assert_eq!(
+            client_of("10.0.0.1", &["1.1.1.1, 6.6.6.6, 198.51.100.7"]),
+            "198.51.100.7:0"
+        );
+        assert_eq!(
+            client_of("10.0.0.0/8", &["1.1.1.1, 198.51.100.7, 10.0.0.2"]),
+            "198.51.100.7:0"
+        );
+        // One header per proxy reads as one list.
+        assert_eq!(
+            client_of("10.0.0.0/8", &["1.1.1.1, 198.51.100.7", "10.0.0.2"]),
+            "198.51.100.7:0"
+        );
+        // An untrusted proxy in the chain is taken for the client.
+        assert_eq!(
+            client_of("10.0.0.1", &["198.51.100.7, 10.0.0.2"]),
+            "10.0.0.2:0"
+        );
+    }
+
+    #[test]
+    fn all_trusted_chains_end_at_the_first_entry() {
+        assert_eq!(
+            client_of("10.0.0.0/8", &["10.0.0.3, 10.0.0.2"]),
+            "10.0.0.3:0"
+        );
+    }
+
+    #[test]
+    fn strips_ports() {
+        assert_eq!(
+            client_of("10.0.0.1", &["198.51.100.7:4321"]),
+            "198.51.100.7:0"
+        );
+        assert_eq!(
+            client_of("10.0.0.1", &["[2001:db8::1]:80"]),
+            "[2001:db8::1]:0"
+        );
+        assert_eq!(client_of("10.0.0.1", &[" 2001:db8::1 "]), "[2001:db8::1]:0");
+    }
+
+    #[test]
+    fn stops_at_malformed_entries() {
+        assert_eq!(client_of("10.0.0.0/8", &["6.6.6.6, unknown"]), PEER);
+        assert_eq!(client_of("10.0.0.0/8", &[""]), PEER);
+        assert_eq!(
+            client_of("10.0.0.0/8", &["6.6.6.6, 1.2.3, 10.0.0.2"]),
+            "10.0.0.2:0"
+        );
+        assert_eq!(
+            client_of("10.0.0.0/8", &["6.6.6.6,,10.0.0.2"]),
+            "10.0.0.2:0"
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Country lookups against a MaxMind GeoLite2/GeoIP2 database.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use maxminddb::{geoip2, Reader};
+
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+/// Where an address is located, as ISO codes (`RO`, `EU`).
+#[derive(Default)]
+pub struct Origin {
+    pub country: Option<String>,
+    pub continent: Option<String>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<GeoIp, String> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|err| format!("cannot open GeoIP database {}: {err}", path.display()))?;
+        Ok(GeoIp { reader })
+    }
+
+    /// Looks up `ip`; addresses missing from the database have no location.
+    pub fn lookup(&self, ip: IpAddr) -> Origin {
+        let record = self
+            .reader
+            .lookup(ip)
+            .and_then(|result| result.decode::<geoip2::Country>());
+        match record {
+            Ok(Some(record)) => Origin {
+                country: record.country.iso_code.map(str::to_string),
+                continent: record.continent.code.map(str::to_string),
+            },
+            _ => Origin::default(),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! Glob patterns over request paths: `*` matches within a path segment and
+//! `**` across segments; every other character matches itself.
+
+pub fn matches(glob: &str, path: &str) -> bool {
+    matches_bytes(glob.as_bytes(), path.as_bytes())
+}
+
+fn matches_bytes(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|skip| matches_bytes(rest, &path[skip..])),
+        [b'*', rest @ ..] => {
+            let segment = path
+                .iter()
+                .position(|&byte| byte == b'/')
+                .unwrap_or(path.len());
+            (0..=segment).any(|skip| matches_bytes(rest, &path[skip..]))
+        }
+        [first, rest @ ..] => path
+            .split_first()
+            .is_some_and(|(byte, path)| byte == first && matches_bytes(rest, path)),
+    }
+}
@@ -0,0 +1,1108 @@
+//! HTTP/2 (RFC 9113) for TLS clients that pick `h2` through ALPN, offered
+//! with `--http2`. Requests are multiplexed on one connection: each stream
+//! is answered in its own task by the same code as HTTP/1, and the frames
+//! of all streams go out through one writer task, within the flow control
+//! windows the client grants.
+//!
+//! Server push is not supported, and priorities are ignored.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::timeout;
+
+use crate::anonymize;
+use crate::bandwidth::Usage;
+use crate::hpack::{self, Decoder};
+use crate::http::{self, Request, Response};
+use crate::locations;
+use crate::panics;
+use crate::server::{self, Server};
+use crate::spool::Spool;
+use crate::timing::Timing;
+use crate::tls::TlsInfo;
+
+/// The protocol id negotiated through ALPN.
+pub const ALPN: &[u8] = b"h2";
+
+/// What a client sends first, before its `SETTINGS`.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Streams a client may have open at once.
+const MAX_STREAMS: usize = 100;
+
+/// Largest frame payload accepted, and sent until the client allows more.
+const FRAME_SIZE: usize = 16 * 1024;
+
+/// Largest header block accepted, as for HTTP/1.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Flow control window of the connection and of new streams, until the
+/// client's settings say otherwise.
+const DEFAULT_WINDOW: i64 = 65_535;
+
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Bytes of a streamed body read at a time.
+const STREAM_BUFFER: usize = 16 * 1024;
+
+/// Frames queued for the writer task.
+const QUEUED_FRAMES: usize = 32;
+
+/// Streams a client may reset within `RESET_WINDOW` before the connection
+/// is ended with `ENHANCE_YOUR_CALM`: opening streams only to reset them
+/// makes the server start work it never gets to send (CVE-2023-44487).
+const MAX_RESETS: u32 = 200;
+const RESET_WINDOW: Duration = Duration::from_secs(30);
+
+// Frame types.
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags.
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes.
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Settings.
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Headers that only mean something to an HTTP/1 connection.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// Why a connection ends before the client closes it.
+enum Fault {
+    Io(io::Error),
+    /// A connection error, reported to the client with `GOAWAY`.
+    Protocol(u32),
+}
+
+impl From<io::Error> for Fault {
+    fn from(err: io::Error) -> Fault {
+        Fault::Io(err)
+    }
+}
+
+/// What the connection shares with the tasks answering its streams.
+struct Shared {
+    /// Encoded frames, for the writer task.
+    frames: mpsc::Sender<Vec<u8>>,
+    flow: Mutex<Flow>,
+    /// Notified whenever a send window grows or a stream goes away.
+    flow_changed: Notify,
+}
+
+/// How much may be sent, as the client's `SETTINGS` and `WINDOW_UPDATE`
+/// frames allow.
+struct Flow {
+    connection: i64,
+    /// Windows of the open streams.
+    streams: HashMap<u32, i64>,
+    /// Window of new streams.
+    initial: i64,
+    max_frame_size: usize,
+}
+
+impl Shared {
+    /// Queues encoded frames, which are sent back to back.
+    async fn send(&self, frames: Vec<u8>) -> io::Result<()> {
+        self.frames
+            .send(frames)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// Waits until some of `wanted` bytes may be sent on `stream`, and
+    /// takes them out of both windows. Fails once the stream is closed.
+    async fn reserve(&self, stream: u32, wanted: usize) -> io::Result<usize> {
+        loop {
+            let changed = self.flow_changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let mut flow = self.flow.lock().unwrap();
+                let limit = wanted.min(flow.max_frame_size) as i64;
+                let connection = flow.connection;
+                let Some(window) = flow.streams.get_mut(&stream) else {
+                    return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+                };
+                let granted = limit.min(connection).min(*window);
+                if granted > 0 {
+                    *window -= granted;
+                    flow.connection -= granted;
+                    return Ok(granted as usize);
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+/// Headers still awaiting their `CONTINUATION` frames.
+struct PendingHeaders {
+    stream: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+/// A stream whose request body is still arriving.
+struct Receiving {
+    request: Request,
+    body: Spool,
+    limit: Option<u64>,
+    timing: Timing,
+}
+
+struct Connection {
+    server: Arc<Server>,
+    peer: SocketAddr,
+    tls: Arc<TlsInfo>,
+    shared: Arc<Shared>,
+    decoder: Decoder,
+    pending_headers: Option<PendingHeaders>,
+    /// Highest stream the client opened.
+    last_stream: u32,
+    receiving: HashMap<u32, Receiving>,
+    /// Streams being answered, by the tasks in `tasks`.
+    answering: HashMap<u32, AbortHandle>,
+    tasks: JoinSet<u32>,
+    /// Streams the client reset since `resets_since`.
+    resets: u32,
+    resets_since: Instant,
+    /// Set once either side sent `GOAWAY`: no new streams are accepted,
+    /// and the connection ends after the open ones.
+    closing: bool,
+}
+
+/// Serves the streams of one connection until the client closes it or
+/// sends no request within `--idle-timeout`.
+pub async fn serve<S>(
+    server: Arc<Server>,
+    stream: S,
+    peer: SocketAddr,
+    tls: Arc<TlsInfo>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+    let mut preface = [0u8; PREFACE.len()];
+    let reading = reader.read_exact(&mut preface);
+    let read = match server.config.idle_timeout {
+        0 => reading.await,
+        secs => match timeout(Duration::from_secs(secs), reading).await {
+            Ok(read) => read,
+            Err(_) => return Ok(()),
+        },
+    };
+    if read.is_err() || preface != PREFACE {
+        return Ok(());
+    }
+
+    let (frames, queue) = mpsc::channel(QUEUED_FRAMES);
+    let writing = tokio::spawn(write_frames(writer, queue));
+    let (incoming, mut received) = mpsc::channel(QUEUED_FRAMES);
+    let reading = tokio::spawn(async move {
+        loop {
+            let frame = read_frame(&mut reader).await;
+            let last = !matches!(frame, Ok(Some(_)));
+            if incoming.send(frame).await.is_err() || last {
+                return;
+            }
+        }
+    });
+
+    let shared = Arc::new(Shared {
+        frames,
+        flow: Mutex::new(Flow {
+            connection: DEFAULT_WINDOW,
+            streams: HashMap::new(),
+            initial: DEFAULT_WINDOW,
+            max_frame_size: FRAME_SIZE,
+        }),
+        flow_changed: Notify::new(),
+    });
+    let mut connection = Connection {
+        server,
+        peer,
+        tls,
+        shared,
+        decoder: Decoder::new(MAX_HEADER_SIZE),
+        pending_headers: None,
+        last_stream: 0,
+        receiving: HashMap::new(),
+        answering: HashMap::new(),
+        tasks: JoinSet::new(),
+        resets: 0,
+        resets_since: Instant::now(),
+        closing: false,
+    };
+    let outcome = connection.run(&mut received).await;
+    reading.abort();
+    connection.tasks.abort_all();
+    while connection.tasks.join_next().await.is_some() {}
+    let outcome = match outcome {
+        Ok(()) => connection.go_away(NO_ERROR).await,
+        Err(Fault::Protocol(code)) => connection.go_away(code).await,
+        Err(Fault::Io(err)) => Err(err),
+    };
+    // The writer task ends once every sender is gone.
+    drop(connection);
+    let written = writing.await.map_err(io::Error::other)?;
+    match outcome {
+        // The client may well have closed first.
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => written,
+        outcome => outcome,
+    }
+}
+
+impl Connection {
+    async fn run(
+        &mut self,
+        received: &mut mpsc::Receiver<Result<Option<Frame>, Fault>>,
+    ) -> Result<(), Fault> {
+        self.send_settings().await?;
+        let idle = match self.server.config.idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        loop {
+            let quiet = self.answering.is_empty() && self.receiving.is_empty();
+            if self.closing && quiet {
+                return Ok(());
+            }
+            tokio::select! {
+                frame = received.recv() => match frame {
+                    Some(Ok(Some(frame))) => self.handle(frame).await?,
+                    Some(Err(fault)) => return Err(fault),
+                    Some(Ok(None)) | None => return Ok(()),
+                },
+                Some(finished) = self.tasks.join_next(), if !self.tasks.is_empty() => {
+                    // Tasks of reset streams are already forgotten.
+                    if let Ok(stream) = finished {
+                        self.close(stream);
+                    }
+                }
+                _ = tokio::time::sleep(idle.unwrap_or_default()), if quiet && idle.is_some() => {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn send_settings(&self) -> io::Result<()> {
+        let mut payload = Vec::new();
+        for (id, value) in [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_SIZE),
+        ] {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        self.shared.send(frame(SETTINGS, 0, 0, &payload)).await
+    }
+
+    async fn handle(&mut self, frame: Frame) -> Result<(), Fault> {
+        if self
+            .pending_headers
+            .as_ref()
+            .is_some_and(|pending| frame.kind != CONTINUATION || frame.stream != pending.stream)
+        {
+            return Err(Fault::Protocol(PROTOCOL_ERROR));
+        }
+        let on_connection = frame.stream == 0;
+        match frame.kind {
+            DATA if !on_connection => self.data(frame).await,
+            HEADERS if !on_connection => self.headers(frame).await,
+            CONTINUATION => self.continuation(frame).await,
+            PRIORITY if !on_connection => Ok(()),
+            RST_STREAM if !on_connection => {
+                if frame.payload.len() != 4 {
+                    return Err(Fault::Protocol(FRAME_SIZE_ERROR));
+                }
+                if frame.stream > self.last_stream {
+                    return Err(Fault::Protocol(PROTOCOL_ERROR));
+                }
+                if self.resets_since.elapsed() > RESET_WINDOW {
+                    (self.resets, self.resets_since) = (0, Instant::now());
+                }
+                self.resets += 1;
+                if self.resets > MAX_RESETS {
+                    return Err(Fault::Protocol(ENHANCE_YOUR_CALM));
+                }
+                self.receiving.remove(&frame.stream);
+                if let Some(task) = self.answering.remove(&frame.stream) {
+                    task.abort();
+                }
+                self.close(frame.stream);
+                Ok(())
+            }
+            SETTINGS if on_connection => self.settings(frame).await,
+            PING if on_connection => {
+                if frame.payload.len() != 8 {
+                    return Err(Fault::Protocol(FRAME_SIZE_ERROR));
+                }
+                if frame.flags & ACK == 0 {
+                    self.shared
+                        .send(self::frame(PING, ACK, 0, &frame.payload))
+                        .await?;
+                }
+                Ok(())
+            }
+            GOAWAY if on_connection => {
+                self.closing = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.window_update(frame).await,
+            DATA | HEADERS | PRIORITY | RST_STREAM | SETTINGS | PUSH_PROMISE | PING | GOAWAY => {
+                Err(Fault::Protocol(PROTOCOL_ERROR))
+            }
+            // Frames of unknown types are ignored.
+            _ => Ok(()),
+        }
+    }
+
+    async fn headers(&mut self, frame: Frame) -> Result<(), Fault> {
+        let mut payload = unpad(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            payload = payload.get(5..).ok_or(Fault::Protocol(FRAME_SIZE_ERROR))?;
+        }
+        let pending = PendingHeaders {
+            stream: frame.stream,
+            end_stream: frame.flags & END_STREAM != 0,
+            block: payload.to_vec(),
+        };
+        match frame.flags & END_HEADERS {
+            0 => {
+                self.pending_headers = Some(pending);
+                Ok(())
+            }
+            _ => self.header_block(pending).await,
+        }
+    }
+
+    async fn continuation(&mut self, frame: Frame) -> Result<(), Fault> {
+        let Some(pending) = &mut self.pending_headers else {
+            return Err(Fault::Protocol(PROTOCOL_ERROR));
+        };
+        pending.block.extend_from_slice(&frame.payload);
+        if pending.block.len() > MAX_HEADER_SIZE {
+            return Err(Fault::Protocol(ENHANCE_YOUR_CALM));
+        }
+        match frame.flags & END_HEADERS {
+            0 => Ok(()),
+            _ => {
+                let pending = self.pending_headers.take().unwrap();
+                self.header_block(pending).await
+            }
+        }
+    }
+
+    async fn header_block(&mut self, headers: PendingHeaders) -> Result<(), Fault> {
+        let PendingHeaders {
+            stream,
+            end_stream,
+            block,
+        } = headers;
+        // Decoded even when the stream is refused, to keep the table in step.
+        let fields = self
+            .decoder
+            .decode(&block)
+            .map_err(|()| Fault::Protocol(COMPRESSION_ERROR))?;
+        if stream <= self.last_stream {
+            // Trailers end the body; their fields are dropped.
+            return match self.receiving.contains_key(&stream) {
+                true if end_stream => self.end_body(stream).await,
+                true => Ok(self.reset(stream, PROTOCOL_ERROR).await?),
+                false => Err(Fault::Protocol(STREAM_CLOSED)),
+            };
+        }
+        if stream.is_multiple_of(2) {
+            return Err(Fault::Protocol(PROTOCOL_ERROR));
+        }
+        self.last_stream = stream;
+        if self.closing {
+            return Ok(());
+        }
+        if self.answering.len() + self.receiving.len() >= MAX_STREAMS {
+            return Ok(self.reset(stream, REFUSED_STREAM).await?);
+        }
+        let Ok(mut request) = request(fields) else {
+            return Ok(self.reset(stream, PROTOCOL_ERROR).await?);
+        };
+        request.tls = Some(self.tls.clone());
+        request.peer = Some(self.peer);
+        let timing = Timing::start();
+        {
+            let mut flow = self.shared.flow.lock().unwrap();
+            let initial = flow.initial;
+            flow.streams.insert(stream, initial);
+        }
+
+        let location = locations::find(&self.server.config.locations, &request.path);
+        let limit = self.server.config.max_body_size(location);
+        if limit.is_some_and(|limit| request.content_length() > limit) {
+            self.refuse(stream, request, 413);
+        } else if end_stream {
+            self.start(stream, request, None, timing);
+        } else {
+            let body = Spool::new(self.server.config.body_buffer_size);
+            self.receiving.insert(
+                stream,
+                Receiving {
+                    request,
+                    body,
+                    limit,
+                    timing,
+                },
+            );
+        }
+        if self.server.draining.load(Ordering::Relaxed) {
+            self.go_away(NO_ERROR).await?;
+            self.closing = true;
+        }
+        Ok(())
+    }
+
+    async fn data(&mut self, frame: Frame) -> Result<(), Fault> {
+        let length = frame.payload.len();
+        // Windows are given back right away: bodies are spooled, and their
+        // size is limited by `--max-body-size` instead.
+        if length > 0 {
+            self.window_update_for(0, length).await?;
+        }
+        let payload = unpad(&frame)?;
+        let stream = frame.stream;
+        let Some(receiving) = self.receiving.get_mut(&stream) else {
+            if stream > self.last_stream {
+                return Err(Fault::Protocol(PROTOCOL_ERROR));
+            }
+            // Left over from a stream that was reset or answered early.
+            return Ok(());
+        };
+        let received = (receiving.body.received() + payload.len()) as u64;
+        if receiving.limit.is_some_and(|limit| received > limit) {
+            let receiving = self.receiving.remove(&stream).unwrap();
+            self.refuse(stream, receiving.request, 413);
+            return Ok(());
+        }
+        receiving.body.write(payload).await?;
+        match frame.flags & END_STREAM {
+            0 if length > 0 => Ok(self.window_update_for(stream, length).await?),
+            0 => Ok(()),
+            _ => self.end_body(stream).await,
+        }
+    }
+
+    /// Answers a request whose body has fully arrived.
+    async fn end_body(&mut self, stream: u32) -> Result<(), Fault> {
+        let Receiving {
+            mut request,
+            body,
+            timing,
+            ..
+        } = self.receiving.remove(&stream).unwrap();
+        let received = body.received();
+        request.body = body.finish().await?;
+        match request.header("Content-Length") {
+            Some(length) if length != received.to_string() => {
+                return Ok(self.reset(stream, PROTOCOL_ERROR).await?);
+            }
+            Some(_) => {}
+            None => request
+                .headers
+                .push(("content-length".to_string(), received.to_string())),
+        }
+        self.start(stream, request, None, timing);
+        Ok(())
+    }
+
+    async fn settings(&mut self, frame: Frame) -> Result<(), Fault> {
+        if frame.flags & ACK != 0 {
+            return match frame.payload.is_empty() {
+                true => Ok(()),
+                false => Err(Fault::Protocol(FRAME_SIZE_ERROR)),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Fault::Protocol(FRAME_SIZE_ERROR));
+        }
+        {
+            let mut flow = self.shared.flow.lock().unwrap();
+            for setting in frame.payload.chunks(6) {
+                let id = u16::from_be_bytes([setting[0], setting[1]]);
+                let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                match id {
+                    SETTINGS_INITIAL_WINDOW_SIZE => {
+                        let value = i64::from(value);
+                        if value > MAX_WINDOW {
+                            return Err(Fault::Protocol(FLOW_CONTROL_ERROR));
+                        }
+                        let change = value - flow.initial;
+                        flow.initial = value;
+                        for window in flow.streams.values_mut() {
+                            *window += change;
+                            if *window > MAX_WINDOW {
+                                return Err(Fault::Protocol(FLOW_CONTROL_ERROR));
+                            }
+                        }
+                    }
+                    SETTINGS_MAX_FRAME_SIZE => {
+                        if !(FRAME_SIZE as u32..=(1 << 24) - 1).contains(&value) {
+                            return Err(Fault::Protocol(PROTOCOL_ERROR));
+                        }
+                        flow.max_frame_size = value as usize;
+                    }
+                    // The others concern what this side sends: larger
+                    // tables it doesn't use, or pushes it doesn't make.
+                    _ => {}
+                }
+            }
+        }
+        self.shared.flow_changed.notify_waiters();
+        Ok(self.shared.send(self::frame(SETTINGS, ACK, 0, &[])).await?)
+    }
+
+    async fn window_update(&mut self, frame: Frame) -> Result<(), Fault> {
+        let [a, b, c, d] = frame.payload[..] else {
+            return Err(Fault::Protocol(FRAME_SIZE_ERROR));
+        };
+        let increment = i64::from(u32::from_be_bytes([a, b, c, d]) & 0x7fff_ffff);
+        let stream = frame.stream;
+        let error = {
+            let mut flow = self.shared.flow.lock().unwrap();
+            let window = match stream {
+                0 => Some(&mut flow.connection),
+                stream => flow.streams.get_mut(&stream),
+            };
+            match window {
+                _ if increment == 0 => Some(PROTOCOL_ERROR),
+                Some(window) if *window + increment > MAX_WINDOW => Some(FLOW_CONTROL_ERROR),
+                Some(window) => {
+                    *window += increment;
+                    None
+                }
+                None => None,
+            }
+        };
+        self.shared.flow_changed.notify_waiters();
+        match error {
+            Some(code) if stream == 0 => Err(Fault::Protocol(code)),
+            Some(code) => Ok(self.reset(stream, code).await?),
+            None => Ok(()),
+        }
+    }
+
+    /// Answers `request` on `stream` in a task of its own, or sends
+    /// `refused` in its place.
+    fn start(&mut self, stream: u32, request: Request, refused: Option<Response>, timing: Timing) {
+        let answering = answer_stream(
+            self.server.clone(),
+            self.shared.clone(),
+            stream,
+            request,
+            self.peer,
+            refused,
+            timing,
+        );
+        let task = self.tasks.spawn(answering);
+        self.answering.insert(stream, task);
+    }
+
+    /// Answers with the error `status` without reading the request body.
+    fn refuse(&mut self, stream: u32, request: Request, status: u16) {
+        server::log_connection(
+            self.server.anonymizer.as_deref(),
+            &request,
+            self.peer,
+            status,
+        );
+        let mut response = Response::error(status);
+        response.negotiate_error(&request);
+        self.start(stream, request, Some(response), Timing::start());
+    }
+
+    /// Forgets a stream that was answered or reset.
+    fn close(&mut self, stream: u32) {
+        self.answering.remove(&stream);
+        self.shared.flow.lock().unwrap().streams.remove(&stream);
+        self.shared.flow_changed.notify_waiters();
+    }
+
+    /// Ends `stream` with a stream error.
+    async fn reset(&mut self, stream: u32, code: u32) -> io::Result<()> {
+        self.receiving.remove(&stream);
+        if let Some(task) = self.answering.remove(&stream) {
+            task.abort();
+        }
+        self.close(stream);
+        self.shared
+            .send(frame(RST_STREAM, 0, stream, &code.to_be_bytes()))
+            .await
+    }
+
+    async fn window_update_for(&self, stream: u32, length: usize) -> io::Result<()> {
+        let increment = (length as u32).to_be_bytes();
+        self.shared
+            .send(frame(WINDOW_UPDATE, 0, stream, &increment))
+            .await
+    }
+
+    async fn go_away(&self, code: u32) -> io::Result<()> {
+        let mut payload = self.last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.shared.send(frame(GOAWAY, 0, 0, &payload)).await
+    }
+}
+
+/// Answers one stream and returns its id. Streams whose response can't be
+/// sent in full are reset, so the client can tell.
+async fn answer_stream(
+    server: Arc<Server>,
+    shared: Arc<Shared>,
+    stream: u32,
+    mut request: Request,
+    peer: SocketAddr,
+    refused: Option<Response>,
+    timing: Timing,
+) -> u32 {
+    let answering = async {
+        let usage = server.bandwidth.usage(&request);
+        let (mut response, body_read) = match refused {
+            Some(response) => (response, false),
+            None => (
+                server::answer(&server, &mut request, peer, &usage, timing)
+                    .await
+                    .0,
+                true,
+            ),
+        };
+        let location = locations::find(&server.config.locations, &request.path);
+        let sending = send_response(&shared, stream, &request, &mut response, &usage);
+        match server.config.write_timeout(location) {
+            Some(limit) => timeout(limit, sending).await.unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "writing response"))
+            })?,
+            None => sending.await?,
+        }
+        // Tells the client to stop sending a body nobody will read.
+        if !body_read {
+            shared
+                .send(frame(RST_STREAM, 0, stream, &NO_ERROR.to_be_bytes()))
+                .await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+    let code = match panics::catch(answering).await {
+        Ok(Ok(())) => return stream,
+        Ok(Err(err)) if err.kind() == io::ErrorKind::BrokenPipe => return stream,
+        Ok(Err(_)) => INTERNAL_ERROR,
+        Err(message) => {
+            let client = anonymize::client(server.anonymizer.as_deref(), peer.ip());
+            eprintln!("panic on stream {stream} from {client}: {message}");
+            INTERNAL_ERROR
+        }
+    };
+    let _ = shared
+        .send(frame(RST_STREAM, 0, stream, &code.to_be_bytes()))
+        .await;
+    stream
+}
+
+/// Sends `response` as `HEADERS` and `DATA` frames.
+async fn send_response(
+    shared: &Shared,
+    stream: u32,
+    request: &Request,
+    response: &mut Response,
+    usage: &Mutex<Usage>,
+) -> io::Result<()> {
+    if request.method == "HEAD" {
+        response.strip_body(&request.version);
+    }
+    let body = response.stream.take();
+    let length = match &body {
+        Some(body) => body.length,
+        None if !http::has_content(response.status) => None,
+        None => Some(response.body.len() as u64),
+    };
+    let status = response.status.to_string();
+    let mut fields: Vec<(String, &str)> = vec![(":status".to_string(), status.as_str())];
+    for (name, value) in &response.headers {
+        let name = name.to_ascii_lowercase();
+        if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            fields.push((name, value));
+        }
+    }
+    let length_value;
+    if let Some(length) = length {
+        if response.header("Content-Length").is_none() {
+            length_value = length.to_string();
+            fields.push(("content-length".to_string(), &length_value));
+        }
+    }
+    let fields: Vec<(&str, &str)> = fields
+        .iter()
+        .map(|(name, value)| (name.as_str(), *value))
+        .collect();
+    let block = hpack::encode(&fields);
+
+    let end_stream = body.is_none() && response.body.is_empty();
+    let max_frame_size = shared.flow.lock().unwrap().max_frame_size;
+    let fragments: Vec<&[u8]> = block.chunks(max_frame_size).collect();
+    let mut frames = Vec::new();
+    for (index, fragment) in fragments.iter().enumerate() {
+        let (kind, mut flags) = match index {
+            0 if end_stream => (HEADERS, END_STREAM),
+            0 => (HEADERS, 0),
+            _ => (CONTINUATION, 0),
+        };
+        if index + 1 == fragments.len() {
+            flags |= END_HEADERS;
+        }
+        frames.extend(frame(kind, flags, stream, fragment));
+    }
+    usage.lock().unwrap().charge(frames.len() as u64);
+    shared.send(frames).await?;
+
+    let Some(mut body) = body else {
+        let mut sent = 0;
+        let content = &response.body;
+        while sent < content.len() {
+            let size = shared.reserve(stream, content.len() - sent).await?;
+            let flags = if sent + size == content.len() {
+                END_STREAM
+            } else {
+                0
+            };
+            let data = frame(DATA, flags, stream, &content[sent..sent + size]);
+            usage.lock().unwrap().charge(data.len() as u64);
+            shared.send(data).await?;
+            sent += size;
+        }
+        return Ok(());
+    };
+    let mut remaining = body.length.unwrap_or(u64::MAX);
+    let mut chunk = vec![0u8; STREAM_BUFFER];
+    while remaining > 0 {
+        let wanted = chunk
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = body.reader.read(&mut chunk[..wanted]).await?;
+        if read == 0 {
+            break;
+        }
+        remaining -= read as u64;
+        let mut sent = 0;
+        while sent < read {
+            let size = shared.reserve(stream, read - sent).await?;
+            let data = frame(DATA, 0, stream, &chunk[sent..sent + size]);
+            usage.lock().unwrap().charge(data.len() as u64);
+            shared.send(data).await?;
+            sent += size;
+        }
+    }
+    shared.send(frame(DATA, END_STREAM, stream, &[])).await
+}
+
+/// Builds the request a header block describes, as HTTP/1 would have
+/// parsed it. `Err` for a malformed block, including values with bytes
+/// that would end a line or a string once handed on, as to a script or an
+/// upstream.
+fn request(fields: Vec<(String, String)>) -> Result<Request, ()> {
+    let (mut method, mut scheme, mut authority, mut target) = (None, None, None, None);
+    let mut headers = Vec::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        if value.contains(['\r', '\n', '\0']) {
+            return Err(());
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // Pseudo-headers come first, once each.
+            let field = match pseudo {
+                _ if !headers.is_empty() || !cookies.is_empty() => return Err(()),
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                "path" => &mut target,
+                _ => return Err(()),
+            };
+            if field.replace(value).is_some() {
+                return Err(());
+            }
+            continue;
+        }
+        if name.is_empty()
+            || !name
+                .bytes()
+                .all(|byte| http::is_token(byte) && !byte.is_ascii_uppercase())
+        {
+            return Err(());
+        }
+        match name.as_str() {
+            name if CONNECTION_HEADERS.contains(&name) => return Err(()),
+            "te" if value != "trailers" => return Err(()),
+            "content-length" if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) => {
+                return Err(());
+            }
+            // Sent one crumb at a time, to compress better.
+            "cookie" => cookies.push(value),
+            _ => headers.push((name, value)),
+        }
+    }
+    // `CONNECT` requests, which have no path, are not supported.
+    let (Some(method), Some(_), Some(target)) = (method, scheme, target) else {
+        return Err(());
+    };
+    if method.is_empty()
+        || !method.bytes().all(http::is_token)
+        || !(target.starts_with('/') || target == "*" && method == "OPTIONS")
+        || target.contains([' ', '\t'])
+    {
+        return Err(());
+    }
+    if let Some(authority) = authority {
+        headers.retain(|(name, _)| name != "host");
+        headers.insert(0, ("host".to_string(), authority));
+    }
+    if !cookies.is_empty() {
+        headers.push(("cookie".to_string(), cookies.join("; ")));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    Ok(Request {
+        method,
+        path: http::percent_decode(path),
+        query: query.to_string(),
+        target,
+        version: "HTTP/2".to_string(),
+        headers,
+        body: Vec::new().into(),
+        tls: None,
+        peer: None,
+    })
+}
+
+/// The payload of a `DATA` or `HEADERS` frame without its padding.
+fn unpad(frame: &Frame) -> Result<&[u8], Fault> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&padding, rest) = frame
+        .payload
+        .split_first()
+        .ok_or(Fault::Protocol(FRAME_SIZE_ERROR))?;
+    rest.len()
+        .checked_sub(usize::from(padding))
+        .map(|length| &rest[..length])
+        .ok_or(Fault::Protocol(PROTOCOL_ERROR))
+}
+
+/// Reads the next frame. `None` when the client has closed the connection.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>, Fault> {
+    let mut head = [0u8; 9];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let length = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    if length > FRAME_SIZE {
+        return Err(Fault::Protocol(FRAME_SIZE_ERROR));
+    }
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame {
+        kind: head[3],
+        flags: head[4],
+        stream: u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff,
+        payload,
+    }))
+}
+
+fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.extend_from_slice(&[kind, flags]);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Writes queued frames until every sender is gone, flushing whenever the
+/// queue runs empty so frames queued together share TLS records.
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut queue: mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(frames) = queue.recv().await {
+        writer.write_all(&frames).await?;
+        while let Ok(frames) = queue.try_recv() {
+            writer.write_all(&frames).await?;
+        }
+        writer.flush().await?;
+    }
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(extra: &[(&str, &str)]) -> Vec<(String, String)> {
+        [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/a?b=c"),
+        ]
+        .iter()
+        .chain(extra)
+        .map(|&(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_frames() {
+        let mut input = frame(HEADERS, END_HEADERS, 3, b"block");
+        // The reserved bit of the stream id is ignored.
+        input.extend_from_slice(&[0, 0, 0, PING, 0, 0x80, 0, 0, 0]);
+        let mut reader = input.as_slice();
+        let first = read_frame(&mut reader).await.ok().flatten().unwrap();
+        assert_eq!(
+            (
+                first.kind,
+                first.flags,
+                first.stream,
+                first.payload.as_slice()
+            ),
+            (HEADERS, END_HEADERS, 3, b"block".as_slice())
+        );
+        let second = read_frame(&mut reader).await.ok().flatten().unwrap();
+        assert_eq!((second.kind, second.stream), (PING, 0));
+        assert!(matches!(read_frame(&mut reader).await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_frames() {
+        let input = frame(DATA, 0, 1, &vec![0; FRAME_SIZE + 1]);
+        let result = read_frame(&mut input.as_slice()).await;
+        assert!(matches!(result, Err(Fault::Protocol(FRAME_SIZE_ERROR))));
+    }
+
+    #[tokio::test]
+    async fn truncated_payload_is_an_error() {
+        let input = frame(DATA, 0, 1, b"data");
+        let result = read_frame(&mut &input[..input.len() - 1]).await;
+        assert!(matches!(result, Err(Fault::Io(_))));
+    }
+
+    #[test]
+    fn strips_padding() {
+        let padded = |payload: &[u8]| Frame {
+            kind: DATA,
+            flags: PADDED,
+            stream: 1,
+            payload: payload.to_vec(),
+        };
+        assert_eq!(unpad(&padded(b"\x02ab\0\0")).ok(), Some(b"ab".as_slice()));
+        assert!(matches!(
+            unpad(&padded(b"\x05ab\0\0")),
+            Err(Fault::Protocol(PROTOCOL_ERROR))
+        ));
+        assert!(matches!(
+            unpad(&padded(b"")),
+            Err(Fault::Protocol(FRAME_SIZE_ERROR))
+        ));
+    }
+
+    #[test]
+    fn builds_requests() {
+        let request = request_of(&[("cookie", "a=1"), ("cookie", "b=2")]);
+        assert_eq!(
+            (request.path.as_str(), request.query.as_str()),
+            ("/a", "b=c")
+        );
+        assert_eq!(request.header("cookie"), Some("a=1; b=2"));
+    }
+
+    fn request_of(extra: &[(&str, &str)]) -> Request {
+        let mut fields = fields(&[(":authority", "example.com")]);
+        fields.extend(
+            extra
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string())),
+        );
+        let request = request(fields).ok().unwrap();
+        assert_eq!(request.header("host"), Some("example.com"));
+        request
+    }
+
+    #[test]
+    fn refuses_malformed_requests() {
+        for extra in [
+            &[("x-a", "b\r\nx-b: c")][..],
+            &[("x-a", "b\0")],
+            &[("X-A", "b")],
+            &[("connection", "close")],
+            &[("te", "gzip")],
+            &[("content-length", "1e3")],
+            &[(":status", "200")],
+            // Pseudo-headers come first.
+            &[("x-a", "b"), (":authority", "example.com")],
+        ] {
+            assert!(request(fields(extra)).is_err(), "{extra:?}");
+        }
+        for path in ["/a b", "/a\r\n", "/a\n", "/a\0", "a", "*"] {
+            let fields = vec![
+                (":method".to_string(), "GET".to_string()),
+                (":scheme".to_string(), "https".to_string()),
+                (":path".to_string(), path.to_string()),
+            ];
+            assert!(request(fields).is_err(), "{path:?}");
+        }
+        assert!(request(fields(&[])[..2].to_vec()).is_err());
+    }
+}
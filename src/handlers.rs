@@ -0,0 +1,259 @@
+//! Handlers chosen by file extension, configured per location with
+//! `handlers = { sh = "cgi", lua = "lua" }`, `worker` for scripts kept
+//! running between requests, or `websocket` for scripts that talk to
+//! WebSocket clients.
+//!
+//! `*` stands for every extension not listed. Without any configured
+//! handler, files below the `--cgi-dir` folders (`/scripts/` by default) or
+//! with a `--cgi-extension` are run as CGI scripts and everything else is
+//! served as a static file.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::fastcgi;
+use crate::http::{Request, Response};
+use crate::locations::Location;
+#[cfg(feature = "lua")]
+use crate::lua;
+use crate::resolve::{resolve, ResolveError, Resolved};
+use crate::scripts::{self, Script};
+use crate::server::{self, Server};
+
+/// Where requests for scripts go when no `--cgi-dir` says otherwise.
+pub const SCRIPTS_PREFIX: &str = "/scripts/";
+
+/// `Allow` header of CGI scripts.
+pub const CGI_METHODS: &str = "GET, HEAD, POST, OPTIONS";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Handler {
+    /// Served as is.
+    Static,
+    /// Run as a CGI-style script.
+    Cgi,
+    /// Answered by long-lived processes of the script, kept in a pool.
+    Worker,
+    /// Run with the embedded Lua interpreter (`lua` feature).
+    Lua,
+    /// Run for each WebSocket connection, exchanging messages as lines on
+    /// its stdin and stdout.
+    #[serde(rename = "websocket")]
+    WebSocket,
+}
+
+/// Picks the handler for `path` from a location's `handlers` table.
+pub fn find(handlers: &BTreeMap<String, Handler>, path: &str) -> Option<Handler> {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    extension
+        .and_then(|extension| handlers.get(&extension))
+        .or_else(|| handlers.get("*"))
+        .copied()
+}
+
+/// The handler for `path` when its location configures none.
+pub fn default(config: &Config, path: &str) -> Handler {
+    match cgi_dir(config, path).is_some() || has_cgi_extension(config, path) {
+        true => Handler::Cgi,
+        false => Handler::Static,
+    }
+}
+
+/// The `--cgi-dir` that `path` is below.
+fn cgi_dir<'a>(config: &'a Config, path: &str) -> Option<&'a str> {
+    config
+        .cgi_dirs
+        .iter()
+        .map(String::as_str)
+        .find(|dir| path.starts_with(dir))
+}
+
+fn has_cgi_extension(config: &Config, name: &str) -> bool {
+    let name = name.rsplit('/').next().unwrap_or_default();
+    name.rsplit_once('.').is_some_and(|(_, extension)| {
+        config
+            .cgi_extensions
+            .iter()
+            .any(|cgi| cgi.eq_ignore_ascii_case(extension))
+    })
+}
+
+/// The folders of `root` that the `--cgi-dir` prefixes stand for.
+pub fn cgi_folders(config: &Config, root: &Path) -> Vec<PathBuf> {
+    config
+        .cgi_dirs
+        .iter()
+        .map(|dir| root.join(dir.trim_matches('/')))
+        .collect()
+}
+
+/// Whether the file at `path` below `root` would be run rather than served,
+/// as a script, by a `--fastcgi` server or by a handler of `location` other
+/// than `static`, so that uploads must not create it. A path outside `root`
+/// is taken to be one.
+pub fn is_executable(
+    config: &Config,
+    location: Option<&Location>,
+    root: &Path,
+    path: &Path,
+) -> bool {
+    let Some(url) = url_path(root, path) else {
+        return true;
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    has_cgi_extension(config, &name)
+        || cgi_folders(config, root)
+            .iter()
+            .any(|folder| path.starts_with(folder))
+        || fastcgi::find(&config.fastcgi, &url).is_some()
+        || !matches!(
+            server::handler_for(config, location, &url),
+            Some(Handler::Static) | None
+        )
+}
+
+/// The request path naming the file at `path` below `root`.
+fn url_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut url = String::new();
+    for component in relative.components() {
+        url.push('/');
+        url.push_str(&component.as_os_str().to_string_lossy());
+    }
+    Some(url)
+}
+
+/// Runs the script at the request path with `handler`, which must not be
+/// `Static`.
+pub async fn run(
+    server: &Server,
+    request: &Request,
+    location: Option<&Location>,
+    handler: Handler,
+) -> Response {
+    let is_cgi = matches!(handler, Handler::Cgi | Handler::Worker);
+    if is_cgi && !matches!(request.method.as_str(), "GET" | "HEAD" | "POST") {
+        let mut response = Response::error(405);
+        response.set_header("Allow", CGI_METHODS);
+        return response;
+    }
+    let root = server.site_for(request).root.clone();
+    let (path, name) = match find_script(&root, &request.path).await {
+        Ok(found) => found,
+        Err(status) => return Response::error(status),
+    };
+    // `/scripts/../x` or a symlink must not run files from elsewhere.
+    if let Some(dir) = cgi_dir(&server.config, &request.path) {
+        if !path.starts_with(root.join(dir.trim_matches('/'))) {
+            return Response::error(403);
+        }
+    }
+    let script = Script {
+        path: &path,
+        name,
+        root: &root,
+    };
+    let timeout = server.config.script_timeout(location);
+    let (config, kv) = (&server.config, server.kv.as_deref());
+    if let Some(ttl) = server.config.script_cache(location) {
+        if is_cgi && is_shared(request, location) {
+            return cached(server, &script, request, handler, timeout, ttl).await;
+        }
+    }
+    match handler {
+        Handler::Static => unreachable!("static files are not run"),
+        Handler::Cgi => scripts::stream_script(&script, request, config, kv, timeout).await,
+        Handler::Worker => {
+            server
+                .workers
+                .run(&script, request, config, kv, timeout)
+                .await
+        }
+        Handler::Lua => run_lua(&path, request).await,
+        Handler::WebSocket => scripts::websocket(&script, request, config, kv),
+    }
+}
+
+/// Whether `request` may be answered from the script response cache, whose
+/// keys hold nothing of who sent it: a `GET` or `HEAD` without credentials,
+/// outside locations that check them with `auth_request`.
+fn is_shared(request: &Request, location: Option<&Location>) -> bool {
+    matches!(request.method.as_str(), "GET" | "HEAD")
+        && request.header("Authorization").is_none()
+        && request.header("Cookie").is_none()
+        && location.is_none_or(|location| location.auth_request.is_none())
+}
+
+/// Answers a `GET` or `HEAD` for `script`, run by `handler`, from the
+/// script response cache, or else runs it and keeps what it answers to a
+/// `GET` for `ttl`. The whole response is read before it is sent.
+async fn cached(
+    server: &Server,
+    script: &Script<'_>,
+    request: &Request,
+    handler: Handler,
+    timeout: Option<Duration>,
+    ttl: Duration,
+) -> Response {
+    let site = server.site_for(request);
+    let key = format!("{}?{}", site.cache_key(&request.path), request.query);
+    if let Some(response) = server.script_responses.get(&key) {
+        return scripts::revalidate(request, response);
+    }
+    let (config, kv) = (&server.config, server.kv.as_deref());
+    let response = match handler {
+        Handler::Worker => {
+            server
+                .workers
+                .render(script, request, config, kv, timeout)
+                .await
+        }
+        _ => scripts::render(script, request, config, kv, timeout).await,
+    };
+    if request.method == "GET" {
+        server.script_responses.insert(&key, &response, ttl);
+    }
+    scripts::revalidate(request, response)
+}
+
+/// The script that `path` names, and the part of `path` naming it: the
+/// longest prefix that is a file, so that `/scripts/app.sh/users/42` runs
+/// `app.sh` and leaves `/users/42` for its `PATH_INFO`.
+async fn find_script<'a>(root: &Path, path: &'a str) -> Result<(PathBuf, &'a str), u16> {
+    let mut name = path;
+    loop {
+        match resolve(root, name).await {
+            Ok(Resolved {
+                path: script,
+                is_dir: false,
+                ..
+            }) => return Ok((script, name.strip_suffix('/').unwrap_or(name))),
+            Ok(_) => return Err(404),
+            Err(ResolveError::NotFound) => {}
+            Err(err) => return Err(err.status()),
+        }
+        match name.rfind('/') {
+            Some(end) if end > 0 => name = &name[..end],
+            _ => return Err(404),
+        }
+    }
+}
+
+#[cfg(feature = "lua")]
+async fn run_lua(script: &Path, request: &Request) -> Response {
+    lua::run_file(script, request).await
+}
+
+/// Config loading refuses `lua` handlers on builds without the feature.
+#[cfg(not(feature = "lua"))]
+async fn run_lua(_script: &Path, _request: &Request) -> Response {
+    Response::error(500)
+}
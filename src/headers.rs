@@ -0,0 +1,45 @@
+//! Extra response headers for static content, from the `[[headers]]`
+//! tables of the config file.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::glob;
+use crate::http::{self, Response};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule {
+    /// Glob matched against the request path.
+    pub path: String,
+    /// Headers set on every response for a matching path.
+    pub set: BTreeMap<String, String>,
+}
+
+impl HeaderRule {
+    /// Rejects names and values that can't be sent as a header line.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in &self.set {
+            if name.is_empty() || !name.bytes().all(http::is_token) {
+                return Err(format!("invalid header name {name:?} for {}", self.path));
+            }
+            if value
+                .bytes()
+                .any(|byte| byte.is_ascii_control() && byte != b'\t')
+            {
+                return Err(format!("invalid value for header {name} for {}", self.path));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets the headers of every rule matching `path`, later rules winning.
+pub fn apply(rules: &[HeaderRule], path: &str, response: &mut Response) {
+    for rule in rules.iter().filter(|rule| glob::matches(&rule.path, path)) {
+        for (name, value) in &rule.set {
+            response.set_header(name, value.as_str());
+        }
+    }
+}
@@ -0,0 +1,498 @@
+//! HPACK (RFC 7541), the header compression of HTTP/2. Header blocks from
+//! clients are decoded against the connection's dynamic table; responses
+//! are encoded without one, as static table references and plain literals,
+//! so streams can encode their headers independently.
+
+use std::collections::VecDeque;
+
+/// Size of the dynamic table clients may use, the protocol's default.
+pub const TABLE_SIZE: usize = 4096;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Code lengths of the canonical Huffman code of Appendix B, for the 256
+/// byte values and the end-of-string symbol.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+
+/// The decoding state of one connection.
+pub struct Decoder {
+    /// Entries added by the client, newest first.
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    /// Largest header list accepted, counted as the table counts entries.
+    max_list_size: usize,
+}
+
+impl Decoder {
+    pub fn new(max_list_size: usize) -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+            max_list_size,
+        }
+    }
+
+    /// Decodes a complete header block. Any error leaves the table out of
+    /// step with the client's, so the connection has to end.
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<(String, String)>, ()> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        while let Some(&first) = block.first() {
+            let (name, value) = if first & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                self.entry(index)?
+            } else if first & 0xe0 == 0x20 {
+                let size = integer(&mut block, 5)?;
+                if size > TABLE_SIZE || !headers.is_empty() {
+                    return Err(());
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // Literals: with indexing, without, or never to be indexed.
+                let indexed = first & 0x40 != 0;
+                let index = integer(&mut block, if indexed { 6 } else { 4 })?;
+                let name = match index {
+                    0 => string(&mut block)?,
+                    index => self.entry(index)?.0,
+                };
+                let value = string(&mut block)?;
+                if indexed {
+                    self.insert(name.clone(), value.clone());
+                }
+                (name, value)
+            };
+            list_size += name.len() + value.len() + 32;
+            if list_size > self.max_list_size {
+                return Err(());
+            }
+            headers.push((name, value));
+        }
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String), ()> {
+        match index {
+            0 => Err(()),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            index => self.table.get(index - 62).cloned().ok_or(()),
+        }
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = name.len() + value.len() + 32;
+        self.evict(size);
+        // An entry larger than the whole table just empties it.
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front((name, value));
+        }
+    }
+
+    /// Drops the oldest entries until `room` more bytes fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + 32;
+        }
+    }
+}
+
+/// Encodes response headers, whose names must already be lowercase.
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for &(name, value) in headers {
+        let exact = STATIC_TABLE
+            .iter()
+            .position(|&entry| entry == (name, value));
+        if let Some(position) = exact {
+            push_integer(&mut block, 0x80, 7, position + 1);
+            continue;
+        }
+        // Literal without indexing, naming a static entry when one matches.
+        match STATIC_TABLE.iter().position(|&(known, _)| known == name) {
+            Some(position) => push_integer(&mut block, 0x00, 4, position + 1),
+            None => {
+                block.push(0x00);
+                push_string(&mut block, name);
+            }
+        }
+        push_string(&mut block, value);
+    }
+    block
+}
+
+/// Reads an integer whose first byte keeps its low `prefix` bits for it.
+fn integer(input: &mut &[u8], prefix: u32) -> Result<usize, ()> {
+    let (&first, rest) = input.split_first().ok_or(())?;
+    *input = rest;
+    let max = (1 << prefix) - 1;
+    let mut value = usize::from(first) & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input.split_first().ok_or(())?;
+        *input = rest;
+        if shift > 28 {
+            return Err(());
+        }
+        value += usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn push_integer(output: &mut Vec<u8>, flags: u8, prefix: u32, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        output.push(flags | value as u8);
+        return;
+    }
+    output.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        output.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+/// Reads a string literal, Huffman-coded or not.
+fn string(input: &mut &[u8]) -> Result<String, ()> {
+    let huffman = input.first().ok_or(())? & 0x80 != 0;
+    let length = integer(input, 7)?;
+    if length > input.len() {
+        return Err(());
+    }
+    let (bytes, rest) = input.split_at(length);
+    *input = rest;
+    let bytes = match huffman {
+        true => huffman_decode(bytes)?,
+        false => bytes.to_vec(),
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Writes a string literal as is; Huffman coding would save a fifth of
+/// the bytes of typical values, but costs more than that to produce.
+fn push_string(output: &mut Vec<u8>, value: &str) {
+    push_integer(output, 0x00, 7, value.len());
+    output.extend_from_slice(value.as_bytes());
+}
+
+/// Decodes the canonical code bit by bit: codes of each length are
+/// consecutive, in the order of their symbols.
+fn huffman_decode(input: &[u8]) -> Result<Vec<u8>, ()> {
+    let mut counts = [0usize; 31];
+    for &length in &HUFFMAN_LENGTHS {
+        counts[usize::from(length)] += 1;
+    }
+    let mut symbols: Vec<usize> = (0..HUFFMAN_LENGTHS.len()).collect();
+    symbols.sort_by_key(|&symbol| HUFFMAN_LENGTHS[symbol]);
+
+    let mut output = Vec::new();
+    // The bits read of the current code, its length, the first code of that
+    // length and the position of that code's symbol in `symbols`.
+    let (mut code, mut length, mut first, mut index) = (0usize, 0usize, 0usize, 0usize);
+    for &byte in input {
+        for shift in (0..8).rev() {
+            code = code << 1 | usize::from(byte >> shift & 1);
+            length += 1;
+            let count = counts[length];
+            if code >= first && code - first < count {
+                match symbols[index + code - first] {
+                    256 => return Err(()),
+                    symbol => output.push(symbol as u8),
+                }
+                (code, length, first, index) = (0, 0, 0, 0);
+                continue;
+            }
+            if length == 30 {
+                return Err(());
+            }
+            index += count;
+            first = (first + count) << 1;
+        }
+    }
+    // What is left must be padding: fewer than eight bits, all ones.
+    if length > 7 || code != (1 << length) - 1 {
+        return Err(());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    type Headers<'a> = &'a [(&'a str, &'a str)];
+
+    /// Decodes `blocks` in turn with one decoder, as RFC 7541 Appendix C
+    /// does, checking each block's headers and the table left after it.
+    fn check(decoder: &mut Decoder, blocks: &[(&str, Headers, usize)]) {
+        for &(block, expected, size) in blocks {
+            let headers = decoder.decode(&hex(block)).unwrap();
+            let headers: Vec<_> = headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            assert_eq!(headers, expected);
+            assert_eq!(decoder.size, size);
+        }
+    }
+
+    const REQUESTS: [Headers; 3] = [
+        &[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ],
+        &[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ],
+        &[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ],
+    ];
+
+    #[test]
+    fn requests_without_huffman() {
+        // C.3
+        check(
+            &mut Decoder::new(usize::MAX),
+            &[
+                (
+                    "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    REQUESTS[0],
+                    57,
+                ),
+                ("8286 84be 5808 6e6f 2d63 6163 6865", REQUESTS[1], 110),
+                (
+                    "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+                    REQUESTS[2],
+                    164,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn requests_with_huffman() {
+        // C.4
+        check(
+            &mut Decoder::new(usize::MAX),
+            &[
+                (
+                    "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+                    REQUESTS[0],
+                    57,
+                ),
+                ("8286 84be 5886 a8eb 1064 9cbf", REQUESTS[1], 110),
+                (
+                    "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+                    REQUESTS[2],
+                    164,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn responses_with_eviction() {
+        // C.6, whose encoder has a table of 256 bytes: the first block
+        // starts with the size update that tells the decoder so.
+        let location = ("location", "https://www.example.com");
+        let date = ("date", "Mon, 21 Oct 2013 20:13:21 GMT");
+        check(
+            &mut Decoder::new(usize::MAX),
+            &[
+                (
+                    "3fe1 01
+                     4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005
+                     9504 0b81 66e0 82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8
+                     e9ae 82ae 43d3",
+                    &[
+                        (":status", "302"),
+                        ("cache-control", "private"),
+                        date,
+                        location,
+                    ],
+                    222,
+                ),
+                (
+                    "4883 640e ffc1 c0bf",
+                    &[
+                        (":status", "307"),
+                        ("cache-control", "private"),
+                        date,
+                        location,
+                    ],
+                    222,
+                ),
+                (
+                    "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d
+                     1bff c05a 839b d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b
+                     3960 d5af 2708 7f36 72c1 ab27 0fb5 291f 9587 3160 65c0 03ed
+                     4ee5 b106 3d50 07",
+                    &[
+                        (":status", "200"),
+                        ("cache-control", "private"),
+                        ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+                        location,
+                        ("content-encoding", "gzip"),
+                        (
+                            "set-cookie",
+                            "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+                        ),
+                    ],
+                    215,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn refuses_bad_blocks() {
+        // Index 0, an index past the tables, a truncated literal, a size
+        // update after a header and Huffman padding that isn't all ones.
+        for block in ["80", "ff00", "4005 6162", "82 3f e1 01", "4081 00 00"] {
+            assert!(
+                Decoder::new(usize::MAX).decode(&hex(block)).is_err(),
+                "{block}"
+            );
+        }
+        // The same header twice is 2 * (1 + 1 + 32) bytes.
+        let block = hex("4001 6101 62 be");
+        assert!(Decoder::new(67).decode(&block).is_err());
+        assert!(Decoder::new(68).decode(&block).is_ok());
+    }
+
+    #[test]
+    fn encodes_what_it_decodes() {
+        let headers = [
+            (":status", "200"),
+            ("content-type", "text/html"),
+            ("x-custom", "value"),
+        ];
+        let block = encode(&headers);
+        assert_eq!(block[0], 0x88);
+        let decoded = Decoder::new(usize::MAX).decode(&block).unwrap();
+        let decoded: Vec<_> = decoded
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(decoded, headers);
+    }
+
+    #[test]
+    fn integers() {
+        // C.1: 1337 with a 5-bit prefix.
+        let mut block = Vec::new();
+        push_integer(&mut block, 0, 5, 1337);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(integer(&mut block.as_slice(), 5), Ok(1337));
+        assert_eq!(integer(&mut [0x0a].as_slice(), 5), Ok(10));
+    }
+}
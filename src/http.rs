@@ -0,0 +1,968 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    DuplexStream, ReadBuf,
+};
+
+use crate::spool::{self, Spool};
+use crate::tls::TlsInfo;
+use crate::websocket::Upgrade;
+
+/// Largest header block accepted before the request is rejected.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Longest chunk-size or trailer line of a chunked request body.
+const MAX_LINE: usize = 4096;
+
+/// Bytes a streamed body is produced and sent in at most.
+pub const STREAM_BUFFER: usize = 16 * 1024;
+
+#[derive(Clone)]
+pub struct Request {
+    pub method: String,
+    /// Request target exactly as sent by the client.
+    pub target: String,
+    /// Percent-decoded path, without the query string.
+    pub path: String,
+    /// Raw query string, without the leading `?`.
+    pub query: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    /// Request body, mapped from a temporary file when it is larger than
+    /// `--body-buffer-size`.
+    pub body: Body,
+    /// Parameters of the TLS connection the request came over, if any.
+    pub tls: Option<Arc<TlsInfo>>,
+    /// Address of the client, or of the one a `--proxy-protocol` balancer
+    /// relays.
+    pub peer: Option<SocketAddr>,
+}
+
+impl Request {
+    /// Returns the first header with the given name, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the client asked to keep the connection open: by default on
+    /// HTTP/1.1, with `Connection: keep-alive` on HTTP/1.0.
+    pub fn keep_alive(&self) -> bool {
+        let tokens = self.header("Connection").unwrap_or_default();
+        let has = |token: &str| {
+            tokens
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        match self.version.as_str() {
+            "HTTP/1.1" => !has("close"),
+            "HTTP/1.0" => has("keep-alive"),
+            _ => false,
+        }
+    }
+
+    /// The announced body length, 0 without a valid `Content-Length`.
+    pub fn content_length(&self) -> u64 {
+        self.header("Content-Length")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// A response body, owned or shared with a cache entry or file mapping.
+#[derive(Clone)]
+pub enum Body {
+    Owned(Vec<u8>),
+    Shared(Arc<dyn AsRef<[u8]> + Send + Sync>),
+}
+
+impl Body {
+    /// Returns the body for modification, copying shared bytes first.
+    pub fn to_mut(&mut self) -> &mut Vec<u8> {
+        if let Body::Shared(shared) = self {
+            *self = Body::Owned((**shared).as_ref().to_vec());
+        }
+        match self {
+            Body::Owned(bytes) => bytes,
+            Body::Shared(_) => unreachable!(),
+        }
+    }
+
+    /// The bytes, for a task that outlives the request; only bodies kept in
+    /// memory are copied.
+    pub fn share(&self) -> Arc<dyn AsRef<[u8]> + Send + Sync> {
+        match self {
+            Body::Owned(bytes) => Arc::new(bytes.clone()),
+            Body::Shared(shared) => shared.clone(),
+        }
+    }
+}
+
+impl Deref for Body {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Body::Owned(bytes) => bytes,
+            Body::Shared(shared) => (**shared).as_ref(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Owned(bytes)
+    }
+}
+
+/// A body produced while it is sent, for output too large or too
+/// long-lived to hold in memory.
+pub struct BodyStream {
+    pub reader: Pin<Box<dyn AsyncRead + Send>>,
+    /// Announced as `Content-Length`; without it the body is sent chunked.
+    pub length: Option<u64>,
+}
+
+/// A body that a task relays from a backend into the other end of `reader`.
+/// It ends in an error rather than a clean end when the task sets `failed`
+/// before letting go of its end, because the backend broke off.
+pub struct Relayed {
+    pub reader: DuplexStream,
+    pub failed: Arc<AtomicBool>,
+}
+
+impl AsyncRead for Relayed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        match Pin::new(&mut self.reader).poll_read(cx, buf) {
+            Poll::Ready(Ok(()))
+                if buf.filled().len() == before && self.failed.load(Ordering::SeqCst) =>
+            {
+                Poll::Ready(Err(io::Error::other("upstream broke off")))
+            }
+            other => other,
+        }
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    /// Replaces `body` when set; see `Response::stream`.
+    pub stream: Option<BodyStream>,
+    /// The body is the built-in error page, which `negotiate_error` may
+    /// replace with a representation the client prefers.
+    pub error_page: bool,
+    /// Takes the connection over when this is a `101 Switching Protocols`.
+    pub upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Response {
+        Response::with_body(status, content_type, Body::Owned(body.into()))
+    }
+
+    pub fn with_body(status: u16, content_type: &str, body: Body) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-type".to_string(), content_type.to_string())],
+            body,
+            stream: None,
+            error_page: false,
+            upgrade: None,
+        }
+    }
+
+    /// A response whose body `produce` writes while the response is being
+    /// sent. `produce` runs in its own task; its writes fail once the client
+    /// has gone, which is its cue to stop. Unless `length` is given, the
+    /// body is sent with chunked encoding (or, to HTTP/1.0 clients, until
+    /// the connection closes).
+    pub fn stream<F, Fut>(
+        status: u16,
+        content_type: &str,
+        length: Option<u64>,
+        produce: F,
+    ) -> Response
+    where
+        F: FnOnce(DuplexStream) -> Fut,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let (writer, reader) = tokio::io::duplex(STREAM_BUFFER);
+        let producing = produce(writer);
+        tokio::spawn(async move {
+            let _ = producing.await;
+        });
+        let mut response = Response::new(status, content_type, Vec::new());
+        response.set_stream(reader, length);
+        response
+    }
+
+    /// Sends what `reader` yields as the body, chunked unless `length` is
+    /// given. A read error cuts the response short, so the client can tell
+    /// it is incomplete.
+    pub fn set_stream(&mut self, reader: impl AsyncRead + Send + 'static, length: Option<u64>) {
+        self.stream = Some(BodyStream {
+            reader: Box::pin(reader),
+            length,
+        });
+    }
+
+    /// A short HTML page describing the status, used for every error response.
+    pub fn error(status: u16) -> Response {
+        let body = format!("<html>{} {}</html>", status, reason(status));
+        let mut response = Response::new(status, "text/html; charset=utf-8", body);
+        response.error_page = true;
+        response
+    }
+
+    /// Re-renders a built-in error page as JSON for clients that prefer
+    /// `application/json` over HTML.
+    pub fn negotiate_error(&mut self, request: &Request) {
+        if !self.error_page {
+            return;
+        }
+        let accept = request.header("Accept").unwrap_or_default();
+        let json = accept_quality(accept, "application/json");
+        if json == 0.0 || json <= accept_quality(accept, "text/html") {
+            return;
+        }
+        let body = format!(
+            "{{\"status\":{},\"error\":{},\"path\":{}}}",
+            self.status,
+            serde_json::Value::from(reason(self.status)),
+            serde_json::Value::from(request.path.as_str())
+        );
+        self.body = body.into_bytes().into();
+        self.set_header("Content-type", "application/json");
+    }
+
+    /// Drops the body of a response to `HEAD`, keeping the framing headers
+    /// the same response to `GET` would have.
+    pub fn strip_body(&mut self, version: &str) {
+        if !has_content(self.status) {
+            self.body = Vec::new().into();
+            self.stream = None;
+            return;
+        }
+        let length = match self.stream.take() {
+            Some(body) => body.length,
+            None => Some(self.body.len() as u64),
+        };
+        self.body = Vec::new().into();
+        match length {
+            Some(length) => self.set_header("Content-Length", length.to_string()),
+            None if version != "HTTP/1.0" => self.set_header("Transfer-Encoding", "chunked"),
+            // Ended by closing the connection, like the body it replaces.
+            None => {
+                self.stream = Some(BodyStream {
+                    reader: Box::pin(tokio::io::empty()),
+                    length: None,
+                })
+            }
+        }
+    }
+
+    /// Whether the client can tell where the body ends without the
+    /// connection closing: always, except for a stream of unknown length
+    /// sent to an HTTP/1.0 client, which can't be chunked.
+    pub fn is_delimited(&self, version: &str) -> bool {
+        version != "HTTP/1.0"
+            || self
+                .stream
+                .as_ref()
+                .is_none_or(|body| body.length.is_some())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Adds `field` to the `Vary` header, keeping the fields already listed.
+    pub fn add_vary(&mut self, field: &str) {
+        let vary = match self.header("Vary") {
+            Some(vary)
+                if vary
+                    .split(',')
+                    .any(|listed| listed.trim().eq_ignore_ascii_case(field)) =>
+            {
+                return;
+            }
+            Some(vary) => format!("{vary}, {field}"),
+            None => field.to_string(),
+        };
+        self.set_header("Vary", vary);
+    }
+
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.into()));
+    }
+}
+
+/// Whether responses with `status` have a body, and so a length to send.
+/// `1xx`, `204` and `304` never do: a `304` stands for a representation
+/// whose `Content-Length` a `0` would contradict (RFC 9110, section 8.6).
+pub fn has_content(status: u16) -> bool {
+    !matches!(status, 100..=199 | 204 | 304)
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        507 => "Insufficient Storage",
+        509 => "Bandwidth Limit Exceeded",
+        _ => "Unknown",
+    }
+}
+
+/// Returns the quality an `Accept` header assigns to `media` (`type/subtype`),
+/// honoring `type/*` and `*/*` ranges. The most specific range wins.
+pub fn accept_quality(accept: &str, media: &str) -> f32 {
+    let (kind, _) = media.split_once('/').unwrap_or((media, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let specificity = if name.eq_ignore_ascii_case(media) {
+            2
+        } else if name
+            .strip_suffix("/*")
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(kind))
+        {
+            1
+        } else if name == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|value| value.parse().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(current, _)| specificity > current) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+/// Reads a single request from the stream. Returns `Ok(None)` when the
+/// connection was closed or the request could not be parsed. Bodies above
+/// `body_buffer_size` bytes are spooled to a temporary file.
+pub async fn read_request<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    body_buffer_size: u64,
+) -> io::Result<Option<Request>> {
+    let Some(Ok(mut request)) = read_head(stream).await? else {
+        return Ok(None);
+    };
+    match read_body(stream, &mut request, body_buffer_size, None).await? {
+        Ok(()) => Ok(Some(request)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Resolves once reading from `stream` fails, as it does when the client
+/// resets the connection. A clean end of input is only a half-close, after
+/// which the client may still wait for its answer, as HTTP/1.0 clients
+/// that shut down their side after sending do; a pipelined request that
+/// arrives instead is left in the buffer for `read_head`. Neither resolves.
+pub async fn closed<S: AsyncBufRead + Unpin>(stream: &mut S) {
+    if stream.fill_buf().await.is_ok() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Reads the request line and headers, and nothing past them, so the body
+/// and any pipelined request stay in `stream` for `read_body` and the next
+/// call. `request.body` is left empty.
+///
+/// Returns `None` when the connection closes first, and the status to answer
+/// for a head that can't be served: 431 past `MAX_HEADER_SIZE`, 505 for
+/// versions other than HTTP/1.0 and HTTP/1.1, 501 for transfer codings
+/// other than `chunked` and 400 for anything malformed, including an
+/// HTTP/1.1 request without a `Host`.
+pub async fn read_head<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<Option<Result<Request, u16>>> {
+    let mut buffer = Vec::new();
+    let header_end = loop {
+        let available = stream.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(None);
+        }
+        let searched = buffer.len().saturating_sub(3);
+        let read = available.len();
+        buffer.extend_from_slice(available);
+        if let Some(end) = find(&buffer[searched..], b"\r\n\r\n") {
+            let header_end = searched + end;
+            stream.consume(read - (buffer.len() - header_end - 4));
+            buffer.truncate(header_end + 4);
+            break header_end;
+        }
+        stream.consume(read);
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Ok(Some(Err(431)));
+        }
+    };
+    if header_end > MAX_HEADER_SIZE {
+        return Ok(Some(Err(431)));
+    }
+    Ok(Some(parse_head(&buffer[..header_end])))
+}
+
+fn parse_head(head: &[u8]) -> Result<Request, u16> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let request_line: Vec<&str> = lines.next().unwrap_or_default().split(' ').collect();
+    let [method, target, version] = request_line.as_slice() else {
+        return Err(400);
+    };
+    if method.is_empty() || !method.bytes().all(is_token) || target.is_empty() {
+        return Err(400);
+    }
+    match *version {
+        "HTTP/1.0" | "HTTP/1.1" => {}
+        other if is_version(other) => return Err(505),
+        _ => return Err(400),
+    }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        // Whitespace before the colon, or a line folded onto the previous
+        // one, would let proxies and this parser disagree on the headers.
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(400);
+        };
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(400);
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    let values = |wanted: &str| -> Vec<&str> {
+        headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    };
+    // Requests whose body length is ambiguous are refused outright, so one
+    // can't be smuggled inside another over a kept-alive connection.
+    let lengths = values("Content-Length");
+    if lengths
+        .iter()
+        .any(|length| length.is_empty() || !length.bytes().all(|byte| byte.is_ascii_digit()))
+        || lengths.windows(2).any(|pair| pair[0] != pair[1])
+    {
+        return Err(400);
+    }
+    // HTTP/1.1 requires a Host; two could name different sites.
+    match (values("Host").len(), *version) {
+        (0, "HTTP/1.1") | (2.., _) => return Err(400),
+        _ => {}
+    }
+    match values("Transfer-Encoding").as_slice() {
+        [] => {}
+        _ if *version == "HTTP/1.0" || !lengths.is_empty() => return Err(400),
+        [coding] if coding.eq_ignore_ascii_case("chunked") => {}
+        _ => return Err(501),
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        path: percent_decode(path),
+        query: query.to_string(),
+        version: version.to_string(),
+        headers,
+        body: Body::Owned(Vec::new()),
+        tls: None,
+        peer: None,
+    })
+}
+
+/// Reads the body of a request returned by `read_head`: `Content-Length`
+/// bytes, or a chunked body, which is then given a `Content-Length` instead.
+/// Bodies above `body_buffer_size` bytes are spooled to a temporary file.
+/// Returns 413 once a chunked body passes `limit` and 400 for malformed
+/// chunks; either way the rest of the body is left unread.
+pub async fn read_body<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    request: &mut Request,
+    body_buffer_size: u64,
+    limit: Option<u64>,
+) -> io::Result<Result<(), u16>> {
+    if request.header("Transfer-Encoding").is_none() {
+        let length = usize::try_from(request.content_length()).unwrap_or(usize::MAX);
+        request.body = spool::read_body(stream, length, body_buffer_size).await?;
+        return Ok(Ok(()));
+    }
+
+    let mut spool = Spool::new(body_buffer_size);
+    let mut chunk = vec![0u8; STREAM_BUFFER];
+    loop {
+        let Some(line) = read_line(stream).await? else {
+            return Ok(Err(400));
+        };
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Ok(Err(400));
+        }
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            return Ok(Err(413));
+        };
+        if size == 0 {
+            break;
+        }
+        if limit.is_some_and(|limit| spool.received().saturating_add(size) as u64 > limit) {
+            return Ok(Err(413));
+        }
+        let mut remaining = size;
+        while remaining > 0 {
+            let wanted = chunk.len().min(remaining);
+            let read = stream.read(&mut chunk[..wanted]).await?;
+            if read == 0 {
+                return Ok(Err(400));
+            }
+            spool.write(&chunk[..read]).await?;
+            remaining -= read;
+        }
+        if read_line(stream).await?.is_none_or(|rest| !rest.is_empty()) {
+            return Ok(Err(400));
+        }
+    }
+    // Trailer fields are read and dropped.
+    loop {
+        match read_line(stream).await? {
+            Some(line) if line.is_empty() => break,
+            Some(_) => {}
+            None => return Ok(Err(400)),
+        }
+    }
+
+    let body = spool.finish().await?;
+    request
+        .headers
+        .retain(|(key, _)| !key.eq_ignore_ascii_case("Transfer-Encoding"));
+    request
+        .headers
+        .push(("Content-Length".to_string(), body.len().to_string()));
+    request.body = body;
+    Ok(Ok(()))
+}
+
+/// Reads a line of a chunked body, without its CRLF. `None` when the line
+/// is cut short or longer than `MAX_LINE`.
+async fn read_line<S: AsyncBufRead + Unpin>(stream: &mut S) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    (&mut *stream)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    Ok(line
+        .strip_suffix(b"\r\n")
+        .map(|line| String::from_utf8_lossy(line).into_owned()))
+}
+
+/// Whether `version` is well-formed, as `HTTP/` and two single digits.
+fn is_version(version: &str) -> bool {
+    matches!(
+        version.strip_prefix("HTTP/").map(str::as_bytes),
+        Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit()
+    )
+}
+
+/// Whether `byte` may appear in a method or header name.
+pub fn is_token(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// Sends `response`, with `Connection: close` unless it says otherwise.
+/// The status line always says HTTP/1.1, the version the server speaks;
+/// `version` is the client's, which decides how the body is framed. A
+/// streamed body is dropped as soon as the client resets the connection,
+/// even while it has nothing to send, or once writing to it fails.
+pub async fn send_response<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    version: &str,
+    response: &mut Response,
+) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    if response.header("Connection").is_none() {
+        response.set_header("Connection", "close");
+    }
+    for (key, value) in &response.headers {
+        head.push_str(&format!("{key}: {value}\r\n"));
+    }
+    let Some(mut body) = response.stream.take() else {
+        // Already set for a response to `HEAD`; see `Response::strip_body`.
+        if has_content(response.status)
+            && response.header("Content-Length").is_none()
+            && response.header("Transfer-Encoding").is_none()
+        {
+            head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&response.body).await?;
+        return stream.flush().await;
+    };
+
+    let chunked = body.length.is_none() && version != "HTTP/1.0";
+    match body.length {
+        Some(length) => head.push_str(&format!("Content-Length: {length}\r\n")),
+        None if chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+        None => {}
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut remaining = body.length.unwrap_or(u64::MAX);
+    let mut chunk = vec![0u8; STREAM_BUFFER];
+    while remaining > 0 {
+        let wanted = chunk
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = tokio::select! {
+            read = body.reader.read(&mut chunk[..wanted]) => read?,
+            () = closed(stream) => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "client left"));
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        remaining -= read as u64;
+        if chunked {
+            stream.write_all(format!("{read:x}\r\n").as_bytes()).await?;
+            stream.write_all(&chunk[..read]).await?;
+            stream.write_all(b"\r\n").await?;
+        } else {
+            stream.write_all(&chunk[..read]).await?;
+        }
+        // Flushed chunk by chunk, so event streams arrive as they happen.
+        stream.flush().await?;
+    }
+    if chunked {
+        stream.write_all(b"0\r\n\r\n").await?;
+    }
+    stream.flush().await
+}
+
+/// Splits a query string into its `key=value` pairs.
+pub fn parse_query(query: &str) -> Vec<(&str, &str)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .collect()
+}
+
+/// Splits an `application/x-www-form-urlencoded` string, as sent in query
+/// strings and form bodies, into its decoded `key=value` pairs.
+pub fn parse_form(input: &str) -> Vec<(String, String)> {
+    parse_query(input)
+        .into_iter()
+        .map(|(key, value)| (form_decode(key), form_decode(value)))
+        .collect()
+}
+
+/// Decodes one name or value of a form: `+` stands for a space, then
+/// `%XX` escapes.
+pub fn form_decode(input: &str) -> String {
+    percent_decode(&input.replace('+', " "))
+}
+
+/// Decodes `%XX` escapes. Invalid escapes are kept as they are.
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Percent-encodes everything in a path but unreserved characters and `/`.
+pub fn percent_encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn head(input: &str) -> Result<Request, u16> {
+        read_head(&mut input.as_bytes()).await.unwrap().unwrap()
+    }
+
+    /// Reads the request in `input` with its body, returning it and what
+    /// is left of `input`.
+    async fn read(input: &str, limit: Option<u64>) -> (Result<Request, u16>, String) {
+        let mut stream = input.as_bytes();
+        let mut request = read_head(&mut stream).await.unwrap().unwrap().unwrap();
+        let read = read_body(&mut stream, &mut request, 1024, limit)
+            .await
+            .unwrap();
+        let rest = String::from_utf8_lossy(stream).into_owned();
+        (read.map(|()| request), rest)
+    }
+
+    #[tokio::test]
+    async fn parses_heads() {
+        let mut stream =
+            "GET /a%20b?x=1 HTTP/1.1\r\nHost: example.com\r\nX-A:  v \r\n\r\nGET".as_bytes();
+        let request = read_head(&mut stream).await.unwrap().unwrap().ok().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target, "/a%20b?x=1");
+        assert_eq!(
+            (request.path.as_str(), request.query.as_str()),
+            ("/a b", "x=1")
+        );
+        assert_eq!(request.header("x-a"), Some("v"));
+        assert!(request.keep_alive());
+        // A pipelined request stays for the next call.
+        assert_eq!(stream, b"GET");
+
+        let request = head("GET / HTTP/1.0\r\n\r\n").await.ok().unwrap();
+        assert!(!request.keep_alive());
+        assert!(read_head(&mut "".as_bytes()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_heads() {
+        for (head_text, status) in [
+            ("GET /\r\n\r\n", 400),
+            ("GET  / HTTP/1.1\r\nHost: a\r\n\r\n", 400),
+            ("G(T / HTTP/1.1\r\nHost: a\r\n\r\n", 400),
+            ("GET / HTTP/2.0\r\nHost: a\r\n\r\n", 505),
+            ("GET / HTTP/one\r\nHost: a\r\n\r\n", 400),
+            ("GET / HTTP/1.1\r\n\r\n", 400),
+            ("GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n", 400),
+            ("GET / HTTP/1.1\r\nHost : a\r\n\r\n", 400),
+            ("GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n", 400),
+        ] {
+            assert_eq!(head(head_text).await.err(), Some(status), "{head_text:?}");
+        }
+        let long = format!(
+            "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_HEADER_SIZE)
+        );
+        assert_eq!(head(&long).await.err(), Some(431));
+    }
+
+    #[tokio::test]
+    async fn refuses_ambiguous_lengths() {
+        for (headers, status) in [
+            ("Content-Length: 5\r\nTransfer-Encoding: chunked", 400),
+            ("Content-Length: 5\r\nContent-Length: 6", 400),
+            ("Content-Length: +5", 400),
+            ("Content-Length: ", 400),
+            ("Transfer-Encoding: gzip", 501),
+            ("Transfer-Encoding: gzip, chunked", 501),
+        ] {
+            let text = format!("POST / HTTP/1.1\r\nHost: a\r\n{headers}\r\n\r\n");
+            assert_eq!(head(&text).await.err(), Some(status), "{headers:?}");
+        }
+        let text = "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(head(text).await.err(), Some(400));
+        let text = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n";
+        assert!(head(text).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reads_bodies() {
+        let text = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhelloGET";
+        let (request, rest) = read(text, None).await;
+        assert_eq!(&*request.ok().unwrap().body, b"hello");
+        assert_eq!(rest, "GET");
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_bodies() {
+        let text = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\nGET";
+        let (request, rest) = read(text, None).await;
+        let request = request.ok().unwrap();
+        assert_eq!(&*request.body, b"hello world");
+        assert_eq!(request.header("Content-Length"), Some("11"));
+        assert_eq!(request.header("Transfer-Encoding"), None);
+        assert_eq!(rest, "GET");
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_chunks() {
+        for (chunks, limit, status) in [
+            ("zz\r\nhello\r\n0\r\n\r\n", None, 400),
+            ("\r\n", None, 400),
+            ("5\r\nhelloX\r\n0\r\n\r\n", None, 400),
+            ("5\r\nhel", None, 400),
+            ("5\r\nhello\r\n0\r\n", None, 400),
+            ("5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", Some(10), 413),
+            ("ffffffffffffffffffff\r\n", None, 413),
+        ] {
+            let text =
+                format!("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{chunks}");
+            assert_eq!(read(&text, limit).await.0.err(), Some(status), "{chunks:?}");
+        }
+    }
+
+    struct Reset;
+
+    impl AsyncRead for Reset {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn only_failures_are_departures() {
+        let wait = std::time::Duration::from_millis(50);
+        // A half-closed client, and one that sent its next request.
+        for input in ["", "GET / HTTP/1.1\r\n"] {
+            let mut stream = input.as_bytes();
+            assert!(tokio::time::timeout(wait, closed(&mut stream))
+                .await
+                .is_err());
+            assert_eq!(stream, input.as_bytes());
+        }
+        let mut reset = tokio::io::BufReader::new(Reset);
+        assert!(tokio::time::timeout(wait, closed(&mut reset)).await.is_ok());
+    }
+
+    async fn sent(mut response: Response) -> String {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let mut server = tokio::io::BufReader::new(server);
+        send_response(&mut server, "HTTP/1.1", &mut response)
+            .await
+            .unwrap();
+        drop(server);
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        sent
+    }
+
+    #[tokio::test]
+    async fn frames_bodies_by_status() {
+        let sent_ok = sent(Response::new(200, "text/plain", "hi")).await;
+        assert!(sent_ok.contains("Content-Length: 2\r\n"), "{sent_ok}");
+        for status in [204, 304] {
+            let response = sent(Response::new(status, "text/plain", Vec::new())).await;
+            assert!(!response.contains("Content-Length"), "{response}");
+        }
+        let mut head = Response::new(304, "text/plain", "ignored");
+        head.strip_body("HTTP/1.1");
+        assert!(head.header("Content-Length").is_none() && head.body.is_empty());
+        let mut head = Response::new(200, "text/plain", "four");
+        head.strip_body("HTTP/1.1");
+        assert_eq!(head.header("Content-Length"), Some("4"));
+    }
+
+    #[test]
+    fn decodes_forms() {
+        assert_eq!(
+            parse_form("a=1+2&b=%26%3d&&c&d=%zz"),
+            [
+                ("a".to_string(), "1 2".to_string()),
+                ("b".to_string(), "&=".to_string()),
+                ("c".to_string(), String::new()),
+                ("d".to_string(), "%zz".to_string()),
+            ]
+        );
+        assert_eq!(percent_decode("%41%4"), "A%4");
+        assert_eq!(percent_encode_path("/a b/#?%é"), "/a%20b/%23%3F%25%C3%A9");
+    }
+
+    #[test]
+    fn weighs_accept_headers() {
+        let accept = "text/*;q=0.5, text/html, */*;q=0.1";
+        assert_eq!(accept_quality(accept, "text/html"), 1.0);
+        assert_eq!(accept_quality(accept, "text/plain"), 0.5);
+        assert_eq!(accept_quality(accept, "image/png"), 0.1);
+        assert_eq!(accept_quality("text/html", "image/png"), 0.0);
+    }
+}
@@ -0,0 +1,65 @@
+//! Decoding of gzip-compressed request bodies (`Content-Encoding: gzip`).
+//!
+//! Bodies are inflated before routing, so scripts and uploads see the
+//! decoded content. Inflating stops at `--max-inflated-size` to defuse
+//! compression bombs.
+
+use std::io::Read;
+
+use flate2::read::MultiGzDecoder;
+
+use crate::digest;
+use crate::http::Request;
+
+/// Default `--max-inflated-size`.
+pub const DEFAULT_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Replaces a gzip-encoded body by its decoded content. Returns the error
+/// status to answer with: `413` past `limit`, `415` for other codings and
+/// `400` for corrupt data or a `Content-Digest` that does not match the
+/// encoded body.
+pub fn decode_body(request: &mut Request, limit: u64) -> Result<(), u16> {
+    let Some(encoding) = request.header("Content-Encoding") else {
+        return Ok(());
+    };
+    let codings: Vec<String> = encoding
+        .split(',')
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .collect();
+    match codings.as_slice() {
+        [] => {}
+        [coding] if coding == "gzip" || coding == "x-gzip" => {}
+        _ => return Err(415),
+    }
+
+    if !codings.is_empty() {
+        // Content-Digest covers the body as sent, so it is checked here;
+        // Repr-Digest covers the decoded representation and is left as is.
+        if let Some(field) = request.header("Content-Digest") {
+            if !digest::verify(field, &request.body) {
+                return Err(400);
+            }
+        }
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&request.body[..])
+            .take(limit + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|_| 400u16)?;
+        if decoded.len() as u64 > limit {
+            return Err(413);
+        }
+        request.body = decoded.into();
+    }
+
+    let length = request.body.len().to_string();
+    request.headers.retain(|(key, _)| {
+        !key.eq_ignore_ascii_case("Content-Encoding") && !key.eq_ignore_ascii_case("Content-Digest")
+    });
+    for (key, value) in &mut request.headers {
+        if key.eq_ignore_ascii_case("Content-Length") {
+            *value = length.clone();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! Key-value store shared by scripts, enabled with `--kv-store`.
+//!
+//! Scripts receive `KV_URL` (`http://127.0.0.1:PORT/_kv/`) and `KV_TOKEN`
+//! and talk to the store over loopback HTTP; requests from other addresses
+//! or without `Authorization: Bearer $KV_TOKEN` answer 404.
+//!
+//! | Method | Path         |                                                  |
+//! |--------|--------------|--------------------------------------------------|
+//! | GET    | `/_kv/KEY`   | the value, or 404                                |
+//! | PUT    | `/_kv/KEY`   | store the body                                   |
+//! | DELETE | `/_kv/KEY`   | remove the key                                   |
+//! | POST   | `/_kv/KEY`   | add the body (default 1) to a numeric value and return it |
+//!
+//! With `--kv-file`, the store is loaded from and saved to a JSON object.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::sync::Mutex;
+
+use crate::admin;
+use crate::http::{Request, Response};
+
+pub const PREFIX: &str = "/_kv/";
+
+pub struct KvStore {
+    entries: Mutex<HashMap<String, String>>,
+    file: Option<PathBuf>,
+    url: String,
+    token: String,
+}
+
+impl KvStore {
+    /// Creates a store served on `port`, loading `file` when it exists.
+    pub fn open(port: u16, file: Option<PathBuf>) -> io::Result<KvStore> {
+        let entries = match &file {
+            Some(path) => match std::fs::read(path) {
+                Ok(data) => serde_json::from_slice(&data).map_err(|err| {
+                    io::Error::other(format!("invalid key-value file {}: {err}", path.display()))
+                })?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                Err(err) => return Err(err),
+            },
+            None => HashMap::new(),
+        };
+        let mut token = [0u8; 24];
+        SystemRandom::new()
+            .fill(&mut token)
+            .map_err(|_| io::Error::other("system random number generator failed"))?;
+        Ok(KvStore {
+            entries: Mutex::new(entries),
+            file,
+            url: format!("http://127.0.0.1:{port}{PREFIX}"),
+            token: URL_SAFE_NO_PAD.encode(token),
+        })
+    }
+
+    /// Environment variables that let a script reach the store.
+    pub fn script_env(&self) -> [(&str, &str); 2] {
+        [("KV_URL", &self.url), ("KV_TOKEN", &self.token)]
+    }
+
+    pub async fn handle(&self, request: &Request, peer: IpAddr) -> Response {
+        let key = request.path.strip_prefix(PREFIX).unwrap_or_default();
+        if !peer.is_loopback() || !admin::is_authorized(request, &self.token) || key.is_empty() {
+            return Response::error(404);
+        }
+
+        let mut entries = self.entries.lock().await;
+        let response = match request.method.as_str() {
+            "GET" => {
+                return match entries.get(key) {
+                    Some(value) => Response::new(200, "text/plain; charset=utf-8", value.clone()),
+                    None => Response::error(404),
+                };
+            }
+            "PUT" => match String::from_utf8(request.body.to_vec()) {
+                Ok(value) => {
+                    entries.insert(key.to_string(), value);
+                    Response::new(204, "text/plain; charset=utf-8", Vec::new())
+                }
+                Err(_) => return Response::error(400),
+            },
+            "DELETE" => match entries.remove(key) {
+                Some(_) => Response::new(204, "text/plain; charset=utf-8", Vec::new()),
+                None => return Response::error(404),
+            },
+            "POST" => {
+                let body = String::from_utf8_lossy(&request.body);
+                let delta = match body.trim() {
+                    "" => 1,
+                    delta => match delta.parse::<i64>() {
+                        Ok(delta) => delta,
+                        Err(_) => return Response::error(400),
+                    },
+                };
+                let current = match entries.get(key).map(|value| value.parse::<i64>()) {
+                    Some(Ok(current)) => current,
+                    Some(Err(_)) => return Response::error(409),
+                    None => 0,
+                };
+                let value = current.saturating_add(delta).to_string();
+                entries.insert(key.to_string(), value.clone());
+                Response::new(200, "text/plain; charset=utf-8", value)
+            }
+            _ => return Response::error(405),
+        };
+
+        if let Some(path) = &self.file {
+            if let Err(err) = save(path, &entries).await {
+                eprintln!("cannot save {}: {err}", path.display());
+                return Response::error(500);
+            }
+        }
+        response
+    }
+}
+
+/// Writes the store next to `path` and renames it into place, so a crash
+/// never leaves a truncated file.
+async fn save(path: &Path, entries: &HashMap<String, String>) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, serde_json::to_vec(entries)?).await?;
+    tokio::fs::rename(&temporary, path).await
+}
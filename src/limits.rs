@@ -0,0 +1,61 @@
+//! Cap on the number of connections a single client may hold open at once.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::cidr::{self, Cidr};
+
+pub struct ConnectionLimiter {
+    /// Connections allowed per client; 0 disables the cap.
+    max: usize,
+    exempt: Vec<Cidr>,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// An open connection counted against its client, released when dropped.
+pub struct ConnectionGuard {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max: usize, exempt: Vec<Cidr>) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max,
+            exempt,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Counts a new connection from `ip`, or returns `Err` if the client
+    /// already has the maximum open.
+    pub fn acquire(&self, ip: IpAddr) -> Result<Option<ConnectionGuard>, ()> {
+        let ip = cidr::canonical(ip);
+        if self.max == 0 || self.exempt.iter().any(|network| network.contains(ip)) {
+            return Ok(None);
+        }
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return Err(());
+        }
+        *count += 1;
+        Ok(Some(ConnectionGuard {
+            ip,
+            open: self.open.clone(),
+        }))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Live reload for `--dev`: served HTML pages get a small script that
+//! listens on an event stream and reloads the page whenever a file under
+//! the root folder changes.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::http::Response;
+
+/// Path of the event stream the injected script connects to.
+pub const PATH: &str = "/__livereload";
+
+/// Time to wait for more changes before telling clients to reload, so that
+/// saving several files at once causes a single reload.
+const SETTLE: Duration = Duration::from_millis(100);
+
+const PING: Duration = Duration::from_secs(30);
+
+const SCRIPT: &str =
+    "new EventSource(\"/__livereload\").addEventListener(\"reload\", () => location.reload());";
+
+/// Adds the live reload script to an HTML response, before `</body>` if present.
+pub fn inject(response: &mut Response) {
+    let is_html = response
+        .header("Content-type")
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    // Streamed bodies go out as they are produced.
+    if !is_html || response.stream.is_some() {
+        return;
+    }
+
+    let nonce = response
+        .header("Content-Security-Policy")
+        .and_then(|policy| policy.split("'nonce-").nth(1))
+        .and_then(|rest| rest.split('\'').next());
+    let tag = match nonce {
+        Some(nonce) => format!("<script nonce=\"{nonce}\">{SCRIPT}</script>"),
+        None => format!("<script>{SCRIPT}</script>"),
+    };
+
+    let lowercase = response.body.to_ascii_lowercase();
+    let position = lowercase
+        .windows(7)
+        .rposition(|window| window == b"</body>")
+        .unwrap_or(response.body.len());
+    response
+        .body
+        .to_mut()
+        .splice(position..position, tag.into_bytes());
+}
+
+/// An event stream that sends a `reload` event after every change, until
+/// the client disconnects.
+pub fn events(mut changes: broadcast::Receiver<PathBuf>) -> Response {
+    let mut response = Response::stream(200, "text/event-stream", None, |mut writer| async move {
+        loop {
+            match tokio::time::timeout(PING, changes.recv()).await {
+                Ok(Ok(_) | Err(RecvError::Lagged(_))) => {
+                    tokio::time::sleep(SETTLE).await;
+                    changes = changes.resubscribe();
+                    writer
+                        .write_all(b"event: reload\ndata: changed\n\n")
+                        .await?;
+                }
+                Ok(Err(RecvError::Closed)) => return Ok(()),
+                Err(_) => writer.write_all(b": ping\n\n").await?,
+            }
+        }
+    });
+    response.set_header("Cache-Control", "no-store");
+    response
+}
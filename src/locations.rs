@@ -0,0 +1,77 @@
+//! Per-path settings loaded from the `[[location]]` tables of the config file.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::access::AccessRule;
+use crate::auth::AuthRequest;
+use crate::handlers::Handler;
+use crate::overload::Priority;
+use crate::upstream::Upstream;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Location {
+    /// URL prefix the location applies to, matched on whole path segments.
+    pub path: String,
+    #[serde(default)]
+    pub allow: Vec<AccessRule>,
+    #[serde(default)]
+    pub deny: Vec<AccessRule>,
+    /// Requires requests to carry a valid `expires`/`sig` pair signed with this key.
+    pub signing_key: Option<String>,
+    /// Injects a per-response nonce into HTML script/style tags and sends a matching CSP.
+    #[serde(default)]
+    pub csp_nonce: bool,
+    /// Overrides `--autoindex` for this location.
+    pub autoindex: Option<bool>,
+    /// Upstream receiving a copy of every request, whose responses are discarded.
+    pub mirror: Option<Upstream>,
+    /// Overrides `--compress` for this location.
+    pub compress: Option<bool>,
+    /// Accepts `PUT` uploads below this location.
+    #[serde(default)]
+    pub writable: bool,
+    /// Largest file, in bytes, an upload may leave behind.
+    pub max_upload_size: Option<u64>,
+    /// Bytes the files below this location may use in total.
+    pub quota: Option<u64>,
+    /// Endpoint asked whether each request may proceed.
+    pub auth_request: Option<AuthRequest>,
+    /// Headers copied from a successful auth answer onto the request.
+    #[serde(default)]
+    pub auth_headers: Vec<String>,
+    /// Handlers by file extension, `*` standing for any other.
+    #[serde(default)]
+    pub handlers: BTreeMap<String, Handler>,
+    /// Overrides `--max-body-size` for this location.
+    pub max_body_size: Option<u64>,
+    /// Overrides `--script-timeout` for this location, in seconds.
+    pub script_timeout: Option<u64>,
+    /// Overrides `--script-cache` for this location, in seconds; 0 keeps
+    /// nothing.
+    pub script_cache: Option<u64>,
+    /// Overrides `--write-timeout` for this location, in seconds.
+    pub write_timeout: Option<u64>,
+    /// Class for `--max-active` queueing: `high`, `normal` or `low`.
+    pub priority: Option<Priority>,
+}
+
+impl Location {
+    fn matches(&self, path: &str) -> bool {
+        let prefix = self.path.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// Returns the location with the longest prefix matching `path`.
+pub fn find<'a>(locations: &'a [Location], path: &str) -> Option<&'a Location> {
+    locations
+        .iter()
+        .filter(|location| location.matches(path))
+        .max_by_key(|location| location.path.trim_end_matches('/').len())
+}
@@ -0,0 +1,131 @@
+//! Request handlers written in Lua, run inside the server process with
+//! `--lua-handlers DIR` on builds with the `lua` feature.
+//!
+//! `/lua/NAME` runs `DIR/NAME.lua`; locations can also run `.lua` files
+//! from the root folder with a `lua` handler. The chunk sees a `request` table
+//! (`method`, `path`, `query`, `headers` with lowercase names, `body`) and
+//! a `response` table: it may set `response.status` and
+//! `response.headers[name]`, to a string or a list of them for a repeated
+//! header, and `response.write(...)` appends to the body.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use mlua::{Either, HookTriggers, Lua, LuaString, Table, VmState};
+
+use crate::http::{parse_form, Request, Response};
+use crate::resolve::{resolve, Resolved};
+use crate::scripts;
+
+pub const PREFIX: &str = "/lua/";
+
+/// How long a handler may run before it is aborted.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Defines `response.write` in the handler's environment.
+const PRELUDE: &str = "
+local body = {}
+response.body = body
+function response.write(...)
+    for i = 1, select('#', ...) do
+        body[#body + 1] = tostring((select(i, ...)))
+    end
+end
+";
+
+/// Runs the handler `name` from `dir` for `request`.
+pub async fn handle(dir: &Path, name: &str, request: &Request) -> Response {
+    let script = match resolve(dir, &format!("/{name}.lua")).await {
+        Ok(Resolved {
+            path,
+            is_dir: false,
+            ..
+        }) => path,
+        Ok(_) => return Response::error(404),
+        Err(err) => return Response::error(err.status()),
+    };
+    run_file(&script, request).await
+}
+
+/// Runs the Lua file at the canonical path `script` for `request`.
+pub async fn run_file(script: &Path, request: &Request) -> Response {
+    let source = match tokio::fs::read(script).await {
+        Ok(source) => source,
+        Err(_) => return Response::error(500),
+    };
+
+    let chunk = script.display().to_string();
+    let request = request.clone();
+    let run = tokio::task::spawn_blocking(move || {
+        // An interpreter of its own, so that nothing the handler changes,
+        // globals or the standard library, outlives its request.
+        run(&Lua::new(), &chunk, &source, &request).map_err(|err| format!("{chunk}: {err}"))
+    });
+    match run.await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => {
+            eprintln!("{err}");
+            Response::error(500)
+        }
+        Err(_) => Response::error(500),
+    }
+}
+
+fn run(lua: &Lua, name: &str, source: &[u8], request: &Request) -> mlua::Result<Response> {
+    let deadline = Instant::now() + TIMEOUT;
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(10_000),
+        move |_, _| match Instant::now() < deadline {
+            true => Ok(VmState::Continue),
+            false => Err(mlua::Error::runtime("handler timed out")),
+        },
+    )?;
+
+    let env = lua.globals();
+
+    let query = lua.create_table()?;
+    for (key, value) in parse_form(&request.query) {
+        query.set(key, value)?;
+    }
+    let headers = lua.create_table()?;
+    for (key, value) in &request.headers {
+        headers.set(key.to_ascii_lowercase(), value.as_str())?;
+    }
+    let input = lua.create_table()?;
+    input.set("method", request.method.as_str())?;
+    input.set("path", request.path.as_str())?;
+    input.set("query", query)?;
+    input.set("headers", headers)?;
+    input.set("body", lua.create_string(&*request.body)?)?;
+    env.set("request", input)?;
+
+    let output = lua.create_table()?;
+    output.set("status", 200)?;
+    output.set("headers", lua.create_table()?)?;
+    env.set("response", output.clone())?;
+
+    lua.load(PRELUDE).exec()?;
+    lua.load(source).set_name(name).exec()?;
+
+    let mut body = Vec::new();
+    for part in output.get::<Table>("body")?.sequence_values::<LuaString>() {
+        body.extend_from_slice(&part?.as_bytes());
+    }
+    let mut response = Response::new(output.get("status")?, "text/plain; charset=utf-8", body);
+    let headers = output.get::<Table>("headers")?;
+    for pair in headers.pairs::<String, Either<Vec<String>, String>>() {
+        let (key, values) = pair?;
+        if scripts::is_framing(&key) {
+            continue;
+        }
+        match values {
+            Either::Left(values) => {
+                for value in values {
+                    scripts::add_header(&mut response, &key, value);
+                }
+            }
+            Either::Right(value) => scripts::add_header(&mut response, &key, value),
+        }
+    }
+    Ok(response)
+}
@@ -1,3 +1,112 @@
-fn main() {
-    println!("Hello, world!");
+mod access;
+mod acme;
+mod admin;
+mod anonymize;
+mod assets;
+mod auth;
+mod bandwidth;
+mod bans;
+mod brotli;
+mod cache;
+mod canonical;
+mod capture;
+mod cgi;
+mod cidr;
+mod coalesce;
+mod compress;
+mod conditional;
+mod config;
+mod crawl;
+#[cfg(unix)]
+mod credentials;
+mod csp;
+mod date;
+mod der;
+mod dev;
+mod digest;
+mod fastcgi;
+mod fdlimit;
+mod files;
+mod forwarded;
+mod geoip;
+mod glob;
+mod h2;
+mod handlers;
+mod headers;
+mod hpack;
+mod http;
+mod inflate;
+mod kv;
+mod limits;
+mod livereload;
+mod locations;
+#[cfg(feature = "lua")]
+mod lua;
+mod maintenance;
+mod mirror;
+mod multipart;
+mod openfiles;
+mod overload;
+mod panics;
+mod proxy;
+mod proxy_protocol;
+mod purge;
+mod range;
+mod redirect;
+mod redirect_map;
+mod replay;
+mod resolve;
+mod scripts;
+mod search;
+mod selftest;
+mod server;
+mod signed;
+mod spool;
+mod thumbs;
+mod timing;
+mod tls;
+mod upload;
+mod upstream;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod userdir;
+mod vhost;
+mod watch;
+mod websocket;
+mod wellknown;
+mod workers;
+
+use std::process::exit;
+
+use config::{Config, USAGE};
+
+#[tokio::main]
+async fn main() {
+    let subcommand = match std::env::args().nth(1).as_deref() {
+        Some("replay") => Some(replay::run(std::env::args().skip(2)).await),
+        Some("selftest") => Some(selftest::run(std::env::args().skip(2)).await),
+        _ => None,
+    };
+    match subcommand {
+        Some(Ok(true)) => return,
+        Some(Ok(false)) => exit(1),
+        Some(Err(err)) => {
+            eprintln!("{err}");
+            exit(2);
+        }
+        None => {}
+    }
+
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            exit(1);
+        }
+    };
+
+    if let Err(err) = server::run(config).await {
+        eprintln!("{err}");
+        exit(1);
+    }
 }
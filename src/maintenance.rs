@@ -0,0 +1,52 @@
+//! Maintenance mode: every request except health checks and the admin API
+//! is answered with 503 and a `Retry-After` header.
+//!
+//! It is active while switched on through the admin API or while the
+//! configured flag file exists, so deploy scripts can simply `touch` it.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::http::Response;
+
+pub struct Maintenance {
+    enabled: AtomicBool,
+    flag: Option<PathBuf>,
+    page: Option<Vec<u8>>,
+    retry_after: u64,
+}
+
+impl Maintenance {
+    pub fn new(flag: Option<PathBuf>, page: Option<Vec<u8>>, retry_after: u64) -> Maintenance {
+        Maintenance {
+            enabled: AtomicBool::new(false),
+            flag,
+            page,
+            retry_after,
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub async fn is_active(&self) -> bool {
+        if self.enabled.load(Ordering::Relaxed) {
+            return true;
+        }
+        match &self.flag {
+            Some(flag) => tokio::fs::try_exists(flag).await.unwrap_or(false),
+            None => false,
+        }
+    }
+
+    pub fn response(&self) -> Response {
+        let mut response = match &self.page {
+            Some(page) => Response::new(503, "text/html; charset=utf-8", page.clone()),
+            None => Response::error(503),
+        };
+        response.set_header("Retry-After", self.retry_after.to_string());
+        response.set_header("Cache-Control", "no-store");
+        response
+    }
+}
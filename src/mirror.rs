@@ -0,0 +1,55 @@
+//! Traffic mirroring: a location with `mirror = "http://host:port"` sends a
+//! copy of every request it receives to that upstream. Mirrored requests run
+//! in the background and their responses are discarded, so a slow or broken
+//! mirror never affects the client.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::http::Request;
+use crate::upstream::{is_hop_by_hop, Upstream};
+
+/// Time a mirrored request may take before it is abandoned.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn send(upstream: &Upstream, request: &Request) {
+    let mut head = format!(
+        "{} {}{} HTTP/1.1\r\nHost: {}\r\n",
+        request.method,
+        upstream.path,
+        request.target,
+        upstream.authority()
+    );
+    for (name, value) in &request.headers {
+        let skipped = ["Host", "Content-Length"]
+            .iter()
+            .any(|skipped| skipped.eq_ignore_ascii_case(name));
+        if !skipped && !is_hop_by_hop(name) {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        request.body.len()
+    ));
+    let mut message = head.into_bytes();
+    message.extend_from_slice(&request.body);
+
+    let upstream = upstream.clone();
+    tokio::spawn(async move {
+        let result = tokio::time::timeout(TIMEOUT, async {
+            let mut stream = upstream.connect().await?;
+            stream.write_all(&message).await?;
+            let mut discard = [0u8; 4096];
+            while stream.read(&mut discard).await? > 0 {}
+            Ok::<_, std::io::Error>(())
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!("mirror {} failed: {err}", upstream.authority()),
+            Err(_) => eprintln!("mirror {} timed out", upstream.authority()),
+        }
+    });
+}
@@ -0,0 +1,243 @@
+//! `multipart/form-data` bodies (RFC 7578), as HTML forms with file inputs
+//! send them to scripts. Fields become `Query_<name>` like urlencoded ones;
+//! each uploaded file is written to a temporary file, which is removed
+//! once the script is done.
+
+use std::path::PathBuf;
+
+use tokio::io::AsyncWriteExt;
+
+use crate::spool;
+
+/// Parts accepted in one body.
+const MAX_PARTS: usize = 1000;
+
+/// A parsed form. Dropping it removes the uploaded files.
+#[derive(Default)]
+pub struct Form {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<Upload>,
+}
+
+/// A file part, stored at `path`.
+pub struct Upload {
+    pub name: String,
+    /// The name the client gave the file, without any folders.
+    pub filename: String,
+    pub content_type: String,
+    pub path: PathBuf,
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The boundary of a `multipart/form-data` content type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let essence = content_type.split(';').next()?.trim();
+    if !essence.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    param(content_type, "boundary").filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// Splits `body` into fields and files. `Err(None)` for a malformed body,
+/// `Err(Some(err))` when a file can't be stored.
+pub async fn parse(body: &[u8], boundary: &str) -> Result<Form, Option<std::io::Error>> {
+    let delimiter = format!("--{boundary}");
+    let mut form = Form::default();
+    let Some(start) = find(body, delimiter.as_bytes(), 0) else {
+        return Err(None);
+    };
+    let mut position = start + delimiter.len();
+    let mut parts = 0;
+    loop {
+        let rest = &body[position..];
+        if rest.starts_with(b"--") {
+            return Ok(form);
+        }
+        if !rest.starts_with(b"\r\n") || parts == MAX_PARTS {
+            return Err(None);
+        }
+        parts += 1;
+        let headers_start = position + 2;
+        let Some(headers_end) = find(body, b"\r\n\r\n", headers_start) else {
+            return Err(None);
+        };
+        let content_start = headers_end + 4;
+        let close = format!("\r\n{delimiter}");
+        let Some(content_end) = find(body, close.as_bytes(), content_start) else {
+            return Err(None);
+        };
+        let headers = String::from_utf8_lossy(&body[headers_start..headers_end]);
+        let content = &body[content_start..content_end];
+        position = content_end + close.len();
+
+        let mut disposition = None;
+        let mut content_type = "text/plain".to_string();
+        for line in headers.split("\r\n") {
+            let Some((key, value)) = line.split_once(':') else {
+                return Err(None);
+            };
+            match key.trim().to_ascii_lowercase().as_str() {
+                "content-disposition" => disposition = Some(value.trim().to_string()),
+                "content-type" => content_type = value.trim().to_string(),
+                _ => {}
+            }
+        }
+        let Some(disposition) = disposition else {
+            return Err(None);
+        };
+        let Some(name) = param(&disposition, "name") else {
+            return Err(None);
+        };
+        let Some(filename) = param(&disposition, "filename") else {
+            form.fields
+                .push((name, String::from_utf8_lossy(content).into_owned()));
+            continue;
+        };
+        // Browsers send only the base name, but older ones sent whole paths.
+        let filename = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+        let path = spool::temp_path("upload");
+        let mut file = spool::private_file().open(&path).await.map_err(Some)?;
+        // Pushed before writing, so a failed write is cleaned up with the form.
+        form.files.push(Upload {
+            name,
+            filename: filename.to_string(),
+            content_type,
+            path,
+        });
+        file.write_all(content).await.map_err(Some)?;
+        file.flush().await.map_err(Some)?;
+    }
+}
+
+/// The value of `name` among the `; key=value` parameters of a header
+/// value, such as `form-data; name="a"; filename="b;c.txt"`.
+fn param(value: &str, name: &str) -> Option<String> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut end = quoted.len();
+                let mut chars = quoted.char_indices();
+                while let Some((index, c)) = chars.next() {
+                    match c {
+                        '"' => {
+                            end = index + 1;
+                            break;
+                        }
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let unquoted = after.split(';').next().unwrap_or_default();
+                (unquoted.trim().to_string(), &after[unquoted.len()..])
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = next.split_once(';')?.1;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|offset| from + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_boundaries() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=----abc").as_deref(),
+            Some("----abc")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b\"").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=abc"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        let long = format!("multipart/form-data; boundary={}", "a".repeat(71));
+        assert_eq!(boundary(&long), None);
+    }
+
+    #[test]
+    fn reads_parameters() {
+        let disposition = r#"form-data; name="a\"b"; filename="c;d.txt""#;
+        assert_eq!(param(disposition, "name").as_deref(), Some("a\"b"));
+        assert_eq!(param(disposition, "FILENAME").as_deref(), Some("c;d.txt"));
+        assert_eq!(
+            param("form-data; name=plain ; x=y", "name").as_deref(),
+            Some("plain")
+        );
+        assert_eq!(param("form-data; name=plain", "filename"), None);
+        assert_eq!(param("form-data", "name"), None);
+    }
+
+    #[tokio::test]
+    async fn parses_fields_and_files() {
+        let body = b"preamble\r\n--XX\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            line one\r\nline two\r\n--XX\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"C:\\\\dir\\\\a.bin\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            \0\x01--X\r\n--XX--\r\n";
+        let form = parse(body, "XX").await.ok().unwrap();
+        assert_eq!(
+            form.fields,
+            [("title".to_string(), "line one\r\nline two".to_string())]
+        );
+        let [upload] = form.files.as_slice() else {
+            panic!("one file expected");
+        };
+        assert_eq!(
+            (upload.name.as_str(), upload.filename.as_str()),
+            ("file", "a.bin")
+        );
+        assert_eq!(upload.content_type, "application/octet-stream");
+        assert_eq!(std::fs::read(&upload.path).unwrap(), b"\0\x01--X");
+
+        // The file goes with the form.
+        let path = upload.path.clone();
+        drop(form);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_bodies() {
+        for body in [
+            &b"no delimiter"[..],
+            b"--XX\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nunterminated",
+            b"--XX\r\nContent-Type: text/plain\r\n\r\nvalue\r\n--XX--",
+            b"--XX\r\nContent-Disposition: form-data\r\n\r\nvalue\r\n--XX--",
+            b"--XX\r\nnot a header\r\n\r\nvalue\r\n--XX--",
+            b"--XXjunk",
+        ] {
+            assert!(
+                matches!(parse(body, "XX").await, Err(None)),
+                "{}",
+                String::from_utf8_lossy(body)
+            );
+        }
+        let empty = parse(b"--XX--\r\n", "XX").await.ok().unwrap();
+        assert!(empty.fields.is_empty() && empty.files.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! Cache of open file handles, enabled with `--open-file-cache N`.
+//!
+//! Media players fetch the same large files over and over. Entries keep the
+//! resolved path, metadata and an open handle per request path, so repeated
+//! requests skip path resolution, `stat` and `open`. An entry is trusted for
+//! `--open-file-cache-valid` seconds before the file is resolved again, and
+//! is dropped immediately when the watcher or an upload reports a change.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::purge::Pattern;
+use crate::resolve::Resolved;
+
+#[derive(Clone)]
+pub struct OpenFile {
+    pub resolved: Resolved,
+    pub file: Arc<File>,
+}
+
+struct Entry {
+    open: OpenFile,
+    opened: Instant,
+    last_used: AtomicU64,
+}
+
+pub struct OpenFileCache {
+    /// Handles kept open; 0 disables the cache.
+    capacity: usize,
+    valid: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+    clock: AtomicU64,
+}
+
+impl OpenFileCache {
+    pub fn new(capacity: usize, valid: Duration) -> OpenFileCache {
+        OpenFileCache {
+            capacity,
+            valid,
+            entries: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Returns the still valid entry for a decoded request path, as given
+    /// by `Site::cache_key`.
+    pub fn get(&self, path: &str) -> Option<OpenFile> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(path)?;
+        if entry.opened.elapsed() > self.valid {
+            entries.remove(path);
+            return None;
+        }
+        entry.last_used.store(
+            self.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        Some(entry.open.clone())
+    }
+
+    /// Opens a resolved file and keeps its handle for `path`.
+    pub async fn open(&self, path: &str, resolved: &Resolved) -> io::Result<OpenFile> {
+        let file = crate::resolve::open(&resolved.path).await?.into_std().await;
+        let open = OpenFile {
+            resolved: resolved.clone(),
+            file: Arc::new(file),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(path) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            path.to_string(),
+            Entry {
+                open: open.clone(),
+                opened: Instant::now(),
+                last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+            },
+        );
+        Ok(open)
+    }
+
+    /// Drops every entry whose file is at or below the canonical `path`.
+    pub fn invalidate(&self, path: &Path) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| !entry.open.resolved.path.starts_with(path));
+    }
+
+    /// Drops the entries whose request path matches `pattern`.
+    pub fn purge(&self, pattern: &Pattern) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|key, _| !pattern.matches_key(key));
+        before - entries.len()
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Reads `len` bytes at `offset` without moving a shared file position.
+pub async fn read_at(file: Arc<File>, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if let Some(result) = crate::uring::read_at(file.clone(), offset, len).await {
+        return result;
+    }
+    tokio::task::spawn_blocking(move || {
+        let mut buffer = vec![0; len];
+        let mut filled = 0;
+        while filled < len {
+            #[cfg(unix)]
+            let read = std::os::unix::fs::FileExt::read_at(
+                &*file,
+                &mut buffer[filled..],
+                offset + filled as u64,
+            )?;
+            #[cfg(windows)]
+            let read = std::os::windows::fs::FileExt::seek_read(
+                &*file,
+                &mut buffer[filled..],
+                offset + filled as u64,
+            )?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        buffer.truncate(filled);
+        Ok(buffer)
+    })
+    .await?
+}
@@ -0,0 +1,146 @@
+//! Admission of requests under `--max-active`: at most that many requests
+//! are handled at once, and the rest wait in a queue of `--queue-size`
+//! ordered by priority, then arrival. Health checks and the admin API are
+//! never held back; `low` requests are answered 503 as soon as every slot
+//! is busy instead of waiting, and a full queue makes room for a request by
+//! dropping the newest one of a lower class.
+
+use std::cmp::Reverse;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+use tokio::sync::oneshot;
+
+/// How urgent a request is, set per location with `priority = "high"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+    /// Health checks and the admin API, which skip the queue.
+    #[serde(skip)]
+    Critical,
+}
+
+pub struct Scheduler {
+    /// Requests handled at once; 0 disables admission control.
+    max_active: usize,
+    queue_size: usize,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    queue: Vec<Waiter>,
+    arrivals: u64,
+}
+
+struct Waiter {
+    priority: Priority,
+    arrival: u64,
+    wake: oneshot::Sender<Slot>,
+}
+
+enum Admission {
+    Now(Slot),
+    Queued(oneshot::Receiver<Slot>),
+}
+
+/// A request being handled, counted against `--max-active` until dropped.
+/// Dropping it hands the slot to the best waiting request.
+pub struct Slot {
+    state: Option<Arc<Mutex<State>>>,
+}
+
+impl Scheduler {
+    pub fn new(max_active: usize, queue_size: usize) -> Scheduler {
+        Scheduler {
+            max_active,
+            queue_size,
+            state: Arc::default(),
+        }
+    }
+
+    /// Waits for a slot, or returns `Err` when the request is shed.
+    pub async fn admit(&self, priority: Priority) -> Result<Option<Slot>, ()> {
+        if self.max_active == 0 || priority == Priority::Critical {
+            return Ok(None);
+        }
+        match self.enqueue(priority)? {
+            Admission::Now(slot) => Ok(Some(slot)),
+            Admission::Queued(waiting) => waiting.await.map(Some).map_err(|_| ()),
+        }
+    }
+
+    fn enqueue(&self, priority: Priority) -> Result<Admission, ()> {
+        let mut state = self.state.lock().unwrap();
+        if state.active < self.max_active {
+            state.active += 1;
+            return Ok(Admission::Now(Slot {
+                state: Some(self.state.clone()),
+            }));
+        }
+        if priority == Priority::Low {
+            return Err(());
+        }
+        state.queue.retain(|waiter| !waiter.wake.is_closed());
+        if state.queue.len() >= self.queue_size {
+            // Dropping the evicted waiter's sender sheds it.
+            let newest_lowest = state
+                .queue
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, waiter)| (waiter.priority, Reverse(waiter.arrival)));
+            match newest_lowest {
+                Some((index, waiter)) if waiter.priority < priority => {
+                    state.queue.swap_remove(index);
+                }
+                _ => return Err(()),
+            }
+        }
+        let (wake, waiting) = oneshot::channel();
+        state.arrivals += 1;
+        let arrival = state.arrivals;
+        state.queue.push(Waiter {
+            priority,
+            arrival,
+            wake,
+        });
+        Ok(Admission::Queued(waiting))
+    }
+}
+
+impl State {
+    /// Removes the waiter to be admitted next.
+    fn next_waiter(&mut self) -> Option<Waiter> {
+        let index = self
+            .queue
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, waiter)| (waiter.priority, Reverse(waiter.arrival)))
+            .map(|(index, _)| index)?;
+        Some(self.queue.swap_remove(index))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let Some(shared) = self.state.take() else {
+            return;
+        };
+        let mut state = shared.lock().unwrap();
+        while let Some(waiter) = state.next_waiter() {
+            let slot = Slot {
+                state: Some(shared.clone()),
+            };
+            match waiter.wake.send(slot) {
+                Ok(()) => return,
+                // The waiter gave up; disarm the slot so it doesn't release twice.
+                Err(mut slot) => slot.state = None,
+            }
+        }
+        state.active -= 1;
+    }
+}
@@ -0,0 +1,496 @@
+//! Reverse proxying: `--proxy /api=http://127.0.0.1:9000` forwards requests
+//! below `/api` to that upstream and streams its answer back.
+//!
+//! The method, target, headers and body are passed on, with the upstream's
+//! path prepended to the target, `Host` naming the upstream, and
+//! `X-Forwarded-For`, `X-Forwarded-Host` and `X-Forwarded-Proto` describing
+//! the client. Upstream connections are not reused: every request opens
+//! its own and asks for it to be closed. A WebSocket handshake is passed on
+//! instead, and if the upstream switches protocols the client's connection
+//! is joined to it.
+//!
+//! A prefix may list several upstreams, which `--proxy-balance` picks from;
+//! one that fails is passed over for `FAIL_TIMEOUT`.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+};
+use tokio::net::TcpStream;
+
+use crate::http::{Relayed, Request, Response, STREAM_BUFFER};
+use crate::upstream::{is_hop_by_hop, Upstream};
+use crate::websocket::{self, Upgrade};
+
+/// Time the upstream may take to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time the upstream may take to answer with a complete head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest answer head read from an upstream.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// How long an upstream that failed is passed over.
+const FAIL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How `--proxy-balance` picks among the upstreams of a route.
+#[derive(Clone, Copy, Default)]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+impl std::str::FromStr for Balance {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Balance, ()> {
+        match value {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-connections" => Ok(Balance::LeastConnections),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct ProxyRoute {
+    /// Request path prefix, without a trailing `/`.
+    pub prefix: String,
+    pub backends: Vec<Arc<Backend>>,
+    /// Where the next round-robin pick starts.
+    next: AtomicUsize,
+}
+
+/// An upstream of a route, with what balancing needs to know about it.
+pub struct Backend {
+    pub upstream: Upstream,
+    /// Requests whose answer is still being relayed from it.
+    active: AtomicUsize,
+    /// When a connection to it or its answer last failed.
+    failed: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    fn is_down(&self) -> bool {
+        self.failed
+            .lock()
+            .unwrap()
+            .is_some_and(|failed| failed.elapsed() < FAIL_TIMEOUT)
+    }
+
+    fn fail(&self) {
+        *self.failed.lock().unwrap() = Some(Instant::now());
+    }
+}
+
+/// Counts a request as active on its backend until dropped with the body.
+struct Active(Arc<Backend>);
+
+impl Active {
+    fn new(backend: &Arc<Backend>) -> Active {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Active(backend.clone())
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl std::str::FromStr for ProxyRoute {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<ProxyRoute, String> {
+        let (prefix, urls) = value
+            .split_once('=')
+            .ok_or_else(|| format!("invalid proxy {value}, expected PREFIX=URL[,URL...]"))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("proxy prefix {prefix} must start with /"));
+        }
+        let mut backends = Vec::new();
+        for url in urls.split(',') {
+            backends.push(Arc::new(Backend {
+                upstream: url.trim().parse()?,
+                active: AtomicUsize::new(0),
+                failed: Mutex::new(None),
+            }));
+        }
+        Ok(ProxyRoute {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            backends,
+            next: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl ProxyRoute {
+    fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(&self.prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// The backends in the order to try them: those up, as `balance` ranks
+    /// them, then those that failed recently, the longest ago first.
+    fn candidates(&self, balance: Balance) -> Vec<Arc<Backend>> {
+        let count = self.backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        // Rotated even for least-connections, so ties are spread out.
+        let mut rotated: Vec<Arc<Backend>> = (0..count)
+            .map(|offset| self.backends[(start + offset) % count].clone())
+            .collect();
+        if let Balance::LeastConnections = balance {
+            rotated.sort_by_key(|backend| backend.active.load(Ordering::Relaxed));
+        }
+        let (up, mut down): (Vec<_>, Vec<_>) =
+            rotated.into_iter().partition(|backend| !backend.is_down());
+        down.sort_by_key(|backend| *backend.failed.lock().unwrap());
+        up.into_iter().chain(down).collect()
+    }
+}
+
+/// The route with the longest prefix covering `path`.
+pub fn find<'a>(routes: &'a [ProxyRoute], path: &str) -> Option<&'a ProxyRoute> {
+    routes
+        .iter()
+        .filter(|route| route.matches(path))
+        .max_by_key(|route| route.prefix.len())
+}
+
+/// Forwards `request` to one of the route's upstreams. Upstreams that
+/// refuse the connection are marked down and the next one is tried, since
+/// nothing was sent yet; once the request is out it is not sent again.
+/// Answers `502` when no upstream can be reached or the one that was sends
+/// no valid answer, and `504` when it is too slow.
+pub async fn forward(
+    route: &ProxyRoute,
+    balance: Balance,
+    request: &Request,
+    peer: SocketAddr,
+) -> Response {
+    let mut last_error = None;
+    for backend in route.candidates(balance) {
+        let active = Active::new(&backend);
+        let upstream = &backend.upstream;
+        let connected = tokio::time::timeout(CONNECT_TIMEOUT, upstream.connect())
+            .await
+            .unwrap_or_else(|_| Err(timed_out()));
+        let stream = match connected {
+            Ok(stream) => stream,
+            Err(err) => {
+                backend.fail();
+                eprintln!("proxy to {} failed: {err}", upstream.authority());
+                last_error = Some(err);
+                continue;
+            }
+        };
+        return match exchange(upstream, stream, active, request, peer).await {
+            Ok(response) => response,
+            Err(err) => {
+                backend.fail();
+                eprintln!(
+                    "proxy {} {} to {} failed: {err}",
+                    request.method,
+                    request.path,
+                    upstream.authority()
+                );
+                error_response(&err)
+            }
+        };
+    }
+    last_error.map_or_else(|| Response::error(502), |err| error_response(&err))
+}
+
+fn error_response(err: &io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::TimedOut => Response::error(504),
+        _ => Response::error(502),
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "timed out")
+}
+
+async fn exchange(
+    upstream: &Upstream,
+    stream: TcpStream,
+    active: Active,
+    request: &Request,
+    peer: SocketAddr,
+) -> io::Result<Response> {
+    let upgrading = websocket::is_upgrade(request);
+    let mut stream = BufReader::new(stream);
+    stream
+        .write_all(&head(upstream, request, peer, upgrading))
+        .await?;
+    stream.write_all(&request.body).await?;
+    stream.flush().await?;
+
+    let reading = read_head(&mut stream, upgrading);
+    let (status, headers) = tokio::time::timeout(HEAD_TIMEOUT, reading)
+        .await
+        .map_err(|_| timed_out())??;
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+    let chunked = header("Transfer-Encoding")
+        .is_some_and(|coding| coding.to_ascii_lowercase().contains("chunked"));
+    let length = match header("Content-Length") {
+        Some(length) => Some(
+            length
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| io::Error::other("invalid Content-Length"))?,
+        ),
+        None => None,
+    };
+    let connection = header("Connection").unwrap_or_default();
+
+    let mut response = Response::new(status, "", Vec::new());
+    response.headers = headers
+        .into_iter()
+        .filter(|(name, _)| {
+            !is_hop_by_hop(name)
+                && !name.eq_ignore_ascii_case("Content-Length")
+                && !listed(&connection, name)
+        })
+        .collect();
+    if status == 101 {
+        response.set_header("Upgrade", "websocket");
+        response.upgrade = Some(Upgrade::Tunnel(Box::new(Counted {
+            reader: stream,
+            _active: active,
+        })));
+    } else if request.method == "HEAD" || status == 204 || status == 304 {
+        // What a `GET` would have been answered with, for `strip_body`.
+        response.set_stream(tokio::io::empty(), length.filter(|_| !chunked));
+    } else if chunked {
+        response = relay_chunked(response, stream, active);
+    } else {
+        match length {
+            Some(length) => response.set_stream(
+                Counted {
+                    reader: stream.take(length),
+                    _active: active,
+                },
+                Some(length),
+            ),
+            // Delimited by the end of the connection.
+            None => response.set_stream(
+                Counted {
+                    reader: stream,
+                    _active: active,
+                },
+                None,
+            ),
+        }
+    }
+    Ok(response)
+}
+
+/// The request head sent upstream, asking to switch to WebSocket when
+/// `upgrading`.
+fn head(upstream: &Upstream, request: &Request, peer: SocketAddr, upgrading: bool) -> Vec<u8> {
+    let mut head = format!(
+        "{} {}{} HTTP/1.1\r\nHost: {}\r\n",
+        request.method,
+        upstream.path,
+        request.target,
+        upstream.authority()
+    );
+    let connection = request.header("Connection").unwrap_or_default();
+    let mut forwarded_for = None;
+    for (name, value) in &request.headers {
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for = Some(value.as_str());
+            continue;
+        }
+        // The body has been read already, so there is nothing to expect.
+        let skipped = [
+            "Host",
+            "Content-Length",
+            "Expect",
+            "X-Forwarded-Host",
+            "X-Forwarded-Proto",
+        ]
+        .iter()
+        .any(|skipped| skipped.eq_ignore_ascii_case(name));
+        if !skipped && !is_hop_by_hop(name) && !listed(connection, name) {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    let client = peer.ip().to_canonical();
+    match forwarded_for {
+        Some(earlier) => head.push_str(&format!("X-Forwarded-For: {earlier}, {client}\r\n")),
+        None => head.push_str(&format!("X-Forwarded-For: {client}\r\n")),
+    }
+    if let Some(host) = request.header("Host") {
+        head.push_str(&format!("X-Forwarded-Host: {host}\r\n"));
+    }
+    let proto = match request.tls {
+        Some(_) => "https",
+        None => "http",
+    };
+    head.push_str(&format!("X-Forwarded-Proto: {proto}\r\n"));
+    head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    match upgrading {
+        true => head.push_str("Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n"),
+        false => head.push_str("Connection: close\r\n\r\n"),
+    }
+    head.into_bytes()
+}
+
+/// Whether `name` is one of the headers a `Connection` value lists as
+/// applying to this connection only.
+fn listed(connection: &str, name: &str) -> bool {
+    connection
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case(name))
+}
+
+/// Reads the status and headers of the final answer, skipping interim
+/// `1xx` ones other than the `101` that ends an upgrade.
+async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut BufReader<S>,
+    upgrading: bool,
+) -> io::Result<(u16, Vec<(String, String)>)> {
+    loop {
+        let mut size = 0;
+        let mut line = String::new();
+        read_line(stream, &mut line, &mut size).await?;
+        let status: u16 = line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .filter(|_| line.starts_with("HTTP/1."))
+            .ok_or_else(|| io::Error::other("invalid status line"))?;
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            read_line(stream, &mut line, &mut size).await?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| io::Error::other("invalid header"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        if !(100..200).contains(&status) || (status == 101 && upgrading) {
+            return Ok((status, headers));
+        }
+    }
+}
+
+async fn read_line<S: AsyncRead + Unpin>(
+    stream: &mut BufReader<S>,
+    line: &mut String,
+    size: &mut usize,
+) -> io::Result<()> {
+    let limit = (MAX_HEAD_SIZE - *size) as u64;
+    let read = (&mut *stream).take(limit).read_line(line).await?;
+    *size += read;
+    if read == 0 || !line.ends_with('\n') {
+        return Err(io::Error::other("incomplete answer head"));
+    }
+    Ok(())
+}
+
+/// Passes a chunked body on as it arrives, decoded, dropping the trailers.
+fn relay_chunked(
+    mut response: Response,
+    mut stream: BufReader<TcpStream>,
+    active: Active,
+) -> Response {
+    let (mut writer, reader) = tokio::io::duplex(STREAM_BUFFER);
+    let failed = Arc::new(AtomicBool::new(false));
+    let relayed = Relayed {
+        reader,
+        failed: failed.clone(),
+    };
+    tokio::spawn(async move {
+        let decoded: io::Result<()> = async {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let mut size = 0;
+                read_line(&mut stream, &mut line, &mut size).await?;
+                let digits = line.split(';').next().unwrap_or_default().trim();
+                let length = u64::from_str_radix(digits, 16)
+                    .map_err(|_| io::Error::other("invalid chunk size"))?;
+                if length == 0 {
+                    return Ok(());
+                }
+                let chunk = &mut (&mut stream).take(length);
+                if tokio::io::copy(chunk, &mut writer).await? < length {
+                    return Err(io::Error::other("truncated chunk"));
+                }
+                line.clear();
+                read_line(&mut stream, &mut line, &mut size).await?;
+            }
+        }
+        .await;
+        if decoded.is_err() {
+            // Set before the writer is dropped, so the reader sees it at
+            // the end of the data.
+            failed.store(true, Ordering::SeqCst);
+        }
+    });
+    response.set_stream(
+        Counted {
+            reader: relayed,
+            _active: active,
+        },
+        None,
+    );
+    response
+}
+
+/// A body that keeps its request counted as active until it is dropped.
+struct Counted<R> {
+    reader: R,
+    _active: Active,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+/// Writes go through too, for the upstream side of a WebSocket tunnel.
+impl<R: AsyncWrite + Unpin> AsyncWrite for Counted<R> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.reader).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,204 @@
+//! The PROXY protocol (versions 1 and 2), with `--proxy-protocol`.
+//!
+//! Load balancers such as HAProxy or a cloud provider's send it ahead of
+//! the connection's own bytes to name the client they relay. Its source
+//! address then stands for the peer everywhere: bans, connection limits,
+//! the access log and `REMOTE_ADDR`. Connections that don't start with it
+//! are refused, as the protocol requires.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Time the balancer may take to send the header.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Start of every version 2 header.
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 header, line break included.
+const MAX_V1_LENGTH: usize = 107;
+
+/// Reads the header from the start of `stream`, reading nothing past it.
+/// Returns the client it names, or `peer` for connections the balancer
+/// made on its own behalf, such as health checks.
+pub async fn read<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer: SocketAddr,
+) -> io::Result<SocketAddr> {
+    let mut start = [0u8; 5];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        return read_v1(stream, peer).await;
+    }
+    if start == SIGNATURE[..5] {
+        return read_v2(stream, peer).await;
+    }
+    Err(invalid("no PROXY protocol header"))
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, peer: SocketAddr) -> io::Result<SocketAddr> {
+    // Byte by byte, since whatever follows belongs to the connection.
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_V1_LENGTH {
+            return Err(invalid("PROXY header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("invalid PROXY header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(peer),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY source address"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("PROXY source address of the wrong family"));
+            }
+            let port = port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY source port"))?;
+            Ok(SocketAddr::new(ip, port))
+        }
+        _ => Err(invalid("invalid PROXY header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S, peer: SocketAddr) -> io::Result<SocketAddr> {
+    let mut rest = [0u8; 11];
+    stream.read_exact(&mut rest).await?;
+    if rest[..7] != SIGNATURE[5..] {
+        return Err(invalid("invalid PROXY signature"));
+    }
+    let (version_command, family) = (rest[7], rest[8]);
+    let length = usize::from(u16::from_be_bytes([rest[9], rest[10]]));
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+    match version_command {
+        // LOCAL: the balancer speaking for itself.
+        0x20 => return Ok(peer),
+        0x21 => {}
+        _ => return Err(invalid("unsupported PROXY version or command")),
+    }
+    // Stream sockets over IPv4 or IPv6; anything else keeps the peer. What
+    // follows the addresses are TLVs, which are ignored.
+    match family {
+        0x11 if length >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        0x21 if length >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        0x11 | 0x21 => Err(invalid("PROXY addresses too short")),
+        _ => Ok(peer),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9000);
+
+    /// The address `input` names and what is left of it after the header.
+    async fn parse(input: &[u8]) -> io::Result<(SocketAddr, Vec<u8>)> {
+        let mut stream = input;
+        let address = read(&mut stream, PEER).await?;
+        Ok((address, stream.to_vec()))
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header.extend_from_slice(b"GET");
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_version_1() {
+        let (address, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET")
+            .await
+            .unwrap();
+        assert_eq!(address, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(rest, b"GET");
+
+        let (address, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(address, "[2001:db8::1]:4000".parse().unwrap());
+        let (address, _) = parse(b"PROXY UNKNOWN whatever\r\n").await.unwrap();
+        assert_eq!(address, PEER);
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_version_1_headers() {
+        let long = format!("PROXY UNKNOWN {}\r\n", "a".repeat(MAX_V1_LENGTH));
+        for header in [
+            "PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n",
+            "PROXY TCP4 192.0.2.1 192.0.2.2 70000 2\r\n",
+            "PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            "PROXY TCP5 192.0.2.1 192.0.2.2 1 2\r\n",
+            "PROXY TCP4 192.0.2.1 192.0.2.2 1 2",
+            "GET / HTTP/1.1\r\n\r\n",
+            &long,
+        ] {
+            assert!(parse(header.as_bytes()).await.is_err(), "{header:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_version_2() {
+        let mut ipv4 = vec![192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let (address, rest) = parse(&v2(0x21, 0x11, &ipv4)).await.unwrap();
+        assert_eq!(address, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(rest, b"GET");
+        // TLVs after the addresses are skipped.
+        ipv4.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let (address, rest) = parse(&v2(0x21, 0x11, &ipv4)).await.unwrap();
+        assert_eq!((address.port(), rest.as_slice()), (56324, &b"GET"[..]));
+
+        let mut ipv6 = [0u8; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        let (address, _) = parse(&v2(0x21, 0x21, &ipv6)).await.unwrap();
+        assert_eq!(address, "[2001:db8::1]:4000".parse().unwrap());
+
+        // LOCAL connections and other families keep the peer.
+        let (address, rest) = parse(&v2(0x20, 0x11, &ipv4)).await.unwrap();
+        assert_eq!((address, rest.as_slice()), (PEER, &b"GET"[..]));
+        let (address, _) = parse(&v2(0x21, 0x31, &[0; 216])).await.unwrap();
+        assert_eq!(address, PEER);
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_version_2_headers() {
+        for header in [
+            v2(0x21, 0x11, &[192, 0, 2, 1]),
+            v2(0x21, 0x21, &[0; 12]),
+            v2(0x22, 0x11, &[0; 12]),
+            v2(0x11, 0x11, &[0; 12]),
+        ] {
+            assert!(parse(&header).await.is_err(), "{header:02x?}");
+        }
+        let mut bad_signature = v2(0x21, 0x11, &[0; 12]);
+        bad_signature[6] = b'X';
+        assert!(parse(&bad_signature).await.is_err());
+        let truncated = v2(0x21, 0x11, &[0; 12]);
+        assert!(parse(&truncated[..20]).await.is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! Purging cached responses by request path, through the admin API.
+//!
+//! A purge is broadcast to every shard, which drops matching entries from
+//! its file, open-file, listing and script response caches and reports how
+//! many it removed.
+
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::sync::{broadcast, mpsc};
+
+use crate::glob;
+
+/// Request paths to purge: one exact path, a glob, or everything.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Path(String),
+    Glob(String),
+    All,
+}
+
+impl Pattern {
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            Pattern::Path(exact) => path == exact,
+            Pattern::Glob(pattern) => glob::matches(pattern, path),
+            Pattern::All => true,
+        }
+    }
+
+    /// Matches a cache key, which is a request path or, for a virtual
+    /// host, `//HOST` and the request path.
+    pub fn matches_key(&self, key: &str) -> bool {
+        let path = match key.strip_prefix("//") {
+            Some(qualified) => qualified.find('/').map_or("", |slash| &qualified[slash..]),
+            None => key,
+        };
+        self.matches(path)
+    }
+
+    /// Matches the canonical file `path` by its request path below `root`.
+    pub fn matches_file(&self, root: &Path, path: &Path) -> bool {
+        if let Pattern::All = self {
+            return true;
+        }
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        let mut url = String::new();
+        for component in relative.components() {
+            url.push('/');
+            url.push_str(&component.as_os_str().to_string_lossy());
+        }
+        self.matches(&url)
+    }
+}
+
+/// A purge sent to the shards, with where to report the number of entries
+/// each one removed.
+#[derive(Clone)]
+pub struct Purge {
+    pub pattern: Pattern,
+    pub done: mpsc::UnboundedSender<usize>,
+}
+
+/// How long to wait for the shards to report back.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends `pattern` to every shard listening on `shards` and returns the total
+/// number of entries removed by the shards that answered in time.
+pub async fn broadcast(shards: &broadcast::Sender<Purge>, pattern: Pattern) -> usize {
+    let (done, mut counts) = mpsc::unbounded_channel();
+    let Ok(receivers) = shards.send(Purge { pattern, done }) else {
+        return 0;
+    };
+    let mut total = 0;
+    for _ in 0..receivers {
+        match tokio::time::timeout(TIMEOUT, counts.recv()).await {
+            Ok(Some(count)) => total += count,
+            _ => break,
+        }
+    }
+    total
+}
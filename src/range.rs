@@ -0,0 +1,182 @@
+//! Byte ranges of static files (RFC 9110, section 14): `Range: bytes=0-499`,
+//! `bytes=500-` and `bytes=-500` are answered `206 Partial Content`, and a
+//! range starting past the end `416 Range Not Satisfiable`. Requests for
+//! several ranges at once get the whole file, as do those whose `If-Range`
+//! names another version of it.
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::http::{Body, Request, Response};
+
+/// What to send for a representation of `length` bytes with `etag` and
+/// `last_modified`: `None` for all of it, `Err` when the requested range
+/// lies outside it.
+pub fn requested(
+    request: &Request,
+    etag: &str,
+    last_modified: Option<&str>,
+    length: u64,
+) -> Option<Result<Range<u64>, ()>> {
+    let range = request.header("Range")?;
+    if request
+        .header("If-Range")
+        .is_some_and(|if_range| if_range.trim() != etag && Some(if_range.trim()) != last_modified)
+    {
+        return None;
+    }
+    parse(range, length)
+}
+
+fn parse(value: &str, length: u64) -> Option<Result<Range<u64>, ()>> {
+    let (unit, range) = value.trim().split_once('=')?;
+    if !unit.eq_ignore_ascii_case("bytes") || range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.trim().split_once('-')?;
+    let number = |digits: &str| match digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        true => digits.parse::<u64>().ok(),
+        false => None,
+    };
+    if start.is_empty() {
+        let suffix = number(end)?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok(length.saturating_sub(suffix)..length));
+    }
+    let start = number(start)?;
+    let end = match end {
+        "" => length,
+        end => number(end)
+            .filter(|&end| end >= start)?
+            .saturating_add(1)
+            .min(length),
+    };
+    match start < length {
+        true => Some(Ok(start..end)),
+        false => Some(Err(())),
+    }
+}
+
+/// A `206` carrying `part`, which is `range` of a representation of
+/// `length` bytes.
+pub fn partial(content_type: &str, part: Body, range: Range<u64>, length: u64) -> Response {
+    let Range { start, end } = range;
+    let mut response = Response::with_body(206, content_type, part);
+    response.set_header(
+        "Content-Range",
+        format!("bytes {start}-{}/{length}", end - 1),
+    );
+    response
+}
+
+/// A `416` telling the client how long the representation is.
+pub fn not_satisfiable(length: u64) -> Response {
+    let mut response = Response::error(416);
+    response.set_header("Content-Range", format!("bytes */{length}"));
+    response
+}
+
+/// Part of a shared body, which stays shared instead of being copied.
+struct Slice {
+    whole: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    range: Range<usize>,
+}
+
+impl AsRef<[u8]> for Slice {
+    fn as_ref(&self) -> &[u8] {
+        &(*self.whole).as_ref()[self.range.clone()]
+    }
+}
+
+/// `range` of `content`, without copying it when it is shared.
+pub fn slice(content: &Body, range: Range<usize>) -> Body {
+    match content {
+        Body::Shared(whole) => Body::Shared(Arc::new(Slice {
+            whole: whole.clone(),
+            range,
+        })),
+        Body::Owned(bytes) => bytes[range].to_vec().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            target: "/".to_string(),
+            path: "/".to_string(),
+            query: String::new(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new().into(),
+            tls: None,
+            peer: None,
+        }
+    }
+
+    #[test]
+    fn parses_ranges() {
+        for (value, expected) in [
+            ("bytes=0-499", Some(Ok(0..500))),
+            ("BYTES = 0-0", None),
+            ("bytes=0-0", Some(Ok(0..1))),
+            ("bytes=500-", Some(Ok(500..1000))),
+            ("bytes=-500", Some(Ok(500..1000))),
+            ("bytes=-2000", Some(Ok(0..1000))),
+            ("bytes=900-5000", Some(Ok(900..1000))),
+            ("bytes=1000-", Some(Err(()))),
+            ("bytes=-0", Some(Err(()))),
+            ("bytes=5-4", None),
+            ("bytes=0-1,5-6", None),
+            ("bytes=+1-2", None),
+            ("bytes=-", None),
+            ("items=0-1", None),
+        ] {
+            assert_eq!(parse(value, 1000), expected, "{value}");
+        }
+        assert_eq!(parse("bytes=-5", 0), Some(Err(())));
+        assert_eq!(parse("bytes=0-", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn if_range_must_match() {
+        let range = ("Range", "bytes=0-9");
+        let modified = "Mon, 21 Oct 2013 20:13:21 GMT";
+        let requested = |headers: &[(&str, &str)]| {
+            super::requested(&request(headers), "\"v1\"", Some(modified), 100)
+        };
+        assert_eq!(requested(&[range]), Some(Ok(0..10)));
+        assert_eq!(requested(&[range, ("If-Range", "\"v1\"")]), Some(Ok(0..10)));
+        assert_eq!(requested(&[range, ("If-Range", modified)]), Some(Ok(0..10)));
+        assert_eq!(requested(&[range, ("If-Range", "\"v2\"")]), None);
+        assert_eq!(requested(&[]), None);
+    }
+
+    #[test]
+    fn describes_parts() {
+        let response = partial("text/plain", b"bcd".to_vec().into(), 1..4, 10);
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 1-3/10"));
+        let response = not_satisfiable(10);
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+    }
+
+    #[test]
+    fn slices_bodies() {
+        let owned: Body = b"abcdef".to_vec().into();
+        assert_eq!(&*slice(&owned, 1..3), b"bc");
+        let whole: Arc<dyn AsRef<[u8]> + Send + Sync> = Arc::new(b"abcdef".to_vec());
+        let part = slice(&Body::Shared(whole), 2..6);
+        assert!(matches!(part, Body::Shared(_)));
+        assert_eq!(&*part, b"cdef");
+    }
+}
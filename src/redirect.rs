@@ -0,0 +1,121 @@
+//! Plain HTTP listener that sends every client to the HTTPS site.
+//!
+//! Requests are answered with a 301 to the `https://` URL for the same host,
+//! path and query. ACME HTTP-01 challenges are the exception: tokens under
+//! `/.well-known/acme-challenge/` are answered from `--acme-domain`'s pending
+//! challenges, or else from the same directory in the root folder, so
+//! certificates can be issued and renewed.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::acme::{Challenges, CHALLENGE_PREFIX};
+use crate::anonymize::{self, Anonymizer};
+use crate::fdlimit::Backoff;
+use crate::http::{self, Request, Response};
+use crate::server::log_connection;
+use crate::spool;
+
+pub async fn run(
+    listener: TcpListener,
+    root: PathBuf,
+    https_port: u16,
+    anonymizer: Option<Arc<Anonymizer>>,
+    challenges: Option<Arc<Challenges>>,
+) {
+    let root = Arc::new(root);
+    let mut backoff = Backoff::default();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                backoff.wait(&err).await;
+                continue;
+            }
+        };
+        backoff.reset();
+        let root = root.clone();
+        let anonymizer = anonymizer.clone();
+        let challenges = challenges.clone();
+        tokio::spawn(async move {
+            let anonymizer = anonymizer.as_deref();
+            let challenges = challenges.as_deref();
+            if let Err(err) = handle(stream, peer, &root, https_port, anonymizer, challenges).await
+            {
+                let client = anonymize::client(anonymizer, peer.ip());
+                eprintln!("connection from {client} failed: {err}");
+            }
+        });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    peer: SocketAddr,
+    root: &Path,
+    https_port: u16,
+    anonymizer: Option<&Anonymizer>,
+    challenges: Option<&Challenges>,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let Some(request) = http::read_request(&mut stream, spool::DEFAULT_THRESHOLD).await? else {
+        return Ok(());
+    };
+
+    let mut response = match request.path.strip_prefix(CHALLENGE_PREFIX) {
+        Some(token) => match challenges.and_then(|challenges| challenges.answer(token)) {
+            Some(response) => response,
+            None => challenge(root, token).await,
+        },
+        None => redirect(&request, https_port),
+    };
+    log_connection(anonymizer, &request, peer, response.status);
+    http::send_response(&mut stream, &request.version, &mut response).await
+}
+
+fn redirect(request: &Request, https_port: u16) -> Response {
+    let Some(host) = request.header("Host") else {
+        return Response::error(400);
+    };
+    let host = strip_port(host);
+    let location = match https_port {
+        443 => format!("https://{host}{}", request.target),
+        port => format!("https://{host}:{port}{}", request.target),
+    };
+
+    let mut response = Response::error(301);
+    response.set_header("Location", location);
+    response
+}
+
+async fn challenge(root: &Path, token: &str) -> Response {
+    let valid = !token.is_empty()
+        && token
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+    if !valid {
+        return Response::error(404);
+    }
+    let path = root
+        .join(CHALLENGE_PREFIX.trim_start_matches('/'))
+        .join(token);
+    match tokio::fs::read(path).await {
+        Ok(content) => Response::new(200, "text/plain", content),
+        Err(_) => Response::error(404),
+    }
+}
+
+/// Removes the port from a `Host` header value, keeping IPv6 brackets.
+pub fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host
+            .split_once(']')
+            .map_or(host, |(address, _)| &host[..address.len() + 1]);
+    }
+    host.split_once(':').map_or(host, |(name, _)| name)
+}
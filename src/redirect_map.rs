@@ -0,0 +1,134 @@
+//! Redirects listed in a `_redirects` file in the root folder.
+//!
+//! Each line holds a source path, a destination and an optional status
+//! (301 by default), separated by whitespace; `#` starts a comment:
+//!
+//! ```text
+//! /old-page      /new-page
+//! /blog/*        https://blog.example.com/:splat   302
+//! ```
+//!
+//! A source ending in `/*` matches everything below it, and `:splat` in the
+//! destination is replaced by the matched rest. The file is re-read whenever
+//! its modification time changes.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::http::{Request, Response};
+
+pub const FILE: &str = "_redirects";
+
+struct Rule {
+    from: String,
+    /// `from` ends with `/*`, which has been stripped.
+    prefix: bool,
+    to: String,
+    status: u16,
+}
+
+struct Loaded {
+    modified: Option<SystemTime>,
+    rules: Arc<Vec<Rule>>,
+}
+
+pub struct RedirectMap {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl RedirectMap {
+    pub fn new(root: &Path) -> RedirectMap {
+        let path = root.join(FILE);
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let rules = Arc::new(load(&path));
+        RedirectMap {
+            path,
+            loaded: Mutex::new(Loaded { modified, rules }),
+        }
+    }
+
+    /// Returns the redirect for the request's path, if a rule matches.
+    pub async fn find(&self, request: &Request) -> Option<Response> {
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let rules = {
+            let mut loaded = self.loaded.lock().unwrap();
+            if loaded.modified != modified {
+                loaded.modified = modified;
+                loaded.rules = Arc::new(load(&self.path));
+            }
+            loaded.rules.clone()
+        };
+
+        let (rule, splat) = rules.iter().find_map(|rule| {
+            let splat = match rule.prefix {
+                true => request
+                    .path
+                    .strip_prefix(rule.from.as_str())
+                    .filter(|rest| rest.is_empty() || rest.starts_with('/'))?
+                    .trim_start_matches('/'),
+                false if request.path == rule.from => "",
+                false => return None,
+            };
+            Some((rule, splat))
+        })?;
+
+        let mut location = rule.to.replace(":splat", splat);
+        if !request.query.is_empty() && !location.contains('?') {
+            location.push('?');
+            location.push_str(&request.query);
+        }
+        let mut response = Response::error(rule.status);
+        response.set_header("Location", location);
+        Some(response)
+    }
+}
+
+fn load(path: &Path) -> Vec<Rule> {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    let mut rules = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_rule(line) {
+            Ok(rule) => rules.push(rule),
+            Err(err) => eprintln!("{}:{}: {err}", path.display(), number + 1),
+        }
+    }
+    rules
+}
+
+fn parse_rule(line: &str) -> Result<Rule, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (from, to, status) = match fields[..] {
+        [from, to] => (from, to, 301),
+        [from, to, status] => match status.parse() {
+            Ok(status @ (301 | 302 | 303 | 307 | 308)) => (from, to, status),
+            _ => return Err(format!("unsupported redirect status `{status}`")),
+        },
+        _ => return Err("expected `FROM TO [STATUS]`".to_string()),
+    };
+    if !from.starts_with('/') {
+        return Err(format!("source `{from}` must start with `/`"));
+    }
+    let (from, prefix) = match from.strip_suffix("/*") {
+        Some(from) => (from, true),
+        None => (from, false),
+    };
+    Ok(Rule {
+        from: from.to_string(),
+        prefix,
+        to: to.to_string(),
+        status,
+    })
+}
@@ -0,0 +1,205 @@
+//! `rustywebserver replay CAPTURE_DIR HOST:PORT`: re-issues requests
+//! recorded with `--capture` against a running server and reports every
+//! response whose status or headers differ from the recorded one.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::capture::Record;
+
+pub const USAGE: &str = "Usage: rustywebserver replay CAPTURE_DIR HOST:PORT [OPTIONS]
+
+Options:
+    --ignore-header NAME  do not compare response header NAME (repeatable; Date is always ignored)";
+
+/// Headers that differ between any two responses and are never compared.
+const ALWAYS_IGNORED: &[&str] = &["date", "connection"];
+
+struct Options {
+    dir: PathBuf,
+    address: String,
+    ignored: Vec<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut positional = Vec::new();
+    let mut ignored: Vec<String> = ALWAYS_IGNORED.iter().map(|name| name.to_string()).collect();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ignore-header" => {
+                let name = args.next().ok_or("--ignore-header requires a value")?;
+                ignored.push(name.to_ascii_lowercase());
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ => positional.push(arg),
+        }
+    }
+    let [dir, address] = <[String; 2]>::try_from(positional)
+        .map_err(|_| "expected CAPTURE_DIR and HOST:PORT".to_string())?;
+    Ok(Options {
+        dir: PathBuf::from(dir),
+        address,
+        ignored,
+    })
+}
+
+/// Runs the subcommand. Returns whether every response matched.
+pub async fn run(args: impl IntoIterator<Item = String>) -> Result<bool, String> {
+    let options = parse_args(args).map_err(|err| format!("{err}\n\n{USAGE}"))?;
+
+    let mut files = Vec::new();
+    let entries = std::fs::read_dir(&options.dir)
+        .map_err(|err| format!("cannot read {}: {err}", options.dir.display()))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let mut differing = 0;
+    for path in &files {
+        let record = load(path)?;
+        let (status, headers) = match send(&options.address, &record).await {
+            Ok(response) => response,
+            Err(err) => return Err(format!("{}: {err}", options.address)),
+        };
+        let differences = compare(&record, status, &headers, &options.ignored);
+        if !differences.is_empty() {
+            differing += 1;
+            println!("{} {} {}", file_name(path), record.method, record.target);
+            for difference in differences {
+                println!("    {difference}");
+            }
+        }
+    }
+    println!(
+        "{} requests replayed, {differing} with different responses",
+        files.len()
+    );
+    Ok(differing == 0)
+}
+
+fn load(path: &Path) -> Result<Record, String> {
+    let data =
+        std::fs::read(path).map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+    serde_json::from_slice(&data).map_err(|err| format!("invalid record {}: {err}", path.display()))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Sends the recorded request and returns the status and headers of the
+/// response.
+async fn send(address: &str, record: &Record) -> Result<(u16, Vec<(String, String)>), String> {
+    let body = record.body()?;
+    let mut head = format!("{} {} HTTP/1.1\r\n", record.method, record.target);
+    // Recorded HTTP/1.0 requests may lack the Host HTTP/1.1 requires.
+    if !record
+        .headers
+        .iter()
+        .any(|(key, _)| key.eq_ignore_ascii_case("Host"))
+    {
+        head.push_str(&format!("Host: {address}\r\n"));
+    }
+    for (key, value) in &record.headers {
+        if !["connection", "content-length", "transfer-encoding"]
+            .contains(&key.to_ascii_lowercase().as_str())
+        {
+            head.push_str(&format!("{key}: {value}\r\n"));
+        }
+    }
+    let (status, headers, _) = exchange(address, head, &body).await?;
+    Ok((status, headers))
+}
+
+/// Sends a request made of `head` (the request line and headers, without
+/// `Content-Length` and `Connection`) and `body`, and returns the status,
+/// headers and body of the response.
+pub async fn exchange(
+    address: &str,
+    mut head: String,
+    body: &[u8],
+) -> Result<(u16, Vec<(String, String)>, Vec<u8>), String> {
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+
+    let io = |err: std::io::Error| err.to_string();
+    let mut stream = TcpStream::connect(address).await.map_err(io)?;
+    stream.write_all(head.as_bytes()).await.map_err(io)?;
+    stream.write_all(body).await.map_err(io)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(io)?;
+
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("incomplete response")?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or("invalid status line")?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok((status, headers, response[end + 4..].to_vec()))
+}
+
+/// Describes how the replayed response differs from the recorded one.
+fn compare(
+    record: &Record,
+    status: u16,
+    headers: &[(String, String)],
+    ignored: &[String],
+) -> Vec<String> {
+    let mut differences = Vec::new();
+    if status != record.status {
+        differences.push(format!("status: {} -> {status}", record.status));
+    }
+
+    let group = |headers: &[(String, String)]| {
+        let mut grouped: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (key, value) in headers {
+            let key = key.to_ascii_lowercase();
+            if !ignored.contains(&key) {
+                grouped.entry(key).or_default().push(value.clone());
+            }
+        }
+        grouped
+    };
+    let before = group(&record.response_headers);
+    let after = group(headers);
+    for key in before
+        .keys()
+        .chain(after.keys().filter(|key| !before.contains_key(*key)))
+    {
+        let old = before.get(key).map(|values| values.join(", "));
+        let new = after.get(key).map(|values| values.join(", "));
+        match (old, new) {
+            (Some(old), Some(new)) if old != new => {
+                differences.push(format!("{key}: {old} -> {new}"))
+            }
+            (Some(old), None) => differences.push(format!("{key}: {old} -> (missing)")),
+            (None, Some(new)) => differences.push(format!("{key}: (missing) -> {new}")),
+            _ => {}
+        }
+    }
+    differences
+}
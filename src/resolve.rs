@@ -0,0 +1,343 @@
+//! Maps request paths onto the filesystem without escaping the root.
+//!
+//! The decoded path is first normalized lexically: `/` and `\` both separate
+//! components, `.` is dropped and `..` pops a component, so traversal that
+//! would climb above the root is refused before touching the disk. The
+//! result is then canonicalized and checked for containment component by
+//! component, which catches symlinks pointing outside the root. Files are
+//! finally opened with `O_NOFOLLOW`, so a symlink swapped in after the check
+//! is not followed. On Windows, components naming a drive or an alternate
+//! data stream are refused as well.
+
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::fs::File;
+
+#[derive(Debug)]
+pub enum ResolveError {
+    NotFound,
+    Forbidden,
+}
+
+impl ResolveError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ResolveError::NotFound => 404,
+            ResolveError::Forbidden => 403,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Resolved {
+    /// Canonical path, always inside the root.
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub metadata: Metadata,
+}
+
+/// Resolves the decoded request `path` under the canonical `root`.
+pub async fn resolve(root: &Path, path: &str) -> Result<Resolved, ResolveError> {
+    resolve_allowing(root, path, &[]).await
+}
+
+/// Like `resolve`, but serves the hidden components named in `visible`.
+pub async fn resolve_allowing(
+    root: &Path,
+    path: &str,
+    visible: &[&str],
+) -> Result<Resolved, ResolveError> {
+    let relative = normalize_allowing(path, visible)?;
+    let canonical = match tokio::fs::canonicalize(root.join(&relative)).await {
+        Ok(canonical) => canonical,
+        // A file where a directory should be, as in `/app.sh/users/42`.
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+            ) =>
+        {
+            return Err(ResolveError::NotFound)
+        }
+        Err(_) => return Err(ResolveError::Forbidden),
+    };
+
+    let inside = canonical
+        .strip_prefix(root)
+        .map_err(|_| ResolveError::Forbidden)?;
+    if inside.components().any(|component| {
+        let name = component.as_os_str();
+        is_hidden(name) && !visible.iter().any(|visible| name == *visible)
+    }) {
+        return Err(ResolveError::Forbidden);
+    }
+
+    let metadata = tokio::fs::metadata(&canonical)
+        .await
+        .map_err(|_| ResolveError::NotFound)?;
+    Ok(Resolved {
+        path: canonical,
+        is_dir: metadata.is_dir(),
+        metadata,
+    })
+}
+
+/// Resolves `path` in each of the canonical `roots` in turn and returns the
+/// first match. A path refused in one layer is refused outright rather than
+/// looked up further down.
+pub async fn resolve_layered(
+    roots: &[PathBuf],
+    path: &str,
+    visible: &[&str],
+) -> Result<Resolved, ResolveError> {
+    for root in roots {
+        match resolve_allowing(root, path, visible).await {
+            Err(ResolveError::NotFound) => continue,
+            result => return result,
+        }
+    }
+    Err(ResolveError::NotFound)
+}
+
+/// Resolves the destination of a write, creating the parent directories
+/// that don't exist yet inside the root. The file itself need not exist.
+/// `NotFound` means a file stands where a directory should be.
+pub async fn resolve_write(root: &Path, path: &str) -> Result<PathBuf, ResolveError> {
+    let relative = normalize(path)?;
+    let name = relative.file_name().ok_or(ResolveError::Forbidden)?;
+    let parent = relative.parent().unwrap_or(Path::new(""));
+
+    let mut missing = Vec::new();
+    let mut ancestor = parent;
+    let mut directory = loop {
+        match resolve(root, &ancestor.to_string_lossy()).await {
+            Ok(resolved) if resolved.is_dir => break resolved.path,
+            Ok(_) => return Err(ResolveError::NotFound),
+            Err(ResolveError::NotFound) => {
+                missing.push(ancestor.file_name().ok_or(ResolveError::NotFound)?);
+                ancestor = ancestor.parent().unwrap_or(Path::new(""));
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    for component in missing.iter().rev() {
+        directory.push(component);
+        match tokio::fs::create_dir(&directory).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(_) => return Err(ResolveError::NotFound),
+        }
+    }
+
+    // Resolved again, in case a symlink was swapped in while creating.
+    let parent = resolve(root, &parent.to_string_lossy()).await?;
+    if !parent.is_dir {
+        return Err(ResolveError::NotFound);
+    }
+    Ok(parent.path.join(name))
+}
+
+/// Lexically normalizes a request path into a path relative to the root.
+pub fn normalize(path: &str) -> Result<PathBuf, ResolveError> {
+    normalize_allowing(path, &[])
+}
+
+fn normalize_allowing(path: &str, visible: &[&str]) -> Result<PathBuf, ResolveError> {
+    if path.contains('\0') {
+        return Err(ResolveError::Forbidden);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop().ok_or(ResolveError::Forbidden)?;
+            }
+            _ if component.starts_with('.') && !visible.contains(&component) => {
+                return Err(ResolveError::Forbidden);
+            }
+            // A drive (`C:`) or alternate data stream (`file:stream`) would
+            // escape the root or bypass the rules checked on the file name.
+            _ if cfg!(windows) && component.contains(':') => {
+                return Err(ResolveError::Forbidden);
+            }
+            _ => components.push(component),
+        }
+    }
+    Ok(components.iter().collect())
+}
+
+/// Opens a resolved file without following a symlink in its final component.
+pub async fn open(path: &Path) -> io::Result<File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    options.open(path).await
+}
+
+pub fn is_hidden(name: &std::ffi::OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::spool;
+
+    /// A root holding `a/b.txt`, `.hidden/c.txt`, `.well-known/d.txt` and,
+    /// beside it, `outside.txt`, removed when dropped.
+    struct Root {
+        parent: PathBuf,
+        path: PathBuf,
+    }
+
+    impl Root {
+        fn new() -> Root {
+            let parent = spool::temp_path("resolve");
+            let path = parent.join("root");
+            for dir in ["a", ".hidden", ".well-known"] {
+                std::fs::create_dir_all(path.join(dir)).unwrap();
+            }
+            for file in [
+                "a/b.txt",
+                ".hidden/c.txt",
+                ".well-known/d.txt",
+                "../outside.txt",
+            ] {
+                std::fs::write(path.join(file), file).unwrap();
+            }
+            let path = path.canonicalize().unwrap();
+            Root { parent, path }
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.parent);
+        }
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        for (path, expected) in [
+            ("/", ""),
+            ("/a/./b.txt", "a/b.txt"),
+            ("//a//b.txt", "a/b.txt"),
+            ("/a/../b.txt", "b.txt"),
+            ("/a\\b.txt", "a/b.txt"),
+        ] {
+            assert_eq!(normalize(path).unwrap(), Path::new(expected), "{path}");
+        }
+        for path in [
+            "/..",
+            "/a/../..",
+            "/a/..\\..\\etc",
+            "/.git/config",
+            "/a/.env",
+            "/a\0",
+        ] {
+            assert!(
+                matches!(normalize(path), Err(ResolveError::Forbidden)),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            normalize_allowing("/.well-known/x", &[".well-known"]).unwrap(),
+            Path::new(".well-known/x")
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_inside_the_root() {
+        let root = Root::new();
+        let resolved = resolve(&root.path, "/a/b.txt").await.unwrap();
+        assert_eq!(resolved.path, root.path.join("a/b.txt"));
+        assert!(!resolved.is_dir);
+        assert!(resolve(&root.path, "/a").await.unwrap().is_dir);
+        for (path, status) in [
+            ("/missing", 404),
+            ("/a/b.txt/c", 404),
+            ("/../outside.txt", 403),
+            ("/a/../../outside.txt", 403),
+            ("/.hidden/c.txt", 403),
+            ("/.well-known/d.txt", 403),
+        ] {
+            let status_of = resolve(&root.path, path)
+                .await
+                .err()
+                .map(|err| err.status());
+            assert_eq!(status_of, Some(status), "{path}");
+        }
+        let visible = resolve_allowing(&root.path, "/.well-known/d.txt", &[".well-known"]).await;
+        assert!(visible.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_symlinks_out_of_the_root() {
+        let root = Root::new();
+        let outside = root.parent.join("outside.txt");
+        std::os::unix::fs::symlink(&outside, root.path.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&root.parent, root.path.join("up")).unwrap();
+        std::os::unix::fs::symlink(root.path.join(".hidden"), root.path.join("shown")).unwrap();
+        std::os::unix::fs::symlink(root.path.join("a/b.txt"), root.path.join("b.txt")).unwrap();
+        for path in ["/link.txt", "/up/outside.txt", "/shown/c.txt"] {
+            assert!(
+                matches!(
+                    resolve(&root.path, path).await,
+                    Err(ResolveError::Forbidden)
+                ),
+                "{path}"
+            );
+        }
+        // A link that stays inside resolves to its target, which `open`
+        // reaches only through the resolved path.
+        let resolved = resolve(&root.path, "/b.txt").await.unwrap();
+        assert_eq!(resolved.path, root.path.join("a/b.txt"));
+        assert!(open(&resolved.path).await.is_ok());
+        assert!(open(&root.path.join("b.txt")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn layers_roots() {
+        let (lower, upper) = (Root::new(), Root::new());
+        std::fs::write(upper.path.join("a/b.txt"), "upper").unwrap();
+        std::fs::write(lower.path.join("only.txt"), "lower").unwrap();
+        let roots = [upper.path.clone(), lower.path.clone()];
+        let resolved = resolve_layered(&roots, "/a/b.txt", &[]).await.unwrap();
+        assert!(resolved.path.starts_with(&upper.path));
+        let resolved = resolve_layered(&roots, "/only.txt", &[]).await.unwrap();
+        assert!(resolved.path.starts_with(&lower.path));
+        assert!(matches!(
+            resolve_layered(&roots, "/.hidden/c.txt", &[]).await,
+            Err(ResolveError::Forbidden)
+        ));
+    }
+
+    #[tokio::test]
+    async fn creates_write_destinations() {
+        let root = Root::new();
+        let destination = resolve_write(&root.path, "/new/dir/file.txt")
+            .await
+            .unwrap();
+        assert_eq!(destination, root.path.join("new/dir/file.txt"));
+        assert!(root.path.join("new/dir").is_dir());
+        assert!(matches!(
+            resolve_write(&root.path, "/a/b.txt/file.txt").await,
+            Err(ResolveError::NotFound)
+        ));
+        for path in ["/../escape.txt", "/.hidden/new.txt", "/"] {
+            assert!(
+                matches!(
+                    resolve_write(&root.path, path).await,
+                    Err(ResolveError::Forbidden)
+                ),
+                "{path}"
+            );
+        }
+    }
+}
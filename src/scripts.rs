@@ -0,0 +1,767 @@
+use std::ffi::OsString;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::time::{Instant, Sleep};
+
+use crate::cgi;
+use crate::conditional;
+use crate::config::Config;
+use crate::fdlimit;
+use crate::http::{parse_form, Request, Response};
+use crate::kv::KvStore;
+use crate::multipart::{self, Form};
+use crate::websocket::{self, Upgrade};
+
+/// A script to run: its file, the part of the request path that names it,
+/// followed by its `PATH_INFO`, and the root folder it was found in.
+pub struct Script<'a> {
+    pub path: &'a Path,
+    pub name: &'a str,
+    pub root: &'a Path,
+}
+
+/// Resource limits of every script, set with `--script-cpu`,
+/// `--script-memory`, `--script-file-size` and `--script-open-files`.
+#[derive(Clone, Copy, Default)]
+pub struct ScriptLimits {
+    /// CPU time, in seconds.
+    pub cpu_time: Option<u64>,
+    /// Address space, in bytes.
+    pub memory: Option<u64>,
+    /// Size of any file written, in bytes.
+    pub file_size: Option<u64>,
+    pub open_files: Option<u64>,
+}
+
+impl ScriptLimits {
+    pub fn is_empty(&self) -> bool {
+        [self.cpu_time, self.memory, self.file_size, self.open_files]
+            .iter()
+            .all(Option::is_none)
+    }
+
+    /// Applies the limits to the calling process, between fork and exec.
+    /// Limits above the server's own hard limits are lowered to them, since
+    /// only root could raise those.
+    #[cfg(unix)]
+    fn apply(&self) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu_time),
+            (libc::RLIMIT_AS, self.memory),
+            (libc::RLIMIT_FSIZE, self.file_size),
+            (libc::RLIMIT_NOFILE, self.open_files),
+        ];
+        for (resource, limit) in limits {
+            let Some(limit) = limit else {
+                continue;
+            };
+            let mut current = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            // SAFETY: `current` is a valid rlimit to write to.
+            if unsafe { libc::getrlimit(resource, &mut current) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let limit = (limit as libc::rlim_t).min(current.rlim_max);
+            let wanted = libc::rlimit {
+                rlim_cur: limit,
+                rlim_max: limit,
+            };
+            // SAFETY: `wanted` is a valid rlimit to read.
+            if unsafe { libc::setrlimit(resource, &wanted) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Variables of the server's environment that scripts get, besides those of
+/// `--script-env`.
+#[cfg(not(windows))]
+const INHERITED_ENV: &[&str] = &["PATH", "LANG", "LC_ALL", "TZ", "TMPDIR"];
+
+/// Windows programs also need to find the system and a temporary folder.
+#[cfg(windows)]
+const INHERITED_ENV: &[&str] = &[
+    "PATH",
+    "PATHEXT",
+    "SystemRoot",
+    "windir",
+    "ComSpec",
+    "TEMP",
+    "TMP",
+];
+
+/// Output of a streamed script past which, without an empty line, it is
+/// taken to be all body and no headers.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Runs `script` and turns its output into a response.
+///
+/// The script runs in its own folder with the standard CGI/1.1 variables
+/// for the server's port, such as `REQUEST_METHOD`, `QUERY_STRING` and
+/// `HTTP_*`, along with `SCRIPT_FILENAME` and `DOCUMENT_ROOT`. It also gets
+/// the request method and path as `Method` and `Path`, and every query
+/// parameter (and for urlencoded POST requests, every form field) as
+/// `Query_<name>`, while the request body is written to its stdin. Files
+/// uploaded as `multipart/form-data` are stored in temporary files named by
+/// `File_<name>`, with the client's `Filename_<name>` and `Filetype_<name>`,
+/// and removed once the script is done. With a key-value store `KV_URL` and
+/// `KV_TOKEN` are set too, and over TLS `HTTPS`, `SSL_PROTOCOL`,
+/// `SSL_CIPHER`, `SSL_TLS_SNI` and `SSL_ALPN`. Its output is a header block,
+/// an empty line and the body, whose `Status` header sets the status; a
+/// non-zero exit status answers 500, and a script still running after
+/// `timeout` is killed and answers `504`. A response whose `ETag` or
+/// `Last-Modified` matches the request's `If-None-Match` or
+/// `If-Modified-Since` is sent as a `304`.
+pub async fn execute_script(
+    script: &Script<'_>,
+    request: &Request,
+    config: &Config,
+    kv: Option<&KvStore>,
+    timeout: Option<Duration>,
+) -> Response {
+    revalidate(request, render(script, request, config, kv, timeout).await)
+}
+
+/// Runs the script like `execute_script`, but leaves its response as the
+/// script wrote it rather than turn it into a `304`.
+pub async fn render(
+    script: &Script<'_>,
+    request: &Request,
+    config: &Config,
+    kv: Option<&KvStore>,
+    timeout: Option<Duration>,
+) -> Response {
+    let form = match read_form(request, config).await {
+        Ok(form) => form,
+        Err(response) => return response,
+    };
+    let mut command = prepare(script, request, config, kv, &form);
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => return fdlimit::error_response(&err),
+    };
+    let mut group = Group(child.id());
+    feed(&mut child, request);
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut output = Vec::new();
+    let running = async {
+        stdout.read_to_end(&mut output).await?;
+        child.wait().await
+    };
+    let status = match timeout {
+        Some(limit) => match tokio::time::timeout(limit, running).await {
+            Ok(status) => status,
+            Err(_) => return timed_out(script.path, limit),
+        },
+        None => running.await,
+    };
+    if status.is_ok() {
+        group.disarm();
+    }
+    match status {
+        Ok(status) if status.success() => parse_output(&output),
+        Ok(_) => Response::error(500),
+        Err(err) => fdlimit::error_response(&err),
+    }
+}
+
+/// Runs the script like `execute_script`, but sends its body as it is
+/// written, chunked, once the header block is complete. A script that fails
+/// or times out after that can no longer change the status, so the response
+/// is cut short instead. Event streams (`text/event-stream`) are meant to
+/// last, so `timeout` only bounds the wait for their header block.
+pub async fn stream_script(
+    script: &Script<'_>,
+    request: &Request,
+    config: &Config,
+    kv: Option<&KvStore>,
+    timeout: Option<Duration>,
+) -> Response {
+    let deadline = timeout.map(|limit| Instant::now() + limit);
+    let form = match read_form(request, config).await {
+        Ok(form) => form,
+        Err(response) => return response,
+    };
+    let mut command = prepare(script, request, config, kv, &form);
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => return fdlimit::error_response(&err),
+    };
+    let mut group = Group(child.id());
+    feed(&mut child, request);
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut output = Vec::new();
+    let reading = async {
+        while find_head(&output).is_none() && output.len() < MAX_HEAD {
+            if stdout.read_buf(&mut output).await? == 0 {
+                return child.wait().await.map(Some);
+            }
+        }
+        Ok(None)
+    };
+    let read = match (deadline, timeout) {
+        (Some(deadline), Some(limit)) => match tokio::time::timeout_at(deadline, reading).await {
+            Ok(read) => read,
+            Err(_) => return timed_out(script.path, limit),
+        },
+        _ => reading.await,
+    };
+    if let Ok(Some(_)) = read {
+        group.disarm();
+    }
+    match read {
+        Ok(Some(status)) if status.success() => revalidate(request, parse_output(&output)),
+        Ok(Some(_)) => Response::error(500),
+        Err(err) => fdlimit::error_response(&err),
+        Ok(None) => {
+            let body_start = find_head(&output).map_or(0, |(_, body_start)| body_start);
+            let mut response = parse_output(&output[..body_start]);
+            let events = is_event_stream(&response);
+            if events && response.header("Cache-Control").is_none() {
+                response.set_header("Cache-Control", "no-cache");
+            }
+            let body = ScriptBody {
+                pending: output[body_start..].to_vec(),
+                stdout,
+                group,
+                exit: Box::pin(async move {
+                    let status = child.wait().await;
+                    drop(form);
+                    status
+                }),
+                deadline: deadline
+                    .zip(timeout)
+                    .filter(|_| !events)
+                    .map(|(deadline, limit)| {
+                        (
+                            Box::pin(tokio::time::sleep_until(deadline)),
+                            script.path.to_path_buf(),
+                            limit,
+                        )
+                    }),
+            };
+            response.set_stream(body, None);
+            revalidate(request, response)
+        }
+    }
+}
+
+/// Starts `script` for the WebSocket handshake `request`, and
+/// answers with the `101` that hands the connection over to it. It gets the
+/// environment of `execute_script`, without form fields.
+pub fn websocket(
+    script: &Script<'_>,
+    request: &Request,
+    config: &Config,
+    kv: Option<&KvStore>,
+) -> Response {
+    let mut response = match websocket::handshake(request) {
+        Ok(response) => response,
+        Err(response) => return response,
+    };
+    let mut command = prepare(script, request, config, kv, &Form::default());
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    match command.spawn() {
+        Ok(child) => response.upgrade = Some(Upgrade::Script(Box::new(child))),
+        Err(err) => return fdlimit::error_response(&err),
+    }
+    response
+}
+
+/// Writes the request body to the script's stdin, then closes it. This runs
+/// in the background, so a script that answers before reading all of its
+/// input isn't held up, and one that never reads it just doesn't get it.
+fn feed(child: &mut Child, request: &Request) {
+    let Some(mut stdin) = child.stdin.take() else {
+        return;
+    };
+    let body = request.body.share();
+    tokio::spawn(async move {
+        let _ = stdin.write_all((*body).as_ref()).await;
+    });
+}
+
+/// The form fields of a `POST`, with the files of a `multipart/form-data`
+/// body stored in temporary files, which belong to `--cgi-user` if set.
+pub async fn read_form(request: &Request, config: &Config) -> Result<Form, Response> {
+    if request.method != "POST" {
+        return Ok(Form::default());
+    }
+    let content_type = request.header("Content-Type").unwrap_or_default();
+    let Some(boundary) = multipart::boundary(content_type) else {
+        // Other bodies are left to stdin alone.
+        let media = content_type.split(';').next().unwrap_or_default().trim();
+        if !media.is_empty() && !media.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Ok(Form::default());
+        }
+        return Ok(Form {
+            fields: parse_form(&String::from_utf8_lossy(&request.body)),
+            files: Vec::new(),
+        });
+    };
+    let form = multipart::parse(&request.body, &boundary)
+        .await
+        .map_err(|err| match err {
+            Some(err) => fdlimit::error_response(&err),
+            None => Response::error(400),
+        })?;
+    // Saved by the server for itself alone.
+    #[cfg(unix)]
+    if let Some(credentials) = config.script_credentials {
+        let (uid, gid) = (Some(credentials.uid), Some(credentials.gid));
+        for upload in &form.files {
+            if let Err(err) = std::os::unix::fs::chown(&upload.path, uid, gid) {
+                eprintln!("cannot hand {} to --cgi-user: {err}", upload.path.display());
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = config;
+    Ok(form)
+}
+
+/// The command running `script` for `request` and its `form`.
+fn prepare(
+    script: &Script<'_>,
+    request: &Request,
+    config: &Config,
+    kv: Option<&KvStore>,
+    form: &Form,
+) -> Command {
+    let mut command = base_command(script, config);
+    command.envs(variables(script, request, config, kv, form));
+    command
+}
+
+/// The command starting `script` with everything but the request: in its
+/// own folder and process group, under the limits and user configured, and
+/// with the server's share of the environment.
+pub fn base_command(script: &Script<'_>, config: &Config) -> Command {
+    let mut command = command(script.path, &config.interpreters);
+    command.kill_on_drop(true);
+    // Its own process group, so that a timeout can stop what it started.
+    #[cfg(unix)]
+    {
+        let (limits, credentials) = (config.script_limits, config.script_credentials);
+        // SAFETY: setpgid, getrlimit, setrlimit and the calls switching
+        // credentials are async-signal-safe, and nothing is allocated.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                limits.apply()?;
+                // Last, since the limits might be lowered no further once
+                // the script's own.
+                match credentials {
+                    Some(credentials) => credentials.apply(),
+                    None => Ok(()),
+                }
+            });
+        }
+    }
+    // Nothing of the server's environment but what programs need to run,
+    // and nothing named by the client outside a prefix of its own.
+    command.env_clear();
+    for name in INHERITED_ENV {
+        if let Some(value) = std::env::var_os(name) {
+            command.env(name, value);
+        }
+    }
+    for (name, value) in &config.script_env {
+        let value = value
+            .as_ref()
+            .map(OsString::from)
+            .or_else(|| std::env::var_os(name));
+        if let Some(value) = value {
+            command.env(name, value);
+        }
+    }
+    command
+        .env("SCRIPT_FILENAME", script.path)
+        .env("DOCUMENT_ROOT", script.root);
+    // So that scripts find the files next to them by relative paths.
+    if let Some(dir) = script.path.parent() {
+        command.current_dir(dir);
+    }
+    command
+}
+
+/// The variables that describe `request` and its `form` to `script`.
+pub fn variables(
+    script: &Script<'_>,
+    request: &Request,
+    config: &Config,
+    kv: Option<&KvStore>,
+    form: &Form,
+) -> Vec<(String, String)> {
+    let mut variables = cgi::variables(request, script.name, config.port);
+    let mut set = |name: &str, value: &str| {
+        // Names come from the client, and neither can be put in the
+        // environment as they are.
+        if name.contains(['=', '\0']) || value.contains('\0') {
+            return;
+        }
+        variables.push((name.to_string(), value.to_string()));
+    };
+    set("Method", &request.method);
+    set("Path", &request.path);
+    for (key, value) in kv.iter().flat_map(|kv| kv.script_env()) {
+        set(key, value);
+    }
+    for (key, value) in request.tls.iter().flat_map(|tls| tls.env()) {
+        set(key, value);
+    }
+    for (key, value) in parse_form(&request.query) {
+        set(&format!("Query_{key}"), &value);
+    }
+    for (key, value) in &form.fields {
+        set(&format!("Query_{key}"), value);
+    }
+    for upload in &form.files {
+        set(
+            &format!("File_{}", upload.name),
+            &upload.path.to_string_lossy(),
+        );
+        set(&format!("Filename_{}", upload.name), &upload.filename);
+        set(&format!("Filetype_{}", upload.name), &upload.content_type);
+    }
+    variables
+}
+
+/// Answers for a script that outlived `limit`, which is killed along with
+/// its `Group`.
+pub fn timed_out(path: &Path, limit: Duration) -> Response {
+    eprintln!(
+        "script {} killed after {}s",
+        path.display(),
+        limit.as_secs()
+    );
+    Response::error(504)
+}
+
+/// The process group a script leads, killed when this is dropped before
+/// the script is done: on a timeout, or when its answer is no longer wanted
+/// because the client went away. Whatever the script started goes with it,
+/// where dropping the child only stops the script itself.
+pub struct Group(pub Option<u32>);
+
+impl Group {
+    /// For a script that exited and was waited for, whose process ID may
+    /// soon be reused.
+    pub fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Group {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        if let Some(pid) = self.0.and_then(|pid| libc::pid_t::try_from(pid).ok()) {
+            // SAFETY: kill has no memory safety requirements.
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+        }
+    }
+
+    /// Windows has no process groups; dropping the child stops the script.
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
+
+/// Turns a response whose validators match the request into a `304`.
+pub fn revalidate(request: &Request, mut response: Response) -> Response {
+    if response.status == 200
+        && conditional::not_modified(
+            request,
+            response.header("ETag"),
+            response.header("Last-Modified"),
+        )
+    {
+        response.status = 304;
+        response.body = Vec::new().into();
+        // Stops the script, whose output is no longer wanted.
+        response.stream = None;
+    }
+    response
+}
+
+/// The body of a streamed script: what was read along with the headers,
+/// then the rest of its output. Ends in an error if the script fails or
+/// outlives its deadline.
+struct ScriptBody {
+    pending: Vec<u8>,
+    stdout: ChildStdout,
+    group: Group,
+    /// Owns the child, so dropping the body kills the script.
+    exit: Pin<Box<dyn Future<Output = io::Result<ExitStatus>> + Send>>,
+    /// When the script is killed, with the path and limit to log.
+    deadline: Option<(Pin<Box<Sleep>>, PathBuf, Duration)>,
+}
+
+impl AsyncRead for ScriptBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let body = &mut *self;
+        if let Some((deadline, path, limit)) = &mut body.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                timed_out(path, *limit);
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "script timed out",
+                )));
+            }
+        }
+        if !body.pending.is_empty() {
+            let count = body.pending.len().min(buf.remaining());
+            buf.put_slice(&body.pending[..count]);
+            body.pending.drain(..count);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        match Pin::new(&mut body.stdout).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled => {}
+            poll => return poll,
+        }
+        let exit = body.exit.as_mut().poll(cx);
+        if let Poll::Ready(Ok(_)) = exit {
+            body.group.disarm();
+        }
+        match exit {
+            Poll::Ready(Ok(status)) if status.success() => Poll::Ready(Ok(())),
+            Poll::Ready(Ok(status)) => Poll::Ready(Err(io::Error::other(format!(
+                "script exited with {status}"
+            )))),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// An `--interpreter` entry: scripts with `extension` are run by `program`,
+/// given `args` and then the script's path.
+pub struct Interpreter {
+    pub extension: String,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl std::str::FromStr for Interpreter {
+    type Err = String;
+
+    /// `EXT=PROGRAM`, with or without the dot, where `PROGRAM` may carry
+    /// arguments of its own, as in `.py=python3 -u`.
+    fn from_str(value: &str) -> Result<Interpreter, String> {
+        let invalid = || format!("invalid --interpreter {value}, expected EXT=PROGRAM");
+        let (extension, program) = value.split_once('=').ok_or_else(invalid)?;
+        let extension = extension.trim().trim_start_matches('.');
+        let mut words = program.split_whitespace().map(str::to_string);
+        let program = words.next().ok_or_else(invalid)?;
+        if extension.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Interpreter {
+            extension: extension.to_ascii_lowercase(),
+            program,
+            args: words.collect(),
+        })
+    }
+}
+
+/// The command running the script at `path`: through the interpreter
+/// mapped to its extension, if any, else as the platform would.
+fn command(path: &Path, interpreters: &[Interpreter]) -> Command {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    let interpreter = interpreters
+        .iter()
+        .find(|interpreter| Some(&interpreter.extension) == extension.as_ref());
+    let Some(interpreter) = interpreter else {
+        return default_command(path);
+    };
+    let mut command = Command::new(&interpreter.program);
+    command.args(&interpreter.args).arg(path);
+    command
+}
+
+/// The command running the script at `path`, which must be executable.
+#[cfg(not(windows))]
+fn default_command(path: &Path) -> Command {
+    Command::new(path)
+}
+
+/// The command running the script at `path`. Windows has no executable bit
+/// or shebang lines, so the interpreter is picked by extension; anything
+/// else is started directly.
+#[cfg(windows)]
+fn default_command(path: &Path) -> Command {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    let (program, args): (&str, &[&str]) = match extension.as_deref() {
+        Some("bat" | "cmd") => ("cmd", &["/C"]),
+        Some("ps1") => (
+            "powershell",
+            &["-NoProfile", "-ExecutionPolicy", "Bypass", "-File"],
+        ),
+        Some("py") => ("python", &[]),
+        Some("pl") => ("perl", &[]),
+        Some("sh") => ("sh", &[]),
+        _ => return Command::new(path),
+    };
+    let mut command = Command::new(program);
+    command.args(args).arg(path);
+    command
+}
+
+/// Splits script output into its header block and body.
+pub fn parse_output(output: &[u8]) -> Response {
+    let (head, body) = split_head(output);
+    let mut response = Response {
+        status: 200,
+        headers: Vec::new(),
+        body: body.to_vec().into(),
+        stream: None,
+        error_page: false,
+        upgrade: None,
+    };
+    for line in String::from_utf8_lossy(head).lines() {
+        if let Some((key, value)) = line.split_once(':') {
+            if is_framing(key.trim()) {
+                continue;
+            }
+            add_header(&mut response, key.trim(), value.trim());
+        }
+    }
+    if response.header("Content-type").is_none() {
+        response.set_header("Content-type", "text/plain; charset=utf-8");
+    }
+    cgi::apply_status(&mut response);
+    response
+}
+
+fn is_event_stream(response: &Response) -> bool {
+    let content_type = response.header("Content-type").unwrap_or_default();
+    content_type
+        .split(';')
+        .next()
+        .is_some_and(|media| media.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Fields a response has only once, so a later line replaces an earlier one.
+const SINGLE_FIELDS: [&str; 3] = ["Status", "Content-Type", "Location"];
+
+/// Adds a header of a script's response. Repeated headers, such as one
+/// `Set-Cookie` per cookie, are all kept.
+pub fn add_header(response: &mut Response, name: &str, value: impl Into<String>) {
+    match SINGLE_FIELDS
+        .iter()
+        .any(|single| single.eq_ignore_ascii_case(name))
+    {
+        true => response.set_header(name, value),
+        false => response.headers.push((name.to_string(), value.into())),
+    }
+}
+
+/// Headers the server sets itself from the body it sends.
+pub fn is_framing(name: &str) -> bool {
+    name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding")
+}
+
+/// Splits output at the first empty line (`\n\n` or `\r\n\r\n`). Output
+/// without one is treated as a body without headers.
+fn split_head(output: &[u8]) -> (&[u8], &[u8]) {
+    match find_head(output) {
+        Some((head_end, body_start)) => (&output[..head_end], &output[body_start..]),
+        None => (&output[..0], output),
+    }
+}
+
+/// Where the header block ends and the body starts, once the empty line
+/// after the headers is there.
+pub fn find_head(output: &[u8]) -> Option<(usize, usize)> {
+    let mut start = 0;
+    while let Some(offset) = output[start..].iter().position(|&byte| byte == b'\n') {
+        let end = start + offset;
+        if matches!(&output[start..end], b"" | b"\r") {
+            return Some((start, end + 1));
+        }
+        start = end + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values<'a>(response: &'a Response, name: &str) -> Vec<&'a str> {
+        response
+            .headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_repeated_headers() {
+        let response = parse_output(
+            b"Content-Type: text/html\r\nSet-Cookie: a=1\r\nset-cookie: b=2\r\n\
+              Content-Type: text/plain\r\n\r\nbody",
+        );
+        assert_eq!(values(&response, "Set-Cookie"), ["a=1", "b=2"]);
+        assert_eq!(values(&response, "Content-Type"), ["text/plain"]);
+        assert_eq!(&*response.body, b"body");
+    }
+
+    #[test]
+    fn reads_status_and_location() {
+        let response = parse_output(b"Status: 404 Not Found\nStatus: 403\n\n");
+        assert_eq!(response.status, 403);
+        assert!(values(&response, "Status").is_empty());
+        assert_eq!(
+            values(&response, "Content-Type"),
+            ["text/plain; charset=utf-8"]
+        );
+
+        let response = parse_output(b"Location: /a\nLocation: /b\n\n");
+        assert_eq!(response.status, 302);
+        assert_eq!(values(&response, "Location"), ["/b"]);
+    }
+
+    #[test]
+    fn drops_framing_headers() {
+        let response = parse_output(b"Content-Length: 1\nTransfer-Encoding: chunked\n\nbody");
+        assert!(values(&response, "Content-Length").is_empty());
+        assert!(values(&response, "Transfer-Encoding").is_empty());
+        // Output without a header block is all body.
+        assert_eq!(&*parse_output(b"just text").body, b"just text");
+    }
+}
@@ -0,0 +1,93 @@
+//! `?q=` search on directories, enabled with `--search`.
+//!
+//! File and directory names below the requested directory are matched
+//! case-insensitively; with `&content=1`, the contents of small text files
+//! are searched too. Results are an HTML list, or JSON for clients that
+//! prefer it.
+
+use std::path::{Path, PathBuf};
+
+use crate::files::{content_type, escape_html};
+use crate::http::{accept_quality, Request, Response};
+
+/// Text files larger than this are matched by name only.
+const MAX_CONTENT_SIZE: u64 = 256 * 1024;
+
+/// Results returned before the search stops.
+const MAX_RESULTS: usize = 200;
+
+/// Returns the URL paths of the entries below `dir` matching `query`.
+pub async fn search(dir: &Path, url_path: &str, query: &str, contents: bool) -> Vec<String> {
+    let needle = query.to_lowercase();
+    let base = url_path.trim_end_matches('/');
+    let mut results = Vec::new();
+    let mut pending: Vec<(PathBuf, String)> = vec![(dir.to_path_buf(), base.to_string())];
+    while let Some((dir, url)) = pending.pop() {
+        let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            // Symlinks are skipped, as they may lead outside the root.
+            let Ok(metadata) = tokio::fs::symlink_metadata(entry.path()).await else {
+                continue;
+            };
+            let entry_url = format!("{url}/{name}");
+            let matched = name.to_lowercase().contains(&needle)
+                || (contents
+                    && metadata.is_file()
+                    && contains_text(&entry.path(), metadata.len(), &needle).await);
+            if metadata.is_dir() {
+                pending.push((entry.path(), entry_url.clone()));
+                if matched {
+                    results.push(format!("{entry_url}/"));
+                }
+            } else if matched && metadata.is_file() {
+                results.push(entry_url);
+            }
+            if results.len() >= MAX_RESULTS {
+                results.sort();
+                return results;
+            }
+        }
+    }
+    results.sort();
+    results
+}
+
+async fn contains_text(path: &Path, len: u64, needle: &str) -> bool {
+    let content_type = content_type(path);
+    let is_text = content_type.starts_with("text/")
+        || content_type == "application/json"
+        || content_type == "application/xml";
+    if !is_text || len > MAX_CONTENT_SIZE {
+        return false;
+    }
+    match tokio::fs::read(path).await {
+        Ok(content) => String::from_utf8_lossy(&content)
+            .to_lowercase()
+            .contains(needle),
+        Err(_) => false,
+    }
+}
+
+pub fn render(request: &Request, query: &str, results: &[String]) -> Response {
+    let accept = request.header("Accept").unwrap_or_default();
+    if accept_quality(accept, "application/json") > accept_quality(accept, "text/html") {
+        let body = serde_json::json!({ "query": query, "results": results });
+        return Response::new(200, "application/json", body.to_string());
+    }
+
+    let mut html = format!("<html><h1>Search for {}</h1><ul>", escape_html(query));
+    for result in results {
+        html.push_str(&format!(
+            "<li><a href=\"{result}\">{result}</a></li>",
+            result = escape_html(result)
+        ));
+    }
+    html.push_str("</ul></html>");
+    Response::new(200, "text/html; charset=utf-8", html)
+}
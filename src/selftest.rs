@@ -0,0 +1,354 @@
+//! `rustywebserver selftest ROOT_FOLDER [OPTIONS]`: starts the server on an
+//! ephemeral port with the given options and checks that it behaves: a file
+//! of the root is served as it is on disk, missing files answer 404, hidden
+//! files and traversal answer 403, directories are listed with autoindex,
+//! and scripts see their environment and POST body. Scripts are checked on
+//! a probe script in a temporary root folder, so ROOT_FOLDER is never
+//! written to.
+
+use std::path::{Path, PathBuf};
+
+use tokio::net::TcpListener;
+
+use crate::config::Config;
+use crate::http;
+use crate::replay;
+use crate::server;
+
+pub const USAGE: &str = "Usage: rustywebserver selftest ROOT_FOLDER [OPTIONS]
+
+Starts the server on an ephemeral port with OPTIONS, which are those of the
+server itself, and reports which checks pass.";
+
+#[cfg(not(windows))]
+const PROBE: (&str, &str) = (
+    "selftest.sh",
+    "#!/bin/sh\necho 'Content-type: text/plain'\necho\n\
+     echo \"Method=$Method\"\necho \"Query_probe=$Query_probe\"\necho \"Selftest=$HTTP_SELFTEST\"\n",
+);
+
+#[cfg(windows)]
+const PROBE: (&str, &str) = (
+    "selftest.bat",
+    "@echo off\r\necho Content-type: text/plain\r\necho.\r\n\
+     echo Method=%Method%\r\necho Query_probe=%Query_probe%\r\necho Selftest=%HTTP_SELFTEST%\r\n",
+);
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(&'static str),
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn expect_status(&self, status: u16) -> Outcome {
+        match self.status == status {
+            true => Outcome::Pass,
+            false => Outcome::Fail(format!("expected {status}, got {}", self.status)),
+        }
+    }
+}
+
+/// Runs the subcommand. Returns whether every check passed.
+pub async fn run(args: impl IntoIterator<Item = String>) -> Result<bool, String> {
+    let mut args = args.into_iter();
+    let root = match args.next() {
+        Some(root) if !root.starts_with("--") => PathBuf::from(root),
+        _ => return Err(format!("expected ROOT_FOLDER\n\n{USAGE}")),
+    };
+    let options: Vec<String> = args.collect();
+
+    let config = load_config(&root, &options)?;
+    let autoindex = config.autoindex;
+    let address = start(config).await?;
+    let mut results = vec![
+        ("static file", static_file(&address, &root).await),
+        ("missing file", missing_file(&address).await),
+        ("hidden file", hidden_file(&address).await),
+        ("path traversal", traversal(&address).await),
+        (
+            "directory listing",
+            listing(&address, &root, autoindex).await,
+        ),
+    ];
+
+    let probe_root =
+        std::env::temp_dir().join(format!("rustywebserver-selftest-{}", std::process::id()));
+    let probe_config = load_config(&probe_root, &options)?;
+    let dir = probe_config.cgi_dirs[0].clone();
+    let scripts = match write_probe(&probe_root, &dir) {
+        Ok(()) => {
+            let address = start(probe_config).await?;
+            vec![
+                (
+                    "script environment",
+                    script_environment(&address, &dir).await,
+                ),
+                ("script POST body", script_post(&address, &dir).await),
+            ]
+        }
+        Err(err) => vec![(
+            "script environment",
+            Outcome::Fail(format!("cannot write probe script: {err}")),
+        )],
+    };
+    let _ = std::fs::remove_dir_all(&probe_root);
+    results.extend(scripts);
+
+    let mut failed = 0;
+    let mut skipped = 0;
+    println!();
+    for (name, outcome) in &results {
+        match outcome {
+            Outcome::Pass => println!("PASS {name}"),
+            Outcome::Fail(reason) => {
+                failed += 1;
+                println!("FAIL {name}: {reason}");
+            }
+            Outcome::Skip(reason) => {
+                skipped += 1;
+                println!("SKIP {name}: {reason}");
+            }
+        }
+    }
+    println!(
+        "{} passed, {failed} failed, {skipped} skipped",
+        results.len() - failed - skipped
+    );
+    Ok(failed == 0)
+}
+
+fn load_config(root: &Path, options: &[String]) -> Result<Config, String> {
+    let args = ["0".to_string(), root.to_string_lossy().into_owned()]
+        .into_iter()
+        .chain(options.iter().cloned());
+    let config = Config::from_args(args)?;
+    if config.tls_cert.is_some() || !config.acme_domains.is_empty() {
+        return Err(
+            "selftest can't check a server running with --tls-cert or --acme-domain".to_string(),
+        );
+    }
+    Ok(config)
+}
+
+/// Starts a server on an ephemeral loopback port and returns its address.
+async fn start(config: Config) -> Result<String, String> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(|err| err.to_string())?;
+    let address = listener.local_addr().map_err(|err| err.to_string())?;
+    server::spawn(config, listener)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(address.to_string())
+}
+
+async fn fetch(
+    address: &str,
+    method: &str,
+    target: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<Response, Outcome> {
+    let mut head = format!("{method} {target} HTTP/1.1\r\nHost: {address}\r\n");
+    for (key, value) in headers {
+        head.push_str(&format!("{key}: {value}\r\n"));
+    }
+    let (status, headers, body) = replay::exchange(address, head, body)
+        .await
+        .map_err(Outcome::Fail)?;
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+async fn static_file(address: &str, root: &Path) -> Outcome {
+    let Some(path) = first_file(root, root) else {
+        return Outcome::Skip("no file to request");
+    };
+    let Ok(content) = std::fs::read(root.join(path.trim_start_matches('/'))) else {
+        return Outcome::Fail(format!("cannot read {path}"));
+    };
+    let response = match fetch(address, "GET", &http::percent_encode_path(&path), &[], &[]).await {
+        Ok(response) => response,
+        Err(outcome) => return outcome,
+    };
+    if response.status != 200 {
+        return Outcome::Fail(format!("{path}: expected 200, got {}", response.status));
+    }
+    // HTML may be rewritten (CSP nonces, live reload), so only its status counts.
+    let transformed = response.header("Content-Encoding").is_some()
+        || response
+            .header("Content-type")
+            .is_some_and(|content_type| content_type.starts_with("text/html"));
+    match transformed || response.body == content {
+        true => Outcome::Pass,
+        false => Outcome::Fail(format!("{path}: body differs from the file")),
+    }
+}
+
+async fn missing_file(address: &str) -> Outcome {
+    let target = format!("/selftest-missing-{}", std::process::id());
+    match fetch(address, "GET", &target, &[], &[]).await {
+        Ok(response) => response.expect_status(404),
+        Err(outcome) => outcome,
+    }
+}
+
+async fn hidden_file(address: &str) -> Outcome {
+    match fetch(address, "GET", "/.selftest", &[], &[]).await {
+        Ok(response) => response.expect_status(403),
+        Err(outcome) => outcome,
+    }
+}
+
+async fn traversal(address: &str) -> Outcome {
+    match fetch(address, "GET", "/../../etc/passwd", &[], &[]).await {
+        Ok(response) => response.expect_status(403),
+        Err(outcome) => outcome,
+    }
+}
+
+async fn listing(address: &str, root: &Path, autoindex: bool) -> Outcome {
+    if !autoindex {
+        return Outcome::Skip("--autoindex is off");
+    }
+    let Some((dir, entry)) = unindexed_dir(root, root) else {
+        return Outcome::Skip("every directory has an index.html");
+    };
+    let response = match fetch(address, "GET", &http::percent_encode_path(&dir), &[], &[]).await {
+        Ok(response) => response,
+        Err(outcome) => return outcome,
+    };
+    if response.status != 200 {
+        return Outcome::Fail(format!("{dir}: expected 200, got {}", response.status));
+    }
+    match String::from_utf8_lossy(&response.body).contains(&entry) {
+        true => Outcome::Pass,
+        false => Outcome::Fail(format!("{dir}: listing does not mention {entry}")),
+    }
+}
+
+async fn script_environment(address: &str, dir: &str) -> Outcome {
+    let target = format!("{dir}{}?probe=get", PROBE.0);
+    let response = match fetch(address, "GET", &target, &[("Selftest", "yes")], &[]).await {
+        Ok(response) => response,
+        Err(outcome) => return outcome,
+    };
+    expect_output(
+        &response,
+        &["Method=GET", "Query_probe=get", "Selftest=yes"],
+    )
+}
+
+async fn script_post(address: &str, dir: &str) -> Outcome {
+    let target = format!("{dir}{}", PROBE.0);
+    let headers = [("Content-Type", "application/x-www-form-urlencoded")];
+    let response = match fetch(address, "POST", &target, &headers, b"probe=post").await {
+        Ok(response) => response,
+        Err(outcome) => return outcome,
+    };
+    expect_output(&response, &["Method=POST", "Query_probe=post"])
+}
+
+fn expect_output(response: &Response, lines: &[&str]) -> Outcome {
+    if response.status != 200 {
+        return Outcome::Fail(format!("expected 200, got {}", response.status));
+    }
+    let output = String::from_utf8_lossy(&response.body);
+    match lines
+        .iter()
+        .find(|line| !output.lines().any(|output| output.trim_end() == **line))
+    {
+        Some(line) => Outcome::Fail(format!("output lacks {line}")),
+        None => Outcome::Pass,
+    }
+}
+
+/// Writes the probe script into the folder of the CGI prefix `dir`.
+fn write_probe(root: &Path, dir: &str) -> std::io::Result<()> {
+    let scripts = root.join(dir.trim_matches('/'));
+    std::fs::create_dir_all(&scripts)?;
+    let path = scripts.join(PROBE.0);
+    std::fs::write(&path, PROBE.1)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Visible entries of `dir`, sorted so the checks are repeatable.
+fn entries(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut entries: Vec<(String, PathBuf)> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| {
+            (
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            )
+        })
+        .filter(|(name, _)| !name.starts_with('.'))
+        .collect();
+    entries.sort();
+    entries
+}
+
+/// The URL path of `path` below `root`.
+fn url_path(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let components: Vec<String> = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    format!("/{}", components.join("/"))
+}
+
+/// The first regular file below `dir`, outside the scripts folder.
+fn first_file(root: &Path, dir: &Path) -> Option<String> {
+    let entries = entries(dir);
+    let files = entries.iter().filter(|(_, path)| path.is_file());
+    if let Some((_, path)) = files.into_iter().next() {
+        return Some(url_path(root, path));
+    }
+    entries
+        .iter()
+        .filter(|(name, path)| path.is_dir() && !(dir == root && name == "scripts"))
+        .find_map(|(_, path)| first_file(root, path))
+}
+
+/// A directory without `index.html`, and one of its entries.
+fn unindexed_dir(root: &Path, dir: &Path) -> Option<(String, String)> {
+    let entries = entries(dir);
+    if !dir.join("index.html").exists() {
+        if let Some((name, _)) = entries.first() {
+            let mut path = url_path(root, dir);
+            if !path.ends_with('/') {
+                path.push('/');
+            }
+            return Some((path, name.clone()));
+        }
+    }
+    entries
+        .iter()
+        .filter(|(_, path)| path.is_dir())
+        .find_map(|(_, path)| unindexed_dir(root, path))
+}
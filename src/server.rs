@@ -0,0 +1,1117 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+use crate::access;
+use crate::acme::{Acme, Certificates, Challenges, CHALLENGE_PREFIX};
+use crate::admin;
+use crate::anonymize::{self, Anonymizer};
+use crate::assets::{self, AssetManifest};
+use crate::auth;
+use crate::bandwidth::{Bandwidth, Metered, Usage};
+use crate::bans::BanList;
+use crate::cache::{FileCache, ListingCache, ResponseCache};
+use crate::canonical;
+use crate::capture::Capture;
+use crate::coalesce::SingleFlight;
+use crate::compress;
+use crate::config::Config;
+use crate::crawl;
+use crate::dev;
+use crate::fastcgi;
+use crate::fdlimit::Backoff;
+use crate::files;
+use crate::forwarded;
+use crate::geoip::GeoIp;
+use crate::h2;
+use crate::handlers::{self, Handler};
+use crate::headers;
+use crate::http::{self, reason, Request, Response};
+use crate::inflate;
+use crate::kv::{self, KvStore};
+use crate::limits::{ConnectionGuard, ConnectionLimiter};
+use crate::livereload;
+use crate::locations::{self, Location};
+#[cfg(feature = "lua")]
+use crate::lua;
+use crate::maintenance::Maintenance;
+use crate::mirror;
+use crate::openfiles::OpenFileCache;
+use crate::overload::{Priority, Scheduler};
+use crate::panics;
+use crate::proxy;
+use crate::proxy_protocol;
+use crate::purge::{self, Pattern, Purge};
+use crate::redirect;
+use crate::redirect_map::RedirectMap;
+use crate::signed;
+use crate::thumbs::{self, Thumbnails};
+use crate::timing::Timing;
+use crate::tls::{self, TlsInfo};
+use crate::upload;
+use crate::vhost;
+use crate::watch::Watcher;
+use crate::wellknown;
+use crate::workers::Pools;
+
+/// Liveness endpoint, answered even in maintenance mode.
+const HEALTH_PATH: &str = "/healthz";
+
+/// Readiness endpoint for load balancers, which fails while draining.
+const READY_PATH: &str = "/readyz";
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `Allow` header of thumbnails.
+const THUMBNAIL_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// State shared by every connection. In sharded mode each shard has its
+/// own, so caches, bans and counters are never synchronized across cores.
+pub struct Server {
+    pub config: Arc<Config>,
+    /// The folders served, shared by every shard.
+    site: Arc<RwLock<Arc<Site>>>,
+    /// The sites of `--vhost`, in the same order.
+    vhosts: Arc<Vec<Arc<Site>>>,
+    pub bans: BanList,
+    pub connections: ConnectionLimiter,
+    /// Requests being handled, capped by `--max-active`.
+    pub scheduler: Scheduler,
+    pub geoip: Option<Arc<GeoIp>>,
+    /// Value of the Strict-Transport-Security header, when enabled.
+    pub hsts: Option<String>,
+    pub cache: FileCache,
+    pub open_files: OpenFileCache,
+    pub listings: ListingCache,
+    /// Responses of scripts, with `--script-cache`.
+    pub script_responses: ResponseCache,
+    /// Reads of uncached files in progress, shared by concurrent requests.
+    pub reads: SingleFlight,
+    pub assets: Option<Arc<AssetManifest>>,
+    pub maintenance: Maintenance,
+    /// Image previews for directory listings, with `--thumbnails`.
+    pub thumbnails: Option<Thumbnails>,
+    /// Watches the root folder with `--watch` or `--dev`.
+    pub watcher: Option<Arc<Watcher>>,
+    /// Purges requested through the admin API, delivered to every shard.
+    pub purges: broadcast::Sender<Purge>,
+    /// Wraps accepted connections with `--tls-cert` and `--tls-key`, or
+    /// with the `--acme-domain` certificate.
+    pub tls: Option<TlsAcceptor>,
+    /// Pending ACME challenges, with `--acme-domain`.
+    pub acme: Option<Arc<Challenges>>,
+    /// Records requests with `--capture`.
+    pub capture: Option<Arc<Capture>>,
+    /// Key-value store for scripts, with `--kv-store`.
+    pub kv: Option<Arc<KvStore>>,
+    /// Processes kept for scripts of the `worker` handler.
+    pub workers: Pools,
+    /// Set through the admin API before a planned restart; shared by every
+    /// shard.
+    pub draining: Arc<AtomicBool>,
+    /// Masks or hashes client addresses in the access log, with
+    /// `--anonymize-ips`.
+    pub anonymizer: Option<Arc<Anonymizer>>,
+    /// Bytes sent per host and their quotas; shared by every shard.
+    pub bandwidth: Arc<Bandwidth>,
+    /// Canonical `--lua-handlers` directory.
+    #[cfg(feature = "lua")]
+    pub lua_handlers: Option<PathBuf>,
+}
+
+/// The folders being served. `POST /_admin/root` replaces it as a whole, so
+/// a request sees either the old tree or the new one, never a mix.
+pub struct Site {
+    /// The `--vhost` name it is served for, unless it is the root folder's.
+    pub host: Option<String>,
+    /// Canonical form of the root folder.
+    pub root: PathBuf,
+    /// `root` followed by the canonical `--overlay-base` folders, in the
+    /// order static files are looked up.
+    pub roots: Vec<PathBuf>,
+    /// Rules from `_redirects` in the root folder.
+    pub redirects: RedirectMap,
+}
+
+impl Site {
+    fn new(host: Option<String>, root: PathBuf, overlay_bases: &[PathBuf]) -> Site {
+        let mut roots = vec![root.clone()];
+        roots.extend_from_slice(overlay_bases);
+        Site {
+            host,
+            redirects: RedirectMap::new(&root),
+            root,
+            roots,
+        }
+    }
+
+    /// Key of `path` in the caches kept by request path, which the sites
+    /// share.
+    pub fn cache_key(&self, path: &str) -> String {
+        match &self.host {
+            Some(host) => format!("//{host}{path}"),
+            None => path.to_string(),
+        }
+    }
+}
+
+impl Server {
+    /// The folders to serve the current request from. Take it once per
+    /// request so a root switch can't split it between two trees.
+    pub fn site(&self) -> Arc<Site> {
+        self.site.read().unwrap().clone()
+    }
+
+    /// The site of the request's `--vhost`, or else the root folder's.
+    pub fn site_for(&self, request: &Request) -> Arc<Site> {
+        match vhost::find(&self.config.vhosts, request) {
+            Some(index) => self.vhosts[index].clone(),
+            None => self.site(),
+        }
+    }
+
+    /// Every folder served, by the root folder's site or a virtual host's.
+    pub fn all_roots(&self) -> Vec<PathBuf> {
+        let mut roots = self.site().roots.clone();
+        for site in self.vhosts.iter() {
+            roots.extend(site.roots.iter().cloned());
+        }
+        roots
+    }
+
+    /// Serves the canonical folder `root` from now on, keeping the overlay
+    /// bases, and drops whatever the caches of every shard hold from the
+    /// old one.
+    pub async fn switch_root(&self, root: PathBuf) -> usize {
+        let site = Site::new(None, root, &self.site().roots[1..]);
+        *self.site.write().unwrap() = Arc::new(site);
+        purge::broadcast(&self.purges, Pattern::All).await
+    }
+}
+
+/// Read-only state loaded once at startup, from which each `Server` is built.
+struct Shared {
+    config: Arc<Config>,
+    site: Arc<RwLock<Arc<Site>>>,
+    vhosts: Arc<Vec<Arc<Site>>>,
+    geoip: Option<Arc<GeoIp>>,
+    hsts: Option<String>,
+    maintenance_page: Option<Vec<u8>>,
+    assets: Option<Arc<AssetManifest>>,
+    watcher: Option<Arc<Watcher>>,
+    purges: broadcast::Sender<Purge>,
+    tls: Option<TlsAcceptor>,
+    acme: Option<Arc<Challenges>>,
+    capture: Option<Arc<Capture>>,
+    kv: Option<Arc<KvStore>>,
+    draining: Arc<AtomicBool>,
+    anonymizer: Option<Arc<Anonymizer>>,
+    bandwidth: Arc<Bandwidth>,
+    #[cfg(feature = "lua")]
+    lua_handlers: Option<PathBuf>,
+}
+
+impl Shared {
+    fn server(&self) -> io::Result<Server> {
+        let config = &self.config;
+        let thumbnails = match config.thumbnails {
+            true => Some(Thumbnails::new(config.thumbnail_dir.clone())?),
+            false => None,
+        };
+        Ok(Server {
+            config: config.clone(),
+            site: self.site.clone(),
+            vhosts: self.vhosts.clone(),
+            bans: BanList::new(config.bans.clone()),
+            connections: ConnectionLimiter::new(
+                config.max_connections_per_ip,
+                config.connection_limit_exempt.clone(),
+            ),
+            scheduler: Scheduler::new(config.max_active, config.queue_size),
+            geoip: self.geoip.clone(),
+            hsts: self.hsts.clone(),
+            cache: FileCache::new(config.file_cache),
+            open_files: OpenFileCache::new(
+                config.open_file_cache,
+                Duration::from_secs(config.open_file_cache_valid),
+            ),
+            listings: ListingCache::default(),
+            script_responses: ResponseCache::default(),
+            reads: SingleFlight::default(),
+            assets: self.assets.clone(),
+            maintenance: Maintenance::new(
+                config.maintenance_flag.clone(),
+                self.maintenance_page.clone(),
+                config.maintenance_retry_after,
+            ),
+            thumbnails,
+            watcher: self.watcher.clone(),
+            purges: self.purges.clone(),
+            tls: self.tls.clone(),
+            acme: self.acme.clone(),
+            capture: self.capture.clone(),
+            kv: self.kv.clone(),
+            workers: Pools::default(),
+            draining: self.draining.clone(),
+            bandwidth: self.bandwidth.clone(),
+            anonymizer: self.anonymizer.clone(),
+            #[cfg(feature = "lua")]
+            lua_handlers: self.lua_handlers.clone(),
+        })
+    }
+}
+
+pub async fn run(config: Config) -> io::Result<()> {
+    let shared = prepare(config).await?;
+    if let Some(shards) = shared.config.shards {
+        return run_sharded(&shared, shards).await;
+    }
+
+    let port = shared.config.port;
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    println!("Server listening on 0.0.0.0:{port}");
+    serve(Arc::new(shared.server()?), listener).await
+}
+
+/// Serves `config` on `listener` in the background, for `selftest`.
+pub async fn spawn(config: Config, listener: TcpListener) -> io::Result<()> {
+    let shared = prepare(config).await?;
+    tokio::spawn(serve(Arc::new(shared.server()?), listener));
+    Ok(())
+}
+
+/// Loads everything the configuration refers to.
+async fn prepare(config: Config) -> io::Result<Shared> {
+    let config = Arc::new(config);
+    let root = config.root.canonicalize()?;
+    println!("Root folder: {}", root.display());
+
+    let geoip = match &config.geoip_db {
+        Some(path) => Some(Arc::new(GeoIp::open(path).map_err(io::Error::other)?)),
+        None => None,
+    };
+
+    let mut overlay_bases = Vec::new();
+    for base in &config.overlay_bases {
+        let base = base.canonicalize()?;
+        println!("Overlay base: {}", base.display());
+        overlay_bases.push(base);
+    }
+
+    let mut vhosts = Vec::new();
+    for vhost in &config.vhosts {
+        let root = vhost.root.canonicalize()?;
+        println!("Virtual host {}: {}", vhost.name, root.display());
+        let site = Site::new(Some(vhost.name.clone()), root, &overlay_bases);
+        vhosts.push(Arc::new(site));
+    }
+
+    let anonymizer = config
+        .anonymize_ips
+        .map(|mode| Arc::new(Anonymizer::new(mode)));
+    let acme = match config.acme_domains.is_empty() {
+        true => None,
+        false => Some(Arc::new(Challenges::default())),
+    };
+    if let Some(port) = config.https_redirect {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        println!("Redirecting HTTP on 0.0.0.0:{port} to HTTPS");
+        tokio::spawn(redirect::run(
+            listener,
+            root.clone(),
+            config.https_port,
+            anonymizer.clone(),
+            acme.clone(),
+        ));
+    }
+
+    let hsts = config
+        .hsts_max_age
+        .map(|max_age| match config.hsts_preload {
+            true => format!("max-age={max_age}; includeSubDomains; preload"),
+            false => format!("max-age={max_age}"),
+        });
+
+    let maintenance_page = match &config.maintenance_page {
+        Some(path) => Some(std::fs::read(path)?),
+        None => None,
+    };
+    let assets = match &config.asset_manifest {
+        Some(path) => Some(Arc::new(
+            AssetManifest::load(path).map_err(io::Error::other)?,
+        )),
+        None => None,
+    };
+    let watcher = match config.watch || config.dev {
+        true => Some(Arc::new(Watcher::new(&root).map_err(io::Error::other)?)),
+        false => None,
+    };
+
+    let tls = match (&config.tls_cert, &config.tls_key, &acme) {
+        (Some(cert), Some(key), _) => Some(tls::acceptor(
+            cert,
+            key,
+            config.http2,
+            config.tls_client_ca.as_deref(),
+        )?),
+        (_, _, Some(challenges)) => {
+            let certificates = Arc::new(Certificates::default());
+            tokio::spawn(Acme::new(&config, challenges.clone(), certificates.clone()).run());
+            Some(tls::resolving_acceptor(
+                certificates,
+                config.http2,
+                config.tls_client_ca.as_deref(),
+            )?)
+        }
+        _ => None,
+    };
+    let capture = match &config.capture {
+        Some(dir) => Some(Arc::new(Capture::new(dir.clone())?)),
+        None => None,
+    };
+    let kv = match config.kv_store {
+        true => Some(Arc::new(KvStore::open(
+            config.port,
+            config.kv_file.clone(),
+        )?)),
+        false => None,
+    };
+    let lua_handlers = match &config.lua_handlers {
+        Some(dir) => Some(dir.canonicalize()?),
+        None => None,
+    };
+    if let Some(dir) = &lua_handlers {
+        println!("Lua handlers: {}", dir.display());
+    }
+
+    let bandwidth = Arc::new(Bandwidth::new(&config.bandwidth_quotas));
+    Ok(Shared {
+        config,
+        site: Arc::new(RwLock::new(Arc::new(Site::new(None, root, &overlay_bases)))),
+        vhosts: Arc::new(vhosts),
+        geoip,
+        hsts,
+        maintenance_page,
+        assets,
+        watcher,
+        purges: broadcast::channel(16).0,
+        tls,
+        acme,
+        capture,
+        kv,
+        draining: Arc::default(),
+        anonymizer,
+        bandwidth,
+        #[cfg(feature = "lua")]
+        lua_handlers,
+    })
+}
+
+/// Runs `shards` single-threaded runtimes, one per core when 0, each with
+/// its own `SO_REUSEPORT` listener and its own `Server`.
+async fn run_sharded(shared: &Shared, shards: usize) -> io::Result<()> {
+    let shards = match shards {
+        0 => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+        shards => shards,
+    };
+    let port = shared.config.port;
+    let mut threads = Vec::new();
+    for shard in 0..shards {
+        let server = Arc::new(shared.server()?);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let thread = std::thread::Builder::new()
+            .name(format!("shard-{shard}"))
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let socket = TcpSocket::new_v4()?;
+                    socket.set_reuseaddr(true)?;
+                    #[cfg(unix)]
+                    socket.set_reuseport(true)?;
+                    socket.bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+                    serve(server, socket.listen(1024)?).await
+                })
+            })?;
+        threads.push(thread);
+    }
+    println!("Server listening on 0.0.0.0:{port} with {shards} shards");
+
+    tokio::task::spawn_blocking(move || {
+        for thread in threads {
+            match thread.join() {
+                Ok(result) => result?,
+                Err(_) => return Err(io::Error::other("shard panicked")),
+            }
+        }
+        Ok(())
+    })
+    .await?
+}
+
+async fn serve(server: Arc<Server>, listener: TcpListener) -> io::Result<()> {
+    if let Some(watcher) = &server.watcher {
+        tokio::spawn(invalidate_on_change(server.clone(), watcher.subscribe()));
+    }
+    tokio::spawn(purge_on_request(server.clone(), server.purges.subscribe()));
+
+    let mut backoff = Backoff::default();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                backoff.wait(&err).await;
+                continue;
+            }
+        };
+        backoff.reset();
+        let server = server.clone();
+        if !server.config.proxy_protocol {
+            if let Some(guard) = admit(&server, peer) {
+                tokio::spawn(serve_connection(server, stream, peer, guard));
+            }
+            continue;
+        }
+        tokio::spawn(async move {
+            let mut stream = stream;
+            let reading = proxy_protocol::read(&mut stream, peer);
+            let client = match timeout(proxy_protocol::TIMEOUT, reading).await {
+                Ok(Ok(client)) => client,
+                Ok(Err(err)) => {
+                    let balancer = anonymize::client(server.anonymizer.as_deref(), peer.ip());
+                    return eprintln!("connection from {balancer} failed: {err}");
+                }
+                Err(_) => return,
+            };
+            if let Some(guard) = admit(&server, client) {
+                serve_connection(server, stream, client, guard).await;
+            }
+        });
+    }
+}
+
+/// Whether to serve a connection from `peer`, given bans and connection
+/// limits; the guard keeps its place among the open ones until dropped.
+fn admit(server: &Server, peer: SocketAddr) -> Option<Option<ConnectionGuard>> {
+    if server.bans.is_banned(peer.ip()) {
+        return None;
+    }
+    let Ok(guard) = server.connections.acquire(peer.ip()) else {
+        let client = anonymize::client(server.anonymizer.as_deref(), peer.ip());
+        eprintln!("refused connection from {client}: too many open");
+        return None;
+    };
+    Some(guard)
+}
+
+async fn serve_connection(
+    server: Arc<Server>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    _guard: Option<ConnectionGuard>,
+) {
+    let handling = async {
+        match &server.tls {
+            Some(acceptor) => match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let info = Arc::new(TlsInfo::from_connection(stream.get_ref().1));
+                    match info.alpn.as_deref() == Some("h2") {
+                        true => h2::serve(server.clone(), stream, peer, info).await,
+                        false => handle_connection(&server, stream, peer, Some(info)).await,
+                    }
+                }
+                Ok(Err(err)) => Err(err),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake")),
+            },
+            None => handle_connection(&server, stream, peer, None).await,
+        }
+    };
+    let client = || anonymize::client(server.anonymizer.as_deref(), peer.ip());
+    match panics::catch(handling).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => eprintln!("connection from {} failed: {err}", client()),
+        Err(message) => eprintln!("panic on connection from {}: {message}", client()),
+    }
+}
+
+/// Drops cached state for every path the watcher reports as changed.
+async fn invalidate_on_change(server: Arc<Server>, mut changes: broadcast::Receiver<PathBuf>) {
+    loop {
+        match changes.recv().await {
+            Ok(path) => {
+                server.cache.invalidate(&path);
+                server.open_files.invalidate(&path);
+            }
+            Err(RecvError::Lagged(_)) => {
+                server.cache.clear();
+                server.open_files.clear();
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Applies purges sent through the admin API to this shard's caches.
+async fn purge_on_request(server: Arc<Server>, mut purges: broadcast::Receiver<Purge>) {
+    loop {
+        match purges.recv().await {
+            Ok(Purge { pattern, done }) => {
+                let removed = server.cache.purge(&server.all_roots(), &pattern)
+                    + server.open_files.purge(&pattern)
+                    + server.listings.purge(&pattern)
+                    + server.script_responses.purge(&pattern);
+                let _ = done.send(removed);
+            }
+            Err(RecvError::Lagged(_)) => {
+                server.cache.clear();
+                server.open_files.clear();
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Serves the requests of one connection until the client closes it or
+/// asks to, or sends no request within `--idle-timeout`.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    server: &Server,
+    stream: S,
+    peer: SocketAddr,
+    tls: Option<Arc<TlsInfo>>,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(Metered::new(stream));
+    loop {
+        let reading = http::read_head(&mut stream);
+        let head = match server.config.idle_timeout {
+            0 => reading.await?,
+            secs => match timeout(Duration::from_secs(secs), reading).await {
+                Ok(head) => head?,
+                Err(_) => return Ok(()),
+            },
+        };
+        let mut request = match head {
+            Some(Ok(request)) => request,
+            Some(Err(status)) => {
+                let mut response = Response::error(status);
+                return http::send_response(&mut stream, "HTTP/1.1", &mut response).await;
+            }
+            None => return Ok(()),
+        };
+        request.tls = tls.clone();
+        request.peer = Some(peer);
+        let usage = server.bandwidth.usage(&request);
+        stream.get_mut().charge_to(usage.clone());
+        if !handle_request(server, &mut stream, peer, request, &usage).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// Answers one request. Returns whether the connection may carry another.
+async fn handle_request<S: AsyncBufRead + AsyncWrite + Unpin>(
+    server: &Server,
+    stream: &mut S,
+    peer: SocketAddr,
+    mut request: Request,
+    usage: &Mutex<Usage>,
+) -> io::Result<bool> {
+    let timing = Timing::start();
+    let keep_alive = request.keep_alive() && !server.draining.load(Ordering::Relaxed);
+    let location = locations::find(&server.config.locations, &request.path);
+    let limit = server.config.max_body_size(location);
+    let reading = match limit.is_some_and(|limit| request.content_length() > limit) {
+        true => Err(413),
+        false => {
+            let buffer_size = server.config.body_buffer_size;
+            http::read_body(stream, &mut request, buffer_size, limit).await?
+        }
+    };
+    if let Err(status) = reading {
+        log_connection(server.anonymizer.as_deref(), &request, peer, status);
+        let mut response = Response::error(status);
+        response.negotiate_error(&request);
+        // The rest of the body is still on its way, so the connection can't
+        // be reused.
+        return respond(stream, &request, &mut response, false).await;
+    }
+    // A client that resets the connection stops its answer, and with it any
+    // script or upstream request working on it. One that only shuts down
+    // its side still gets the answer.
+    let (mut response, reusable) = tokio::select! {
+        answered = answer(server, &mut request, peer, usage, timing) => answered,
+        () = http::closed(stream) => {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("client left before {} was answered", request.path),
+            ));
+        }
+    };
+    if let Some(upgrade) = response.upgrade.take() {
+        response.set_header("Connection", "Upgrade");
+        http::send_response(stream, &request.version, &mut response).await?;
+        upgrade.run(stream).await?;
+        return Ok(false);
+    }
+    let sending = respond(stream, &request, &mut response, keep_alive && reusable);
+    match server.config.write_timeout(location) {
+        Some(limit) => timeout(limit, sending)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "writing response"))),
+        None => sending.await,
+    }
+}
+
+/// Produces the response to a request whose body has been read, over
+/// HTTP/1 or HTTP/2, and logs it. Also returns whether the connection may
+/// carry another request after it, which an event stream rules out.
+pub async fn answer(
+    server: &Server,
+    request: &mut Request,
+    peer: SocketAddr,
+    usage: &Mutex<Usage>,
+    mut timing: Timing,
+) -> (Response, bool) {
+    let peer = forwarded::client(&server.config.trusted_proxies, request, peer);
+    request.peer = Some(peer);
+    // Clients behind a trusted proxy can't be refused at accept time, and a
+    // ban may start while a connection is open.
+    if server.bans.is_banned(peer.ip()) {
+        log_connection(server.anonymizer.as_deref(), request, peer, 403);
+        let mut response = Response::error(403);
+        response.negotiate_error(request);
+        return (response, true);
+    }
+    let location = locations::find(&server.config.locations, &request.path);
+    if let Err(status) = inflate::decode_body(request, server.config.max_inflated_size) {
+        log_connection(server.anonymizer.as_deref(), request, peer, status);
+        let mut response = Response::error(status);
+        response.negotiate_error(request);
+        return (response, true);
+    }
+
+    if let Some(watcher) = &server.watcher {
+        if request.path == livereload::PATH {
+            log_connection(server.anonymizer.as_deref(), request, peer, 200);
+            return (livereload::events(watcher.subscribe()), false);
+        }
+    }
+
+    let priority = match request.path == HEALTH_PATH
+        || request.path == READY_PATH
+        || request.path.starts_with(admin::PREFIX)
+    {
+        true => Priority::Critical,
+        false => location
+            .and_then(|location| location.priority)
+            .unwrap_or(Priority::Normal),
+    };
+    if priority != Priority::Critical {
+        if let Err(mut response) = server.bandwidth.check(request, usage) {
+            log_connection(server.anonymizer.as_deref(), request, peer, response.status);
+            response.negotiate_error(request);
+            return (response, true);
+        }
+    }
+    let Ok(_slot) = server.scheduler.admit(priority).await else {
+        log_connection(server.anonymizer.as_deref(), request, peer, 503);
+        let mut response = Response::error(503);
+        response.set_header("Retry-After", "1");
+        response.negotiate_error(request);
+        return (response, true);
+    };
+    timing.mark("parse");
+    let started = Instant::now();
+    let mut response = match server.config.dev {
+        true => match dev::preflight(request) {
+            Some(response) => response,
+            None => route_isolated(server, request, peer, &mut timing).await,
+        },
+        false => route_isolated(server, request, peer, &mut timing).await,
+    };
+    timing.mark("route");
+    response.negotiate_error(request);
+    if server.config.dev {
+        livereload::inject(&mut response);
+        dev::apply(request, &mut response);
+    }
+    let compress = location
+        .and_then(|location| location.compress)
+        .unwrap_or(server.config.compression.enabled);
+    if compress {
+        compress::apply(&server.config.compression, request, &mut response);
+    }
+    // Browsers ignore it over plain HTTP, where anyone could have added it.
+    if let Some(hsts) = server.hsts.as_ref().filter(|_| request.tls.is_some()) {
+        response.set_header("Strict-Transport-Security", hsts.as_str());
+    }
+    if server.config.server_timing {
+        timing.mark("write");
+        timing.apply(&mut response);
+    }
+    log_connection(server.anonymizer.as_deref(), request, peer, response.status);
+    if server.config.dev {
+        dev::log_details(request, started.elapsed());
+    }
+    if matches!(response.status, 401 | 403) {
+        server
+            .bans
+            .record_failure(peer.ip(), response.status, &request.path);
+    }
+    if let Some(capture) = &server.capture {
+        capture.record(request, &response);
+    }
+    (response, true)
+}
+
+/// Sends `response` with the `Connection` header matching `keep_alive`,
+/// closing anyway when only the end of the connection can end the body.
+/// Returns whether the connection stays open.
+async fn respond<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &Request,
+    response: &mut Response,
+    keep_alive: bool,
+) -> io::Result<bool> {
+    if request.method == "HEAD" {
+        response.strip_body(&request.version);
+    }
+    let keep_alive = keep_alive && response.is_delimited(&request.version);
+    let connection = match keep_alive {
+        true => "keep-alive",
+        false => "close",
+    };
+    response.set_header("Connection", connection);
+    http::send_response(stream, &request.version, response).await?;
+    Ok(keep_alive)
+}
+
+/// Routes `request`, answering 500 if handling it panics.
+async fn route_isolated(
+    server: &Server,
+    request: &Request,
+    peer: SocketAddr,
+    timing: &mut Timing,
+) -> Response {
+    match panics::catch(route(server, request, peer, timing)).await {
+        Ok(response) => response,
+        Err(message) => {
+            let client = anonymize::client(server.anonymizer.as_deref(), peer.ip());
+            eprintln!(
+                "panic handling {} {} from {client}: {message}",
+                request.method, request.path
+            );
+            Response::error(500)
+        }
+    }
+}
+
+async fn route(
+    server: &Server,
+    request: &Request,
+    peer: SocketAddr,
+    timing: &mut Timing,
+) -> Response {
+    if let Some(response) = canonical::redirect(&server.config.host_redirects, request) {
+        return response;
+    }
+    if let (Some(challenges), Some(token)) =
+        (&server.acme, request.path.strip_prefix(CHALLENGE_PREFIX))
+    {
+        if let Some(response) = challenges.answer(token) {
+            return response;
+        }
+    }
+    if request.target == "*" {
+        return match request.method.as_str() {
+            "OPTIONS" => options(server_methods(server)),
+            _ => Response::error(400),
+        };
+    }
+    if request.path == HEALTH_PATH {
+        return Response::new(200, "text/plain; charset=utf-8", "ok\n");
+    }
+    if request.path == READY_PATH {
+        return match server.draining.load(Ordering::Relaxed) {
+            true => Response::new(503, "text/plain; charset=utf-8", "draining\n"),
+            false => Response::new(200, "text/plain; charset=utf-8", "ready\n"),
+        };
+    }
+    if request.path.starts_with(admin::PREFIX) {
+        return admin::handle(server, request).await;
+    }
+    if let Some(kv) = &server.kv {
+        if request.path.starts_with(kv::PREFIX) {
+            return kv.handle(request, peer.ip()).await;
+        }
+    }
+    if server.maintenance.is_active().await {
+        return server.maintenance.response();
+    }
+    if let Some(response) = server.site_for(request).redirects.find(request).await {
+        return response;
+    }
+
+    if let Some(thumbnails) = &server.thumbnails {
+        if let Some(image) = request.path.strip_prefix(thumbs::PREFIX) {
+            let image = format!("/{image}");
+            if let Err(response) = authorize(server, request, &image, peer) {
+                return response;
+            }
+            match request.method.as_str() {
+                "GET" | "HEAD" => {}
+                "OPTIONS" => return options(THUMBNAIL_METHODS.to_string()),
+                _ => return not_allowed(THUMBNAIL_METHODS.to_string()),
+            }
+            let site = server.site_for(request);
+            return timing
+                .measure("fs", thumbnails.serve(&site.roots, &image))
+                .await;
+        }
+    }
+
+    let location = match authorize(server, request, &request.path, peer) {
+        Ok(location) => location,
+        Err(response) => return response,
+    };
+    // Before `auth_request`: preflights carry no credentials.
+    if request.method == "OPTIONS" {
+        if let Some(methods) = allowed_methods(server, location, &request.path) {
+            return options(methods);
+        }
+    }
+    let authorized;
+    let request = match location {
+        Some(Location {
+            auth_request: Some(auth_request),
+            auth_headers,
+            ..
+        }) => match timing
+            .measure(
+                "proxy",
+                auth::check(server, auth_request, auth_headers, request),
+            )
+            .await
+        {
+            Ok(forwarded) => {
+                authorized = forwarded;
+                &authorized
+            }
+            Err(response) => return response,
+        },
+        _ => request,
+    };
+    if let Some(mirror) = location.and_then(|location| location.mirror.as_ref()) {
+        mirror::send(mirror, request);
+    }
+
+    #[cfg(feature = "lua")]
+    if let Some(dir) = &server.lua_handlers {
+        if let Some(name) = request.path.strip_prefix(lua::PREFIX) {
+            return timing
+                .measure("script", lua::handle(dir, name, request))
+                .await;
+        }
+    }
+
+    if let Some(route) = proxy::find(&server.config.proxies, &request.path) {
+        return timing
+            .measure(
+                "proxy",
+                proxy::forward(route, server.config.proxy_balance, request, peer),
+            )
+            .await;
+    }
+
+    if let Some(route) = fastcgi::find(&server.config.fastcgi, &request.path) {
+        let forwarding = fastcgi::forward(server, route, request);
+        return timing.measure("script", forwarding).await;
+    }
+
+    let handler = handler_for(&server.config, location, &request.path);
+    if let Some(handler @ (Handler::Cgi | Handler::Worker | Handler::Lua | Handler::WebSocket)) =
+        handler
+    {
+        let running = handlers::run(server, request, location, handler);
+        return timing.measure("script", running).await;
+    }
+
+    let writable = location.is_some_and(|location| location.writable);
+    if request.method == "PUT" && (writable || server.config.allow_put) {
+        return timing
+            .measure("fs", upload::put(server, request, location))
+            .await;
+    }
+    if request.method == "DELETE" && server.config.allow_delete {
+        return timing
+            .measure("fs", upload::delete(server, request, location))
+            .await;
+    }
+    if !matches!(request.method.as_str(), "GET" | "HEAD") {
+        let methods = allowed_methods(server, location, &request.path).unwrap_or_default();
+        return not_allowed(methods);
+    }
+
+    if upload::is_config_file(&request.path) {
+        return Response::error(404);
+    }
+
+    if request.path == wellknown::SECURITY_TXT_PATH {
+        if let Some(security_txt) = &server.config.security_txt {
+            return security_txt.response();
+        }
+    }
+
+    let (path, cache_control) = match &server.assets {
+        Some(assets) => match assets.resolve(&request.path) {
+            Some(fingerprinted) => (fingerprinted, Some(assets::REVALIDATE)),
+            None if assets.is_fingerprinted(&request.path) => {
+                (request.path.as_str(), Some(assets::IMMUTABLE))
+            }
+            None => (request.path.as_str(), None),
+        },
+        None => (request.path.as_str(), None),
+    };
+    let serving = files::serve(server, request, path, location);
+    let mut response = timing.measure("fs", serving).await;
+    if response.status == 404 && request.path == wellknown::FAVICON_PATH {
+        if let Some(fallback) = server.config.fallback_favicon {
+            return wellknown::favicon(fallback);
+        }
+    }
+    if response.status == 404 {
+        if request.path == crawl::ROBOTS_PATH && server.config.robots_txt {
+            return crawl::robots_txt(&server.config, request);
+        }
+        if request.path == crawl::SITEMAP_PATH && server.config.sitemap {
+            let site = server.site_for(request);
+            let sitemap = crawl::sitemap(&server.config, &site.roots, request);
+            return timing.measure("fs", sitemap).await;
+        }
+    }
+    if let Some(cache_control) = cache_control {
+        if matches!(response.status, 200 | 304) {
+            response.set_header("Cache-Control", cache_control);
+        }
+    }
+    headers::apply(&server.config.header_rules, &request.path, &mut response);
+    response
+}
+
+/// The handler for `path`: from `location`'s `handlers` table, if it has
+/// one, else the default.
+pub fn handler_for(config: &Config, location: Option<&Location>, path: &str) -> Option<Handler> {
+    match location.filter(|location| !location.handlers.is_empty()) {
+        Some(location) => handlers::find(&location.handlers, path),
+        None => Some(handlers::default(config, path)),
+    }
+}
+
+/// The `Allow` header for `path`, or `None` when a Lua handler answers and
+/// decides for itself.
+fn allowed_methods(server: &Server, location: Option<&Location>, path: &str) -> Option<String> {
+    #[cfg(feature = "lua")]
+    if server.lua_handlers.is_some() && path.starts_with(lua::PREFIX) {
+        return None;
+    }
+    match handler_for(&server.config, location, path) {
+        Some(Handler::Lua) => None,
+        Some(Handler::Cgi | Handler::Worker) => Some(handlers::CGI_METHODS.to_string()),
+        Some(Handler::WebSocket) => Some("GET".to_string()),
+        Some(Handler::Static) | None => {
+            let writable = location.is_some_and(|location| location.writable);
+            let mut methods = vec!["GET", "HEAD"];
+            if writable || server.config.allow_put {
+                methods.push("PUT");
+            }
+            if server.config.allow_delete {
+                methods.push("DELETE");
+            }
+            methods.push("OPTIONS");
+            Some(methods.join(", "))
+        }
+    }
+}
+
+/// The `Allow` header for `OPTIONS *`: every method some resource takes.
+fn server_methods(server: &Server) -> String {
+    let mut methods = vec!["GET", "HEAD", "POST"];
+    let writable = server
+        .config
+        .locations
+        .iter()
+        .any(|location| location.writable);
+    if writable || server.config.allow_put {
+        methods.push("PUT");
+    }
+    if server.config.allow_delete {
+        methods.push("DELETE");
+    }
+    methods.push("OPTIONS");
+    methods.join(", ")
+}
+
+fn options(methods: String) -> Response {
+    let mut response = Response::new(204, "text/plain", Vec::new());
+    response.set_header("Allow", methods);
+    response
+}
+
+fn not_allowed(methods: String) -> Response {
+    let mut response = Response::error(405);
+    response.set_header("Allow", methods);
+    response
+}
+
+/// Applies the access rules and URL signature of the location matching `path`.
+fn authorize<'a>(
+    server: &'a Server,
+    request: &Request,
+    path: &str,
+    peer: SocketAddr,
+) -> Result<Option<&'a Location>, Response> {
+    let location = locations::find(&server.config.locations, path);
+    if let Some(location) = location {
+        let ip = peer.ip();
+        if !access::is_allowed(&location.allow, &location.deny, ip, server.geoip.as_deref()) {
+            return Err(Response::error(403));
+        }
+        if let Some(key) = &location.signing_key {
+            if !signed::verify(key, path, &request.query) {
+                return Err(Response::error(403));
+            }
+        }
+    }
+    Ok(location)
+}
+
+/// Prints the access log line for a request, with the client address
+/// anonymized when `anonymizer` is given.
+pub fn log_connection(
+    anonymizer: Option<&Anonymizer>,
+    request: &Request,
+    peer: SocketAddr,
+    status: u16,
+) {
+    let tls = match &request.tls {
+        Some(tls) => format!(" [{}]", tls.summary()),
+        None => String::new(),
+    };
+    let client = anonymize::client(anonymizer, peer.ip());
+    println!(
+        "{} {client} {} -> {} ({}){tls}",
+        request.method,
+        request.path,
+        status,
+        reason(status)
+    );
+}
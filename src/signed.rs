@@ -0,0 +1,138 @@
+//! HMAC-signed, expiring URLs.
+//!
+//! A signed URL carries `expires` (a Unix timestamp) and `sig`, the
+//! lowercase hex HMAC-SHA256 of the path followed by `?expires=<expires>`,
+//! keyed with the location's `signing_key`:
+//!
+//! ```text
+//! printf '%s' '/downloads/report.pdf?expires=1700000000' | openssl dgst -sha256 -hmac KEY
+//! ```
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+
+use crate::http::parse_query;
+
+/// Checks the signature and expiry of a request for `path` with `query`.
+pub fn verify(key: &str, path: &str, query: &str) -> bool {
+    let params = parse_query(query);
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    };
+    let (Some(expires), Some(signature)) = (param("expires"), param("sig")) else {
+        return false;
+    };
+    let (Ok(expiry), Some(signature)) = (expires.parse::<u64>(), decode_hex(signature)) else {
+        return false;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    if now > expiry {
+        return false;
+    }
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    let message = format!("{path}?expires={expires}");
+    hmac::verify(&key, message.as_bytes(), &signature).is_ok()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "secret";
+
+    fn signature(key: &str, path: &str, expires: u64) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+        let tag = hmac::sign(&key, format!("{path}?expires={expires}").as_bytes());
+        tag.as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    fn in_an_hour() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600
+    }
+
+    #[test]
+    fn accepts_valid_signatures() {
+        let expires = in_an_hour();
+        let sig = signature(KEY, "/downloads/report.pdf", expires);
+        assert!(verify(
+            KEY,
+            "/downloads/report.pdf",
+            &format!("expires={expires}&sig={sig}")
+        ));
+        // Parameter order and other parameters don't matter.
+        let query = format!("sig={sig}&download=1&expires={expires}");
+        assert!(verify(KEY, "/downloads/report.pdf", &query));
+    }
+
+    #[test]
+    fn refuses_tampered_links() {
+        let expires = in_an_hour();
+        let sig = signature(KEY, "/downloads/report.pdf", expires);
+        let query = format!("expires={expires}&sig={sig}");
+        assert!(!verify(KEY, "/downloads/other.pdf", &query));
+        assert!(!verify(KEY, "/downloads/report.pdf/", &query));
+        assert!(!verify("other", "/downloads/report.pdf", &query));
+        let later = format!("expires={}&sig={sig}", expires + 1);
+        assert!(!verify(KEY, "/downloads/report.pdf", &later));
+        let last = match sig.ends_with('0') {
+            true => '1',
+            false => '0',
+        };
+        let flipped = format!("expires={expires}&sig={}{last}", &sig[..sig.len() - 1]);
+        assert!(!verify(KEY, "/downloads/report.pdf", &flipped));
+    }
+
+    #[test]
+    fn refuses_expired_links() {
+        let expires = in_an_hour() - 7200;
+        let sig = signature(KEY, "/a", expires);
+        assert!(!verify(KEY, "/a", &format!("expires={expires}&sig={sig}")));
+    }
+
+    #[test]
+    fn refuses_malformed_signatures() {
+        let expires = in_an_hour();
+        let sig = signature(KEY, "/a", expires);
+        for bad in [
+            &sig[..sig.len() - 1],
+            &sig[..sig.len() - 2],
+            &format!("{sig}00"),
+            &format!("zz{}", &sig[2..]),
+            "",
+        ] {
+            assert!(
+                !verify(KEY, "/a", &format!("expires={expires}&sig={bad}")),
+                "{bad}"
+            );
+        }
+        assert!(!verify(KEY, "/a", &format!("sig={sig}")));
+        assert!(!verify(KEY, "/a", &format!("expires={expires}")));
+        assert!(!verify(KEY, "/a", &format!("expires=soon&sig={sig}")));
+    }
+}
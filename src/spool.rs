@@ -0,0 +1,129 @@
+//! Request bodies larger than `--body-buffer-size` are written to a
+//! temporary file as they arrive and memory-mapped once complete, so a large
+//! upload lives in the page cache instead of the server's heap.
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::http::Body;
+
+/// Default `--body-buffer-size`.
+pub const DEFAULT_THRESHOLD: u64 = 1024 * 1024;
+
+/// Reads a body of `length` bytes, and not a byte more, so a pipelined
+/// request stays in `stream`. Bodies above `threshold` go through a
+/// temporary file. A body cut short by the client is returned as far as it
+/// got.
+pub async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    length: usize,
+    threshold: u64,
+) -> io::Result<Body> {
+    let mut spool = Spool::new(threshold);
+    let mut chunk = vec![0u8; 64 * 1024];
+    while spool.received() < length {
+        let wanted = chunk.len().min(length - spool.received());
+        let read = stream.read(&mut chunk[..wanted]).await?;
+        if read == 0 {
+            break;
+        }
+        spool.write(&chunk[..read]).await?;
+    }
+    spool.finish().await
+}
+
+/// A body being received: kept in memory up to the threshold, then moved
+/// to a temporary file.
+pub struct Spool {
+    threshold: u64,
+    memory: Vec<u8>,
+    file: Option<File>,
+    len: usize,
+}
+
+impl Spool {
+    pub fn new(threshold: u64) -> Spool {
+        Spool {
+            threshold,
+            memory: Vec::new(),
+            file: None,
+            len: 0,
+        }
+    }
+
+    /// Bytes written so far.
+    pub fn received(&self) -> usize {
+        self.len
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.len += bytes.len();
+        if self.file.is_none() && self.len as u64 > self.threshold {
+            let mut file = create().await?;
+            file.write_all(&std::mem::take(&mut self.memory)).await?;
+            self.file = Some(file);
+        }
+        match &mut self.file {
+            Some(file) => file.write_all(bytes).await,
+            None => {
+                self.memory.extend_from_slice(bytes);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn finish(self) -> io::Result<Body> {
+        let Some(mut file) = self.file else {
+            return Ok(Body::Owned(self.memory));
+        };
+        file.flush().await?;
+        let file = file.into_std().await;
+        // SAFETY: nobody else knows about the file (it is already unlinked on
+        // unix), so it cannot be truncated while mapped.
+        let mapped = unsafe { Mmap::map(&file)? };
+        Ok(Body::Shared(Arc::new(mapped)))
+    }
+}
+
+/// A fresh path in the temporary folder, `rustywebserver-KIND-PID-N`.
+pub fn temp_path(kind: &str) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    env::temp_dir().join(format!(
+        "rustywebserver-{kind}-{}-{}",
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Options creating a new file only the server's user can read.
+pub fn private_file() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options
+}
+
+/// Creates a temporary file that disappears with its last handle.
+async fn create() -> io::Result<File> {
+    let path = temp_path("body");
+    #[cfg_attr(not(windows), allow(unused_mut))]
+    let mut options = private_file();
+    #[cfg(windows)]
+    options.custom_flags(FILE_FLAG_DELETE_ON_CLOSE);
+    let file = options.open(&path).await?;
+    #[cfg(unix)]
+    tokio::fs::remove_file(&path).await?;
+    Ok(file)
+}
+
+#[cfg(windows)]
+const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
@@ -0,0 +1,100 @@
+//! Thumbnails for images in directory listings, enabled with `--thumbnails`.
+//!
+//! `/_thumb/<path>` answers a small PNG preview of the image at `<path>`,
+//! generated on first request and kept in the thumbnail directory. Cached
+//! previews are keyed by the image's path, size and mtime, so replacing an
+//! image produces a new one.
+
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+
+use image::ImageFormat;
+use ring::digest;
+use tokio::io::AsyncReadExt;
+
+use crate::cache;
+use crate::http::Response;
+use crate::resolve::{self, resolve_layered};
+
+pub const PREFIX: &str = "/_thumb/";
+
+/// Width and height previews are scaled to fit.
+const SIZE: u32 = 128;
+
+/// Images larger than this get no preview.
+const MAX_SOURCE_SIZE: u64 = 32 * 1024 * 1024;
+
+pub struct Thumbnails {
+    dir: PathBuf,
+}
+
+impl Thumbnails {
+    pub fn new(dir: PathBuf) -> io::Result<Thumbnails> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Thumbnails { dir })
+    }
+
+    /// Serves the preview of the image at the decoded request `path`.
+    pub async fn serve(&self, roots: &[PathBuf], path: &str) -> Response {
+        let image = match resolve_layered(roots, path, &[]).await {
+            Ok(image) if !image.is_dir && is_image(path) => image,
+            Ok(_) => return Response::error(404),
+            Err(err) => return Response::error(err.status()),
+        };
+        if image.metadata.len() > MAX_SOURCE_SIZE {
+            return Response::error(404);
+        }
+
+        let key = format!("{}{}", image.path.display(), cache::etag(&image.metadata));
+        let hash = digest::digest(&digest::SHA256, key.as_bytes());
+        let name: String = hash
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let cached = self.dir.join(format!("{name}.png"));
+
+        let preview = match tokio::fs::read(&cached).await {
+            Ok(preview) => preview,
+            Err(_) => {
+                let mut source = Vec::new();
+                let read = async {
+                    resolve::open(&image.path)
+                        .await?
+                        .read_to_end(&mut source)
+                        .await
+                };
+                if read.await.is_err() {
+                    return Response::error(500);
+                }
+                let Ok(Ok(preview)) = tokio::task::spawn_blocking(move || render(&source)).await
+                else {
+                    return Response::error(404);
+                };
+                let partial = cached.with_extension("tmp");
+                if tokio::fs::write(&partial, &preview).await.is_ok() {
+                    let _ = tokio::fs::rename(&partial, &cached).await;
+                }
+                preview
+            }
+        };
+
+        let mut response = Response::new(200, "image/png", preview);
+        response.set_header("Cache-Control", "public, max-age=86400");
+        response
+    }
+}
+
+pub fn is_image(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    [".jpg", ".jpeg", ".png", ".gif"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+fn render(source: &[u8]) -> image::ImageResult<Vec<u8>> {
+    let thumbnail = image::load_from_memory(source)?.thumbnail(SIZE, SIZE);
+    let mut png = Vec::new();
+    thumbnail.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
@@ -0,0 +1,54 @@
+//! `Server-Timing` response header for `--server-timing`: how long reading
+//! the request, routing it and the handler (`fs`, `script` or `proxy`) took,
+//! and `write`, the time left between the handler and the first byte sent.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::http::Response;
+
+/// Phase durations of one request, in the order they first happened.
+pub struct Timing {
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timing {
+    pub fn start() -> Timing {
+        Timing {
+            last: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Charges the time since the previous mark to `phase`.
+    pub fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    /// Charges the time so far to `route` and the time `handler` takes to
+    /// `phase`.
+    pub async fn measure<T>(&mut self, phase: &'static str, handler: impl Future<Output = T>) -> T {
+        self.mark("route");
+        let output = handler.await;
+        self.mark(phase);
+        output
+    }
+
+    /// Sets the header, as `parse;dur=0.120, route;dur=0.015, ...` in
+    /// milliseconds.
+    pub fn apply(&self, response: &mut Response) {
+        let value: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(name, duration)| format!("{name};dur={:.3}", duration.as_secs_f64() * 1000.0))
+            .collect();
+        response.set_header("Server-Timing", value.join(", "));
+    }
+}
@@ -0,0 +1,205 @@
+//! HTTPS with `--tls-cert` and `--tls-key`, or with `--acme-domain`, using
+//! rustls. With `--tls-client-ca`, clients must also present a certificate
+//! issued by one of the CAs in that file.
+//!
+//! The parameters negotiated for each connection are kept with its requests,
+//! so they show up in the access log and in the environment of scripts.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ResolvesServerCert, WantsServerCert, WebPkiClientVerifier};
+use rustls::{ConfigBuilder, RootCertStore, ServerConfig, ServerConnection};
+use tokio_rustls::TlsAcceptor;
+
+use crate::der;
+use crate::h2;
+
+/// Loads the PEM certificate chain and private key into an acceptor, which
+/// offers HTTP/2 as well as HTTP/1.1 with `http2`, and requires client
+/// certificates issued by the PEM CAs of `client_ca`.
+pub fn acceptor(
+    cert: &Path,
+    key: &Path,
+    http2: bool,
+    client_ca: Option<&Path>,
+) -> io::Result<TlsAcceptor> {
+    let chain = load_certificates(cert)?;
+    let key_der = PrivateKeyDer::from_pem_file(key).map_err(|err| invalid(key, err))?;
+
+    let config = builder(client_ca)?
+        .with_single_cert(chain, key_der)
+        .map_err(|err| io::Error::other(format!("invalid certificate or key: {err}")))?;
+    Ok(finish(config, http2))
+}
+
+/// An acceptor presenting whatever certificate `resolver` holds at the time
+/// of each handshake.
+pub fn resolving_acceptor(
+    resolver: Arc<dyn ResolvesServerCert>,
+    http2: bool,
+    client_ca: Option<&Path>,
+) -> io::Result<TlsAcceptor> {
+    Ok(finish(
+        builder(client_ca)?.with_cert_resolver(resolver),
+        http2,
+    ))
+}
+
+fn builder(client_ca: Option<&Path>) -> io::Result<ConfigBuilder<ServerConfig, WantsServerCert>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let Some(client_ca) = client_ca else {
+        return Ok(builder.with_no_client_auth());
+    };
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(client_ca)? {
+        roots.add(certificate).map_err(|err| {
+            io::Error::other(format!("invalid CA in {}: {err}", client_ca.display()))
+        })?;
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .build()
+        .map_err(|err| io::Error::other(format!("{}: {err}", client_ca.display())))?;
+    Ok(builder.with_client_cert_verifier(verifier))
+}
+
+fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .map_err(|err| invalid(path, err))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid(path, err))
+}
+
+fn invalid(path: &Path, err: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::other(format!("cannot load {}: {err}", path.display()))
+}
+
+fn finish(mut config: ServerConfig, http2: bool) -> TlsAcceptor {
+    config.alpn_protocols = match http2 {
+        true => vec![h2::ALPN.to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// What a TLS handshake settled on.
+#[derive(Debug)]
+pub struct TlsInfo {
+    /// `TLSv1.2` or `TLSv1.3`.
+    pub protocol: String,
+    /// IANA name of the cipher suite, such as `TLS13_AES_128_GCM_SHA256`.
+    pub cipher: String,
+    /// Server name sent by the client.
+    pub sni: Option<String>,
+    /// Application protocol agreed through ALPN.
+    pub alpn: Option<String>,
+    /// The verified certificate the client presented, with `--tls-client-ca`.
+    pub client: Option<ClientCert>,
+}
+
+/// Identity of a client certificate.
+#[derive(Debug)]
+pub struct ClientCert {
+    /// Subject distinguished name, such as `CN=alice,O=Example`.
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub issuer: String,
+    /// Serial number in upper-case hexadecimal.
+    pub serial: String,
+}
+
+impl ClientCert {
+    fn parse(certificate: &[u8]) -> Option<ClientCert> {
+        let fields = der::certificate_fields(certificate)?;
+        let serial = fields.first()?.1;
+        // A leading zero only keeps the integer positive.
+        let serial = serial
+            .strip_prefix(&[0])
+            .filter(|rest| !rest.is_empty())
+            .unwrap_or(serial);
+        let issuer = fields.get(2)?.1;
+        let subject = fields.get(4)?.1;
+        Some(ClientCert {
+            subject: der::distinguished_name(subject)?,
+            common_name: der::name_attribute(subject, "CN"),
+            issuer: der::distinguished_name(issuer)?,
+            serial: serial.iter().map(|byte| format!("{byte:02X}")).collect(),
+        })
+    }
+}
+
+impl TlsInfo {
+    pub fn from_connection(connection: &ServerConnection) -> TlsInfo {
+        let protocol = match connection.protocol_version() {
+            Some(rustls::ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+            Some(rustls::ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+            Some(other) => format!("{other:?}"),
+            None => "unknown".to_string(),
+        };
+        let cipher = connection
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+            .unwrap_or("unknown")
+            .to_string();
+        TlsInfo {
+            protocol,
+            cipher,
+            sni: connection.server_name().map(str::to_string),
+            alpn: connection
+                .alpn_protocol()
+                .map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+            client: connection
+                .peer_certificates()
+                .and_then(|chain| ClientCert::parse(chain.first()?)),
+        }
+    }
+
+    /// Environment variables for scripts, named as in Apache's mod_ssl.
+    pub fn env(&self) -> Vec<(&'static str, &str)> {
+        let mut env = vec![
+            ("HTTPS", "on"),
+            ("SSL_PROTOCOL", self.protocol.as_str()),
+            ("SSL_CIPHER", self.cipher.as_str()),
+        ];
+        if let Some(sni) = &self.sni {
+            env.push(("SSL_TLS_SNI", sni));
+        }
+        if let Some(alpn) = &self.alpn {
+            env.push(("SSL_ALPN", alpn));
+        }
+        match &self.client {
+            Some(client) => {
+                env.push(("SSL_CLIENT_VERIFY", "SUCCESS"));
+                env.push(("SSL_CLIENT_S_DN", &client.subject));
+                env.push(("SSL_CLIENT_I_DN", &client.issuer));
+                env.push(("SSL_CLIENT_M_SERIAL", &client.serial));
+                if let Some(common_name) = &client.common_name {
+                    env.push(("SSL_CLIENT_S_DN_CN", common_name));
+                }
+            }
+            None => env.push(("SSL_CLIENT_VERIFY", "NONE")),
+        }
+        env
+    }
+
+    /// Short form for the access log.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} {} sni={} alpn={}",
+            self.protocol,
+            self.cipher,
+            self.sni.as_deref().unwrap_or("-"),
+            self.alpn.as_deref().unwrap_or("-")
+        );
+        if let Some(client) = &self.client {
+            summary.push_str(&format!(" client={}", client.subject));
+        }
+        summary
+    }
+}
@@ -0,0 +1,355 @@
+//! `PUT` into locations marked `writable`, or anywhere with `--allow-put`.
+//!
+//! A request without `Content-Range` replaces the whole file. With
+//! `Content-Range: bytes START-END/TOTAL` (or `/*`) the body is written at
+//! `START`, leaving any gap before it as a hole, so large files can be sent
+//! in segments, in parallel or resumed. A known `TOTAL` sets the final file
+//! size. Missing parent directories are created; writes of files that would
+//! be run, in the scripts folders, with a `--cgi-extension`, matching a
+//! `--fastcgi` pattern or mapped to a handler other than `static`, are
+//! refused with `403`, so an upload can't become runnable code. So are
+//! writes of the files in the root folder that configure the server, such
+//! as `_redirects`.
+//!
+//! Uploads are refused with `413` above the location's `max_upload_size`,
+//! and with `507` when they would take the location's directory past its
+//! `quota` or the whole root folder past `--upload-quota`.
+//!
+//! A `Content-Digest` sent with the body (or a `Repr-Digest`, for whole-file
+//! uploads) is checked before anything is written, and so are `If-Match` and
+//! `If-Unmodified-Since` (`412` when they fail).
+//!
+//! `DELETE`, with `--allow-delete`, removes a file, a symlink (not what it
+//! points to) or an empty directory, under the same rules.
+
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::conditional;
+use crate::digest;
+use crate::handlers;
+use crate::http::{Request, Response};
+use crate::locations::Location;
+use crate::redirect_map;
+use crate::resolve::{normalize, resolve, resolve_write, ResolveError};
+use crate::server::Server;
+
+/// Files in the root folder that configure the server rather than being
+/// served.
+const CONFIG_FILES: [&str; 1] = [redirect_map::FILE];
+
+struct ContentRange {
+    start: u64,
+    end: u64,
+    total: Option<u64>,
+}
+
+pub async fn put(server: &Server, request: &Request, location: Option<&Location>) -> Response {
+    let range = match request.header("Content-Range") {
+        Some(value) => match parse_content_range(value) {
+            Some(range) => Some(range),
+            None => return Response::error(400),
+        },
+        None => None,
+    };
+    if let Some(status) = range
+        .as_ref()
+        .and_then(|range| refusal(range, request.body.len() as u64))
+    {
+        return Response::error(status);
+    }
+
+    let content_digest = request.header("Content-Digest");
+    let repr_digest = request.header("Repr-Digest").filter(|_| range.is_none());
+    let digests_match = [content_digest, repr_digest]
+        .into_iter()
+        .flatten()
+        .all(|field| digest::verify(field, &request.body));
+    if !digests_match {
+        return Response::error(400);
+    }
+
+    if is_config_file(&request.path) {
+        return Response::error(403);
+    }
+    let root = server.site_for(request).root.clone();
+    let path = match resolve_write(&root, &request.path).await {
+        Ok(path) => path,
+        Err(ResolveError::NotFound) => return Response::error(409),
+        Err(err) => return Response::error(err.status()),
+    };
+    if handlers::is_executable(&server.config, location, &root, &path) {
+        return Response::error(403);
+    }
+    let existing = match tokio::fs::symlink_metadata(&path).await {
+        Ok(metadata) if metadata.is_file() => Some(metadata),
+        Ok(_) => return Response::error(409),
+        Err(_) => None,
+    };
+    if !conditional::write_allowed(request, existing.as_ref()) {
+        return Response::error(412);
+    }
+    let existed = existing.is_some();
+    let old_size = existing.map_or(0, |metadata| metadata.len());
+
+    let body = request.body.len() as u64;
+    let new_size = match &range {
+        Some(range) => range
+            .total
+            .unwrap_or_else(|| old_size.max(range.start + body)),
+        None => body,
+    };
+    if location
+        .and_then(|location| location.max_upload_size)
+        .is_some_and(|max| new_size > max)
+    {
+        return Response::error(413);
+    }
+    let growth = new_size.saturating_sub(old_size);
+    if growth > 0 {
+        if let Some((location, Some(quota))) = location.map(|location| (location, location.quota)) {
+            let directory = match resolve(&root, &location.path).await {
+                Ok(resolved) => resolved.path,
+                Err(_) => root.clone(),
+            };
+            if disk_usage(&directory).await + growth > quota {
+                return Response::error(507);
+            }
+        }
+        if let Some(quota) = server.config.upload_quota {
+            if disk_usage(&root).await + growth > quota {
+                return Response::error(507);
+            }
+        }
+    }
+
+    let result = write(&path, range.as_ref(), &request.body).await;
+    server.cache.invalidate(&path);
+    server.open_files.invalidate(&path);
+    if let Err(err) = result {
+        eprintln!("cannot write {}: {err}", path.display());
+        return Response::error(500);
+    }
+
+    match existed {
+        true => Response::new(204, "text/plain", Vec::new()),
+        false => {
+            let mut response = Response::new(201, "text/plain", "Created\n");
+            response.set_header("Location", request.target.as_str());
+            response
+        }
+    }
+}
+
+pub async fn delete(server: &Server, request: &Request, location: Option<&Location>) -> Response {
+    let root = server.site_for(request).root.clone();
+    let relative = match normalize(&request.path) {
+        Ok(relative) => relative,
+        Err(err) => return Response::error(err.status()),
+    };
+    let Some(name) = relative.file_name() else {
+        return Response::error(403);
+    };
+    if is_config_file(&request.path) {
+        return Response::error(403);
+    }
+    let parent = relative.parent().unwrap_or(Path::new(""));
+    let path = match resolve(&root, &parent.to_string_lossy()).await {
+        Ok(parent) if parent.is_dir => parent.path.join(name),
+        Ok(_) => return Response::error(404),
+        Err(err) => return Response::error(err.status()),
+    };
+    if handlers::is_executable(&server.config, location, &root, &path) {
+        return Response::error(403);
+    }
+    let metadata = match tokio::fs::symlink_metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(_) => return Response::error(404),
+    };
+    if !conditional::write_allowed(request, Some(&metadata)) {
+        return Response::error(412);
+    }
+
+    let result = match metadata.is_dir() {
+        true => tokio::fs::remove_dir(&path).await,
+        false => tokio::fs::remove_file(&path).await,
+    };
+    server.cache.invalidate(&path);
+    server.open_files.invalidate(&path);
+    match result {
+        Ok(()) => Response::new(204, "text/plain", Vec::new()),
+        // Only empty directories are removed.
+        Err(_) if metadata.is_dir() => Response::error(409),
+        Err(err) => {
+            eprintln!("cannot delete {}: {err}", path.display());
+            Response::error(500)
+        }
+    }
+}
+
+/// Total size of the files below `directory`, not following symlinks.
+async fn disk_usage(directory: &Path) -> u64 {
+    let mut total = 0;
+    let mut pending: Vec<PathBuf> = vec![directory.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        let Ok(mut entries) = tokio::fs::read_dir(&directory).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Ok(metadata) = tokio::fs::symlink_metadata(entry.path()).await else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                total += metadata.len();
+            }
+        }
+    }
+    total
+}
+
+/// Whether the request `path` names one of `CONFIG_FILES`, however it is
+/// spelled.
+pub fn is_config_file(path: &str) -> bool {
+    normalize(path).is_ok_and(|relative| {
+        CONFIG_FILES
+            .iter()
+            .any(|file| relative.as_path() == Path::new(file))
+    })
+}
+
+/// Writes `body` to `path`, as the whole file or at `range`.
+async fn write(path: &Path, range: Option<&ContentRange>, body: &[u8]) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(range.is_none());
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    let mut file = options.open(path).await?;
+    if let Some(range) = range {
+        file.seek(SeekFrom::Start(range.start)).await?;
+    }
+    file.write_all(body).await?;
+    if let Some(total) = range.and_then(|range| range.total) {
+        file.set_len(total).await?;
+    }
+    file.flush().await
+}
+
+/// The status refusing `range` for a body of `length` bytes: `400` when
+/// their lengths differ, `416` when it ends past its total.
+fn refusal(range: &ContentRange, length: u64) -> Option<u16> {
+    if range.end - range.start + 1 != length {
+        return Some(400);
+    }
+    if range.total.is_some_and(|total| range.end >= total) {
+        return Some(416);
+    }
+    None
+}
+
+/// Parses `bytes START-END/TOTAL`, where `TOTAL` may be `*`.
+fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let (range, total) = value.strip_prefix("bytes ")?.trim().split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = end.parse().ok()?;
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    (start <= end).then_some(ContentRange { start, end, total })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::spool;
+
+    fn range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+        parse_content_range(value).map(|range| (range.start, range.end, range.total))
+    }
+
+    #[test]
+    fn parses_content_ranges() {
+        for (value, expected) in [
+            ("bytes 0-499/1000", Some((0, 499, Some(1000)))),
+            ("bytes 500-999/*", Some((500, 999, None))),
+            ("bytes 7-7/8", Some((7, 7, Some(8)))),
+            // An end past the total parses, and is refused with 416.
+            ("bytes 0-1000/1000", Some((0, 1000, Some(1000)))),
+            ("bytes 5-4/10", None),
+            ("bytes */1000", None),
+            ("bytes 0-499", None),
+            ("bytes -499/1000", None),
+            ("bytes 0-/1000", None),
+            ("bytes 0-x/1000", None),
+            ("bytes 0-9/ten", None),
+            ("bytes 0-9/-1", None),
+            ("items 0-9/10", None),
+        ] {
+            assert_eq!(range(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn checks_ranges_against_bodies() {
+        let checked = |value, length| refusal(&parse_content_range(value).unwrap(), length);
+        assert_eq!(checked("bytes 0-499/1000", 500), None);
+        assert_eq!(checked("bytes 0-499/1000", 499), Some(400));
+        assert_eq!(checked("bytes 0-499/*", 501), Some(400));
+        assert_eq!(checked("bytes 999-999/1000", 1), None);
+        assert_eq!(checked("bytes 1000-1000/1000", 1), Some(416));
+        assert_eq!(checked("bytes 500-1499/1000", 1000), Some(416));
+    }
+
+    #[test]
+    fn refuses_config_files() {
+        for path in [
+            "/_redirects",
+            "//_redirects",
+            "/./_redirects",
+            "/a/../_redirects",
+            "/_redirects/",
+        ] {
+            assert!(is_config_file(path), "{path}");
+        }
+        for path in ["/a/_redirects", "/_redirects.txt", "/redirects", "/"] {
+            assert!(!is_config_file(path), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_segments_in_any_order() {
+        let path = spool::temp_path("upload-test");
+        let segments = [
+            ("bytes 6-9/10", "ghij"),
+            ("bytes 0-2/10", "abc"),
+            // Overlapping the first segment: the later write wins.
+            ("bytes 2-5/10", "CDEF"),
+        ];
+        for (value, body) in segments {
+            let range = parse_content_range(value).unwrap();
+            write(&path, Some(&range), body.as_bytes()).await.unwrap();
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"abCDEFghij");
+
+        // Without a total the file only grows; a total truncates it.
+        let range = parse_content_range("bytes 0-1/*").unwrap();
+        write(&path, Some(&range), b"AB").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"ABCDEFghij");
+        let range = parse_content_range("bytes 0-1/4").unwrap();
+        write(&path, Some(&range), b"xy").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"xyCD");
+
+        // A hole is left before a segment sent first.
+        write(&path, None, b"").await.unwrap();
+        let range = parse_content_range("bytes 3-3/*").unwrap();
+        write(&path, Some(&range), b"d").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"\0\0\0d");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
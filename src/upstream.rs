@@ -0,0 +1,151 @@
+//! Backend HTTP servers, written `http://host[:port][/path]`.
+//!
+//! Host names are resolved asynchronously and the addresses kept for
+//! `DNS_TTL`; after that the next connection resolves them again, falling
+//! back to the old addresses if the lookup fails. Connections rotate through
+//! the addresses, moving on to the next one when a connect fails.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::net::TcpStream;
+
+/// How long resolved addresses are used before the host is looked up again.
+const DNS_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    /// Path prefix prepended to forwarded request targets, without a trailing `/`.
+    pub path: String,
+    /// Addresses shared by every clone of this upstream.
+    addresses: Arc<Mutex<Option<Addresses>>>,
+    next: Arc<AtomicUsize>,
+}
+
+struct Addresses {
+    list: Vec<SocketAddr>,
+    resolved: Instant,
+}
+
+impl Upstream {
+    /// Value for the `Host` header of forwarded requests.
+    pub fn authority(&self) -> String {
+        match self.port {
+            80 => self.host.clone(),
+            port => format!("{}:{port}", self.host),
+        }
+    }
+
+    pub async fn connect(&self) -> io::Result<TcpStream> {
+        let addresses = self.resolve().await?;
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+        for offset in 0..addresses.len() {
+            let address = addresses[(start + offset) % addresses.len()];
+            match TcpStream::connect(address).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::other("no addresses")))
+    }
+
+    async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let stale = {
+            let addresses = self.addresses.lock().unwrap();
+            match addresses.as_ref() {
+                Some(addresses) if addresses.resolved.elapsed() < DNS_TTL => {
+                    return Ok(addresses.list.clone());
+                }
+                Some(addresses) => Some(addresses.list.clone()),
+                None => None,
+            }
+        };
+
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        match tokio::net::lookup_host((host, self.port)).await {
+            Ok(found) => {
+                let list: Vec<SocketAddr> = found.collect();
+                if list.is_empty() {
+                    return stale.ok_or_else(|| io::Error::other("host has no addresses"));
+                }
+                *self.addresses.lock().unwrap() = Some(Addresses {
+                    list: list.clone(),
+                    resolved: Instant::now(),
+                });
+                Ok(list)
+            }
+            Err(err) => {
+                eprintln!("cannot resolve upstream {}: {err}", self.host);
+                let list = stale.ok_or(err)?;
+                // Keep the old addresses for another TTL rather than
+                // looking the host up again on every connection.
+                *self.addresses.lock().unwrap() = Some(Addresses {
+                    list: list.clone(),
+                    resolved: Instant::now(),
+                });
+                Ok(list)
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Upstream {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Upstream, String> {
+        url.parse()
+    }
+}
+
+impl std::str::FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Upstream, String> {
+        let invalid = || format!("invalid upstream {url}, expected http://host[:port][/path]");
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Upstream {
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+            addresses: Arc::default(),
+            next: Arc::default(),
+        })
+    }
+}
+
+/// Headers that describe a single connection and are not forwarded.
+pub fn is_hop_by_hop(name: &str) -> bool {
+    [
+        "Connection",
+        "Keep-Alive",
+        "Proxy-Authenticate",
+        "Proxy-Authorization",
+        "TE",
+        "Trailer",
+        "Transfer-Encoding",
+        "Upgrade",
+    ]
+    .iter()
+    .any(|hop| hop.eq_ignore_ascii_case(name))
+}
@@ -0,0 +1,148 @@
+//! io_uring file reads, with the `io-uring` feature on Linux.
+//!
+//! A dedicated thread owns the ring: reads are queued to it over a channel,
+//! submitted in batches and completed through oneshot channels, so many
+//! concurrent reads cost a handful of `io_uring_enter` calls instead of one
+//! blocking-pool hop and `pread` each. If the kernel refuses to set up a
+//! ring, reads fall back to `pread` on the blocking pool.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::sync::mpsc;
+use std::sync::{Arc, OnceLock};
+
+use io_uring::{opcode, types, IoUring};
+use tokio::sync::oneshot;
+
+/// Submission queue size; more reads wait in the channel.
+const ENTRIES: u32 = 256;
+
+struct Read {
+    file: Arc<File>,
+    offset: u64,
+    buffer: Vec<u8>,
+    filled: usize,
+    done: oneshot::Sender<io::Result<Vec<u8>>>,
+}
+
+static RING: OnceLock<Option<mpsc::Sender<Read>>> = OnceLock::new();
+
+/// Reads `len` bytes at `offset` through the ring, or returns `None` when
+/// io_uring is unavailable.
+pub async fn read_at(file: Arc<File>, offset: u64, len: usize) -> Option<io::Result<Vec<u8>>> {
+    let ring = RING.get_or_init(start).as_ref()?;
+    let (done, result) = oneshot::channel();
+    let read = Read {
+        file,
+        offset,
+        buffer: vec![0; len],
+        filled: 0,
+        done,
+    };
+    ring.send(read).ok()?;
+    result.await.ok()
+}
+
+fn start() -> Option<mpsc::Sender<Read>> {
+    let ring = match IoUring::new(ENTRIES) {
+        Ok(ring) => ring,
+        Err(err) => {
+            eprintln!("io_uring unavailable, using the blocking pool: {err}");
+            return None;
+        }
+    };
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("io-uring".to_string())
+        .spawn(move || run(ring, receiver))
+        .ok()?;
+    Some(sender)
+}
+
+fn run(mut ring: IoUring, receiver: mpsc::Receiver<Read>) {
+    let mut in_flight: HashMap<u64, Read> = HashMap::new();
+    let mut next_id = 0u64;
+    loop {
+        if in_flight.is_empty() {
+            let Ok(read) = receiver.recv() else {
+                return;
+            };
+            submit(&mut ring, &mut in_flight, &mut next_id, read);
+        }
+        while in_flight.len() < ENTRIES as usize {
+            let Ok(read) = receiver.try_recv() else {
+                break;
+            };
+            submit(&mut ring, &mut in_flight, &mut next_id, read);
+        }
+
+        if let Err(err) = ring.submit_and_wait(1) {
+            if err.kind() != io::ErrorKind::Interrupted {
+                for (_, read) in in_flight.drain() {
+                    let _ = read
+                        .done
+                        .send(Err(io::Error::new(err.kind(), err.to_string())));
+                }
+            }
+            continue;
+        }
+
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|entry| (entry.user_data(), entry.result()))
+            .collect();
+        for (id, result) in completed {
+            let Some(mut read) = in_flight.remove(&id) else {
+                continue;
+            };
+            match result {
+                result if result < 0 => {
+                    let _ = read.done.send(Err(io::Error::from_raw_os_error(-result)));
+                }
+                0 => {
+                    read.buffer.truncate(read.filled);
+                    let _ = read.done.send(Ok(read.buffer));
+                }
+                read_bytes => {
+                    read.filled += read_bytes as usize;
+                    if read.filled == read.buffer.len() {
+                        let _ = read.done.send(Ok(read.buffer));
+                    } else {
+                        submit(&mut ring, &mut in_flight, &mut next_id, read);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Queues the unread part of `read`. The buffer is owned by `in_flight`
+/// until its completion arrives, so the kernel never writes freed memory.
+fn submit(ring: &mut IoUring, in_flight: &mut HashMap<u64, Read>, next_id: &mut u64, read: Read) {
+    if read.filled == read.buffer.len() {
+        let _ = read.done.send(Ok(read.buffer));
+        return;
+    }
+    let id = *next_id;
+    *next_id += 1;
+    let read = in_flight.entry(id).or_insert(read);
+    let remaining = &mut read.buffer[read.filled..];
+    let entry = opcode::Read::new(
+        types::Fd(read.file.as_raw_fd()),
+        remaining.as_mut_ptr(),
+        remaining.len() as u32,
+    )
+    .offset(read.offset + read.filled as u64)
+    .build()
+    .user_data(id);
+
+    // SAFETY: the buffer and file stay alive in `in_flight` until the
+    // completion for `id` is reaped.
+    while unsafe { ring.submission().push(&entry) }.is_err() {
+        if ring.submit().is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Per-user folders with `--userdir`, as in Apache's `mod_userdir`:
+//! `/~alice/notes.html` is served from `/home/alice/public_html/notes.html`
+//! for the pattern `/home/*/public_html`.
+//!
+//! The user's folder acts as the root for the rest of the path, so the
+//! usual traversal, symlink and hidden-file checks keep requests inside it.
+
+use std::io;
+use std::path::PathBuf;
+
+use crate::resolve::ResolveError;
+
+/// Request paths starting with this name a user folder.
+pub const PREFIX: &str = "/~";
+
+/// Checks an `--userdir` pattern.
+pub fn validate(pattern: &str) -> Result<(), String> {
+    match pattern.matches('*').count() {
+        1 => Ok(()),
+        _ => Err(format!(
+            "--userdir {pattern} must contain exactly one * standing for the user name"
+        )),
+    }
+}
+
+/// Maps a request path under `/~USER` to the canonical folder of USER and
+/// the path below it. Returns `None` for paths outside `/~`.
+pub async fn map<'a>(
+    pattern: &str,
+    path: &'a str,
+) -> Option<Result<(PathBuf, &'a str), ResolveError>> {
+    let rest = path.strip_prefix(PREFIX)?;
+    let (user, below) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    if !is_user_name(user) {
+        return Some(Err(ResolveError::NotFound));
+    }
+    let folder = pattern.replace('*', user);
+    Some(match tokio::fs::canonicalize(&folder).await {
+        Ok(folder) if folder.is_dir() => Ok((folder, below)),
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(ResolveError::Forbidden),
+        _ => Err(ResolveError::NotFound),
+    })
+}
+
+/// Portable user names: letters, digits, `.`, `_` and `-`, not starting with
+/// a dot, so the name can't climb out of the pattern.
+fn is_user_name(user: &str) -> bool {
+    !user.is_empty()
+        && !user.starts_with('.')
+        && user
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'_' | b'-'))
+}
@@ -0,0 +1,59 @@
+//! Virtual hosts: `--vhost blog.example.com=/srv/blog` serves requests whose
+//! `Host` is `blog.example.com` from `/srv/blog` instead of the root folder.
+//! A leading `*.` matches any subdomain; requests for hosts that match no
+//! rule are served from the root folder.
+
+use std::path::PathBuf;
+
+use crate::http::Request;
+use crate::redirect::strip_port;
+
+pub struct VirtualHost {
+    /// Lower-case host name, or `*.` and the domain its subdomains share.
+    pub name: String,
+    pub root: PathBuf,
+}
+
+impl std::str::FromStr for VirtualHost {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<VirtualHost, ()> {
+        let (name, root) = value.split_once('=').ok_or(())?;
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if name.is_empty() || name == "*." || root.is_empty() {
+            return Err(());
+        }
+        Ok(VirtualHost {
+            name,
+            root: PathBuf::from(root),
+        })
+    }
+}
+
+impl VirtualHost {
+    fn matches(&self, host: &str) -> bool {
+        match self.name.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            None => host == self.name,
+        }
+    }
+}
+
+/// Index of the host that serves `request`: an exact name before any
+/// wildcard, and among wildcards the longest domain.
+pub fn find(hosts: &[VirtualHost], request: &Request) -> Option<usize> {
+    let host = request.header("Host")?;
+    let host = strip_port(host).trim_end_matches('.').to_ascii_lowercase();
+    hosts
+        .iter()
+        .enumerate()
+        .filter(|(_, vhost)| vhost.matches(&host))
+        .max_by_key(|(index, vhost)| {
+            let exact = !vhost.name.starts_with("*.");
+            // Earlier rules win ties.
+            (exact, vhost.name.len(), std::cmp::Reverse(*index))
+        })
+        .map(|(index, _)| index)
+}
@@ -0,0 +1,43 @@
+//! Filesystem change notifications for the root folder.
+
+use std::path::{Path, PathBuf};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use tokio::sync::broadcast;
+
+/// Changes buffered per subscriber before the oldest are dropped.
+const CAPACITY: usize = 256;
+
+pub struct Watcher {
+    _watcher: RecommendedWatcher,
+    changes: broadcast::Sender<PathBuf>,
+}
+
+impl Watcher {
+    /// Watches `root` recursively, broadcasting the path of every changed file.
+    pub fn new(root: &Path) -> notify::Result<Watcher> {
+        let (changes, _) = broadcast::channel(CAPACITY);
+        let sender = changes.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+                if matches!(event.kind, EventKind::Access(_)) {
+                    return;
+                }
+                for path in event.paths {
+                    let _ = sender.send(path);
+                }
+            })?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        Ok(Watcher {
+            _watcher: watcher,
+            changes,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PathBuf> {
+        self.changes.subscribe()
+    }
+}
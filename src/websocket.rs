@@ -0,0 +1,260 @@
+//! WebSocket upgrades (RFC 6455) of HTTP/1.1 connections.
+//!
+//! Below a `--proxy` prefix the handshake is forwarded, and once the
+//! upstream agrees the two connections are joined byte for byte. Scripts
+//! run by the `websocket` handler speak in messages instead: every message
+//! the client sends is written to the script's stdin followed by a line
+//! break, and every line the script prints is sent back as a message. The
+//! connection closes when the script exits or the client leaves, which
+//! stops the script.
+
+use std::io;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Child;
+
+use crate::http::{Request, Response};
+
+/// Appended to the client's key to prove the handshake was understood.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message taken from a client, all its fragments together.
+const MAX_MESSAGE: usize = 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// Close codes.
+const NORMAL: u16 = 1000;
+const PROTOCOL_ERROR: u16 = 1002;
+const TOO_BIG: u16 = 1009;
+
+/// A connection that an upgraded response can take over.
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// What becomes of a connection once its `101 Switching Protocols` is sent.
+pub enum Upgrade {
+    /// Bytes pass both ways unchanged, to and from a proxied upstream.
+    Tunnel(Box<dyn Io>),
+    /// Messages go to and from a script, whose stdin and stdout are piped.
+    Script(Box<Child>),
+}
+
+impl Upgrade {
+    /// Serves the upgraded `client` connection until either side ends it.
+    pub async fn run<S: AsyncRead + AsyncWrite + Unpin>(self, client: &mut S) -> io::Result<()> {
+        match self {
+            Upgrade::Tunnel(mut upstream) => {
+                tokio::io::copy_bidirectional(client, &mut upstream).await?;
+                Ok(())
+            }
+            Upgrade::Script(child) => relay(client, *child).await,
+        }
+    }
+}
+
+/// Whether `request` asks to switch to the WebSocket protocol.
+pub fn is_upgrade(request: &Request) -> bool {
+    let lists = |name: &str, token: &str| {
+        request
+            .header(name)
+            .unwrap_or_default()
+            .split(',')
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    request.method == "GET"
+        && request.version == "HTTP/1.1"
+        && lists("Connection", "upgrade")
+        && lists("Upgrade", "websocket")
+}
+
+/// The `101` completing the handshake `request` starts, to be given its
+/// `upgrade`, or the error answering a request that isn't one.
+pub fn handshake(request: &Request) -> Result<Response, Response> {
+    if !is_upgrade(request) {
+        let mut response = Response::error(426);
+        response.set_header("Upgrade", "websocket");
+        return Err(response);
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        let mut response = Response::error(426);
+        response.set_header("Sec-WebSocket-Version", "13");
+        return Err(response);
+    }
+    let key = request.header("Sec-WebSocket-Key").unwrap_or_default();
+    if STANDARD.decode(key).map_or(true, |nonce| nonce.len() != 16) {
+        return Err(Response::error(400));
+    }
+    let hash = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{key}{GUID}").as_bytes(),
+    );
+    let mut response = Response::new(101, "", Vec::new());
+    response.headers.clear();
+    response.set_header("Upgrade", "websocket");
+    response.set_header("Sec-WebSocket-Accept", STANDARD.encode(hash));
+    Ok(response)
+}
+
+/// A frame from the client, unmasked.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Passes messages between `client` and the script `child` until one of
+/// them is done.
+async fn relay<S: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut S,
+    mut child: Child,
+) -> io::Result<()> {
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    // Both reads are only ever appended to, so either may be cut short when
+    // the other one finishes first.
+    let mut received = Vec::new();
+    let mut printed = Vec::new();
+    let mut message: Option<Vec<u8>> = None;
+    loop {
+        tokio::select! {
+            read = client.read_buf(&mut received) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+                loop {
+                    let frame = match parse_frame(&mut received) {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(code) => return close(client, code).await,
+                    };
+                    match frame.opcode {
+                        TEXT | BINARY if message.is_none() => message = Some(frame.payload),
+                        CONTINUATION if message.is_some() => {
+                            let so_far = message.as_mut().unwrap();
+                            if so_far.len() + frame.payload.len() > MAX_MESSAGE {
+                                return close(client, TOO_BIG).await;
+                            }
+                            so_far.extend_from_slice(&frame.payload);
+                        }
+                        PING => write_frame(client, PONG, &frame.payload).await?,
+                        PONG => {}
+                        CLOSE => {
+                            let code = frame.payload.get(..2).map_or(NORMAL, |code| {
+                                u16::from_be_bytes([code[0], code[1]])
+                            });
+                            return close(client, code).await;
+                        }
+                        _ => return close(client, PROTOCOL_ERROR).await,
+                    }
+                    if frame.fin && frame.opcode < CLOSE {
+                        let mut line = message.take().unwrap_or_default();
+                        line.push(b'\n');
+                        stdin.write_all(&line).await?;
+                    }
+                }
+            }
+            read = stdout.read_buf(&mut printed) => {
+                let done = read? == 0;
+                while let Some(end) = printed.iter().position(|&byte| byte == b'\n') {
+                    let line: Vec<u8> = printed.drain(..=end).collect();
+                    send_line(client, &line[..end]).await?;
+                }
+                if done {
+                    if !printed.is_empty() {
+                        send_line(client, &printed).await?;
+                    }
+                    return close(client, NORMAL).await;
+                }
+            }
+        }
+    }
+}
+
+/// Takes the next complete frame off the start of `buffer`, or the close
+/// code that the frame there calls for.
+fn parse_frame(buffer: &mut Vec<u8>) -> Result<Option<Frame>, u16> {
+    let [first, second, ..] = buffer[..] else {
+        return Ok(None);
+    };
+    let (fin, opcode) = (first & 0x80 != 0, first & 0x0f);
+    // Clients must mask what they send, and no extension was agreed on.
+    if second & 0x80 == 0 || first & 0x70 != 0 {
+        return Err(PROTOCOL_ERROR);
+    }
+    let (length, start) = match second & 0x7f {
+        126 if buffer.len() >= 4 => (u64::from(u16::from_be_bytes([buffer[2], buffer[3]])), 4),
+        127 if buffer.len() >= 10 => (u64::from_be_bytes(buffer[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        length => (u64::from(length), 2),
+    };
+    if opcode >= CLOSE && (!fin || length > 125) {
+        return Err(PROTOCOL_ERROR);
+    }
+    if length > MAX_MESSAGE as u64 {
+        return Err(TOO_BIG);
+    }
+    let end = start + 4 + length as usize;
+    if buffer.len() < end {
+        return Ok(None);
+    }
+    let mask: [u8; 4] = buffer[start..start + 4].try_into().unwrap();
+    let payload = buffer[start + 4..end]
+        .iter()
+        .enumerate()
+        .map(|(index, byte)| byte ^ mask[index % 4])
+        .collect();
+    buffer.drain(..end);
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload,
+    }))
+}
+
+/// Sends a line the script printed, as text unless it isn't UTF-8.
+async fn send_line<S: AsyncWrite + Unpin>(client: &mut S, line: &[u8]) -> io::Result<()> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let opcode = match std::str::from_utf8(line) {
+        Ok(_) => TEXT,
+        Err(_) => BINARY,
+    };
+    write_frame(client, opcode, line).await
+}
+
+/// Ends the connection with a close frame carrying `code`.
+async fn close<S: AsyncWrite + Unpin>(client: &mut S, code: u16) -> io::Result<()> {
+    write_frame(client, CLOSE, &code.to_be_bytes()).await
+}
+
+/// Sends one unfragmented frame; servers don't mask theirs.
+async fn write_frame<S: AsyncWrite + Unpin>(
+    client: &mut S,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    client.write_all(&frame).await?;
+    client.flush().await
+}
@@ -0,0 +1,87 @@
+//! Built-in answers for paths that browsers and scanners ask every site for.
+
+use serde::Deserialize;
+
+use crate::http::Response;
+
+pub const FAVICON_PATH: &str = "/favicon.ico";
+pub const SECURITY_TXT_PATH: &str = "/.well-known/security.txt";
+
+const DEFAULT_FAVICON: &[u8] = include_bytes!("../static/favicon.ico");
+
+/// What to answer for `/favicon.ico` when the root folder has none.
+#[derive(Clone, Copy)]
+pub enum FallbackFavicon {
+    /// A small embedded icon.
+    Icon,
+    /// `204 No Content`.
+    Empty,
+}
+
+impl std::str::FromStr for FallbackFavicon {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<FallbackFavicon, ()> {
+        match value {
+            "icon" => Ok(FallbackFavicon::Icon),
+            "empty" => Ok(FallbackFavicon::Empty),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn favicon(fallback: FallbackFavicon) -> Response {
+    let mut response = match fallback {
+        FallbackFavicon::Icon => Response::new(200, "image/x-icon", DEFAULT_FAVICON),
+        FallbackFavicon::Empty => Response::new(204, "image/x-icon", Vec::new()),
+    };
+    response.set_header("Cache-Control", "public, max-age=86400");
+    response
+}
+
+/// Fields of an RFC 9116 security.txt, from the `[security_txt]` config table.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityTxt {
+    pub contact: Vec<String>,
+    pub expires: String,
+    #[serde(default)]
+    pub encryption: Vec<String>,
+    #[serde(default)]
+    pub acknowledgments: Vec<String>,
+    pub policy: Option<String>,
+    pub hiring: Option<String>,
+    pub canonical: Option<String>,
+    pub preferred_languages: Option<String>,
+}
+
+impl SecurityTxt {
+    pub fn response(&self) -> Response {
+        let mut text = String::new();
+        let mut field = |name: &str, value: &str| {
+            text.push_str(&format!("{name}: {value}\n"));
+        };
+        for contact in &self.contact {
+            field("Contact", contact);
+        }
+        field("Expires", &self.expires);
+        for encryption in &self.encryption {
+            field("Encryption", encryption);
+        }
+        for acknowledgments in &self.acknowledgments {
+            field("Acknowledgments", acknowledgments);
+        }
+        let optional = [
+            ("Policy", &self.policy),
+            ("Hiring", &self.hiring),
+            ("Canonical", &self.canonical),
+            ("Preferred-Languages", &self.preferred_languages),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                field(name, value);
+            }
+        }
+        Response::new(200, "text/plain; charset=utf-8", text)
+    }
+}
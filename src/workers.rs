@@ -0,0 +1,241 @@
+//! Long-lived processes for hot scripts, with the `worker` handler, as in
+//! `handlers = { py = "worker" }`: rather than a process per request, up to
+//! `--workers` processes are kept per script, each answering request after
+//! request until it has served `--worker-requests` of them.
+//!
+//! Workers start like CGI scripts, in the script's folder and with the
+//! server's share of the environment, but read their requests from stdin:
+//! a 4-byte big-endian length and the request's variables as `NAME=VALUE`
+//! entries, each ended by a NUL byte, then a length and the body. They
+//! answer on stdout with a length and what a CGI script would print: a
+//! header block, an empty line and the body. A worker that exits, breaks
+//! the protocol or outlives the script timeout is killed, and its request
+//! answered with `502` or `504`; the next request starts a new one.
+//!
+//! Each shard keeps pools of its own, and responses are read whole before
+//! they are sent.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::Semaphore;
+
+use crate::config::Config;
+use crate::fdlimit;
+use crate::http::{Request, Response};
+use crate::kv::KvStore;
+use crate::scripts::{self, Group, Script};
+
+/// Largest response taken from a worker.
+const MAX_OUTPUT: usize = 64 * 1024 * 1024;
+
+/// Time a worker being replaced has to exit once its stdin is closed.
+const EXIT_GRACE: Duration = Duration::from_secs(1);
+
+/// The pools of every script run by the `worker` handler, by path.
+#[derive(Default)]
+pub struct Pools {
+    pools: Mutex<HashMap<PathBuf, Arc<Pool>>>,
+}
+
+struct Pool {
+    /// One permit per worker that may be busy at once.
+    permits: Semaphore,
+    idle: Mutex<Vec<Worker>>,
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    group: Group,
+    /// Requests answered so far.
+    served: u64,
+}
+
+/// Why a request got no answer from a worker.
+enum Failure {
+    /// No worker could be started.
+    Start(io::Error),
+    /// The worker broke off or sent something other than a response.
+    Exchange(io::Error),
+}
+
+impl Pools {
+    /// Has one of the workers of `script` answer `request`, waiting for one
+    /// to be free if all of them are busy. A worker still busy after
+    /// `timeout` is killed and the request answered with `504`.
+    pub async fn run(
+        &self,
+        script: &Script<'_>,
+        request: &Request,
+        config: &Config,
+        kv: Option<&KvStore>,
+        timeout: Option<Duration>,
+    ) -> Response {
+        let response = self.render(script, request, config, kv, timeout).await;
+        scripts::revalidate(request, response)
+    }
+
+    /// Has a worker answer like `run`, but leaves its response as the
+    /// worker wrote it rather than turn it into a `304`.
+    pub async fn render(
+        &self,
+        script: &Script<'_>,
+        request: &Request,
+        config: &Config,
+        kv: Option<&KvStore>,
+        timeout: Option<Duration>,
+    ) -> Response {
+        let form = match scripts::read_form(request, config).await {
+            Ok(form) => form,
+            Err(response) => return response,
+        };
+        let mut message = Vec::new();
+        for (name, value) in scripts::variables(script, request, config, kv, &form) {
+            // Not representable in the environment of a CGI script either.
+            if name.contains('\0') || value.contains('\0') {
+                continue;
+            }
+            message.extend_from_slice(format!("{name}={value}\0").as_bytes());
+        }
+        let (Ok(variables), Ok(body)) = (
+            u32::try_from(message.len()),
+            u32::try_from(request.body.len()),
+        ) else {
+            return Response::error(413);
+        };
+        let mut head = variables.to_be_bytes().to_vec();
+        head.append(&mut message);
+        head.extend_from_slice(&body.to_be_bytes());
+
+        let pool = self.pool(script.path, config.workers);
+        let _permit = pool.permits.acquire().await.expect("never closed");
+        let exchanging = exchange(&pool, script, config, &head, &request.body);
+        let exchanged = match timeout {
+            Some(limit) => match tokio::time::timeout(limit, exchanging).await {
+                Ok(exchanged) => exchanged,
+                // The worker was dropped along with the exchange, which
+                // killed it.
+                Err(_) => return scripts::timed_out(script.path, limit),
+            },
+            None => exchanging.await,
+        };
+        let output = match exchanged {
+            Ok((mut worker, output)) => {
+                worker.served += 1;
+                match config.worker_requests == 0 || worker.served < config.worker_requests {
+                    true => pool.idle.lock().unwrap().push(worker),
+                    false => worker.retire(),
+                }
+                output
+            }
+            Err(Failure::Start(err)) => return fdlimit::error_response(&err),
+            Err(Failure::Exchange(err)) => {
+                eprintln!("worker {} failed: {err}", script.path.display());
+                return Response::error(502);
+            }
+        };
+        scripts::parse_output(&output)
+    }
+
+    /// The pool of the script at `path`, created with `size` permits on its
+    /// first request.
+    fn pool(&self, path: &Path, size: usize) -> Arc<Pool> {
+        let mut pools = self.pools.lock().unwrap();
+        let pool = pools.entry(path.to_path_buf()).or_insert_with(|| {
+            Arc::new(Pool {
+                permits: Semaphore::new(size),
+                idle: Mutex::new(Vec::new()),
+            })
+        });
+        pool.clone()
+    }
+}
+
+/// Sends the request, `head` then `body`, to an idle worker of `pool` or a
+/// new one, and reads its response. A worker that served earlier requests
+/// may have exited since; if it can't take the request a new one does.
+async fn exchange(
+    pool: &Pool,
+    script: &Script<'_>,
+    config: &Config,
+    head: &[u8],
+    body: &[u8],
+) -> Result<(Worker, Vec<u8>), Failure> {
+    let idle = pool.idle.lock().unwrap().pop();
+    let mut worker = match idle {
+        Some(worker) => worker,
+        None => Worker::start(script, config).map_err(Failure::Start)?,
+    };
+    if let Err(err) = worker.send(head, body).await {
+        if worker.served == 0 {
+            return Err(Failure::Exchange(err));
+        }
+        worker = Worker::start(script, config).map_err(Failure::Start)?;
+        worker.send(head, body).await.map_err(Failure::Exchange)?;
+    }
+    let output = worker.receive().await.map_err(Failure::Exchange)?;
+    Ok((worker, output))
+}
+
+impl Worker {
+    fn start(script: &Script<'_>, config: &Config) -> io::Result<Worker> {
+        let mut command = scripts::base_command(script, config);
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let mut child = command.spawn()?;
+        Ok(Worker {
+            stdin: child.stdin.take().expect("stdin is piped"),
+            stdout: child.stdout.take().expect("stdout is piped"),
+            group: Group(child.id()),
+            child,
+            served: 0,
+        })
+    }
+
+    async fn send(&mut self, head: &[u8], body: &[u8]) -> io::Result<()> {
+        self.stdin.write_all(head).await?;
+        self.stdin.write_all(body).await?;
+        self.stdin.flush().await
+    }
+
+    async fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.stdout.read_u32().await?;
+        let length = usize::try_from(length).unwrap_or(usize::MAX);
+        if length > MAX_OUTPUT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("response of {length} bytes"),
+            ));
+        }
+        let mut output = vec![0; length];
+        self.stdout.read_exact(&mut output).await?;
+        Ok(output)
+    }
+
+    /// Closes the worker's stdin, its sign to exit, and kills it if it
+    /// hasn't within `EXIT_GRACE`.
+    fn retire(self) {
+        let Worker {
+            mut child,
+            stdin,
+            mut group,
+            ..
+        } = self;
+        drop(stdin);
+        tokio::spawn(async move {
+            if let Ok(Ok(_)) = tokio::time::timeout(EXIT_GRACE, child.wait()).await {
+                group.disarm();
+            }
+        });
+    }
+}